@@ -9,3 +9,13 @@ kubectl apply -f bitcoin-node/service.yaml
 kubectl apply -f stacks-node/configmap.yaml
 kubectl apply -f stacks-node/statefulset.yaml
 kubectl apply -f stacks-node/service.yaml
+
+# Configure and boot an event-observer, with the bitcoin-node RPC credentials
+# provided through BITCOIN_RPC_USERNAME and BITCOIN_RPC_PASSWORD
+kubectl create secret generic event-observer-bitcoin-rpc \
+  --from-literal=username="${BITCOIN_RPC_USERNAME:-admin}" \
+  --from-literal=password="${BITCOIN_RPC_PASSWORD:-password}" \
+  --dry-run=client -o yaml | kubectl apply -f -
+kubectl apply -f event-observer/configmap.yaml
+kubectl apply -f event-observer/statefulset.yaml
+kubectl apply -f event-observer/service.yaml
@@ -10,3 +10,9 @@ kubectl delete service bitcoin-node
 # kubectl delete statefulset stacks
 # kubectl delete service stacks
 
+
+# Shutdown the event-observer
+kubectl delete configmap event-observer-config
+kubectl delete secret event-observer-bitcoin-rpc
+kubectl delete statefulset event-observer
+kubectl delete service event-observer
@@ -9,7 +9,7 @@ use serde_json::json;
 use std::collections::{BTreeMap, HashSet};
 use std::net::TcpListener;
 use std::path::PathBuf;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::thread;
 use std::time;
 
@@ -24,13 +24,14 @@ use orchestra_lib::clarinet_lib::types::events::{
   NFTTransferEventData, StacksTransactionEvent,
 };
 use orchestra_lib::clarinet_lib::types::{
-  BitcoinBlockData, BitcoinBlockMetadata, BitcoinChainEvent, BlockIdentifier,
+  BitcoinBlockData, BitcoinBlockMetadata, BitcoinChainEvent, BlockIdentifier, ChainConfig,
   ChainUpdatedWithBlockData, ChainsCoordinatorCommand, DevnetConfigFile, StacksBlockData,
   StacksChainEvent, StacksContractDeploymentData, StacksTransactionData, StacksTransactionKind,
   StacksTransactionMetadata, StacksTransactionReceipt, TransactionIdentifier,
 };
 use orchestra_lib::types::{
   Contract, FieldValues, FieldValuesRequest, ProtocolObserverConfig, ProtocolObserverId,
+  RequestError, WalletStateRequest, WalletStateResponse,
 };
 
 use orchestra_lib::datastore::StorageDriver;
@@ -40,6 +41,9 @@ use tungstenite::{
   Message,
 };
 
+/// Longest wait for the state of a wallet, which is indexed when first watched.
+const WALLET_STATE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct PollState {
   protocol_id: u64,
@@ -96,11 +100,23 @@ pub enum StateExplorerWatchTarget {
 pub struct ContractFieldData {
   contract_identifier: String,
   field_name: String,
+  #[serde(default)]
+  at_block_identifier: Option<BlockIdentifier>,
+  #[serde(default)]
+  page_size: u16,
+  #[serde(default)]
+  entries_cursor: Option<String>,
+  #[serde(default)]
+  events_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct WalletData {
   address: String,
+  #[serde(default)]
+  page_size: u16,
+  #[serde(default)]
+  activity_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
@@ -110,6 +126,7 @@ pub enum NetworkResponse {
   StateExplorerInitialization(StateExplorerInitializationUpdate),
   StateExplorerSync(StateExplorerSyncUpdate),
   StateExplorerWatch(StateExplorerWatchUpdate),
+  StateExplorerWalletWatch(WalletStateResponse),
   Noop(NoopUpdate),
   Error(String),
 }
@@ -261,7 +278,15 @@ pub fn run_backend(
           NetworkRequest::OpenProtocol(state) => {
             if protocol_observer_config.is_none() {
               let (config, contracts) =
-                config_and_interface_from_clarinet_manifest_path(&state.manifest_path);
+                match config_and_interface_from_clarinet_manifest_path(&state.manifest_path) {
+                  Ok(result) => result,
+                  Err(message) => {
+                    backend_cmd_tx
+                      .send(BackendCommand::FatalError(message))
+                      .unwrap();
+                    continue;
+                  }
+                };
 
               let protocol_name = config.project.name.clone();
               let update = OpenProtocolUpdate {
@@ -480,22 +505,45 @@ pub fn run_backend(
                       contract_identifier: field.contract_identifier.clone(),
                       field_name: field.field_name.clone(),
                       stacks_block_identifier: watch_state.stacks_block_identifier.clone(),
+                      at_block_identifier: field.at_block_identifier.clone(),
+                      page_size: field.page_size,
+                      entries_cursor: field.entries_cursor.clone(),
+                      events_cursor: field.events_cursor.clone(),
                     },
                   ))
                   .expect("Unable to communicate with backend");
                 match rx.recv() {
-                  Ok(response) => NetworkResponse::StateExplorerWatch(StateExplorerWatchUpdate {
+                  Ok(Ok(response)) => NetworkResponse::StateExplorerWatch(StateExplorerWatchUpdate {
                     stacks_blocks: response.stacks_blocks,
                     bitcoin_blocks: response.bitcoin_blocks,
                     contract_identifier: response.contract_identifier.clone(),
                     field_name: response.field_name.clone(),
                     field_values: response.values.clone(),
                   }),
+                  Ok(Err(err)) => NetworkResponse::Error(err.to_string()),
                   Err(err) => NetworkResponse::Error(format!("{}", err.to_string())),
                 }
               }
               StateExplorerWatchTarget::Wallet(wallet) => {
-                unreachable!()
+                let (tx, rx) = channel();
+
+                let supervisor_tx = match supervisor_tx {
+                  Some(ref supervisor_tx) => supervisor_tx,
+                  _ => panic!("Boot sequence issue"),
+                };
+
+                supervisor_tx
+                  .send(OrchestraSupervisorMessage::GetWalletState(
+                    WalletStateRequest {
+                      tx,
+                      principal: wallet.address.clone(),
+                      protocol_id: state.protocol_id,
+                      page_size: wallet.page_size,
+                      activity_cursor: wallet.activity_cursor.clone(),
+                    },
+                  ))
+                  .expect("Unable to communicate with backend");
+                wallet_state_response(rx, &wallet.address)
               }
             }
           }
@@ -513,19 +561,14 @@ pub fn run_backend(
 
 pub fn config_and_interface_from_clarinet_manifest_path(
   manifest_path: &str,
-) -> (ProtocolObserverConfig, Vec<Contract>) {
-  use orchestra_lib::clarinet_lib::clarity_repl::clarity::types::QualifiedContractIdentifier;
-  use orchestra_lib::types::{ContractSettings, ProjectMetadata, ProtocolObserverId};
-
-  let manifest_path = PathBuf::from(manifest_path);
-
-  let (mut session_settings, _, mut project_config) =
-    load_session_settings(&manifest_path, &StacksNetwork::Devnet).unwrap();
+) -> Result<(ProtocolObserverConfig, Vec<Contract>), String> {
+  let (orchestra_manifest, mut session_settings) =
+    config_from_clarinet_manifest_path(manifest_path)?;
 
   // todo(ludo)
   session_settings.include_boot_contracts = vec!["costs-v2".to_string()];
 
-  let mut session = Session::new(session_settings.clone());
+  let mut session = Session::new(session_settings);
   let analysis = match session.start() {
     Ok((res, analysis)) => analysis,
     Err(e) => panic!(),
@@ -539,59 +582,38 @@ pub fn config_and_interface_from_clarinet_manifest_path(
     })
     .collect::<_>();
 
-  let mut observed_contracts = Vec::new();
-  for contract in session_settings.initial_contracts.iter() {
-    let contract_id = QualifiedContractIdentifier::parse(&format!(
-      "{}.{}",
-      session_settings.initial_deployer.clone().unwrap().address,
-      contract.name.clone().unwrap()
-    ))
-    .unwrap();
-
-    observed_contracts.push((
-      contract_id,
-      ContractSettings {
-        state_explorer_enabled: true,
-        api_generator_enabled: vec![],
-      },
-    ));
-  }
-
-  let orchestra_manifest = ProtocolObserverConfig {
-    identifier: ProtocolObserverId(1),
-    project: ProjectMetadata {
-      name: project_config.project.name,
-      authors: vec![],
-      homepage: "".into(),
-      license: "".into(),
-      description: "".into(),
-    },
-    lambdas: vec![],
-    contracts: observed_contracts,
-    manifest_path,
-  };
-  (orchestra_manifest, interfaces)
+  Ok((orchestra_manifest, interfaces))
 }
 
+/// Loads the config of the protocol observer, lambdas included, from a
+/// Clarinet manifest.
 pub fn config_from_clarinet_manifest_path(
   manifest_path: &str,
-) -> (ProtocolObserverConfig, SessionSettings) {
+) -> Result<(ProtocolObserverConfig, SessionSettings), String> {
   use orchestra_lib::clarinet_lib::clarity_repl::clarity::types::QualifiedContractIdentifier;
-  use orchestra_lib::types::{ContractSettings, ProjectMetadata};
+  use orchestra_lib::types::{load_lambdas_from_manifest, ContractSettings, ProjectMetadata};
 
   let manifest_path = PathBuf::from(manifest_path);
 
-  let (session_settings, _, project_config) =
-    load_session_settings(&manifest_path, &StacksNetwork::Devnet).unwrap();
+  let (session_settings, chain_config, project_config) =
+    load_session_settings(&manifest_path, &StacksNetwork::Devnet)
+      .map_err(|e| format!("unable to load manifest {:?}: {}", manifest_path, e))?;
 
+  let deployer = match session_settings.initial_deployer {
+    Some(ref deployer) => deployer.address.clone(),
+    None => return Err(format!("unable to load manifest {:?}: no deployer", manifest_path)),
+  };
   let mut observed_contracts = Vec::new();
   for contract in session_settings.initial_contracts.iter() {
-    let contract_id = QualifiedContractIdentifier::parse(&format!(
-      "{}.{}",
-      session_settings.initial_deployer.clone().unwrap().address,
-      contract.name.clone().unwrap()
-    ))
-    .unwrap();
+    let contract_name = match contract.name {
+      Some(ref contract_name) => contract_name,
+      None => {
+        return Err(format!("unable to load manifest {:?}: unnamed contract", manifest_path))
+      }
+    };
+    let contract_id =
+      QualifiedContractIdentifier::parse(&format!("{}.{}", deployer, contract_name))
+        .map_err(|e| format!("unable to load contract {}: {:?}", contract_name, e))?;
 
     observed_contracts.push((
       contract_id,
@@ -602,6 +624,8 @@ pub fn config_from_clarinet_manifest_path(
     ));
   }
 
+  let lambdas = load_lambdas_from_manifest(&manifest_path)?;
+
   let orchestra_manifest = ProtocolObserverConfig {
     identifier: ProtocolObserverId(1),
     project: ProjectMetadata {
@@ -611,11 +635,36 @@ pub fn config_from_clarinet_manifest_path(
       license: "".into(),
       description: project_config.project.description.clone(),
     },
-    lambdas: vec![],
+    lambdas,
     contracts: observed_contracts,
     manifest_path: manifest_path,
+    stacks_node_rpc_url: stacks_node_rpc_url(&chain_config),
   };
-  (orchestra_manifest, session_settings)
+  Ok((orchestra_manifest, session_settings))
+}
+
+/// Waits for the state of the wallet `address`, without blocking the backend
+/// past `WALLET_STATE_TIMEOUT`.
+fn wallet_state_response(
+  rx: Receiver<Result<WalletStateResponse, RequestError>>,
+  address: &str,
+) -> NetworkResponse {
+  match rx.recv_timeout(WALLET_STATE_TIMEOUT) {
+    Ok(Ok(response)) => NetworkResponse::StateExplorerWalletWatch(response),
+    Ok(Err(err)) => NetworkResponse::Error(err.to_string()),
+    Err(RecvTimeoutError::Timeout) => {
+      NetworkResponse::Error(format!("timed out waiting for the state of wallet {}", address))
+    }
+    Err(err) => NetworkResponse::Error(err.to_string()),
+  }
+}
+
+/// RPC endpoint of the devnet stacks node, as configured in the project.
+fn stacks_node_rpc_url(chain_config: &ChainConfig) -> Option<String> {
+  chain_config
+    .devnet
+    .as_ref()
+    .map(|devnet| format!("http://localhost:{}", devnet.stacks_node_rpc_port))
 }
 
 pub fn run_frontend(
@@ -767,7 +816,15 @@ pub fn mock_backend(
             NetworkRequest::OpenProtocol(state) => {
               if protocol_observer_config.is_none() {
                 let (config, contracts) =
-                  config_and_interface_from_clarinet_manifest_path(&state.manifest_path);
+                  match config_and_interface_from_clarinet_manifest_path(&state.manifest_path) {
+                    Ok(result) => result,
+                    Err(message) => {
+                      backend_cmd_tx
+                        .send(BackendCommand::FatalError(message))
+                        .unwrap();
+                      continue;
+                    }
+                  };
 
                 let protocol_name = config.project.name.clone();
                 let update = OpenProtocolUpdate {
@@ -787,7 +844,15 @@ pub fn mock_backend(
               if !network_booted {
                 network_booted = true;
                 let (config, settings) =
-                  config_from_clarinet_manifest_path(&boot_state.manifest_path);
+                  match config_from_clarinet_manifest_path(&boot_state.manifest_path) {
+                    Ok(result) => result,
+                    Err(message) => {
+                      backend_cmd_tx
+                        .send(BackendCommand::FatalError(message))
+                        .unwrap();
+                      continue;
+                    }
+                  };
 
                 let mut transactions = vec![];
                 for contract in settings.initial_contracts.iter() {
@@ -857,7 +922,10 @@ pub fn mock_backend(
                     tx,
                   ))
                   .unwrap();
-                let response = rx.recv().unwrap();
+                let response = rx
+                  .recv()
+                  .unwrap()
+                  .expect("Unable to retrieve protocol interfaces");
 
                 let new_block = StacksBlockData {
                   block_identifier: block_identifier(1),
@@ -902,11 +970,14 @@ pub fn mock_backend(
                   tx,
                 ))
                 .unwrap();
-              let response = rx.recv().unwrap();
-
-              NetworkResponse::StateExplorerInitialization(StateExplorerInitializationUpdate {
-                contracts: response.contracts,
-              })
+              match rx.recv().unwrap() {
+                Ok(response) => {
+                  NetworkResponse::StateExplorerInitialization(StateExplorerInitializationUpdate {
+                    contracts: response.contracts,
+                  })
+                }
+                Err(err) => NetworkResponse::Error(err.to_string()),
+              }
             }
             NetworkRequest::StateExplorerSync(state) => {
               let bitcoin_chain_tip =
@@ -933,20 +1004,35 @@ pub fn mock_backend(
                       contract_identifier: field.contract_identifier.clone(),
                       field_name: field.field_name.clone(),
                       stacks_block_identifier: watch_state.stacks_block_identifier.clone(),
+                      at_block_identifier: field.at_block_identifier.clone(),
+                      page_size: field.page_size,
+                      entries_cursor: field.entries_cursor.clone(),
+                      events_cursor: field.events_cursor.clone(),
                     },
                   ));
-                  let response = rx.recv().unwrap();
-
-                  NetworkResponse::StateExplorerWatch(StateExplorerWatchUpdate {
-                    stacks_blocks: response.stacks_blocks.clone(),
-                    bitcoin_blocks: response.bitcoin_blocks.clone(),
-                    contract_identifier: response.contract_identifier.clone(),
-                    field_name: response.field_name.clone(),
-                    field_values: response.values.clone(),
-                  })
+                  match rx.recv().unwrap() {
+                    Ok(response) => NetworkResponse::StateExplorerWatch(StateExplorerWatchUpdate {
+                      stacks_blocks: response.stacks_blocks.clone(),
+                      bitcoin_blocks: response.bitcoin_blocks.clone(),
+                      contract_identifier: response.contract_identifier.clone(),
+                      field_name: response.field_name.clone(),
+                      field_values: response.values.clone(),
+                    }),
+                    Err(err) => NetworkResponse::Error(err.to_string()),
+                  }
                 }
                 StateExplorerWatchTarget::Wallet(wallet) => {
-                  unreachable!()
+                  let (tx, rx) = channel();
+                  frontend_commands_supervisor_tx.send(OrchestraSupervisorMessage::GetWalletState(
+                    WalletStateRequest {
+                      tx,
+                      principal: wallet.address.clone(),
+                      protocol_id: state.protocol_id,
+                      page_size: wallet.page_size,
+                      activity_cursor: wallet.activity_cursor.clone(),
+                    },
+                  ));
+                  wallet_state_response(rx, &wallet.address)
                 }
               }
             }
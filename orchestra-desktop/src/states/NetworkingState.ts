@@ -23,6 +23,10 @@ export type StateExplorerStateUpdateWatch = Record<
   "StateExplorerWatch",
   StateExplorerStateUpdateWatchData
 >;
+export type StateExplorerStateUpdateWalletWatch = Record<
+  "StateExplorerWalletWatch",
+  WalletTargetUpdate
+>;
 export type StateExplorerStateUpdateInit = Record<
   "StateExplorerInitialization",
   StateExplorerStateUpdateInitData
@@ -34,6 +38,7 @@ export type FatalError = Record<"FatalError", String>;
 export interface StateExplorerStateUpdate {
   update:
     | StateExplorerStateUpdateWatch
+    | StateExplorerStateUpdateWalletWatch
     | StateExplorerStateUpdateInit
     | BootNetwork
     | OpenProtocol
@@ -118,6 +123,10 @@ export type WatchedTarget = Record<
 export interface ContractFieldTarget {
   contract_identifier: string;
   field_name: string;
+  at_block_identifier?: BlockIdentifier;
+  page_size?: number;
+  entries_cursor?: string;
+  events_cursor?: string;
 }
 
 export interface ContractFieldTargetUpdate {
@@ -248,37 +257,38 @@ export interface VarValuesData {
   value_type: ClarityAbiType;
   events: Array<DataVarSetEventFormattedValue>;
   events_page_size: number;
-  events_page_index: number;
+  events_next_cursor: string | null;
 }
 
 export interface MapValuesData {
   entries: Array<[[string, string], BlockIdentifier, TransactionIdentifier]>;
   entries_page_size: number;
-  entries_page_index: number;
+  entries_next_cursor: string | null;
   key_type: ClarityAbiType;
   value_type: ClarityAbiType;
   events: Array<MapInsertEvent | MapUpdateEvent | MapDeleteEvent>;
   events_page_size: number;
-  events_page_index: number;
+  events_next_cursor: string | null;
 }
 
 export interface NftValuesData {
   tokens: Array<[[string, string], BlockIdentifier, TransactionIdentifier]>;
   tokens_page_size: number;
-  tokens_page_index: number;
+  tokens_next_cursor: string | null;
   token_type: any;
   events: Array<NftMintEvent | NftTransferEvent | NftBurnEvent>;
   events_page_size: number;
-  events_page_index: number;
+  events_next_cursor: string | null;
 }
 
 export interface FtValuesData {
   balances: Array<[[string, string], BlockIdentifier, TransactionIdentifier]>;
   balances_page_size: number;
-  balances_page_index: number;
+  balances_next_cursor: string | null;
+  total_supply: string | null;
   events: Array<FtMintEvent | FtTransferEvent | FtBurnEvent>;
   events_page_size: number;
-  events_page_index: number;
+  events_next_cursor: string | null;
 }
 
 export interface ContractFieldVarUpdate {
@@ -296,16 +306,40 @@ export interface ContractFieldNFTUpdate {}
 
 export interface WalletTarget {
   address: string;
+  page_size?: number;
+  activity_cursor?: string;
 }
 
 export interface WalletTargetUpdate {
-  address: string;
+  principal: string;
+  block_identifier: BlockIdentifier | null;
+  balances: Array<WalletBalance>;
+  nfts: Array<WalletNft>;
+  activity: Array<WalletActivity>;
+  activity_page_size: number;
+  activity_next_cursor: string | null;
+}
+
+export interface WalletBalance {
+  asset_class_identifier: string;
+  balance: string;
+}
+
+export interface WalletNft {
+  asset_class_identifier: string;
+  hex_asset_identifier: string;
+}
+
+export interface WalletActivity {
+  event: Record<string, any>;
+  block_identifier: BlockIdentifier;
+  transaction_identifier: TransactionIdentifier;
 }
 
 export enum TargetType {
   // Contract = "Contract",
   ContractField = "ContractField",
-  // Wallet = "Wallet",
+  Wallet = "Wallet",
 }
 
 export interface StateExplorerWatchState {
@@ -0,0 +1,262 @@
+use crate::config::{ConfigFile, DEFAULT_CONFIG_PATH};
+use crate::indexer::Indexer;
+use crate::observer::{self, Event, ObserverEvent, StacksEventObserverConfig};
+use crate::recorder;
+use crate::utils;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc::channel;
+
+#[derive(Parser, Debug)]
+#[clap(name = "orchestra-event-observer", author, version, about)]
+struct Opts {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, PartialEq, Clone, Debug)]
+enum Command {
+    /// Start the event observer
+    #[clap(name = "run")]
+    Run(RunCommand),
+    /// Replay a capture of stacks-node payloads through the indexer
+    #[clap(name = "replay")]
+    Replay(ReplayCommand),
+    /// Check the config file and the connectivity with the nodes
+    #[clap(name = "check")]
+    Check(CheckCommand),
+}
+
+#[derive(Parser, PartialEq, Clone, Debug)]
+struct RunCommand {
+    /// Path to the config file
+    #[clap(long = "config", short = 'c')]
+    pub config_path: Option<String>,
+    /// Append every payload received to a capture file
+    #[clap(long = "capture")]
+    pub capture_path: Option<String>,
+}
+
+#[derive(Parser, PartialEq, Clone, Debug)]
+struct ReplayCommand {
+    /// Path to the capture file
+    pub capture_path: String,
+    /// Path to the config file
+    #[clap(long = "config", short = 'c')]
+    pub config_path: Option<String>,
+    /// Write the standardized events to a fixture file
+    #[clap(long = "fixture")]
+    pub fixture_path: Option<String>,
+}
+
+#[derive(Parser, PartialEq, Clone, Debug)]
+struct CheckCommand {
+    /// Path to the config file
+    #[clap(long = "config", short = 'c')]
+    pub config_path: Option<String>,
+}
+
+pub fn main() {
+    let opts: Opts = match Opts::try_parse() {
+        Ok(opts) => opts,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    match opts.command {
+        Command::Run(cmd) => {
+            let mut config = load_config_or_exit(&cmd.config_path);
+            if let Some(capture_path) = cmd.capture_path {
+                config.capture_path = Some(PathBuf::from(capture_path));
+            }
+            // Nodes may not be up yet: only a node answering for another
+            // network prevents the observer from starting.
+            if let Ok((_, network_id)) = check_stacks_node(&config) {
+                if let Err(e) = check_stacks_network_id(&config, network_id) {
+                    println!("error: {}", e);
+                    process::exit(1);
+                }
+            }
+            if let Ok((_, chain)) = check_bitcoin_node(&config) {
+                if let Err(e) = check_bitcoin_chain(&config, &chain) {
+                    println!("error: {}", e);
+                    process::exit(1);
+                }
+            }
+            let (command_tx, command_rx) = channel();
+            let (event_tx, event_rx) = channel();
+            std::thread::spawn(move || {
+                while let Ok(event) = event_rx.recv() {
+                    match event {
+                        ObserverEvent::Info(message) => println!("{}", message),
+                        ObserverEvent::Error(message) => println!("error: {}", message),
+                        ObserverEvent::Fatal(message) => {
+                            println!("fatal: {}", message);
+                            process::exit(1);
+                        }
+                    }
+                }
+            });
+            let future = observer::start_observer(config, command_tx, command_rx, event_tx);
+            let rt = utils::create_basic_runtime();
+            if let Err(e) = rt.block_on(future) {
+                println!("error: unable to spawn event observer: {}", e);
+                process::exit(1);
+            }
+        }
+        Command::Replay(cmd) => {
+            let config = load_config_or_exit(&cmd.config_path);
+            let payloads = match recorder::load_capture(&PathBuf::from(&cmd.capture_path)) {
+                Ok(payloads) => payloads,
+                Err(e) => {
+                    println!("error: {}", e);
+                    process::exit(1);
+                }
+            };
+            let mut indexer = Indexer::new(config.get_indexer_config());
+            let events = match recorder::replay_capture(&mut indexer, payloads) {
+                Ok(events) => events,
+                Err(e) => {
+                    println!("error: {}", e);
+                    process::exit(1);
+                }
+            };
+            for event in events.iter() {
+                match event {
+                    Event::BitcoinChainEvent(event) => println!("bitcoin: {:?}", event),
+                    Event::StacksChainEvent(event) => println!("stacks: {:?}", event),
+                    Event::BitcoinTransactionEvent(event) => {
+                        println!("bitcoin transaction: {:?}", event)
+                    }
+                }
+            }
+            if let Some(fixture_path) = cmd.fixture_path {
+                if let Err(e) = recorder::write_fixture(&events, &PathBuf::from(fixture_path)) {
+                    println!("error: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Command::Check(cmd) => {
+            let config = load_config_or_exit(&cmd.config_path);
+            println!("network: {:?}", config.network);
+            println!("observer port: {}", config.observer_port);
+            println!("event handlers: {:?}", config.event_handlers);
+            println!("event filter: {:?}", config.event_filter);
+            let mut healthy = true;
+            match check_stacks_node(&config).and_then(|(tip, network_id)| {
+                check_stacks_network_id(&config, network_id).map(|_| tip)
+            }) {
+                Ok(tip) => println!("stacks node: reachable (tip height: {})", tip),
+                Err(e) => {
+                    println!("stacks node: {}", e);
+                    healthy = false;
+                }
+            }
+            match check_bitcoin_node(&config)
+                .and_then(|(tip, chain)| check_bitcoin_chain(&config, &chain).map(|_| tip))
+            {
+                Ok(tip) => println!("bitcoin node: reachable (tip height: {})", tip),
+                Err(e) => {
+                    println!("bitcoin node: {}", e);
+                    healthy = false;
+                }
+            }
+            if !healthy {
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn load_config_or_exit(config_path: &Option<String>) -> StacksEventObserverConfig {
+    match load_config(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Loads the config file (falling back on defaults when no path was given and
+/// no config file exists in the working directory) and applies the
+/// environment overrides.
+fn load_config(config_path: &Option<String>) -> Result<StacksEventObserverConfig, String> {
+    let mut config_file = match config_path {
+        Some(path) => ConfigFile::from_path(&PathBuf::from(path))?,
+        None => {
+            let default_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+            if default_path.exists() {
+                ConfigFile::from_path(&default_path)?
+            } else {
+                ConfigFile::default()
+            }
+        }
+    };
+    config_file.apply_env_overrides()?;
+    StacksEventObserverConfig::from_config_file(config_file)
+}
+
+fn check_stacks_node(config: &StacksEventObserverConfig) -> Result<(u64, u32), String> {
+    #[derive(Deserialize)]
+    struct NodeTip {
+        stacks_tip_height: u64,
+        network_id: u32,
+    }
+
+    let url = format!("{}/v2/info", config.get_stacks_node_rpc_url());
+    let res = reqwest::blocking::get(&url).map_err(|e| format!("unreachable ({})", e))?;
+    if !res.status().is_success() {
+        return Err(format!("unhealthy (status {})", res.status()));
+    }
+    let tip: NodeTip = res
+        .json()
+        .map_err(|e| format!("unexpected response ({})", e))?;
+    Ok((tip.stacks_tip_height, tip.network_id))
+}
+
+fn check_bitcoin_node(config: &StacksEventObserverConfig) -> Result<(u64, String), String> {
+    let auth = Auth::UserPass(
+        config.bitcoin_node_username.clone(),
+        config.bitcoin_node_password.clone(),
+    );
+    let rpc = Client::new(&config.get_bitcoin_node_rpc_url(), auth)
+        .map_err(|e| format!("unreachable ({})", e))?;
+    let info = rpc
+        .get_blockchain_info()
+        .map_err(|e| format!("unreachable ({})", e))?;
+    Ok((info.blocks, info.chain))
+}
+
+fn check_stacks_network_id(
+    config: &StacksEventObserverConfig,
+    network_id: u32,
+) -> Result<(), String> {
+    if network_id != config.network.chain_id() {
+        return Err(format!(
+            "node running with chain id {:#010x}, expected {:#010x} ({:?})",
+            network_id,
+            config.network.chain_id(),
+            config.network
+        ));
+    }
+    Ok(())
+}
+
+fn check_bitcoin_chain(config: &StacksEventObserverConfig, chain: &str) -> Result<(), String> {
+    let expected = config.network.get_bitcoin_network();
+    if chain != expected.get_chain_name() {
+        return Err(format!(
+            "node running on chain '{}', expected '{}' ({:?})",
+            chain,
+            expected.get_chain_name(),
+            expected
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,274 @@
+use crate::observer::{EventFilter, EventHandler, StacksEventObserverConfig};
+use orchestra_types::StacksNetwork;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG_PATH: &str = "Observer.toml";
+
+/// Prefix of the environment variables overriding the settings loaded from
+/// the config file, e.g. `ORCHESTRA_STACKS_NODE_RPC_HOST`.
+const ENV_PREFIX: &str = "ORCHESTRA_";
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConfigFile {
+    pub network: Option<NetworkConfigFile>,
+    pub observer: Option<ObserverConfigFile>,
+    pub bitcoin_node: Option<BitcoinNodeConfigFile>,
+    pub stacks_node: Option<StacksNodeConfigFile>,
+    pub event_handlers: Option<Vec<EventHandlerConfigFile>>,
+    pub filters: Option<FiltersConfigFile>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct NetworkConfigFile {
+    pub stacks: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ObserverConfigFile {
+    pub port: Option<u16>,
+    pub normalization_enabled: Option<bool>,
+    pub bitcoin_rpc_proxy_enabled: Option<bool>,
+    pub capture_path: Option<String>,
+    pub max_tip_age_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct BitcoinNodeConfigFile {
+    pub rpc_host: Option<String>,
+    pub rpc_port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct StacksNodeConfigFile {
+    pub rpc_host: Option<String>,
+    pub rpc_port: Option<u16>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EventHandlerConfigFile {
+    pub webhook_url: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FiltersConfigFile {
+    pub bitcoin_blocks: Option<bool>,
+    pub stacks_blocks: Option<bool>,
+    pub stacks_microblocks: Option<bool>,
+}
+
+impl ConfigFile {
+    pub fn from_path(path: &Path) -> Result<ConfigFile, String> {
+        let mut file = File::open(path)
+            .map_err(|e| format!("unable to open config file {:?}: {}", path, e))?;
+        let mut buffer = String::new();
+        file.read_to_string(&mut buffer)
+            .map_err(|e| format!("unable to read config file {:?}: {}", path, e))?;
+        ConfigFile::from_str(&buffer)
+    }
+
+    pub fn from_str(content: &str) -> Result<ConfigFile, String> {
+        toml::from_str(content).map_err(|e| format!("config file malformed: {}", e))
+    }
+
+    /// Overrides the settings of the config file with the `ORCHESTRA_*`
+    /// environment variables that are set.
+    pub fn apply_env_overrides(&mut self) -> Result<(), String> {
+        self.apply_overrides(|key| std::env::var(format!("{}{}", ENV_PREFIX, key)).ok())
+    }
+
+    fn apply_overrides<F>(&mut self, lookup: F) -> Result<(), String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let network = self.network.get_or_insert_with(Default::default);
+        if let Some(value) = lookup("NETWORK") {
+            network.stacks = Some(value);
+        }
+
+        let observer = self.observer.get_or_insert_with(Default::default);
+        if let Some(value) = lookup("OBSERVER_PORT") {
+            observer.port = Some(parse_env("OBSERVER_PORT", &value)?);
+        }
+        if let Some(value) = lookup("NORMALIZATION_ENABLED") {
+            observer.normalization_enabled = Some(parse_env("NORMALIZATION_ENABLED", &value)?);
+        }
+        if let Some(value) = lookup("BITCOIN_RPC_PROXY_ENABLED") {
+            observer.bitcoin_rpc_proxy_enabled =
+                Some(parse_env("BITCOIN_RPC_PROXY_ENABLED", &value)?);
+        }
+        if let Some(value) = lookup("CAPTURE_PATH") {
+            observer.capture_path = Some(value);
+        }
+        if let Some(value) = lookup("MAX_TIP_AGE_SECS") {
+            observer.max_tip_age_secs = Some(parse_env("MAX_TIP_AGE_SECS", &value)?);
+        }
+
+        let bitcoin_node = self.bitcoin_node.get_or_insert_with(Default::default);
+        if let Some(value) = lookup("BITCOIN_NODE_RPC_HOST") {
+            bitcoin_node.rpc_host = Some(value);
+        }
+        if let Some(value) = lookup("BITCOIN_NODE_RPC_PORT") {
+            bitcoin_node.rpc_port = Some(parse_env("BITCOIN_NODE_RPC_PORT", &value)?);
+        }
+        if let Some(value) = lookup("BITCOIN_NODE_USERNAME") {
+            bitcoin_node.username = Some(value);
+        }
+        if let Some(value) = lookup("BITCOIN_NODE_PASSWORD") {
+            bitcoin_node.password = Some(value);
+        }
+
+        let stacks_node = self.stacks_node.get_or_insert_with(Default::default);
+        if let Some(value) = lookup("STACKS_NODE_RPC_HOST") {
+            stacks_node.rpc_host = Some(value);
+        }
+        if let Some(value) = lookup("STACKS_NODE_RPC_PORT") {
+            stacks_node.rpc_port = Some(parse_env("STACKS_NODE_RPC_PORT", &value)?);
+        }
+
+        // A comma separated list replaces the handlers declared in the file.
+        if let Some(value) = lookup("EVENT_HANDLERS") {
+            self.event_handlers = Some(
+                value
+                    .split(',')
+                    .map(|url| url.trim())
+                    .filter(|url| !url.is_empty())
+                    .map(|url| EventHandlerConfigFile {
+                        webhook_url: url.to_string(),
+                    })
+                    .collect(),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("{}{}: unable to parse '{}'", ENV_PREFIX, key, value))
+}
+
+pub fn parse_stacks_network(network: &str) -> Result<StacksNetwork, String> {
+    match network.to_lowercase().as_str() {
+        "devnet" => Ok(StacksNetwork::Devnet),
+        "testnet" => Ok(StacksNetwork::Testnet),
+        "mainnet" => Ok(StacksNetwork::Mainnet),
+        _ => Err(format!(
+            "network '{}' unknown (expected devnet, testnet or mainnet)",
+            network
+        )),
+    }
+}
+
+impl StacksEventObserverConfig {
+    pub fn from_config_file(config_file: ConfigFile) -> Result<StacksEventObserverConfig, String> {
+        let network = match config_file.network.and_then(|n| n.stacks) {
+            Some(network) => parse_stacks_network(&network)?,
+            None => StacksNetwork::Devnet,
+        };
+        // Devnet nodes are usually started with well-known credentials and ports,
+        // other networks have to be explicitly configured.
+        let (default_bitcoin_rpc_port, default_credentials) = match network {
+            StacksNetwork::Devnet => (18443, Some("devnet".to_string())),
+            StacksNetwork::Testnet => (18332, None),
+            StacksNetwork::Mainnet => (8332, None),
+        };
+
+        let observer = config_file.observer.unwrap_or_default();
+        let bitcoin_node = config_file.bitcoin_node.unwrap_or_default();
+        let stacks_node = config_file.stacks_node.unwrap_or_default();
+        let filters = config_file.filters.unwrap_or_default();
+
+        let bitcoin_node_username = match bitcoin_node.username.or(default_credentials.clone()) {
+            Some(username) => username,
+            None => return Err("bitcoin_node.username is required".into()),
+        };
+        let bitcoin_node_password = match bitcoin_node.password.or(default_credentials) {
+            Some(password) => password,
+            None => return Err("bitcoin_node.password is required".into()),
+        };
+
+        let event_handlers = config_file
+            .event_handlers
+            .unwrap_or_default()
+            .into_iter()
+            .map(|handler| EventHandler::WebHook(handler.webhook_url))
+            .collect();
+
+        Ok(StacksEventObserverConfig {
+            normalization_enabled: observer.normalization_enabled.unwrap_or(true),
+            bitcoin_rpc_proxy_enabled: observer.bitcoin_rpc_proxy_enabled.unwrap_or(false),
+            event_handlers,
+            event_filter: EventFilter {
+                bitcoin_blocks: filters.bitcoin_blocks.unwrap_or(true),
+                stacks_blocks: filters.stacks_blocks.unwrap_or(true),
+                stacks_microblocks: filters.stacks_microblocks.unwrap_or(true),
+            },
+            observer_port: observer.port.unwrap_or(20445),
+            bitcoin_node_username,
+            bitcoin_node_password,
+            bitcoin_node_rpc_host: bitcoin_node.rpc_host.unwrap_or("localhost".into()),
+            bitcoin_node_rpc_port: bitcoin_node.rpc_port.unwrap_or(default_bitcoin_rpc_port),
+            stacks_node_rpc_host: stacks_node.rpc_host.unwrap_or("localhost".into()),
+            stacks_node_rpc_port: stacks_node.rpc_port.unwrap_or(20443),
+            capture_path: observer.capture_path.map(PathBuf::from),
+            max_tip_age_secs: observer.max_tip_age_secs.unwrap_or(3600),
+            network,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn config_file_with_env_overrides() {
+        let mut config_file = ConfigFile::from_str(
+            r#"
+            [network]
+            stacks = "testnet"
+
+            [bitcoin_node]
+            rpc_host = "bitcoin.svc.cluster.local"
+            username = "admin"
+            password = "password"
+
+            [[event_handlers]]
+            webhook_url = "http://localhost:19999"
+
+            [filters]
+            stacks_microblocks = false
+            "#,
+        )
+        .unwrap();
+
+        let mut env = HashMap::new();
+        env.insert("STACKS_NODE_RPC_PORT", "30443");
+        env.insert("BITCOIN_NODE_PASSWORD", "secret");
+        config_file
+            .apply_overrides(|key| env.get(key).map(|v| v.to_string()))
+            .unwrap();
+
+        let config = StacksEventObserverConfig::from_config_file(config_file).unwrap();
+        assert_eq!(config.network, StacksNetwork::Testnet);
+        assert_eq!(config.bitcoin_node_rpc_host, "bitcoin.svc.cluster.local");
+        assert_eq!(config.bitcoin_node_rpc_port, 18332);
+        assert_eq!(config.bitcoin_node_password, "secret");
+        assert_eq!(config.stacks_node_rpc_port, 30443);
+        assert_eq!(config.event_handlers.len(), 1);
+        assert!(!config.event_filter.stacks_microblocks);
+        assert!(config.event_filter.stacks_blocks);
+    }
+
+    #[test]
+    fn non_devnet_networks_require_credentials() {
+        let config_file = ConfigFile::from_str("[network]\nstacks = \"mainnet\"").unwrap();
+        assert!(StacksEventObserverConfig::from_config_file(config_file).is_err());
+    }
+}
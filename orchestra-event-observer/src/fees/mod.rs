@@ -0,0 +1,305 @@
+use orchestra_types::{
+    StacksBlockData, StacksChainEvent, StacksFeeEstimate, StacksMicroblocksTrail,
+    StacksTransactionData, StacksTransactionExecutionCost, StacksTransactionKind,
+};
+use std::collections::{HashMap, VecDeque};
+
+/// Number of transactions remembered per payload: older ones are forgotten
+/// so that estimates follow the fee market.
+const SAMPLES_TRACKED: usize = 500;
+
+pub const STX_TRANSFER: &str = "stx-transfer";
+pub const CONTRACT_DEPLOY: &str = "contract-deploy";
+
+pub fn get_contract_call_key(contract_identifier: &str, function_name: &str) -> String {
+    format!("{}::{}", contract_identifier, function_name)
+}
+
+/// Returns the key grouping the transactions comparable to `tx`, or None for
+/// transactions not paying fees (coinbases).
+pub fn get_fee_key(tx: &StacksTransactionData) -> Option<String> {
+    match tx.metadata.kind {
+        StacksTransactionKind::NativeTokenTransfer => Some(STX_TRANSFER.into()),
+        StacksTransactionKind::ContractDeployment(_) => Some(CONTRACT_DEPLOY.into()),
+        StacksTransactionKind::ContractCall(ref data) => Some(get_contract_call_key(
+            &data.contract_identifier,
+            &data.method,
+        )),
+        StacksTransactionKind::Coinbase | StacksTransactionKind::Other => None,
+    }
+}
+
+struct FeeSample {
+    txid: String,
+    fee: u64,
+    execution_cost: Option<StacksTransactionExecutionCost>,
+}
+
+/// Learns the fees paid by the transactions mined in the blocks and
+/// microblocks going through the indexer.
+#[derive(Default)]
+pub struct FeeEstimator {
+    samples: HashMap<String, VecDeque<FeeSample>>,
+}
+
+impl FeeEstimator {
+    pub fn new() -> FeeEstimator {
+        FeeEstimator::default()
+    }
+
+    /// Records the transactions carried by a chain update. Transactions
+    /// already observed (streamed in a microblock, or mined again after a
+    /// reorg) are only counted once, and the ones reorged out are forgotten.
+    pub fn process_chain_event(&mut self, chain_event: &StacksChainEvent) {
+        match chain_event {
+            StacksChainEvent::ChainUpdatedWithBlock(data) => {
+                self.process_block(&data.new_block, &data.anchored_trail);
+            }
+            StacksChainEvent::ChainUpdatedWithReorg(data) => {
+                for (trail, block) in data.old_blocks.iter() {
+                    self.revert_block(block, trail);
+                }
+                for (trail, block) in data.new_blocks.iter() {
+                    self.process_block(block, trail);
+                }
+            }
+            StacksChainEvent::ChainUpdatedWithMicroblock(data) => {
+                self.process_microblocks(&data.current_trail);
+            }
+            StacksChainEvent::ChainUpdatedWithMicroblockReorg(data) => {
+                if let Some(trail) = &data.old_trail {
+                    self.revert_microblocks(trail);
+                }
+                self.process_block(&data.new_block, &data.new_anchored_trail);
+            }
+        }
+    }
+
+    fn process_block(
+        &mut self,
+        block: &StacksBlockData,
+        anchored_trail: &Option<StacksMicroblocksTrail>,
+    ) {
+        for tx in block.transactions.iter() {
+            self.record_transaction(tx);
+        }
+        if let Some(trail) = anchored_trail {
+            self.process_microblocks(trail);
+        }
+    }
+
+    fn process_microblocks(&mut self, trail: &StacksMicroblocksTrail) {
+        for microblock in trail.microblocks.iter() {
+            for tx in microblock.transactions.iter() {
+                self.record_transaction(tx);
+            }
+        }
+    }
+
+    fn revert_block(
+        &mut self,
+        block: &StacksBlockData,
+        anchored_trail: &Option<StacksMicroblocksTrail>,
+    ) {
+        for tx in block.transactions.iter() {
+            self.forget_transaction(tx);
+        }
+        if let Some(trail) = anchored_trail {
+            self.revert_microblocks(trail);
+        }
+    }
+
+    fn revert_microblocks(&mut self, trail: &StacksMicroblocksTrail) {
+        for microblock in trail.microblocks.iter() {
+            for tx in microblock.transactions.iter() {
+                self.forget_transaction(tx);
+            }
+        }
+    }
+
+    fn forget_transaction(&mut self, tx: &StacksTransactionData) {
+        let key = match get_fee_key(tx) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(samples) = self.samples.get_mut(&key) {
+            samples.retain(|sample| sample.txid != tx.transaction_identifier.hash);
+        }
+    }
+
+    pub fn record_transaction(&mut self, tx: &StacksTransactionData) {
+        let key = match get_fee_key(tx) {
+            Some(key) => key,
+            None => return,
+        };
+        let samples = self.samples.entry(key).or_insert_with(VecDeque::new);
+        let txid = &tx.transaction_identifier.hash;
+        if samples.iter().any(|sample| &sample.txid == txid) {
+            return;
+        }
+        if samples.len() == SAMPLES_TRACKED {
+            samples.pop_front();
+        }
+        samples.push_back(FeeSample {
+            txid: txid.clone(),
+            fee: tx.metadata.fee,
+            execution_cost: tx.metadata.execution_cost.clone(),
+        });
+    }
+
+    pub fn estimate(&self, key: &str) -> Option<StacksFeeEstimate> {
+        let samples = match self.samples.get(key) {
+            Some(samples) if !samples.is_empty() => samples,
+            _ => return None,
+        };
+        let fees = samples.iter().map(|s| s.fee).collect::<Vec<_>>();
+        let costs = samples
+            .iter()
+            .filter_map(|s| s.execution_cost.as_ref())
+            .collect::<Vec<_>>();
+        let execution_cost = if costs.is_empty() {
+            None
+        } else {
+            let median = |dimension: fn(&StacksTransactionExecutionCost) -> u64| {
+                percentile(costs.iter().map(|c| dimension(c)).collect(), 50)
+            };
+            Some(StacksTransactionExecutionCost {
+                write_length: median(|c| c.write_length),
+                write_count: median(|c| c.write_count),
+                read_length: median(|c| c.read_length),
+                read_count: median(|c| c.read_count),
+                runtime: median(|c| c.runtime),
+            })
+        };
+        Some(StacksFeeEstimate {
+            samples: samples.len(),
+            fee_low: percentile(fees.clone(), 25),
+            fee_medium: percentile(fees.clone(), 50),
+            fee_high: percentile(fees, 75),
+            execution_cost,
+        })
+    }
+}
+
+/// Nearest-rank percentile of a non-empty set of values.
+fn percentile(mut values: Vec<u64>, rank: usize) -> u64 {
+    values.sort_unstable();
+    values[(values.len() - 1) * rank / 100]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orchestra_types::{ChainUpdatedWithBlockData, ChainUpdatedWithReorgData};
+
+    fn contract_call(txid: &str, function_name: &str, fee: u64) -> StacksTransactionData {
+        serde_json::from_value(json!({
+            "transaction_identifier": { "hash": txid },
+            "operations": [],
+            "metadata": {
+                "success": true,
+                "raw_tx": "0x00",
+                "result": "(ok true)",
+                "sender": "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM",
+                "fee": fee,
+                "kind": {
+                    "ContractCall": {
+                        "contract_identifier": "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.token",
+                        "method": function_name,
+                        "args": ["u1", "'ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM"]
+                    }
+                },
+                "execution_cost": {
+                    "write_length": fee,
+                    "write_count": 1,
+                    "read_length": 2,
+                    "read_count": 3,
+                    "runtime": 4
+                },
+                "receipt": {
+                    "mutated_contracts_radius": [],
+                    "mutated_assets_radius": [],
+                    "events": []
+                },
+                "description": format!(
+                    "invoked: ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.token::{}(u1, 'ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM)",
+                    function_name
+                )
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn fees_are_grouped_by_contract_function() {
+        let mut estimator = FeeEstimator::new();
+        for (i, fee) in [100, 200, 300, 400, 500].iter().enumerate() {
+            estimator.record_transaction(&contract_call(&format!("0x{}", i), "transfer", *fee));
+        }
+        estimator.record_transaction(&contract_call("0x9", "mint", 10_000));
+        // Transactions observed twice are only counted once.
+        estimator.record_transaction(&contract_call("0x0", "transfer", 100));
+
+        let key = get_contract_call_key(
+            "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.token",
+            "transfer",
+        );
+        let estimate = estimator.estimate(&key).unwrap();
+        assert_eq!(estimate.samples, 5);
+        assert_eq!(estimate.fee_low, 200);
+        assert_eq!(estimate.fee_medium, 300);
+        assert_eq!(estimate.fee_high, 400);
+        assert_eq!(estimate.execution_cost.unwrap().write_length, 300);
+        assert!(estimator.estimate(STX_TRANSFER).is_none());
+    }
+
+    #[test]
+    fn reorged_out_transactions_are_forgotten() {
+        let block = |hash: &str, txs: Vec<StacksTransactionData>| -> StacksBlockData {
+            serde_json::from_value(json!({
+                "block_identifier": { "index": 10, "hash": hash },
+                "parent_block_identifier": { "index": 9, "hash": "parent" },
+                "timestamp": 0,
+                "transactions": txs,
+                "metadata": {
+                    "bitcoin_anchor_block_identifier": { "index": 0, "hash": "" },
+                    "pox_cycle_index": 0,
+                    "pox_cycle_position": 0,
+                    "pox_cycle_length": 0
+                }
+            }))
+            .unwrap()
+        };
+        let mut estimator = FeeEstimator::new();
+        let old_block = block(
+            "a",
+            vec![
+                contract_call("0x1", "transfer", 100),
+                contract_call("0x2", "transfer", 5_000),
+            ],
+        );
+        estimator.process_chain_event(&StacksChainEvent::ChainUpdatedWithBlock(
+            ChainUpdatedWithBlockData {
+                new_block: old_block.clone(),
+                anchored_trail: None,
+                confirmed_block: (old_block.clone(), None),
+            },
+        ));
+        let new_block = block("b", vec![contract_call("0x1", "transfer", 100)]);
+        estimator.process_chain_event(&StacksChainEvent::ChainUpdatedWithReorg(
+            ChainUpdatedWithReorgData {
+                old_blocks: vec![(None, old_block)],
+                new_blocks: vec![(None, new_block.clone())],
+                confirmed_block: (new_block, None),
+            },
+        ));
+
+        let key = get_contract_call_key(
+            "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.token",
+            "transfer",
+        );
+        let estimate = estimator.estimate(&key).unwrap();
+        assert_eq!(estimate.samples, 1);
+        assert_eq!(estimate.fee_high, 100);
+    }
+}
@@ -1,9 +1,14 @@
 use crate::indexer::IndexerConfig;
-use orchestra_types::{BitcoinBlockData, BitcoinBlockMetadata, BlockIdentifier};
+use orchestra_types::{
+    BitcoinBlockData, BitcoinBlockMetadata, BitcoinNetwork, BitcoinTransactionData,
+    BitcoinTransactionMetadata, BlockIdentifier, TransactionIdentifier,
+};
 use bitcoincore_rpc::bitcoin::hashes::Hash;
-use bitcoincore_rpc::bitcoin::BlockHash;
+use bitcoincore_rpc::bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoincore_rpc::bitcoin::consensus::params::Params;
+use bitcoincore_rpc::bitcoin::{Block, BlockHash, Network};
 use bitcoincore_rpc::{Auth, Client, RpcApi};
-use clarity_repl::clarity::util::hash::hex_bytes;
+use clarity_repl::clarity::util::hash::{bytes_to_hex, hex_bytes};
 use rocket::serde::json::Value as JsonValue;
 
 #[allow(dead_code)]
@@ -13,49 +18,123 @@ pub struct NewBurnBlock {
     burn_block_height: u64,
     reward_slot_holders: Vec<String>,
     burn_amount: u64,
+    /// Consensus-encoded block, hex encoded. Never sent by the stacks-node: only
+    /// present in captured payloads, so that replays don't depend on bitcoind.
+    #[serde(default)]
+    raw_block: Option<String>,
 }
 
-pub fn standardize_bitcoin_block(
+pub fn retrieve_raw_bitcoin_block(
     indexer_config: &IndexerConfig,
-    marshalled_block: JsonValue,
-) -> BitcoinBlockData {
-    let transactions = vec![];
+    marshalled_block: &JsonValue,
+) -> Result<String, String> {
+    let partial_block: NewBurnBlock = serde_json::from_value(marshalled_block.clone())
+        .map_err(|e| format!("unable to parse burn block: {}", e))?;
+    let block = fetch_bitcoin_block(indexer_config, &partial_block)?;
+    Ok(bytes_to_hex(&serialize(&block)))
+}
 
+fn fetch_bitcoin_block(
+    indexer_config: &IndexerConfig,
+    partial_block: &NewBurnBlock,
+) -> Result<Block, String> {
     let auth = Auth::UserPass(
         indexer_config.bitcoin_node_rpc_username.clone(),
         indexer_config.bitcoin_node_rpc_password.clone(),
     );
 
-    let rpc = Client::new(&indexer_config.bitcoin_node_rpc_url, auth).unwrap();
+    let rpc = Client::new(&indexer_config.bitcoin_node_rpc_url, auth)
+        .map_err(|e| format!("unable to reach bitcoind: {}", e))?;
+
+    let chain = rpc
+        .get_blockchain_info()
+        .map_err(|e| format!("unable to reach bitcoind: {}", e))?
+        .chain;
+    let expected_chain = indexer_config.bitcoin_network.get_chain_name();
+    if chain != expected_chain {
+        return Err(format!(
+            "bitcoind running on chain '{}', expected '{}'",
+            chain, expected_chain
+        ));
+    }
 
-    let partial_block: NewBurnBlock = serde_json::from_value(marshalled_block).unwrap();
-    let block_height = partial_block.burn_block_height;
     let block_hash = {
-        let block_hash_str = partial_block.burn_block_hash.strip_prefix("0x").unwrap();
-        let mut block_hash_bytes = hex_bytes(&block_hash_str).unwrap();
+        let block_hash_str = partial_block
+            .burn_block_hash
+            .strip_prefix("0x")
+            .unwrap_or(&partial_block.burn_block_hash);
+        let mut block_hash_bytes = hex_bytes(&block_hash_str)
+            .map_err(|e| format!("unable to decode block hash: {:?}", e))?;
         block_hash_bytes.reverse();
-        BlockHash::from_slice(&block_hash_bytes).unwrap()
+        BlockHash::from_slice(&block_hash_bytes)
+            .map_err(|e| format!("unable to decode block hash: {:?}", e))?
     };
-    let block = rpc.get_block(&block_hash).unwrap();
+    rpc.get_block(&block_hash)
+        .map_err(|e| format!("unable to retrieve block {}: {}", block_hash, e))
+}
 
-    for _txdata in block.txdata.iter() {
+pub fn standardize_bitcoin_block(
+    indexer_config: &IndexerConfig,
+    marshalled_block: JsonValue,
+) -> Result<BitcoinBlockData, String> {
+    let partial_block: NewBurnBlock = serde_json::from_value(marshalled_block)
+        .map_err(|e| format!("unable to parse burn block: {}", e))?;
+    let block_height = partial_block.burn_block_height;
+    let block: Block = match partial_block.raw_block {
+        Some(ref raw_block) => {
+            let raw_block_bytes =
+                hex_bytes(raw_block).map_err(|e| format!("unable to decode raw block: {:?}", e))?;
+            deserialize(&raw_block_bytes)
+                .map_err(|e| format!("unable to decode raw block: {}", e))?
+        }
+        None => fetch_bitcoin_block(indexer_config, &partial_block)?,
+    };
+    check_proof_of_work_limit(&indexer_config.bitcoin_network, &block)?;
+
+    let mut transactions = vec![];
+    for txdata in block.txdata.iter() {
         // TODO(lgalabru): retrieve stacks transactions
-        // let _ = tx.send(DevnetEvent::debug(format!(
-        //     "Tx.out: {:?}", txdata.output
-        // )));
+        transactions.push(BitcoinTransactionData {
+            transaction_identifier: TransactionIdentifier {
+                hash: txdata.txid().to_string(),
+            },
+            operations: vec![],
+            metadata: BitcoinTransactionMetadata {},
+        });
     }
 
-    BitcoinBlockData {
+    Ok(BitcoinBlockData {
         block_identifier: BlockIdentifier {
             hash: block.header.block_hash().to_string(),
             index: block_height,
         },
         parent_block_identifier: BlockIdentifier {
             hash: block.header.prev_blockhash.to_string(),
-            index: block_height - 1,
+            index: block_height.saturating_sub(1),
         },
         timestamp: block.header.time,
         metadata: BitcoinBlockMetadata {},
         transactions,
+    })
+}
+
+/// Raw blocks don't tell which network they were mined on, but blocks mined
+/// on regtest have a target above the proof-of-work limit of the other networks.
+fn check_proof_of_work_limit(
+    bitcoin_network: &BitcoinNetwork,
+    block: &Block,
+) -> Result<(), String> {
+    let network = match bitcoin_network {
+        BitcoinNetwork::Regtest => Network::Regtest,
+        BitcoinNetwork::Testnet => Network::Testnet,
+        BitcoinNetwork::Mainnet => Network::Bitcoin,
+    };
+    if block.header.target() > Params::new(network).pow_limit {
+        return Err(format!(
+            "block {} was not mined on {:?}: target above the proof-of-work limit",
+            block.header.block_hash(),
+            bitcoin_network
+        ));
     }
+    Ok(())
 }
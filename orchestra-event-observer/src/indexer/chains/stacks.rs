@@ -90,8 +90,9 @@ pub fn standardize_stacks_block(
     indexer_config: &IndexerConfig,
     marshalled_block: JsonValue,
     ctx: &mut StacksChainContext,
-) -> StacksBlockData {
-    let mut block: NewBlock = serde_json::from_value(marshalled_block).unwrap();
+) -> Result<StacksBlockData, String> {
+    let mut block: NewBlock = serde_json::from_value(marshalled_block)
+        .map_err(|e| format!("unable to parse block: {}", e))?;
 
     let pox_cycle_length: u64 =
         (ctx.pox_info.prepare_phase_block_length + ctx.pox_info.reward_phase_block_length).into();
@@ -100,40 +101,46 @@ pub fn standardize_stacks_block(
 
     let mut events = vec![];
     events.append(&mut block.events);
-    let transactions = block
-        .transactions
-        .iter()
-        .map(|t| {
-            let (description, tx_type, fee, sender, sponsor) =
-                get_tx_description(&t.raw_tx).expect("unable to parse transaction");
-            let (operations, receipt) = get_standardized_stacks_operations(
-                &t.txid,
-                &mut events,
-                &mut ctx.asset_class_map,
-                &indexer_config.stacks_node_rpc_url,
-            );
-            StacksTransactionData {
-                transaction_identifier: TransactionIdentifier {
-                    hash: t.txid.clone(),
-                },
-                operations,
-                metadata: StacksTransactionMetadata {
-                    success: t.status == "success",
-                    result: get_value_description(&t.raw_result),
-                    raw_tx: t.raw_tx.clone(),
-                    sender,
-                    fee,
-                    sponsor,
-                    kind: tx_type,
-                    execution_cost: t.execution_cost.clone(),
-                    receipt,
-                    description,
-                },
-            }
-        })
-        .collect();
+    let mut transactions = vec![];
+    for t in block.transactions.iter() {
+        // A transaction that can't be decoded (or issued for another network)
+        // is reported and skipped, instead of taking the whole block down.
+        let (description, tx_type, fee, sender, sponsor) =
+            match get_tx_description(&t.raw_tx, &indexer_config.stacks_network) {
+                Ok(description) => description,
+                Err(e) => {
+                    ctx.rejected_transactions
+                        .push(format!("transaction {}: {}", t.txid, e));
+                    continue;
+                }
+            };
+        let (operations, receipt) = get_standardized_stacks_operations(
+            &t.txid,
+            &mut events,
+            &mut ctx.asset_class_map,
+            &indexer_config.stacks_node_rpc_url,
+        );
+        transactions.push(StacksTransactionData {
+            transaction_identifier: TransactionIdentifier {
+                hash: t.txid.clone(),
+            },
+            operations,
+            metadata: StacksTransactionMetadata {
+                success: t.status == "success",
+                result: get_value_description(&t.raw_result),
+                raw_tx: t.raw_tx.clone(),
+                sender,
+                fee,
+                sponsor,
+                kind: tx_type,
+                execution_cost: t.execution_cost.clone(),
+                receipt,
+                description,
+            },
+        });
+    }
 
-    StacksBlockData {
+    Ok(StacksBlockData {
         block_identifier: BlockIdentifier {
             hash: block.index_block_hash.clone(),
             index: block.block_height,
@@ -153,7 +160,7 @@ pub fn standardize_stacks_block(
             pox_cycle_length: pox_cycle_length.try_into().unwrap(),
         },
         transactions,
-    }
+    })
 }
 
 pub fn standardize_stacks_microblock(
@@ -161,52 +168,59 @@ pub fn standardize_stacks_microblock(
     marshalled_microblock: JsonValue,
     anchored_block_identifier: &BlockIdentifier,
     ctx: &mut StacksChainContext,
-) -> StacksMicroblockData {
-    let mut microblock: NewMicroblock = serde_json::from_value(marshalled_microblock).unwrap();
+) -> Result<StacksMicroblockData, String> {
+    let mut microblock: NewMicroblock = serde_json::from_value(marshalled_microblock)
+        .map_err(|e| format!("unable to parse microblock: {}", e))?;
 
     let mut events = vec![];
     events.append(&mut microblock.events);
-    let transactions = microblock
-        .transactions
-        .iter()
-        .map(|t| {
-            let (description, tx_type, fee, sender, sponsor) =
-                get_tx_description(&t.raw_tx).expect("unable to parse transaction");
-            let (operations, receipt) = get_standardized_stacks_operations(
-                &t.txid,
-                &mut events,
-                &mut ctx.asset_class_map,
-                &indexer_config.stacks_node_rpc_url,
-            );
-            StacksTransactionData {
-                transaction_identifier: TransactionIdentifier {
-                    hash: t.txid.clone(),
-                },
-                operations,
-                metadata: StacksTransactionMetadata {
-                    success: t.status == "success",
-                    result: get_value_description(&t.raw_result),
-                    raw_tx: t.raw_tx.clone(),
-                    sender,
-                    fee,
-                    sponsor,
-                    kind: tx_type,
-                    execution_cost: t.execution_cost.clone(),
-                    receipt,
-                    description,
-                },
-            }
-        })
-        .collect();
+    let mut transactions = vec![];
+    for t in microblock.transactions.iter() {
+        // A transaction that can't be decoded (or issued for another network)
+        // is reported and skipped, instead of taking the whole microblock down.
+        let (description, tx_type, fee, sender, sponsor) =
+            match get_tx_description(&t.raw_tx, &indexer_config.stacks_network) {
+                Ok(description) => description,
+                Err(e) => {
+                    ctx.rejected_transactions
+                        .push(format!("transaction {}: {}", t.txid, e));
+                    continue;
+                }
+            };
+        let (operations, receipt) = get_standardized_stacks_operations(
+            &t.txid,
+            &mut events,
+            &mut ctx.asset_class_map,
+            &indexer_config.stacks_node_rpc_url,
+        );
+        transactions.push(StacksTransactionData {
+            transaction_identifier: TransactionIdentifier {
+                hash: t.txid.clone(),
+            },
+            operations,
+            metadata: StacksTransactionMetadata {
+                success: t.status == "success",
+                result: get_value_description(&t.raw_result),
+                raw_tx: t.raw_tx.clone(),
+                sender,
+                fee,
+                sponsor,
+                kind: tx_type,
+                execution_cost: t.execution_cost.clone(),
+                receipt,
+                description,
+            },
+        });
+    }
 
-    StacksMicroblockData {
+    Ok(StacksMicroblockData {
         block_identifier: BlockIdentifier {
             hash: "".into(),
             index: 0,
         },
         parent_block_identifier: anchored_block_identifier.clone(),
         transactions,
-    }
+    })
 }
 
 pub fn get_value_description(raw_value: &str) -> String {
@@ -229,8 +243,25 @@ pub fn get_value_description(raw_value: &str) -> String {
     value
 }
 
+pub fn get_txid(raw_tx: &str) -> Result<String, ()> {
+    let raw_tx = match raw_tx.strip_prefix("0x") {
+        Some(raw_tx) => raw_tx,
+        _ => return Err(()),
+    };
+    let tx_bytes = match hex_bytes(&raw_tx) {
+        Ok(bytes) => bytes,
+        _ => return Err(()),
+    };
+    let tx = match StacksTransaction::consensus_deserialize(&mut Cursor::new(&tx_bytes)) {
+        Ok(bytes) => bytes,
+        _ => return Err(()),
+    };
+    Ok(format!("0x{}", tx.txid()))
+}
+
 pub fn get_tx_description(
     raw_tx: &str,
+    network: &StacksNetwork,
 ) -> Result<
     (
         String, // Human readable transaction's description (contract-call, publish, ...)
@@ -239,25 +270,36 @@ pub fn get_tx_description(
         String, // Sender's address
         Option<String>, // Sponsor's address (optional)
     ),
-    (),
+    String,
 > {
     let raw_tx = match raw_tx.strip_prefix("0x") {
         Some(raw_tx) => raw_tx,
-        _ => return Err(()),
+        _ => return Err("unable to decode transaction: missing 0x prefix".into()),
     };
     let tx_bytes = match hex_bytes(&raw_tx) {
         Ok(bytes) => bytes,
-        _ => return Err(()),
+        Err(e) => return Err(format!("unable to decode transaction: {:?}", e)),
     };
     let tx = match StacksTransaction::consensus_deserialize(&mut Cursor::new(&tx_bytes)) {
         Ok(bytes) => bytes,
-        _ => return Err(()),
+        Err(e) => return Err(format!("unable to decode transaction: {:?}", e)),
     };
 
+    // Transactions from another network can only reach us through a misconfigured
+    // node: their addresses would be rendered for the wrong network.
+    if tx.chain_id != network.chain_id() || tx.is_mainnet() != network.is_mainnet() {
+        return Err(format!(
+            "issued for chain id {:#010x}, expected {:#010x} ({:?})",
+            tx.chain_id,
+            network.chain_id(),
+            network
+        ));
+    }
+
     let (fee, sender, sponsor) = match tx.auth {
         TransactionAuth::Standard(ref conditions) => (
             conditions.tx_fee(),
-            if tx.is_mainnet() {
+            if network.is_mainnet() {
                 conditions.address_mainnet().to_string()
             } else {
                 conditions.address_testnet().to_string()
@@ -266,12 +308,12 @@ pub fn get_tx_description(
         ),
         TransactionAuth::Sponsored(ref sender_conditions, ref sponsor_conditions) => (
             sponsor_conditions.tx_fee(),
-            if tx.is_mainnet() {
+            if network.is_mainnet() {
                 sender_conditions.address_mainnet().to_string()
             } else {
                 sender_conditions.address_testnet().to_string()
             },
-            Some(if tx.is_mainnet() {
+            Some(if network.is_mainnet() {
                 sponsor_conditions.address_mainnet().to_string()
             } else {
                 sponsor_conditions.address_testnet().to_string()
@@ -290,21 +332,26 @@ pub fn get_tx_description(
             StacksTransactionKind::NativeTokenTransfer,
         ),
         TransactionPayload::ContractCall(ref contract_call) => {
-            let formatted_args = contract_call
-                .function_args
-                .iter()
-                .map(|v| format!("{}", v))
-                .collect::<Vec<String>>()
-                .join(", ");
+            let data = StacksContractCallData {
+                contract_identifier: format!(
+                    "{}.{}",
+                    contract_call.address, contract_call.contract_name
+                ),
+                method: contract_call.function_name.to_string(),
+                args: contract_call
+                    .function_args
+                    .iter()
+                    .map(|v| format!("{}", v))
+                    .collect(),
+            };
             (
                 format!(
-                    "invoked: {}.{}::{}({})",
-                    contract_call.address,
-                    contract_call.contract_name,
-                    contract_call.function_name,
-                    formatted_args
+                    "invoked: {}::{}({})",
+                    data.contract_identifier,
+                    data.method,
+                    data.args.join(", ")
                 ),
-                StacksTransactionKind::ContractCall,
+                StacksTransactionKind::ContractCall(data),
             )
         }
         TransactionPayload::SmartContract(ref smart_contract) => {
@@ -1,8 +1,9 @@
 pub mod chains;
 
 use orchestra_types::{
-    BitcoinChainEvent, BlockIdentifier, ChainUpdatedWithBlockData, ChainUpdatedWithMicroblockData,
-    StacksBlockData, StacksChainEvent, StacksMicroblocksTrail,
+    BitcoinChainEvent, BitcoinNetwork, BlockIdentifier, ChainUpdatedWithBlockData,
+    ChainUpdatedWithMicroblockData, StacksBlockData, StacksChainEvent, StacksMicroblocksTrail,
+    StacksNetwork,
 };
 use stacks_rpc_client::PoxInfo;
 use rocket::serde::json::Value as JsonValue;
@@ -17,6 +18,8 @@ pub struct AssetClassCache {
 pub struct StacksChainContext {
     asset_class_map: HashMap<String, AssetClassCache>,
     pox_info: PoxInfo,
+    /// Transactions skipped while standardizing blocks, with the reason.
+    rejected_transactions: Vec<String>,
 }
 
 impl StacksChainContext {
@@ -24,15 +27,19 @@ impl StacksChainContext {
         StacksChainContext {
             asset_class_map: HashMap::new(),
             pox_info: PoxInfo::default(),
+            rejected_transactions: vec![],
         }
     }
 }
 
+#[derive(Clone)]
 pub struct IndexerConfig {
     pub stacks_node_rpc_url: String,
     pub bitcoin_node_rpc_url: String,
     pub bitcoin_node_rpc_username: String,
     pub bitcoin_node_rpc_password: String,
+    pub stacks_network: StacksNetwork,
+    pub bitcoin_network: BitcoinNetwork,
 }
 
 pub struct Indexer {
@@ -60,8 +67,11 @@ impl Indexer {
         }
     }
 
-    pub fn handle_bitcoin_block(&mut self, marshalled_block: JsonValue) -> BitcoinChainEvent {
-        let block = chains::standardize_bitcoin_block(&self.config, marshalled_block);
+    pub fn handle_bitcoin_block(
+        &mut self,
+        marshalled_block: JsonValue,
+    ) -> Result<BitcoinChainEvent, String> {
+        let block = chains::standardize_bitcoin_block(&self.config, marshalled_block)?;
         if let Some(tip) = self.bitcoin_last_7_blocks.back() {
             if block.block_identifier.index == tip.index + 1 {
                 self.bitcoin_last_7_blocks
@@ -80,15 +90,18 @@ impl Indexer {
             self.bitcoin_last_7_blocks
                 .push_front(block.block_identifier.clone());
         }
-        BitcoinChainEvent::ChainUpdatedWithBlock(block)
+        Ok(BitcoinChainEvent::ChainUpdatedWithBlock(block))
     }
 
-    pub fn handle_stacks_block(&mut self, marshalled_block: JsonValue) -> StacksChainEvent {
+    pub fn handle_stacks_block(
+        &mut self,
+        marshalled_block: JsonValue,
+    ) -> Result<StacksChainEvent, String> {
         let block = chains::standardize_stacks_block(
             &self.config,
             marshalled_block,
             &mut self.stacks_context,
-        );
+        )?;
         let mut anchored_trail = None;
         if let Some((tip, _)) = self.stacks_last_7_blocks.back() {
             if block.block_identifier.index == tip.index + 1 {
@@ -122,21 +135,24 @@ impl Indexer {
             anchored_trail,
             confirmed_block: (confirmed_block, None),
         };
-        StacksChainEvent::ChainUpdatedWithBlock(update)
+        Ok(StacksChainEvent::ChainUpdatedWithBlock(update))
     }
 
     pub fn handle_stacks_microblock(
         &mut self,
         marshalled_microblock: JsonValue,
-    ) -> StacksChainEvent {
-        let (_, anchored_block) = self.stacks_last_7_blocks.back().unwrap();
+    ) -> Result<StacksChainEvent, String> {
+        let (_, anchored_block) = match self.stacks_last_7_blocks.back() {
+            Some(tip) => tip,
+            None => return Err("microblock received before its anchored block".into()),
+        };
 
         let microblock = chains::standardize_stacks_microblock(
             &self.config,
             marshalled_microblock,
             &anchored_block.block_identifier,
             &mut self.stacks_context,
-        );
+        )?;
         self.current_microblock_trail.microblocks.push(microblock);
 
         let update = ChainUpdatedWithMicroblockData {
@@ -144,7 +160,16 @@ impl Indexer {
             current_trail: self.current_microblock_trail.clone(),
         };
 
-        StacksChainEvent::ChainUpdatedWithMicroblock(update)
+        Ok(StacksChainEvent::ChainUpdatedWithMicroblock(update))
+    }
+
+    pub fn get_config(&self) -> &IndexerConfig {
+        &self.config
+    }
+
+    /// Returns the transactions skipped since the last call.
+    pub fn take_rejected_transactions(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.stacks_context.rejected_transactions)
     }
 
     pub fn get_pox_info(&mut self) -> PoxInfo {
@@ -11,6 +11,10 @@ extern crate serde_json;
 #[macro_use]
 extern crate rocket;
 
+pub mod config;
+pub mod fees;
 pub mod observer;
 pub mod indexer;
+pub mod metrics;
+pub mod recorder;
 pub mod utils;
\ No newline at end of file
@@ -0,0 +1,203 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Chains label values.
+pub const BITCOIN: &str = "bitcoin";
+pub const STACKS: &str = "stacks";
+pub const STACKS_MICROBLOCK: &str = "stacks_microblock";
+
+pub struct ObserverMetrics {
+    registry: Registry,
+    blocks_ingested: IntCounterVec,
+    standardization_latency: HistogramVec,
+    webhook_successes: IntCounterVec,
+    webhook_failures: IntCounterVec,
+    webhook_retries: IntCounterVec,
+    reorg_depth: HistogramVec,
+    mempool_size: IntGauge,
+    mempool: Mutex<HashSet<String>>,
+    started_at: Instant,
+    last_bitcoin_block_at: Mutex<Option<Instant>>,
+    last_stacks_block_at: Mutex<Option<Instant>>,
+}
+
+impl ObserverMetrics {
+    pub fn new() -> ObserverMetrics {
+        let registry = Registry::new_custom(Some("orchestra_observer".into()), None)
+            .expect("unable to build registry");
+
+        let blocks_ingested = IntCounterVec::new(
+            Opts::new("blocks_ingested_total", "Blocks received from the nodes"),
+            &["chain"],
+        )
+        .expect("unable to build metric");
+        let standardization_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "standardization_latency_seconds",
+                "Time spent standardizing incoming payloads",
+            ),
+            &["chain"],
+        )
+        .expect("unable to build metric");
+        let webhook_successes = IntCounterVec::new(
+            Opts::new("webhook_successes_total", "Events delivered to webhooks"),
+            &["chain"],
+        )
+        .expect("unable to build metric");
+        let webhook_failures = IntCounterVec::new(
+            Opts::new(
+                "webhook_failures_total",
+                "Events that could not be delivered to webhooks",
+            ),
+            &["chain"],
+        )
+        .expect("unable to build metric");
+        let webhook_retries = IntCounterVec::new(
+            Opts::new("webhook_retries_total", "Webhook deliveries retried"),
+            &["chain"],
+        )
+        .expect("unable to build metric");
+        let reorg_depth = HistogramVec::new(
+            HistogramOpts::new(
+                "reorg_depth_blocks",
+                "Number of blocks rolled back by reorgs",
+            )
+            .buckets(vec![1.0, 2.0, 3.0, 5.0, 7.0, 10.0, 20.0]),
+            &["chain"],
+        )
+        .expect("unable to build metric");
+        let mempool_size = IntGauge::new("mempool_size", "Transactions pending in the mempool")
+            .expect("unable to build metric");
+
+        registry
+            .register(Box::new(blocks_ingested.clone()))
+            .expect("unable to register metric");
+        registry
+            .register(Box::new(standardization_latency.clone()))
+            .expect("unable to register metric");
+        registry
+            .register(Box::new(webhook_successes.clone()))
+            .expect("unable to register metric");
+        registry
+            .register(Box::new(webhook_failures.clone()))
+            .expect("unable to register metric");
+        registry
+            .register(Box::new(webhook_retries.clone()))
+            .expect("unable to register metric");
+        registry
+            .register(Box::new(reorg_depth.clone()))
+            .expect("unable to register metric");
+        registry
+            .register(Box::new(mempool_size.clone()))
+            .expect("unable to register metric");
+
+        ObserverMetrics {
+            registry,
+            blocks_ingested,
+            standardization_latency,
+            webhook_successes,
+            webhook_failures,
+            webhook_retries,
+            reorg_depth,
+            mempool_size,
+            mempool: Mutex::new(HashSet::new()),
+            started_at: Instant::now(),
+            last_bitcoin_block_at: Mutex::new(None),
+            last_stacks_block_at: Mutex::new(None),
+        }
+    }
+
+    pub fn standardization_timer(&self, chain: &str) -> Histogram {
+        self.standardization_latency.with_label_values(&[chain])
+    }
+
+    pub fn record_block_ingested(&self, chain: &str) {
+        self.blocks_ingested.with_label_values(&[chain]).inc();
+        let last_block_at = match chain {
+            BITCOIN => &self.last_bitcoin_block_at,
+            _ => &self.last_stacks_block_at,
+        };
+        if let Ok(mut last_block_at) = last_block_at.lock() {
+            *last_block_at = Some(Instant::now());
+        }
+    }
+
+    pub fn record_webhook_success(&self, chain: &str) {
+        self.webhook_successes.with_label_values(&[chain]).inc();
+    }
+
+    pub fn record_webhook_failure(&self, chain: &str) {
+        self.webhook_failures.with_label_values(&[chain]).inc();
+    }
+
+    pub fn record_webhook_retry(&self, chain: &str) {
+        self.webhook_retries.with_label_values(&[chain]).inc();
+    }
+
+    pub fn record_reorg(&self, chain: &str, depth: usize) {
+        self.reorg_depth
+            .with_label_values(&[chain])
+            .observe(depth as f64);
+    }
+
+    pub fn record_mempool_admissions(&self, txids: Vec<String>) {
+        if let Ok(mut mempool) = self.mempool.lock() {
+            mempool.extend(txids);
+            self.mempool_size.set(mempool.len() as i64);
+        }
+    }
+
+    /// Transactions leave the mempool when they are dropped or mined.
+    pub fn record_mempool_evictions(&self, txids: &[String]) {
+        if let Ok(mut mempool) = self.mempool.lock() {
+            for txid in txids.iter() {
+                mempool.remove(txid);
+            }
+            self.mempool_size.set(mempool.len() as i64);
+        }
+    }
+
+    /// Age of the most recent block received for a given chain. Until a first
+    /// block is received, the uptime of the observer is returned instead.
+    pub fn get_tip_age(&self, chain: &str) -> Duration {
+        let last_block_at = match chain {
+            BITCOIN => &self.last_bitcoin_block_at,
+            _ => &self.last_stacks_block_at,
+        };
+        match last_block_at.lock() {
+            Ok(last_block_at) => last_block_at.unwrap_or(self.started_at).elapsed(),
+            _ => self.started_at.elapsed(),
+        }
+    }
+
+    pub fn encode(&self) -> Result<String, String> {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mempool_size_tracks_admissions_and_evictions() {
+        let metrics = ObserverMetrics::new();
+        metrics.record_mempool_admissions(vec!["0x01".into(), "0x02".into(), "0x03".into()]);
+        metrics.record_mempool_evictions(&vec!["0x02".into(), "0x04".into()]);
+        assert_eq!(metrics.mempool_size.get(), 2);
+
+        metrics.record_block_ingested(STACKS);
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded.contains("orchestra_observer_mempool_size 2"));
+        assert!(encoded.contains("orchestra_observer_blocks_ingested_total{chain=\"stacks\"} 1"));
+    }
+}
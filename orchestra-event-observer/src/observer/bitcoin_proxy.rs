@@ -0,0 +1,278 @@
+use super::BitcoinRPCRequest;
+use orchestra_types::{
+    BitcoinBlockData, BitcoinChainEvent, BitcoinTransactionBroadcastData,
+    BitcoinTransactionConfirmedData, BitcoinTransactionEvent, BlockIdentifier,
+    TransactionIdentifier,
+};
+use rocket::serde::json::Value as JsonValue;
+use std::collections::HashMap;
+
+/// Past this number of blocks, a confirmation is considered final and the
+/// transaction stops being tracked.
+const CONFIRMATIONS_TRACKED: u64 = 7;
+
+/// Past this number of blocks, an unconfirmed transaction stops being tracked:
+/// bitcoind evicts transactions from its mempool after two weeks.
+const PENDING_BLOCKS_TRACKED: u64 = 2016;
+
+/// Returns the requests of a JSON-RPC call, which can either be a single
+/// request or a batch of requests.
+pub fn parse_rpc_requests(body: &JsonValue) -> Vec<BitcoinRPCRequest> {
+    let requests = match body {
+        JsonValue::Array(requests) => requests.clone(),
+        request => vec![request.clone()],
+    };
+    requests
+        .into_iter()
+        .filter_map(|request| serde_json::from_value(request).ok())
+        .collect()
+}
+
+/// Matches the `sendrawtransaction` requests with the responses returned by
+/// bitcoind, and returns the transactions that were accepted.
+pub fn extract_broadcasts(
+    requests: &[BitcoinRPCRequest],
+    response: &JsonValue,
+) -> Vec<BitcoinTransactionBroadcastData> {
+    let responses = match response {
+        JsonValue::Array(responses) => responses.iter().collect::<Vec<_>>(),
+        response => vec![response],
+    };
+    let mut broadcasts = vec![];
+    for request in requests.iter() {
+        if request.method != "sendrawtransaction" {
+            continue;
+        }
+        let raw_tx = match request.params.get(0).and_then(|p| p.as_str()) {
+            Some(raw_tx) => raw_tx.to_string(),
+            None => continue,
+        };
+        let response = responses
+            .iter()
+            .find(|r| r.get("id") == Some(&request.id) || responses.len() == 1);
+        let txid = match response {
+            Some(response) if response.get("error").map_or(true, |e| e.is_null()) => {
+                match response.get("result").and_then(|r| r.as_str()) {
+                    Some(txid) => txid.to_string(),
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        broadcasts.push(BitcoinTransactionBroadcastData {
+            transaction_identifier: TransactionIdentifier { hash: txid },
+            raw_tx,
+        });
+    }
+    broadcasts
+}
+
+/// Keeps track of the transactions broadcasted through the proxy, until they
+/// are buried deep enough in the chain, or expired from the mempool.
+#[derive(Default)]
+pub struct BroadcastTracker {
+    /// Broadcasts, with the height of the tip when they started being pending
+    /// (unknown until the first chain update).
+    pending: HashMap<String, (BitcoinTransactionBroadcastData, Option<u64>)>,
+    confirmed: HashMap<String, (BitcoinTransactionBroadcastData, BlockIdentifier)>,
+    tip_height: Option<u64>,
+}
+
+impl BroadcastTracker {
+    pub fn new() -> BroadcastTracker {
+        BroadcastTracker::default()
+    }
+
+    pub fn track(&mut self, broadcast: BitcoinTransactionBroadcastData) {
+        self.pending.insert(
+            broadcast.transaction_identifier.hash.clone(),
+            (broadcast, self.tip_height),
+        );
+    }
+
+    /// Returns the confirmations carried by a chain update. Transactions
+    /// confirmed in blocks rolled back by a reorg are pending again.
+    pub fn process_chain_event(
+        &mut self,
+        chain_event: &BitcoinChainEvent,
+    ) -> Vec<BitcoinTransactionEvent> {
+        let new_blocks = match chain_event {
+            BitcoinChainEvent::ChainUpdatedWithBlock(block) => vec![block],
+            BitcoinChainEvent::ChainUpdatedWithReorg(old_blocks, new_blocks) => {
+                for block in old_blocks.iter() {
+                    self.unconfirm_block(block);
+                }
+                new_blocks.iter().collect()
+            }
+        };
+
+        let mut events = vec![];
+        for block in new_blocks.iter() {
+            for tx in block.transactions.iter() {
+                let broadcast = match self.pending.remove(&tx.transaction_identifier.hash) {
+                    Some((broadcast, _)) => broadcast,
+                    None => continue,
+                };
+                events.push(BitcoinTransactionEvent::TransactionConfirmed(
+                    BitcoinTransactionConfirmedData {
+                        transaction_identifier: broadcast.transaction_identifier.clone(),
+                        block_identifier: block.block_identifier.clone(),
+                    },
+                ));
+                self.confirmed.insert(
+                    broadcast.transaction_identifier.hash.clone(),
+                    (broadcast, block.block_identifier.clone()),
+                );
+            }
+        }
+
+        if let Some(tip) = new_blocks.last() {
+            let tip_height = tip.block_identifier.index;
+            self.confirmed.retain(|_, (_, block_identifier)| {
+                tip_height.saturating_sub(block_identifier.index) < CONFIRMATIONS_TRACKED
+            });
+            self.pending.retain(|_, (_, pending_since)| {
+                let since = *pending_since.get_or_insert(tip_height);
+                tip_height.saturating_sub(since) < PENDING_BLOCKS_TRACKED
+            });
+            self.tip_height = Some(tip_height);
+        }
+        events
+    }
+
+    fn unconfirm_block(&mut self, block: &BitcoinBlockData) {
+        let txids = self
+            .confirmed
+            .iter()
+            .filter(|(_, (_, block_identifier))| block_identifier == &block.block_identifier)
+            .map(|(txid, _)| txid.clone())
+            .collect::<Vec<_>>();
+        for txid in txids.into_iter() {
+            if let Some((broadcast, _)) = self.confirmed.remove(&txid) {
+                self.pending
+                    .insert(txid, (broadcast, Some(block.block_identifier.index)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orchestra_types::{
+        BitcoinBlockMetadata, BitcoinTransactionData, BitcoinTransactionMetadata,
+    };
+
+    fn block(index: u64, hash: &str, txids: &[&str]) -> BitcoinBlockData {
+        BitcoinBlockData {
+            block_identifier: BlockIdentifier {
+                index,
+                hash: hash.into(),
+            },
+            parent_block_identifier: BlockIdentifier {
+                index: index - 1,
+                hash: format!("{}-parent", hash),
+            },
+            timestamp: 0,
+            transactions: txids
+                .iter()
+                .map(|txid| BitcoinTransactionData {
+                    transaction_identifier: TransactionIdentifier {
+                        hash: txid.to_string(),
+                    },
+                    operations: vec![],
+                    metadata: BitcoinTransactionMetadata {},
+                })
+                .collect(),
+            metadata: BitcoinBlockMetadata {},
+        }
+    }
+
+    #[test]
+    fn batch_broadcasts_are_matched_by_id() {
+        let body = json!([
+            { "jsonrpc": "1.0", "id": 1, "method": "getblockcount", "params": [] },
+            { "jsonrpc": "1.0", "id": 2, "method": "sendrawtransaction", "params": ["00aa"] },
+            { "jsonrpc": "1.0", "id": 3, "method": "sendrawtransaction", "params": ["00bb"] },
+        ]);
+        let response = json!([
+            { "id": 1, "result": 100, "error": null },
+            { "id": 3, "result": "txid-bb", "error": null },
+            { "id": 2, "result": null, "error": { "code": -26, "message": "dust" } },
+        ]);
+        let requests = parse_rpc_requests(&body);
+        let broadcasts = extract_broadcasts(&requests, &response);
+        assert_eq!(broadcasts.len(), 1);
+        assert_eq!(broadcasts[0].transaction_identifier.hash, "txid-bb");
+        assert_eq!(broadcasts[0].raw_tx, "00bb");
+    }
+
+    fn broadcast(txid: &str) -> BitcoinTransactionBroadcastData {
+        BitcoinTransactionBroadcastData {
+            transaction_identifier: TransactionIdentifier { hash: txid.into() },
+            raw_tx: "00".into(),
+        }
+    }
+
+    #[test]
+    fn confirmations_are_reverted_by_reorgs() {
+        let mut tracker = BroadcastTracker::new();
+        tracker.track(broadcast("txid"));
+
+        let events = tracker.process_chain_event(&BitcoinChainEvent::ChainUpdatedWithBlock(block(
+            10,
+            "a",
+            &["other", "txid"],
+        )));
+        assert_eq!(events.len(), 1);
+
+        let events = tracker.process_chain_event(&BitcoinChainEvent::ChainUpdatedWithReorg(
+            vec![block(10, "a", &["other", "txid"])],
+            vec![block(10, "b", &[]), block(11, "c", &["txid"])],
+        ));
+        match &events[..] {
+            [BitcoinTransactionEvent::TransactionConfirmed(data)] => {
+                assert_eq!(data.block_identifier.hash, "c")
+            }
+            _ => panic!("expected a confirmation in the new fork"),
+        }
+    }
+
+    #[test]
+    fn unconfirmed_broadcasts_expire() {
+        let mut tracker = BroadcastTracker::new();
+        tracker.track(broadcast("dropped"));
+        tracker.process_chain_event(&BitcoinChainEvent::ChainUpdatedWithBlock(block(
+            10,
+            "a",
+            &[],
+        )));
+        tracker.process_chain_event(&BitcoinChainEvent::ChainUpdatedWithBlock(block(
+            11,
+            "b",
+            &[],
+        )));
+        tracker.track(broadcast("txid"));
+
+        let height = 10 + PENDING_BLOCKS_TRACKED;
+        tracker.process_chain_event(&BitcoinChainEvent::ChainUpdatedWithBlock(block(
+            height,
+            "c",
+            &[],
+        )));
+        assert!(!tracker.pending.contains_key("dropped"));
+        assert!(tracker.pending.contains_key("txid"));
+
+        let events = tracker.process_chain_event(&BitcoinChainEvent::ChainUpdatedWithBlock(block(
+            height + 1,
+            "d",
+            &["dropped", "txid"],
+        )));
+        match &events[..] {
+            [BitcoinTransactionEvent::TransactionConfirmed(data)] => {
+                assert_eq!(data.transaction_identifier.hash, "txid")
+            }
+            _ => panic!("expected the confirmation of the tracked broadcast only"),
+        }
+    }
+}
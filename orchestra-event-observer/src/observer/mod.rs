@@ -1,12 +1,23 @@
+mod bitcoin_proxy;
+
+use crate::fees::{self, FeeEstimator};
 use crate::indexer::{chains, Indexer, IndexerConfig};
+use crate::metrics::{self, ObserverMetrics};
+use crate::recorder::{PayloadRecorder, RecordedPayloadKind};
 use crate::utils;
-use orchestra_types::{BitcoinChainEvent, ChainsCoordinatorCommand, StacksNetwork, StacksChainEvent};
-use stacks_rpc_client::{PoxInfo, StacksRpc};
+use bitcoin_proxy::BroadcastTracker;
+use orchestra_types::{
+    BitcoinChainEvent, BitcoinNetwork, BitcoinTransactionBroadcastData, BitcoinTransactionEvent,
+    ChainsCoordinatorCommand, StacksChainEvent, StacksNetwork,
+};
+use stacks_rpc_client::{AsyncStacksRpc, PoxInfo, RetryPolicy, StacksRpc};
 use rocket::config::{Config, LogLevel};
+use rocket::http::{ContentType, Status};
+use rocket::response::status::Custom;
 use rocket::serde::json::{json, Json, Value as JsonValue};
-use rocket::serde::Deserialize;
+use rocket::serde::{Deserialize, Serialize};
 use rocket::State;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::error::Error;
 use std::iter::FromIterator;
@@ -14,8 +25,9 @@ use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
 use std::str;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use reqwest::Client as HttpClient;
 
 #[derive(Deserialize)]
@@ -30,58 +42,234 @@ pub struct NewTransaction {
 pub enum Event {
     BitcoinChainEvent(BitcoinChainEvent),
     StacksChainEvent(StacksChainEvent),
+    BitcoinTransactionEvent(BitcoinTransactionEvent),
+}
+
+/// Networks the observer is attached to, added to every event posted to webhooks.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainEventTag {
+    pub network: StacksNetwork,
+    pub bitcoin_network: BitcoinNetwork,
+    pub chain_id: u32,
+}
+
+impl ChainEventTag {
+    pub fn new(network: &StacksNetwork) -> ChainEventTag {
+        ChainEventTag {
+            network: network.clone(),
+            bitcoin_network: network.get_bitcoin_network(),
+            chain_id: network.chain_id(),
+        }
+    }
+}
+
+/// Event sent to the event handlers, along with the networks it was observed on.
+/// When posted to webhooks, the event is nested rather than flattened: serde
+/// can't flatten the tuple variants of the chain events.
+#[derive(Clone, Debug, Serialize)]
+pub struct TaggedEvent<T> {
+    pub tag: ChainEventTag,
+    pub event: T,
+}
+
+fn serialize_tagged_event<T: Serialize>(tag: &ChainEventTag, event: &T) -> Result<Vec<u8>, String> {
+    let tagged_event = TaggedEvent {
+        tag: tag.clone(),
+        event,
+    };
+    rocket::serde::json::serde_json::to_vec(&tagged_event)
+        .map_err(|e| format!("unable to serialize event: {}", e))
 }
 
 #[derive(Clone, Debug)]
 pub enum EventHandler {
-    InProcess(Sender<Event>),
+    InProcess(Sender<TaggedEvent<Event>>),
     WebHook(String),
 }
 
 impl EventHandler {
 
-    async fn propagate_stacks_event(&self, stacks_event: &StacksChainEvent) {
+    fn propagate_stacks_event(
+        &self,
+        stacks_event: &StacksChainEvent,
+        tag: &ChainEventTag,
+        webhook_workers: &mut WebhookWorkers,
+    ) -> Result<(), String> {
         match self {
             EventHandler::InProcess(event_sender) => {
-                let _ = event_sender.send(Event::StacksChainEvent(stacks_event.clone()));
+                let _ = event_sender.send(TaggedEvent {
+                    tag: tag.clone(),
+                    event: Event::StacksChainEvent(stacks_event.clone()),
+                });
             }
             EventHandler::WebHook(host) => {
                 let path = "chain-events/stacks";
                 let url = format!("{}/{}", host, path);
-                let body = rocket::serde::json::serde_json::to_vec(&stacks_event).unwrap();
-                let http_client = HttpClient::builder().build().expect("Unable to build http client");
-                let _ = http_client
-                    .post(url)
-                    .header("Content-Type", "application/json")
-                    .body(body)
-                    .send()
-                    .await;
+                let body = serialize_tagged_event(tag, stacks_event)?;
+                webhook_workers.deliver(host, url, body, metrics::STACKS)?;
             }
         }
+        Ok(())
     }
 
-    async fn propagate_bitcoin_event(&self, bitcoin_event: &BitcoinChainEvent) {
+    fn propagate_bitcoin_event(
+        &self,
+        bitcoin_event: &BitcoinChainEvent,
+        tag: &ChainEventTag,
+        webhook_workers: &mut WebhookWorkers,
+    ) -> Result<(), String> {
         match self {
             EventHandler::InProcess(event_sender) => {
-                let _ = event_sender.send(Event::BitcoinChainEvent(bitcoin_event.clone()));
+                let _ = event_sender.send(TaggedEvent {
+                    tag: tag.clone(),
+                    event: Event::BitcoinChainEvent(bitcoin_event.clone()),
+                });
             }
             EventHandler::WebHook(host) => {
                 let path = "chain-events/bitcoin";
                 let url = format!("{}/{}", host, path);
-                let body = rocket::serde::json::serde_json::to_vec(&bitcoin_event).unwrap();
-                let http_client = HttpClient::builder().build().expect("Unable to build http client");
-                let res = http_client
-                    .post(url)
-                    .header("Content-Type", "application/json")
-                    .body(body)
-                    .send()
-                    .await;
+                let body = serialize_tagged_event(tag, bitcoin_event)?;
+                webhook_workers.deliver(host, url, body, metrics::BITCOIN)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn propagate_bitcoin_transaction_event(
+        &self,
+        transaction_event: &BitcoinTransactionEvent,
+        tag: &ChainEventTag,
+        webhook_workers: &mut WebhookWorkers,
+    ) -> Result<(), String> {
+        match self {
+            EventHandler::InProcess(event_sender) => {
+                let _ = event_sender.send(TaggedEvent {
+                    tag: tag.clone(),
+                    event: Event::BitcoinTransactionEvent(transaction_event.clone()),
+                });
+            }
+            EventHandler::WebHook(host) => {
+                let path = "chain-events/bitcoin-transactions";
+                let url = format!("{}/{}", host, path);
+                let body = serialize_tagged_event(tag, transaction_event)?;
+                webhook_workers.deliver(host, url, body, metrics::BITCOIN)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+const WEBHOOK_MAX_ATTEMPTS: u32 = 3;
+
+/// Chain event serialized for a webhook.
+struct WebhookDelivery {
+    url: String,
+    body: Vec<u8>,
+    chain: &'static str,
+}
+
+/// Posts the chain events to the webhooks off the command loop, so that a
+/// webhook being retried does not hold back the other handlers. Events are
+/// delivered by a worker per webhook, in order.
+struct WebhookWorkers {
+    metrics: Arc<ObserverMetrics>,
+    workers: HashMap<String, Sender<WebhookDelivery>>,
+}
+
+impl WebhookWorkers {
+    fn new(metrics: Arc<ObserverMetrics>) -> WebhookWorkers {
+        WebhookWorkers {
+            metrics,
+            workers: HashMap::new(),
+        }
+    }
+
+    fn deliver(
+        &mut self,
+        host: &str,
+        url: String,
+        body: Vec<u8>,
+        chain: &'static str,
+    ) -> Result<(), String> {
+        let metrics = &self.metrics;
+        let worker = self
+            .workers
+            .entry(host.to_string())
+            .or_insert_with(|| spawn_webhook_worker(metrics.clone()));
+        if let Err(e) = worker.send(WebhookDelivery { url, body, chain }) {
+            // The worker is gone: a new one is spawned for the next event
+            self.workers.remove(host);
+            return Err(format!("unable to schedule delivery to {}: {}", host, e));
+        }
+        Ok(())
+    }
+}
+
+fn spawn_webhook_worker(metrics: Arc<ObserverMetrics>) -> Sender<WebhookDelivery> {
+    let (deliveries_tx, deliveries_rx) = channel::<WebhookDelivery>();
+    std::thread::spawn(move || {
+        let rt = utils::create_basic_runtime();
+        while let Ok(delivery) = deliveries_rx.recv() {
+            rt.block_on(post_to_webhook(
+                delivery.url,
+                delivery.body,
+                delivery.chain,
+                &metrics,
+            ));
+        }
+    });
+    deliveries_tx
+}
+
+/// Posts a chain event to a webhook, retrying with an exponential backoff
+/// until the webhook acknowledges it with a 2xx status.
+async fn post_to_webhook(url: String, body: Vec<u8>, chain: &str, metrics: &ObserverMetrics) {
+    let http_client = HttpClient::builder().build().expect("Unable to build http client");
+    for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+        let res = http_client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(body.clone())
+            .send()
+            .await;
+        if let Ok(ref response) = res {
+            if response.status().is_success() {
+                metrics.record_webhook_success(chain);
+                return;
             }
         }
+        if attempt < WEBHOOK_MAX_ATTEMPTS {
+            metrics.record_webhook_retry(chain);
+            tokio::time::sleep(Duration::from_millis(500 * 2u64.pow(attempt - 1))).await;
+        }
     }
+    metrics.record_webhook_failure(chain);
+}
 
-    async fn notify_bitcoin_transaction_proxied(&self) {
+/// Kinds of chain events forwarded to the event handlers.
+#[derive(Clone, Debug)]
+pub struct EventFilter {
+    pub bitcoin_blocks: bool,
+    pub stacks_blocks: bool,
+    pub stacks_microblocks: bool,
+}
 
+impl EventFilter {
+    pub fn allow_all() -> EventFilter {
+        EventFilter {
+            bitcoin_blocks: true,
+            stacks_blocks: true,
+            stacks_microblocks: true,
+        }
+    }
+
+    pub fn accepts_stacks_event(&self, stacks_event: &StacksChainEvent) -> bool {
+        match stacks_event {
+            StacksChainEvent::ChainUpdatedWithBlock(_)
+            | StacksChainEvent::ChainUpdatedWithReorg(_) => self.stacks_blocks,
+            StacksChainEvent::ChainUpdatedWithMicroblock(_)
+            | StacksChainEvent::ChainUpdatedWithMicroblockReorg(_) => self.stacks_microblocks,
+        }
     }
 }
 
@@ -90,6 +278,7 @@ pub struct StacksEventObserverConfig {
     pub normalization_enabled: bool,
     pub bitcoin_rpc_proxy_enabled: bool,
     pub event_handlers: Vec<EventHandler>,
+    pub event_filter: EventFilter,
     pub observer_port: u16,
     pub bitcoin_node_username: String,
     pub bitcoin_node_password: String,
@@ -97,6 +286,37 @@ pub struct StacksEventObserverConfig {
     pub bitcoin_node_rpc_port: u16,
     pub stacks_node_rpc_host: String,
     pub stacks_node_rpc_port: u16,
+    pub capture_path: Option<PathBuf>,
+    pub network: StacksNetwork,
+    /// Above this age, the chain tip is considered stale and `/health/ready` fails.
+    pub max_tip_age_secs: u64,
+}
+
+impl StacksEventObserverConfig {
+    pub fn get_indexer_config(&self) -> IndexerConfig {
+        IndexerConfig {
+            stacks_node_rpc_url: self.get_stacks_node_rpc_url(),
+            bitcoin_node_rpc_url: self.get_bitcoin_node_rpc_url(),
+            bitcoin_node_rpc_username: self.bitcoin_node_username.clone(),
+            bitcoin_node_rpc_password: self.bitcoin_node_password.clone(),
+            stacks_network: self.network.clone(),
+            bitcoin_network: self.network.get_bitcoin_network(),
+        }
+    }
+
+    pub fn get_stacks_node_rpc_url(&self) -> String {
+        format!(
+            "http://{}:{}",
+            self.stacks_node_rpc_host, self.stacks_node_rpc_port
+        )
+    }
+
+    pub fn get_bitcoin_node_rpc_url(&self) -> String {
+        format!(
+            "http://{}:{}",
+            self.bitcoin_node_rpc_host, self.bitcoin_node_rpc_port
+        )
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -109,7 +329,9 @@ pub struct ContractReadonlyCall {
 pub enum ObserverCommand {
     PropagateBitcoinChainEvent(BitcoinChainEvent),
     PropagateStacksChainEvent(StacksChainEvent),
-    NotifyBitcoinTransactionProxied,
+    NotifyBitcoinTransactionProxied(BitcoinTransactionBroadcastData),
+    NotifyPayloadRejected(String),
+    NotifyCaptureIncomplete(String),
     Terminate,
 }
 
@@ -130,6 +352,7 @@ pub struct BitcoinRPCRequest {
     /// Identifier for this Request, which should appear in the response
     pub id: serde_json::Value,
     /// jsonrpc field, MUST be "2.0"
+    #[serde(default)]
     pub jsonrpc: serde_json::Value,
 }
 
@@ -140,23 +363,15 @@ pub async fn start_observer(
     observer_events_tx: Sender<ObserverEvent>,
 ) -> Result<(), Box<dyn Error>> {
 
-    let indexer = Indexer::new(IndexerConfig {
-        stacks_node_rpc_url: format!(
-            "http://{}:{}",
-            config.stacks_node_rpc_host,
-            config.stacks_node_rpc_port
-        ),
-        bitcoin_node_rpc_url: format!(
-            "http://{}:{}",
-            config.bitcoin_node_rpc_host,
-            config.bitcoin_node_rpc_port
-        ),
-        bitcoin_node_rpc_username: config.bitcoin_node_username.clone(),
-        bitcoin_node_rpc_password: config.bitcoin_node_password.clone(),
-    });
+    let indexer = Indexer::new(config.get_indexer_config());
 
     let port = config.observer_port;
 
+    let recorder = PayloadRecorder::new(config.capture_path.as_ref())?;
+    let recorder_mutex = Arc::new(Mutex::new(recorder));
+    let metrics = Arc::new(ObserverMetrics::new());
+    let fee_estimator_rw_lock = Arc::new(RwLock::new(FeeEstimator::new()));
+
     let config_mutex = Arc::new(Mutex::new(config.clone()));
     let indexer_rw_lock = Arc::new(RwLock::new(indexer));
 
@@ -174,22 +389,32 @@ pub async fn start_observer(
 
     let mut routes = routes![
         handle_ping,
+        handle_metrics,
+        handle_liveness_probe,
+        handle_readiness_probe,
         handle_new_bitcoin_block,
         handle_new_stacks_block,
         handle_new_microblocks,
         handle_new_mempool_tx,
         handle_drop_mempool_tx,
+        handle_get_fee_estimate,
+        handle_get_contract_call_fee_estimate,
     ];
 
     if config.bitcoin_rpc_proxy_enabled {
         routes.append(&mut routes![handle_bitcoin_rpc_call]);
     }
 
+    let moved_metrics = metrics.clone();
+    let moved_fee_estimator_rw_lock = fee_estimator_rw_lock.clone();
     let _ = std::thread::spawn(move || {
         let future = rocket::custom(rocket_config)
             .manage(indexer_rw_lock)
             .manage(config_mutex)
             .manage(background_job_tx_mutex)
+            .manage(recorder_mutex)
+            .manage(moved_metrics)
+            .manage(moved_fee_estimator_rw_lock)
             .mount(
                 "/",
                 routes,
@@ -201,6 +426,9 @@ pub async fn start_observer(
 
     // This loop is used for handling background jobs, emitted by HTTP calls.
     let stop_miner = Arc::new(AtomicBool::new(false));
+    let mut broadcast_tracker = BroadcastTracker::new();
+    let mut webhook_workers = WebhookWorkers::new(metrics.clone());
+    let tag = ChainEventTag::new(&config.network);
 
     loop {
         let command = match observer_commands_rx.recv() {
@@ -216,18 +444,64 @@ pub async fn start_observer(
                 break;
             }
             ObserverCommand::PropagateBitcoinChainEvent(event) => {
+                if let BitcoinChainEvent::ChainUpdatedWithReorg(ref old_blocks, _) = event {
+                    metrics.record_reorg(metrics::BITCOIN, old_blocks.len());
+                }
+                for transaction_event in broadcast_tracker.process_chain_event(&event).iter() {
+                    for event_handler in config.event_handlers.iter() {
+                        if let Err(e) = event_handler.propagate_bitcoin_transaction_event(
+                            transaction_event,
+                            &tag,
+                            &mut webhook_workers,
+                        ) {
+                            let _ = observer_events_tx.send(ObserverEvent::Error(e));
+                        }
+                    }
+                }
+                if !config.event_filter.bitcoin_blocks {
+                    continue;
+                }
                 for event_handler in config.event_handlers.iter() {
-                    event_handler.propagate_bitcoin_event(&event).await;
+                    if let Err(e) =
+                        event_handler.propagate_bitcoin_event(&event, &tag, &mut webhook_workers)
+                    {
+                        let _ = observer_events_tx.send(ObserverEvent::Error(e));
+                    }
                 }
             }
             ObserverCommand::PropagateStacksChainEvent(event) => {
+                if let StacksChainEvent::ChainUpdatedWithReorg(ref update) = event {
+                    metrics.record_reorg(metrics::STACKS, update.old_blocks.len());
+                }
+                if let Ok(mut fee_estimator) = fee_estimator_rw_lock.write() {
+                    fee_estimator.process_chain_event(&event);
+                }
+                if !config.event_filter.accepts_stacks_event(&event) {
+                    continue;
+                }
                 for event_handler in config.event_handlers.iter() {
-                    event_handler.propagate_stacks_event(&event).await;
+                    if let Err(e) =
+                        event_handler.propagate_stacks_event(&event, &tag, &mut webhook_workers)
+                    {
+                        let _ = observer_events_tx.send(ObserverEvent::Error(e));
+                    }
                 }
             }
-            ObserverCommand::NotifyBitcoinTransactionProxied => {
+            ObserverCommand::NotifyPayloadRejected(message)
+            | ObserverCommand::NotifyCaptureIncomplete(message) => {
+                let _ = observer_events_tx.send(ObserverEvent::Error(message));
+            }
+            ObserverCommand::NotifyBitcoinTransactionProxied(broadcast) => {
+                broadcast_tracker.track(broadcast.clone());
+                let transaction_event = BitcoinTransactionEvent::TransactionBroadcast(broadcast);
                 for event_handler in config.event_handlers.iter() {
-                    event_handler.notify_bitcoin_transaction_proxied().await;
+                    if let Err(e) = event_handler.propagate_bitcoin_transaction_event(
+                        &transaction_event,
+                        &tag,
+                        &mut webhook_workers,
+                    ) {
+                        let _ = observer_events_tx.send(ObserverEvent::Error(e));
+                    }
                 }
             }
         }
@@ -243,25 +517,264 @@ pub fn handle_ping() -> Json<JsonValue> {
     }))
 }
 
+#[get("/metrics")]
+pub fn handle_metrics(metrics: &State<Arc<ObserverMetrics>>) -> Custom<(ContentType, String)> {
+    match metrics.encode() {
+        Ok(body) => Custom(Status::Ok, (ContentType::Plain, body)),
+        Err(e) => Custom(Status::InternalServerError, (ContentType::Plain, e)),
+    }
+}
+
+/// Returns the fees paid by recent STX transfers ("stx-transfer") or
+/// contract deployments ("contract-deploy").
+#[get("/v1/fees/<kind>")]
+pub fn handle_get_fee_estimate(
+    kind: &str,
+    fee_estimator_rw_lock: &State<Arc<RwLock<FeeEstimator>>>,
+) -> Custom<Json<JsonValue>> {
+    if kind != fees::STX_TRANSFER && kind != fees::CONTRACT_DEPLOY {
+        return Custom(
+            Status::NotFound,
+            Json(json!({
+                "status": 404,
+                "result": format!("unknown transaction kind {}", kind),
+            })),
+        );
+    }
+    get_fee_estimate(kind, fee_estimator_rw_lock.inner())
+}
+
+/// Returns the fees paid by recent calls to a contract function.
+#[get("/v1/fees/contract-call/<contract_identifier>/<function_name>")]
+pub fn handle_get_contract_call_fee_estimate(
+    contract_identifier: &str,
+    function_name: &str,
+    fee_estimator_rw_lock: &State<Arc<RwLock<FeeEstimator>>>,
+) -> Custom<Json<JsonValue>> {
+    let key = fees::get_contract_call_key(contract_identifier, function_name);
+    get_fee_estimate(&key, fee_estimator_rw_lock.inner())
+}
+
+fn get_fee_estimate(
+    key: &str,
+    fee_estimator_rw_lock: &RwLock<FeeEstimator>,
+) -> Custom<Json<JsonValue>> {
+    let estimate = match fee_estimator_rw_lock.read() {
+        Ok(fee_estimator) => fee_estimator.estimate(key),
+        _ => {
+            return Custom(
+                Status::InternalServerError,
+                Json(json!({
+                    "status": 500,
+                    "result": "fee estimator lock poisoned",
+                })),
+            )
+        }
+    };
+    match estimate {
+        Some(estimate) => Custom(
+            Status::Ok,
+            Json(json!({
+                "status": 200,
+                "result": estimate,
+            })),
+        ),
+        None => Custom(
+            Status::NotFound,
+            Json(json!({
+                "status": 404,
+                "result": format!("no transaction observed for {}", key),
+            })),
+        ),
+    }
+}
+
+/// Fails once the indexer became unusable, which can only be fixed by a restart.
+#[get("/health/live")]
+pub fn handle_liveness_probe(
+    indexer_rw_lock: &State<Arc<RwLock<Indexer>>>,
+) -> Custom<Json<JsonValue>> {
+    if indexer_rw_lock.inner().is_poisoned() {
+        return Custom(
+            Status::ServiceUnavailable,
+            Json(json!({
+                "status": 503,
+                "result": "indexer lock poisoned",
+            })),
+        );
+    }
+    Custom(
+        Status::Ok,
+        Json(json!({
+            "status": 200,
+            "result": "Ok",
+        })),
+    )
+}
+
+/// Fails while the nodes are unreachable or while the chain tips are stale.
+#[get("/health/ready")]
+pub async fn handle_readiness_probe(
+    indexer_rw_lock: &State<Arc<RwLock<Indexer>>>,
+    config: &State<Arc<Mutex<StacksEventObserverConfig>>>,
+    metrics: &State<Arc<ObserverMetrics>>,
+) -> Custom<Json<JsonValue>> {
+    let config = match config.inner().lock() {
+        Ok(config) => config.clone(),
+        _ => {
+            return Custom(
+                Status::ServiceUnavailable,
+                Json(json!({
+                    "status": 503,
+                    "result": "config lock poisoned",
+                })),
+            )
+        }
+    };
+
+    let mut failures = vec![];
+    if indexer_rw_lock.inner().is_poisoned() {
+        failures.push("indexer lock poisoned".to_string());
+    }
+    if let Err(e) = check_stacks_node_connectivity(&config).await {
+        failures.push(format!("stacks node: {}", e));
+    }
+    if let Err(e) = check_bitcoin_node_connectivity(&config).await {
+        failures.push(format!("bitcoin node: {}", e));
+    }
+    for chain in [metrics::STACKS, metrics::BITCOIN].iter() {
+        let tip_age = metrics.get_tip_age(chain);
+        if tip_age.as_secs() > config.max_tip_age_secs {
+            failures.push(format!(
+                "{} tip stale (no block received for {}s)",
+                chain,
+                tip_age.as_secs()
+            ));
+        }
+    }
+
+    if failures.is_empty() {
+        Custom(
+            Status::Ok,
+            Json(json!({
+                "status": 200,
+                "result": "Ok",
+            })),
+        )
+    } else {
+        Custom(
+            Status::ServiceUnavailable,
+            Json(json!({
+                "status": 503,
+                "result": failures,
+            })),
+        )
+    }
+}
+
+async fn check_stacks_node_connectivity(config: &StacksEventObserverConfig) -> Result<(), String> {
+    AsyncStacksRpc::new(&config.get_stacks_node_rpc_url())
+        .with_timeout(Duration::from_secs(5))
+        .with_retry_policy(RetryPolicy::none())
+        .get_info()
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+async fn check_bitcoin_node_connectivity(
+    config: &StacksEventObserverConfig,
+) -> Result<(), String> {
+    let token = base64::encode(format!(
+        "{}:{}",
+        config.bitcoin_node_username, config.bitcoin_node_password
+    ));
+    let res = HttpClient::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .map_err(|e| e.to_string())?
+        .post(config.get_bitcoin_node_rpc_url())
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Basic {}", token))
+        .json(&json!({
+            "jsonrpc": "1.0",
+            "id": "orchestra",
+            "method": "getblockchaininfo",
+            "params": [],
+        }))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    match res.status().is_success() {
+        true => Ok(()),
+        false => Err(format!("status {}", res.status())),
+    }
+}
+
 #[post("/new_burn_block", format = "json", data = "<marshalled_block>")]
 pub fn handle_new_bitcoin_block(
     indexer_rw_lock: &State<Arc<RwLock<Indexer>>>,
     marshalled_block: Json<JsonValue>,
     background_job_tx: &State<Arc<Mutex<Sender<ObserverCommand>>>>,
-) -> Json<JsonValue> {
+    recorder: &State<Arc<Mutex<PayloadRecorder>>>,
+    metrics: &State<Arc<ObserverMetrics>>,
+) -> Custom<Json<JsonValue>> {
+    let mut marshalled_block = marshalled_block.into_inner();
+
+    // When capturing, embed the full bitcoin block in the recorded payload,
+    // so that replaying the capture does not require the original bitcoind.
+    // The recorder is not locked while bitcoind is queried.
+    let capturing = match recorder.inner().lock() {
+        Ok(recorder) => recorder.is_enabled(),
+        _ => false,
+    };
+    if capturing {
+        let indexer_config = match indexer_rw_lock.inner().read() {
+            Ok(indexer) => Ok(indexer.get_config().clone()),
+            _ => Err("indexer unavailable".to_string()),
+        };
+        let raw_block = indexer_config.and_then(|indexer_config| {
+            chains::bitcoin::retrieve_raw_bitcoin_block(&indexer_config, &marshalled_block)
+        });
+        let mut recorded_block = marshalled_block.clone();
+        if let Some(payload) = recorded_block.as_object_mut() {
+            match raw_block {
+                Ok(raw_block) => {
+                    payload.insert("raw_block".into(), json!(raw_block));
+                }
+                Err(e) => {
+                    // Recorded, so that replaying the capture fails loudly
+                    // instead of silently querying another bitcoind.
+                    payload.insert("raw_block_error".into(), json!(e));
+                    if let Ok(tx) = background_job_tx.inner().lock() {
+                        let _ = tx.send(ObserverCommand::NotifyCaptureIncomplete(format!(
+                            "bitcoin block not captured: {}",
+                            e
+                        )));
+                    }
+                }
+            }
+        }
+        if let Ok(mut recorder) = recorder.inner().lock() {
+            let _ = recorder.record(RecordedPayloadKind::NewBurnBlock, &recorded_block);
+        }
+    }
 
     // Standardize the structure of the block, and identify the
     // kind of update that this new block would imply, taking
     // into account the last 7 blocks.
+    let timer = metrics.standardization_timer(metrics::BITCOIN).start_timer();
     let chain_update = match indexer_rw_lock.inner().write() {
-        Ok(mut indexer) => indexer.handle_bitcoin_block(marshalled_block.into_inner()),
-        _ => {
-            return Json(json!({
-                "status": 200,
-                "result": "Ok",
-            }))
-        }
+        Ok(mut indexer) => indexer.handle_bitcoin_block(marshalled_block),
+        _ => return payload_acknowledged(),
     };
+    let chain_update = match chain_update {
+        Ok(chain_update) => chain_update,
+        Err(e) => return payload_rejected(background_job_tx, "burn block", e),
+    };
+
+    timer.observe_duration();
+    metrics.record_block_ingested(metrics::BITCOIN);
 
     let background_job_tx = background_job_tx.inner();
     match background_job_tx.lock() {
@@ -271,10 +784,7 @@ pub fn handle_new_bitcoin_block(
         _ => {}
     };
 
-    Json(json!({
-        "status": 200,
-        "result": "Ok",
-    }))
+    payload_acknowledged()
 }
 
 #[post("/new_block", format = "application/json", data = "<marshalled_block>")]
@@ -282,24 +792,43 @@ pub fn handle_new_stacks_block(
     indexer_rw_lock: &State<Arc<RwLock<Indexer>>>,
     marshalled_block: Json<JsonValue>,
     background_job_tx: &State<Arc<Mutex<Sender<ObserverCommand>>>>,
-) -> Json<JsonValue> {
+    recorder: &State<Arc<Mutex<PayloadRecorder>>>,
+    metrics: &State<Arc<ObserverMetrics>>,
+) -> Custom<Json<JsonValue>> {
+    if let Ok(mut recorder) = recorder.inner().lock() {
+        let _ = recorder.record(RecordedPayloadKind::NewBlock, &marshalled_block);
+    }
+
     // Standardize the structure of the block, and identify the
     // kind of update that this new block would imply, taking
     // into account the last 7 blocks.
-    let (pox_info, chain_event) = match indexer_rw_lock.inner().write() {
+    let timer = metrics.standardization_timer(metrics::STACKS).start_timer();
+    let (pox_info, chain_event, rejected_transactions) = match indexer_rw_lock.inner().write() {
         Ok(mut indexer) => {
             let pox_info = indexer.get_pox_info();
             let chain_event = indexer.handle_stacks_block(marshalled_block.into_inner());
-            (pox_info, chain_event)
-        }
-        _ => {
-            return Json(json!({
-                "status": 200,
-                "result": "Ok",
-            }))
+            (pox_info, chain_event, indexer.take_rejected_transactions())
         }
+        _ => return payload_acknowledged(),
+    };
+    notify_rejected_transactions(background_job_tx, rejected_transactions);
+    let chain_event = match chain_event {
+        Ok(chain_event) => chain_event,
+        Err(e) => return payload_rejected(background_job_tx, "stacks block", e),
     };
 
+    timer.observe_duration();
+    metrics.record_block_ingested(metrics::STACKS);
+    if let StacksChainEvent::ChainUpdatedWithBlock(ref update) = chain_event {
+        let mined_txids = update
+            .new_block
+            .transactions
+            .iter()
+            .map(|tx| tx.transaction_identifier.hash.clone())
+            .collect::<Vec<_>>();
+        metrics.record_mempool_evictions(&mined_txids);
+    }
+
     let background_job_tx = background_job_tx.inner();
     match background_job_tx.lock() {
         Ok(tx) => {
@@ -308,10 +837,7 @@ pub fn handle_new_stacks_block(
         _ => {}
     };
 
-    Json(json!({
-        "status": 200,
-        "result": "Ok",
-    }))
+    payload_acknowledged()
 }
 
 #[post(
@@ -323,23 +849,42 @@ pub fn handle_new_microblocks(
     indexer_rw_lock: &State<Arc<RwLock<Indexer>>>,
     marshalled_microblock: Json<JsonValue>,
     background_job_tx: &State<Arc<Mutex<Sender<ObserverCommand>>>>,
-) -> Json<JsonValue> {
+    recorder: &State<Arc<Mutex<PayloadRecorder>>>,
+    metrics: &State<Arc<ObserverMetrics>>,
+) -> Custom<Json<JsonValue>> {
+    if let Ok(mut recorder) = recorder.inner().lock() {
+        let _ = recorder.record(RecordedPayloadKind::NewMicroblocks, &marshalled_microblock);
+    }
 
     // Standardize the structure of the microblock, and identify the
     // kind of update that this new microblock would imply
-    let chain_event = match indexer_rw_lock.inner().write() {
+    let timer = metrics.standardization_timer(metrics::STACKS_MICROBLOCK).start_timer();
+    let (chain_event, rejected_transactions) = match indexer_rw_lock.inner().write() {
         Ok(mut indexer) => {
             let chain_event = indexer.handle_stacks_microblock(marshalled_microblock.into_inner());
-            chain_event
-        }
-        _ => {
-            return Json(json!({
-                "status": 200,
-                "result": "Ok",
-            }))
+            (chain_event, indexer.take_rejected_transactions())
         }
+        _ => return payload_acknowledged(),
+    };
+    notify_rejected_transactions(background_job_tx, rejected_transactions);
+    let chain_event = match chain_event {
+        Ok(chain_event) => chain_event,
+        Err(e) => return payload_rejected(background_job_tx, "stacks microblock", e),
     };
 
+    timer.observe_duration();
+    metrics.record_block_ingested(metrics::STACKS_MICROBLOCK);
+    if let StacksChainEvent::ChainUpdatedWithMicroblock(ref update) = chain_event {
+        if let Some(microblock) = update.current_trail.microblocks.last() {
+            let mined_txids = microblock
+                .transactions
+                .iter()
+                .map(|tx| tx.transaction_identifier.hash.clone())
+                .collect::<Vec<_>>();
+            metrics.record_mempool_evictions(&mined_txids);
+        }
+    }
+
     let background_job_tx = background_job_tx.inner();
     match background_job_tx.lock() {
         Ok(tx) => {
@@ -348,25 +893,72 @@ pub fn handle_new_microblocks(
         _ => {}
     };
 
-    Json(json!({
-        "status": 200,
-        "result": "Ok",
-    }))
+    payload_acknowledged()
+}
+
+fn payload_acknowledged() -> Custom<Json<JsonValue>> {
+    Custom(
+        Status::Ok,
+        Json(json!({
+            "status": 200,
+            "result": "Ok",
+        })),
+    )
+}
+
+fn payload_rejected(
+    background_job_tx: &State<Arc<Mutex<Sender<ObserverCommand>>>>,
+    payload: &str,
+    error: String,
+) -> Custom<Json<JsonValue>> {
+    if let Ok(tx) = background_job_tx.inner().lock() {
+        let _ = tx.send(ObserverCommand::NotifyPayloadRejected(format!(
+            "{} rejected: {}",
+            payload, error
+        )));
+    }
+    Custom(
+        Status::BadRequest,
+        Json(json!({
+            "status": 400,
+            "result": error,
+        })),
+    )
+}
+
+fn notify_rejected_transactions(
+    background_job_tx: &State<Arc<Mutex<Sender<ObserverCommand>>>>,
+    rejected_transactions: Vec<String>,
+) {
+    if rejected_transactions.is_empty() {
+        return;
+    }
+    if let Ok(tx) = background_job_tx.inner().lock() {
+        for rejected_transaction in rejected_transactions.into_iter() {
+            let _ = tx.send(ObserverCommand::NotifyPayloadRejected(format!(
+                "{} skipped",
+                rejected_transaction
+            )));
+        }
+    }
 }
 
 #[post("/new_mempool_tx", format = "application/json", data = "<raw_txs>")]
 pub fn handle_new_mempool_tx(
     raw_txs: Json<Vec<String>>,
     background_job_tx: &State<Arc<Mutex<Sender<ObserverCommand>>>>,
+    recorder: &State<Arc<Mutex<PayloadRecorder>>>,
+    metrics: &State<Arc<ObserverMetrics>>,
 ) -> Json<JsonValue> {
+    if let Ok(mut recorder) = recorder.inner().lock() {
+        let _ = recorder.record(RecordedPayloadKind::NewMempoolTx, &json!(*raw_txs));
+    }
+
     let decoded_transactions = raw_txs
         .iter()
-        .map(|t| {
-            let (txid, ..) =
-                chains::stacks::get_tx_description(t).expect("unable to parse transaction");
-            txid
-        })
+        .filter_map(|t| chains::stacks::get_txid(t).ok())
         .collect::<Vec<String>>();
+    metrics.record_mempool_admissions(decoded_transactions.clone());
 
     // if let Ok(tx_sender) = devnet_events_tx.lock() {
     //     for tx in decoded_transactions.into_iter() {
@@ -380,62 +972,173 @@ pub fn handle_new_mempool_tx(
     }))
 }
 
-#[post("/drop_mempool_tx", format = "application/json")]
-pub fn handle_drop_mempool_tx() -> Json<JsonValue> {
+#[post("/drop_mempool_tx", format = "application/json", data = "<payload>")]
+pub fn handle_drop_mempool_tx(
+    payload: Json<JsonValue>,
+    recorder: &State<Arc<Mutex<PayloadRecorder>>>,
+    metrics: &State<Arc<ObserverMetrics>>,
+) -> Json<JsonValue> {
+    if let Ok(mut recorder) = recorder.inner().lock() {
+        let _ = recorder.record(RecordedPayloadKind::DropMempoolTx, &payload);
+    }
+
+    if let Some(dropped_txids) = payload.get("dropped_txids").and_then(|t| t.as_array()) {
+        let dropped_txids = dropped_txids
+            .iter()
+            .filter_map(|txid| txid.as_str().map(|txid| txid.to_string()))
+            .collect::<Vec<_>>();
+        metrics.record_mempool_evictions(&dropped_txids);
+    }
+
     Json(json!({
         "status": 200,
         "result": "Ok",
     }))
 }
 
+/// Forwards JSON-RPC calls (single or batched) to bitcoind, passing statuses and
+/// errors through untouched, and reports the transactions accepted by
+/// `sendrawtransaction`.
 #[post("/", format = "application/json", data = "<bitcoin_rpc_call>")]
 pub async fn handle_bitcoin_rpc_call(
     config: &State<Arc<Mutex<StacksEventObserverConfig>>>,
-    bitcoin_rpc_call: Json<BitcoinRPCRequest>,
+    bitcoin_rpc_call: Json<JsonValue>,
     background_job_tx: &State<Arc<Mutex<Sender<ObserverCommand>>>>,
-) -> Json<JsonValue> {
-    use base64::encode;
-    use reqwest::Client;
+) -> Custom<Json<JsonValue>> {
+    let bitcoin_rpc_call = bitcoin_rpc_call.into_inner();
+    let requests = bitcoin_proxy::parse_rpc_requests(&bitcoin_rpc_call);
 
-    let bitcoin_rpc_call = bitcoin_rpc_call.into_inner().clone();
-    let method = bitcoin_rpc_call.method.clone();
-    let body = rocket::serde::json::serde_json::to_vec(&bitcoin_rpc_call).unwrap();
-
-    let builder = match config.inner().lock() {
-        Ok(config) => {
-            let token = encode(format!(
+    let (url, token) = match config.inner().lock() {
+        Ok(config) => (
+            config.get_bitcoin_node_rpc_url(),
+            base64::encode(format!(
                 "{}:{}",
-                config.bitcoin_node_username,
-                config.bitcoin_node_password
-            ));
+                config.bitcoin_node_username, config.bitcoin_node_password
+            )),
+        ),
+        _ => return bitcoin_rpc_error(Status::InternalServerError, "config lock poisoned"),
+    };
+
+    let http_client = match HttpClient::builder().timeout(Duration::from_secs(60)).build() {
+        Ok(http_client) => http_client,
+        Err(e) => return bitcoin_rpc_error(Status::InternalServerError, &e.to_string()),
+    };
+    let res = match http_client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Basic {}", token))
+        .json(&bitcoin_rpc_call)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            return bitcoin_rpc_error(
+                Status::BadGateway,
+                &format!("unable to reach bitcoind: {}", e),
+            )
+        }
+    };
 
-            let client = Client::new();
-            client
-                .post(format!(
-                    "http://localhost:{}/",
-                    config.bitcoin_node_rpc_port
-                ))
-                .header("Content-Type", "application/json")
-                .header("Authorization", format!("Basic {}", token))
+    // bitcoind reports RPC errors with a 500 and a JSON body, and auth errors
+    // with an empty body: both are returned with their original status.
+    let status = Status::from_code(res.status().as_u16()).unwrap_or(Status::BadGateway);
+    let body = match res.text().await {
+        Ok(body) => body,
+        Err(e) => {
+            return bitcoin_rpc_error(
+                Status::BadGateway,
+                &format!("unable to read bitcoind response: {}", e),
+            )
+        }
+    };
+    let response: JsonValue = match serde_json::from_str(&body) {
+        Ok(response) => response,
+        Err(_) => {
+            let message = if body.is_empty() {
+                status.to_string()
+            } else {
+                body
+            };
+            return bitcoin_rpc_error(status, &message);
         }
-        _ => unreachable!(),
     };
 
-    if method == "sendrawtransaction" {
-        let background_job_tx = background_job_tx.inner();
-        match background_job_tx.lock() {
-            Ok(tx) => {
-                // let _ = tx.send(ObserverCommand::PropagateStacksChainEvent(chain_event));
+    let broadcasts = bitcoin_proxy::extract_broadcasts(&requests, &response);
+    if !broadcasts.is_empty() {
+        if let Ok(tx) = background_job_tx.inner().lock() {
+            for broadcast in broadcasts.into_iter() {
+                let _ = tx.send(ObserverCommand::NotifyBitcoinTransactionProxied(broadcast));
             }
-            _ => {}
-        };
-    
-        // if let Ok(background_job_tx) = background_job_tx_mutex.lock() {
-        //     let _ = background_job_tx.send(ChainsCoordinatorCommand::BitcoinOpSent);
-        // }
+        }
     }
 
-    let res = builder.body(body).send().await.unwrap();
+    Custom(status, Json(response))
+}
+
+fn bitcoin_rpc_error(status: Status, message: &str) -> Custom<Json<JsonValue>> {
+    Custom(
+        status,
+        Json(json!({
+            "result": null,
+            "error": {
+                "code": -32603,
+                "message": message,
+            },
+            "id": null,
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tuple_variants_are_serialized_with_their_tag() {
+        let tag = ChainEventTag::new(&StacksNetwork::Devnet);
+        let event = BitcoinChainEvent::ChainUpdatedWithReorg(vec![], vec![]);
+        let body = serialize_tagged_event(&tag, &event).unwrap();
+        let body: JsonValue = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["tag"]["chain_id"], json!(0x80000000u32));
+        assert_eq!(body["tag"]["bitcoin_network"], json!("Regtest"));
+        assert_eq!(body["event"]["ChainUpdatedWithReorg"], json!([[], []]));
+    }
+
+    #[test]
+    fn in_process_handlers_receive_the_tag() {
+        let tag = ChainEventTag::new(&StacksNetwork::Testnet);
+        let mut webhook_workers = WebhookWorkers::new(Arc::new(ObserverMetrics::new()));
+        let (tx, rx) = channel();
+        let event_handler = EventHandler::InProcess(tx);
+        let event = BitcoinChainEvent::ChainUpdatedWithReorg(vec![], vec![]);
+        event_handler
+            .propagate_bitcoin_event(&event, &tag, &mut webhook_workers)
+            .unwrap();
 
-    Json(res.json().await.unwrap())
+        let tagged_event = rx.try_recv().unwrap();
+        assert_eq!(tagged_event.tag.bitcoin_network, BitcoinNetwork::Testnet);
+        assert_eq!(tagged_event.tag.chain_id, 0x80000000);
+        match tagged_event.event {
+            Event::BitcoinChainEvent(BitcoinChainEvent::ChainUpdatedWithReorg(_, _)) => {}
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn webhooks_being_retried_do_not_block_propagation() {
+        let tag = ChainEventTag::new(&StacksNetwork::Devnet);
+        let mut webhook_workers = WebhookWorkers::new(Arc::new(ObserverMetrics::new()));
+        let event_handler = EventHandler::WebHook("http://127.0.0.1:1".into());
+        let event = BitcoinChainEvent::ChainUpdatedWithReorg(vec![], vec![]);
+
+        let started_at = std::time::Instant::now();
+        for _ in 0..3 {
+            event_handler
+                .propagate_bitcoin_event(&event, &tag, &mut webhook_workers)
+                .unwrap();
+        }
+        assert!(started_at.elapsed() < Duration::from_millis(500));
+        assert_eq!(webhook_workers.workers.len(), 1);
+    }
 }
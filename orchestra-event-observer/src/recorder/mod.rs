@@ -0,0 +1,304 @@
+use crate::indexer::Indexer;
+use crate::observer::Event;
+use rocket::serde::json::Value as JsonValue;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordedPayloadKind {
+    NewBurnBlock,
+    NewBlock,
+    NewMicroblocks,
+    NewMempoolTx,
+    DropMempoolTx,
+}
+
+/// Raw body received from the stacks-node, as it was posted to the observer.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RecordedPayload {
+    /// Reception time, in milliseconds since the Unix Epoch.
+    pub timestamp: u64,
+    pub kind: RecordedPayloadKind,
+    pub payload: JsonValue,
+}
+
+/// Appends every raw payload received by the observer to a capture file, one
+/// JSON document per line. A recorder built without a path is a no-op.
+pub struct PayloadRecorder {
+    file: Option<File>,
+}
+
+impl PayloadRecorder {
+    pub fn new(capture_path: Option<&PathBuf>) -> Result<PayloadRecorder, String> {
+        let file = match capture_path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("unable to open capture file {:?}: {}", path, e))?,
+            ),
+            None => None,
+        };
+        Ok(PayloadRecorder { file })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn record(&mut self, kind: RecordedPayloadKind, payload: &JsonValue) -> Result<(), String> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Ok(()),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let entry = RecordedPayload {
+            timestamp,
+            kind,
+            payload: payload.clone(),
+        };
+        let mut line = serde_json::to_vec(&entry).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        file.write_all(&line)
+            .and_then(|_| file.flush())
+            .map_err(|e| format!("unable to append to capture file: {}", e))
+    }
+}
+
+pub fn load_capture(capture_path: &Path) -> Result<Vec<RecordedPayload>, String> {
+    let file = File::open(capture_path)
+        .map_err(|e| format!("unable to open capture file {:?}: {}", capture_path, e))?;
+    let mut payloads = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("unable to read capture file: {}", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let payload = serde_json::from_str::<RecordedPayload>(&line)
+            .map_err(|e| format!("malformed entry at line {}: {}", i + 1, e))?;
+        payloads.push(payload);
+    }
+    Ok(payloads)
+}
+
+/// Feeds a capture back through the indexer, in the order the payloads were
+/// received. Mempool payloads are not standardized by the indexer and are skipped.
+/// Burn blocks whose bitcoin block could not be captured are rejected.
+pub fn replay_capture(
+    indexer: &mut Indexer,
+    payloads: Vec<RecordedPayload>,
+) -> Result<Vec<Event>, String> {
+    let mut events = vec![];
+    for (i, entry) in payloads.into_iter().enumerate() {
+        match entry.kind {
+            RecordedPayloadKind::NewBurnBlock => {
+                if let Some(e) = entry.payload.get("raw_block_error") {
+                    return Err(format!(
+                        "entry {} incomplete: bitcoin block not captured ({})",
+                        i + 1,
+                        e
+                    ));
+                }
+                let event = indexer
+                    .handle_bitcoin_block(entry.payload)
+                    .map_err(|e| format!("entry {} rejected: {}", i + 1, e))?;
+                events.push(Event::BitcoinChainEvent(event));
+            }
+            RecordedPayloadKind::NewBlock => {
+                let event = indexer
+                    .handle_stacks_block(entry.payload)
+                    .map_err(|e| format!("entry {} rejected: {}", i + 1, e))?;
+                events.push(Event::StacksChainEvent(event));
+            }
+            RecordedPayloadKind::NewMicroblocks => {
+                let event = indexer
+                    .handle_stacks_microblock(entry.payload)
+                    .map_err(|e| format!("entry {} rejected: {}", i + 1, e))?;
+                events.push(Event::StacksChainEvent(event));
+            }
+            RecordedPayloadKind::NewMempoolTx | RecordedPayloadKind::DropMempoolTx => {}
+        }
+    }
+    Ok(events)
+}
+
+/// Writes the standardized events produced by a replay as a JSON array, in a
+/// shape that can be checked in under `orchestra-lib/src/fixtures`.
+pub fn write_fixture(events: &[Event], fixture_path: &Path) -> Result<(), String> {
+    let entries = events
+        .iter()
+        .map(|event| match event {
+            Event::BitcoinChainEvent(event) => json!({ "bitcoin": event }),
+            Event::StacksChainEvent(event) => json!({ "stacks": event }),
+            Event::BitcoinTransactionEvent(event) => json!({ "bitcoin_transaction": event }),
+        })
+        .collect::<Vec<_>>();
+    let bytes = serde_json::to_vec_pretty(&entries).map_err(|e| e.to_string())?;
+    std::fs::write(fixture_path, bytes)
+        .map_err(|e| format!("unable to write fixture {:?}: {}", fixture_path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::IndexerConfig;
+    use orchestra_types::{
+        BitcoinChainEvent, StacksChainEvent, StacksNetwork, StacksTransactionKind,
+    };
+
+    fn fixture_path() -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("fixtures");
+        path.push("capture.jsonl");
+        path
+    }
+
+    fn indexer(stacks_network: StacksNetwork) -> Indexer {
+        // Unreachable nodes: a replay must not depend on them.
+        Indexer::new(IndexerConfig {
+            stacks_node_rpc_url: "http://127.0.0.1:1".into(),
+            bitcoin_node_rpc_url: "http://127.0.0.1:1".into(),
+            bitcoin_node_rpc_username: "".into(),
+            bitcoin_node_rpc_password: "".into(),
+            bitcoin_network: stacks_network.get_bitcoin_network(),
+            stacks_network,
+        })
+    }
+
+    #[test]
+    fn recorded_payloads_can_be_loaded_back() {
+        let mut capture_path = std::env::temp_dir();
+        capture_path.push("orchestra-capture-test.jsonl");
+        let _ = std::fs::remove_file(&capture_path);
+
+        let mut recorder = PayloadRecorder::new(Some(&capture_path)).unwrap();
+        recorder
+            .record(RecordedPayloadKind::NewMempoolTx, &json!(["0x00"]))
+            .unwrap();
+        recorder
+            .record(
+                RecordedPayloadKind::DropMempoolTx,
+                &json!({ "dropped_txids": [] }),
+            )
+            .unwrap();
+
+        let payloads = load_capture(&capture_path).unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].kind, RecordedPayloadKind::NewMempoolTx);
+        assert_eq!(payloads[0].payload, json!(["0x00"]));
+        assert_eq!(payloads[1].kind, RecordedPayloadKind::DropMempoolTx);
+        let _ = std::fs::remove_file(&capture_path);
+    }
+
+    #[test]
+    fn captures_are_replayed_through_the_indexer() {
+        let payloads = load_capture(&fixture_path()).unwrap();
+        assert_eq!(payloads.len(), 4);
+
+        let mut indexer = indexer(StacksNetwork::Devnet);
+        let events = replay_capture(&mut indexer, payloads).unwrap();
+        assert_eq!(events.len(), 3);
+
+        match &events[0] {
+            Event::BitcoinChainEvent(BitcoinChainEvent::ChainUpdatedWithBlock(block)) => {
+                assert_eq!(block.block_identifier.index, 101);
+                assert_eq!(
+                    block.block_identifier.hash,
+                    "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"
+                );
+                assert_eq!(block.transactions.len(), 1);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        match &events[1] {
+            Event::StacksChainEvent(StacksChainEvent::ChainUpdatedWithBlock(update)) => {
+                assert_eq!(update.new_block.block_identifier.index, 1);
+                let tx = &update.new_block.transactions[0];
+                assert_eq!(
+                    tx.transaction_identifier.hash,
+                    "0xddcdd3733f62b2ff52f6ee577ad889ebd46cd4e5aad84bbf50cd8e17910f7293"
+                );
+                assert_eq!(tx.metadata.kind, StacksTransactionKind::Coinbase);
+                assert_eq!(tx.metadata.result, "(ok true)");
+                assert!(tx.metadata.success);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        match &events[2] {
+            Event::StacksChainEvent(StacksChainEvent::ChainUpdatedWithBlock(update)) => {
+                assert_eq!(update.new_block.block_identifier.index, 2);
+                assert_eq!(
+                    update.new_block.parent_block_identifier.hash,
+                    format!("0x{}", "22".repeat(32))
+                );
+                assert_eq!(update.confirmed_block.0.block_identifier.index, 1);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn replays_reject_burn_blocks_not_captured() {
+        let mut payloads = load_capture(&fixture_path()).unwrap();
+        let payload = payloads[0].payload.as_object_mut().unwrap();
+        payload.remove("raw_block");
+        payload.insert("raw_block_error".into(), json!("unable to reach bitcoind"));
+
+        let mut indexer = indexer(StacksNetwork::Devnet);
+        let error = replay_capture(&mut indexer, payloads).unwrap_err();
+        assert!(error.starts_with("entry 1 incomplete"));
+    }
+
+    #[test]
+    fn replays_reject_burn_blocks_from_another_network() {
+        let payloads = load_capture(&fixture_path()).unwrap();
+        let mut indexer = indexer(StacksNetwork::Mainnet);
+        let error = replay_capture(&mut indexer, payloads).unwrap_err();
+        assert!(error.starts_with("entry 1 rejected: block"));
+        assert!(error.contains("was not mined on Mainnet"));
+    }
+
+    #[test]
+    fn replays_reject_malformed_burn_blocks() {
+        let mut payloads = load_capture(&fixture_path()).unwrap();
+        let payload = payloads[0].payload.as_object_mut().unwrap();
+        payload.insert("raw_block".into(), json!("not a block"));
+
+        let mut indexer = indexer(StacksNetwork::Devnet);
+        let error = replay_capture(&mut indexer, payloads).unwrap_err();
+        assert!(error.starts_with("entry 1 rejected: unable to decode raw block"));
+    }
+
+    #[test]
+    fn transactions_from_another_network_are_skipped() {
+        // The burn blocks of the capture were mined on regtest.
+        let payloads = load_capture(&fixture_path())
+            .unwrap()
+            .into_iter()
+            .filter(|entry| entry.kind != RecordedPayloadKind::NewBurnBlock)
+            .collect();
+        let mut indexer = indexer(StacksNetwork::Mainnet);
+        let events = replay_capture(&mut indexer, payloads).unwrap();
+
+        match &events[0] {
+            Event::StacksChainEvent(StacksChainEvent::ChainUpdatedWithBlock(update)) => {
+                assert_eq!(update.new_block.block_identifier.index, 1);
+                assert!(update.new_block.transactions.is_empty());
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let rejected_transactions = indexer.take_rejected_transactions();
+        assert_eq!(rejected_transactions.len(), 1);
+        assert!(rejected_transactions[0].starts_with(
+            "transaction 0xddcdd3733f62b2ff52f6ee577ad889ebd46cd4e5aad84bbf50cd8e17910f7293"
+        ));
+        assert!(indexer.take_rejected_transactions().is_empty());
+    }
+}
@@ -1,5 +1,8 @@
-use crate::datastore::blocks;
-use crate::datastore::StorageDriver;
+use crate::datastore::keys::{db_key, DBKey};
+use crate::datastore::{
+    Datastore, StorageDriver, WriteBatch, BITCOIN_BLOCKS, CONTRACT_DEPLOYMENTS, STACKS_BLOCKS,
+    STACKS_MICROBLOCKS,
+};
 use clarinet_lib::types::{
     BitcoinBlockData, BlockIdentifier, StacksBlockData, StacksMicroblockData,
     StacksMicroblocksTrail, StacksTransactionKind, TransactionIdentifier,
@@ -7,8 +10,8 @@ use clarinet_lib::types::{
 use kompact::prelude::*;
 use opentelemetry::global;
 use opentelemetry::trace::{Span, Tracer};
-use rocksdb::DB;
 use serde_json;
+use std::collections::HashMap;
 
 #[derive(Clone, Debug)]
 pub enum BlockStoreManagerMessage {
@@ -17,7 +20,7 @@ pub enum BlockStoreManagerMessage {
     ArchiveStacksBlock(StacksBlockData, Option<StacksMicroblocksTrail>),
     RollbackStacksBlocks(Vec<BlockIdentifier>),
     ArchiveStacksMicroblock(StacksMicroblockData),
-    RollbackStacksMicroblocks(Vec<BlockIdentifier>),
+    RollbackStacksMicroblocks(Vec<StacksMicroblockData>),
     Exit,
 }
 
@@ -35,6 +38,8 @@ pub struct ContractInstanciation {
 pub struct BlockStoreManager {
     ctx: ComponentContext<Self>,
     storage_driver: StorageDriver,
+    /// Last block written to each blocks namespace, loaded on start.
+    last_applied_blocks: HashMap<&'static str, BlockIdentifier>,
 }
 
 impl BlockStoreManager {
@@ -43,39 +48,109 @@ impl BlockStoreManager {
         Self {
             ctx: ComponentContext::uninitialised(),
             storage_driver,
+            last_applied_blocks: HashMap::new(),
+        }
+    }
+
+    /// Loads the last block written to each namespace, so that the blocks
+    /// delivered again after a restart are not archived twice.
+    pub fn resume(&mut self) -> Result<(), String> {
+        self.last_applied_blocks.clear();
+        for namespace in [BITCOIN_BLOCKS, STACKS_BLOCKS, STACKS_MICROBLOCKS] {
+            if let Some(block_identifier) = last_applied_block(&self.storage_driver, namespace)? {
+                self.last_applied_blocks.insert(namespace, block_identifier);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn last_applied_block(&self, namespace: &str) -> Option<&BlockIdentifier> {
+        self.last_applied_blocks.get(namespace)
+    }
+
+    /// Whether the block was already archived in the chain leading to the
+    /// last applied block of `namespace`.
+    fn is_archived(
+        &self,
+        db: &dyn Datastore,
+        namespace: &str,
+        block_identifier: &BlockIdentifier,
+    ) -> bool {
+        match self.last_applied_blocks.get(namespace) {
+            Some(last_applied) if last_applied == block_identifier => true,
+            Some(last_applied) if last_applied.index > block_identifier.index => {
+                match db.get(&db_key(DBKey::BlockHash(block_identifier.index))) {
+                    Ok(Some(hash)) => hash == block_identifier.hash.as_bytes(),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Reloads the last applied block of `namespace`, after a rollback.
+    fn reload_last_applied_block(&mut self, namespace: &'static str) {
+        match last_applied_block(&self.storage_driver, namespace) {
+            Ok(Some(block_identifier)) => {
+                self.last_applied_blocks.insert(namespace, block_identifier);
+            }
+            _ => {
+                self.last_applied_blocks.remove(namespace);
+            }
         }
     }
 
-    pub fn store_bitcoin_block(&mut self, block: BitcoinBlockData) {
+    /// Returns false if the block was already archived.
+    pub fn store_bitcoin_block(&mut self, block: BitcoinBlockData) -> bool {
         let block_bytes = serde_json::to_vec(&block).expect("Unable to serialize block");
-        let db = blocks::bitcoin_blocks_db_write(&self.storage_driver);
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch
+            .open(BITCOIN_BLOCKS)
+            .expect("Unable to open bitcoin blocks datastore");
+        if self.is_archived(&*db, BITCOIN_BLOCKS, &block.block_identifier) {
+            return false;
+        }
         db.put(
-            format!("hash:{}", block.block_identifier.hash).as_bytes(),
-            block_bytes,
+            &db_key(DBKey::Block(&block.block_identifier.hash)),
+            &block_bytes,
         )
         .unwrap();
         db.put(
-            block.block_identifier.index.to_be_bytes(),
+            &db_key(DBKey::BlockHash(block.block_identifier.index)),
             block.block_identifier.hash.as_bytes(),
         )
         .unwrap();
-        db.put("tip".as_bytes(), block.block_identifier.index.to_be_bytes())
-            .unwrap();
+        set_tip(&*db, &block.block_identifier);
+        batch.commit().expect("Unable to write bitcoin block");
+        self.last_applied_blocks
+            .insert(BITCOIN_BLOCKS, block.block_identifier);
+        true
     }
 
+    /// Returns false if the block was already archived: its anchored trail is
+    /// then not appended to the parent block again.
     pub fn store_stacks_block(
         &mut self,
         block: StacksBlockData,
         anchored_trail: Option<StacksMicroblocksTrail>,
-    ) {
+    ) -> bool {
         let block_bytes = serde_json::to_vec(&block).expect("Unable to serialize block");
-        let db = blocks::stacks_blocks_db_write(&self.storage_driver);
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch
+            .open(STACKS_BLOCKS)
+            .expect("Unable to open stacks blocks datastore");
+        if self.is_archived(&*db, STACKS_BLOCKS, &block.block_identifier) {
+            return false;
+        }
+        let deployments_db = batch
+            .open(CONTRACT_DEPLOYMENTS)
+            .expect("Unable to open contract deployments datastore");
 
         // Retrieve the parent block and append the transactions from the previous trail
         // note / todo: this choice could have an impact on re-orgs
         if let Some(anchored_trail) = anchored_trail {
             let bytes = db
-                .get(&format!("hash:{}", block.parent_block_identifier.hash).as_bytes())
+                .get(&db_key(DBKey::Block(&block.parent_block_identifier.hash)))
                 .expect("Unable to hit contract storage")
                 .expect("Unable to retrieve contract");
             let mut parent_block = serde_json::from_slice::<StacksBlockData>(&bytes)
@@ -90,8 +165,8 @@ impl BlockStoreManager {
                 serde_json::to_vec(&parent_block).expect("Unable to serialize block");
 
             db.put(
-                format!("hash:{}", block.parent_block_identifier.hash).as_bytes(),
-                parent_block_bytes,
+                &db_key(DBKey::Block(&block.parent_block_identifier.hash)),
+                &parent_block_bytes,
             )
             .unwrap();
         }
@@ -106,32 +181,48 @@ impl BlockStoreManager {
                     };
                     let contract_instanciation_bytes = serde_json::to_vec(&contract_instanciation)
                         .expect("Unable to serialize block");
-                    db.put(
-                        data.contract_identifier.as_bytes(),
-                        contract_instanciation_bytes,
-                    )
-                    .unwrap();
+                    deployments_db
+                        .put(
+                            &db_key(DBKey::ContractDeployment(&data.contract_identifier)),
+                            &contract_instanciation_bytes,
+                        )
+                        .unwrap();
                 }
                 _ => {}
             };
         }
         db.put(
-            format!("hash:{}", block.block_identifier.hash).as_bytes(),
-            block_bytes,
+            &db_key(DBKey::Block(&block.block_identifier.hash)),
+            &block_bytes,
         )
         .unwrap();
         db.put(
-            block.block_identifier.index.to_be_bytes(),
+            &db_key(DBKey::BlockHash(block.block_identifier.index)),
             block.block_identifier.hash.as_bytes(),
         )
         .unwrap();
-        db.put("tip".as_bytes(), block.block_identifier.index.to_be_bytes())
-            .unwrap();
+        set_tip(&*db, &block.block_identifier);
+        batch.commit().expect("Unable to write stacks block");
+        self.last_applied_blocks
+            .insert(STACKS_BLOCKS, block.block_identifier);
+        true
     }
 
-    pub fn store_stacks_microblock(&mut self, microblock: StacksMicroblockData) {
+    /// Returns false if the microblock is the last one archived. Microblocks
+    /// are keyed by the height of their anchored block then by sequence, and
+    /// only the last one can be recognized.
+    pub fn store_stacks_microblock(&mut self, microblock: StacksMicroblockData) -> bool {
+        if self.last_applied_blocks.get(STACKS_MICROBLOCKS) == Some(&microblock.block_identifier) {
+            return false;
+        }
         let block_bytes = serde_json::to_vec(&microblock).expect("Unable to serialize block");
-        let db = blocks::stacks_blocks_db_write(&self.storage_driver);
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch
+            .open(STACKS_MICROBLOCKS)
+            .expect("Unable to open stacks microblocks datastore");
+        let deployments_db = batch
+            .open(CONTRACT_DEPLOYMENTS)
+            .expect("Unable to open contract deployments datastore");
         for tx in microblock.transactions.iter() {
             match tx.metadata.kind {
                 StacksTransactionKind::ContractDeployment(ref data) => {
@@ -142,72 +233,134 @@ impl BlockStoreManager {
                     };
                     let contract_instanciation_bytes = serde_json::to_vec(&contract_instanciation)
                         .expect("Unable to serialize block");
-                    db.put(
-                        data.contract_identifier.as_bytes(),
-                        contract_instanciation_bytes,
-                    )
-                    .unwrap();
+                    deployments_db
+                        .put(
+                            &db_key(DBKey::ContractDeployment(&data.contract_identifier)),
+                            &contract_instanciation_bytes,
+                        )
+                        .unwrap();
                 }
                 _ => {}
             };
         }
         db.put(
-            format!("~:{}", microblock.block_identifier.index).as_bytes(),
-            block_bytes,
-        )
-        .unwrap();
-        db.put(
-            "~tip".as_bytes(),
-            microblock.block_identifier.index.to_be_bytes(),
+            &db_key(DBKey::Microblock(
+                microblock.parent_block_identifier.index,
+                microblock.block_identifier.index,
+            )),
+            &block_bytes,
         )
         .unwrap();
+        set_tip(&*db, &microblock.block_identifier);
+        batch.commit().expect("Unable to write stacks microblock");
+        self.last_applied_blocks
+            .insert(STACKS_MICROBLOCKS, microblock.block_identifier);
+        true
     }
 
     pub fn delete_bitcoin_blocks(&mut self, block_ids: Vec<BlockIdentifier>) {
-        match self.storage_driver {
-            StorageDriver::Filesystem(ref config) => {
-                let mut path = config.working_dir.clone();
-                path.push("bitcoin");
-                let db = DB::open_default(path).unwrap();
-                for block_id in block_ids.iter() {
-                    db.delete(block_id.hash.as_bytes()).unwrap();
-                }
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch
+            .open(BITCOIN_BLOCKS)
+            .expect("Unable to open bitcoin blocks datastore");
+        // The tip moves back to the parent of the oldest block removed
+        if let Some(oldest) = block_ids.iter().min_by_key(|block_id| block_id.index) {
+            if let Some(bytes) = db.get(&db_key(DBKey::Block(&oldest.hash))).unwrap() {
+                let block = serde_json::from_slice::<BitcoinBlockData>(&bytes)
+                    .expect("Unable to deserialize block");
+                set_tip(&*db, &block.parent_block_identifier);
             }
         }
+        for block_id in block_ids.iter() {
+            db.delete(&db_key(DBKey::Block(&block_id.hash))).unwrap();
+            db.delete(&db_key(DBKey::BlockHash(block_id.index)))
+                .unwrap();
+        }
+        batch.commit().expect("Unable to delete bitcoin blocks");
+        self.reload_last_applied_block(BITCOIN_BLOCKS);
     }
 
-    pub fn delete_stacks_microblocks(&mut self, microblock_ids: Vec<BlockIdentifier>) {
-        match self.storage_driver {
-            StorageDriver::Filesystem(ref config) => {
-                let mut path = config.working_dir.clone();
-                path.push("stacks");
-                let db = DB::open_default(path).unwrap();
-                for block_id in microblock_ids.iter() {
-                    // todo(lgalabru): remove contracts, update chain_tip
-                    db.delete(block_id.hash.as_bytes()).unwrap();
-                }
-            }
+    pub fn delete_stacks_microblocks(&mut self, microblocks: Vec<StacksMicroblockData>) {
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch
+            .open(STACKS_MICROBLOCKS)
+            .expect("Unable to open stacks microblocks datastore");
+        for microblock in microblocks.iter() {
+            // todo(lgalabru): remove contracts, update chain_tip
+            db.delete(&db_key(DBKey::Microblock(
+                microblock.parent_block_identifier.index,
+                microblock.block_identifier.index,
+            )))
+            .unwrap();
         }
+        batch.commit().expect("Unable to delete stacks microblocks");
+        self.reload_last_applied_block(STACKS_MICROBLOCKS);
     }
 
     pub fn delete_stacks_blocks(&mut self, block_ids: Vec<BlockIdentifier>) {
-        match self.storage_driver {
-            StorageDriver::Filesystem(ref config) => {
-                let mut path = config.working_dir.clone();
-                path.push("stacks");
-                let db = DB::open_default(path).unwrap();
-                for block_id in block_ids.iter() {
-                    // todo(lgalabru): remove contracts
-                    db.delete(block_id.hash.as_bytes()).unwrap();
-                }
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch
+            .open(STACKS_BLOCKS)
+            .expect("Unable to open stacks blocks datastore");
+        // The tip moves back to the parent of the oldest block removed
+        if let Some(oldest) = block_ids.iter().min_by_key(|block_id| block_id.index) {
+            if let Some(bytes) = db.get(&db_key(DBKey::Block(&oldest.hash))).unwrap() {
+                let block = serde_json::from_slice::<StacksBlockData>(&bytes)
+                    .expect("Unable to deserialize block");
+                set_tip(&*db, &block.parent_block_identifier);
             }
         }
+        for block_id in block_ids.iter() {
+            // todo(lgalabru): remove contracts
+            db.delete(&db_key(DBKey::Block(&block_id.hash))).unwrap();
+            db.delete(&db_key(DBKey::BlockHash(block_id.index)))
+                .unwrap();
+        }
+        batch.commit().expect("Unable to delete stacks blocks");
+        self.reload_last_applied_block(STACKS_BLOCKS);
+    }
+}
+
+/// Moves the tip of a blocks namespace, along with its last applied block.
+fn set_tip(db: &dyn Datastore, block_identifier: &BlockIdentifier) {
+    db.put(&db_key(DBKey::Tip), &block_identifier.index.to_be_bytes())
+        .unwrap();
+    db.put(
+        &db_key(DBKey::LastAppliedBlock),
+        &serde_json::to_vec(block_identifier).expect("Unable to serialize block identifier"),
+    )
+    .unwrap();
+}
+
+/// Returns the last block written to `namespace`, if any.
+pub fn last_applied_block(
+    storage_driver: &StorageDriver,
+    namespace: &str,
+) -> Result<Option<BlockIdentifier>, String> {
+    let db = storage_driver.open(namespace)?;
+    match db.get(&db_key(DBKey::LastAppliedBlock))? {
+        Some(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| format!("unable to read last applied block: {}", e)),
+        None => Ok(None),
     }
 }
 
 impl ComponentLifecycle for BlockStoreManager {
     fn on_start(&mut self) -> Handled {
         info!(self.log(), "BlockStoreManager starting");
+        if let Err(e) = self.resume() {
+            error!(self.log(), "{}", e);
+        }
+        for (namespace, block_identifier) in self.last_applied_blocks.iter() {
+            info!(
+                self.log(),
+                "Resuming {} after block {} - {}",
+                namespace,
+                block_identifier.index,
+                block_identifier.hash
+            );
+        }
 
         Handled::Ok
     }
@@ -231,7 +384,9 @@ impl Actor for BlockStoreManager {
                     self.log(),
                     "BlockStoreManager will archive bitcoin block {}", block.block_identifier.index
                 );
-                self.store_bitcoin_block(block);
+                if !self.store_bitcoin_block(block) {
+                    info!(self.log(), "Bitcoin block already archived, skipping");
+                }
             }
             BlockStoreManagerMessage::RollbackBitcoinBlocks(block_ids) => {
                 info!(self.log(), "BlockStoreManager will rollback bitcoin blocks");
@@ -244,7 +399,9 @@ impl Actor for BlockStoreManager {
                     block.block_identifier.index,
                     block.block_identifier.hash
                 );
-                self.store_stacks_block(block, anchored_trail);
+                if !self.store_stacks_block(block, anchored_trail) {
+                    info!(self.log(), "Stacks block already archived, skipping");
+                }
             }
             BlockStoreManagerMessage::RollbackStacksBlocks(block_ids) => {
                 info!(self.log(), "BlockStoreManager will rollback stacks blocks");
@@ -256,14 +413,16 @@ impl Actor for BlockStoreManager {
                     "BlockStoreManager will archive stacks microblock {}",
                     microblock.block_identifier.index
                 );
-                self.store_stacks_microblock(microblock);
+                if !self.store_stacks_microblock(microblock) {
+                    info!(self.log(), "Stacks microblock already archived, skipping");
+                }
             }
-            BlockStoreManagerMessage::RollbackStacksMicroblocks(microblock_ids) => {
+            BlockStoreManagerMessage::RollbackStacksMicroblocks(microblocks) => {
                 info!(
                     self.log(),
                     "BlockStoreManager will rollback stacks microblocks"
                 );
-                self.delete_stacks_microblocks(microblock_ids);
+                self.delete_stacks_microblocks(microblocks);
             }
             BlockStoreManagerMessage::Exit => {}
         };
@@ -276,3 +435,131 @@ impl Actor for BlockStoreManager {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarinet_lib::types::{
+        StacksBlockMetadata, StacksTransactionData, StacksTransactionMetadata,
+        StacksTransactionReceipt,
+    };
+    use std::collections::HashSet;
+
+    fn transaction(txid: &str) -> StacksTransactionData {
+        StacksTransactionData {
+            transaction_identifier: TransactionIdentifier { hash: txid.into() },
+            operations: vec![],
+            metadata: StacksTransactionMetadata {
+                success: true,
+                result: "".into(),
+                raw_tx: "0x00".to_string(),
+                execution_cost: None,
+                sender: "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM".into(),
+                fee: 0,
+                sponsor: None,
+                kind: StacksTransactionKind::NativeTokenTransfer,
+                receipt: StacksTransactionReceipt {
+                    mutated_contracts_radius: HashSet::new(),
+                    mutated_assets_radius: HashSet::new(),
+                    events: vec![],
+                },
+                description: "".into(),
+            },
+        }
+    }
+
+    fn block(index: u64, transactions: Vec<StacksTransactionData>) -> StacksBlockData {
+        StacksBlockData {
+            block_identifier: BlockIdentifier {
+                index,
+                hash: format!("0x{:02x}", index),
+            },
+            parent_block_identifier: BlockIdentifier {
+                index: index - 1,
+                hash: format!("0x{:02x}", index - 1),
+            },
+            timestamp: 0,
+            transactions,
+            metadata: StacksBlockMetadata {
+                bitcoin_anchor_block_identifier: BlockIdentifier {
+                    index: 0,
+                    hash: "0x00".into(),
+                },
+                pox_cycle_index: 0,
+                pox_cycle_position: 0,
+                pox_cycle_length: 0,
+            },
+        }
+    }
+
+    fn trail(txid: &str) -> StacksMicroblocksTrail {
+        StacksMicroblocksTrail {
+            microblocks: vec![StacksMicroblockData {
+                block_identifier: BlockIdentifier {
+                    index: 0,
+                    hash: "0xm1".into(),
+                },
+                parent_block_identifier: BlockIdentifier {
+                    index: 1,
+                    hash: "0x01".into(),
+                },
+                transactions: vec![transaction(txid)],
+            }],
+        }
+    }
+
+    fn archived_transactions(storage_driver: &StorageDriver, hash: &str) -> usize {
+        let db = storage_driver.open(STACKS_BLOCKS).unwrap();
+        let bytes = db.get(&db_key(DBKey::Block(hash))).unwrap().unwrap();
+        serde_json::from_slice::<StacksBlockData>(&bytes)
+            .unwrap()
+            .transactions
+            .len()
+    }
+
+    #[test]
+    fn blocks_delivered_again_after_restart_are_skipped() {
+        let storage_driver = StorageDriver::in_memory();
+        let mut manager = BlockStoreManager::new(storage_driver.clone());
+        manager.resume().unwrap();
+        assert!(manager.store_stacks_block(block(1, vec![transaction("0x01")]), None));
+        assert!(manager.store_stacks_block(block(2, vec![]), Some(trail("0x02"))));
+        assert_eq!(archived_transactions(&storage_driver, "0x01"), 2);
+
+        // The events of the blocks 1 and 2 are delivered again after a restart
+        let mut manager = BlockStoreManager::new(storage_driver.clone());
+        manager.resume().unwrap();
+        assert_eq!(
+            manager.last_applied_block(STACKS_BLOCKS),
+            Some(&block(2, vec![]).block_identifier)
+        );
+        assert!(!manager.store_stacks_block(block(1, vec![transaction("0x01")]), None));
+        assert!(!manager.store_stacks_block(block(2, vec![]), Some(trail("0x02"))));
+        assert_eq!(archived_transactions(&storage_driver, "0x01"), 2);
+
+        assert!(manager.store_stacks_block(block(3, vec![]), None));
+        manager.delete_stacks_blocks(vec![block(3, vec![]).block_identifier]);
+        assert_eq!(manager.last_applied_block(STACKS_BLOCKS).unwrap().index, 2);
+    }
+
+    #[test]
+    fn microblocks_of_different_anchored_blocks_do_not_collide() {
+        let storage_driver = StorageDriver::in_memory();
+        let mut manager = BlockStoreManager::new(storage_driver.clone());
+        manager.resume().unwrap();
+        let first = trail("0x01").microblocks.remove(0);
+        let mut second = trail("0x02").microblocks.remove(0);
+        second.block_identifier.hash = "0xm2".into();
+        second.parent_block_identifier = block(2, vec![]).block_identifier;
+        assert!(manager.store_stacks_microblock(first));
+        assert!(manager.store_stacks_microblock(second.clone()));
+
+        let db = storage_driver.open(STACKS_MICROBLOCKS).unwrap();
+        assert!(db.get(&db_key(DBKey::Microblock(1, 0))).unwrap().is_some());
+        assert!(db.get(&db_key(DBKey::Microblock(2, 0))).unwrap().is_some());
+
+        manager.delete_stacks_microblocks(vec![second]);
+        assert!(db.get(&db_key(DBKey::Microblock(1, 0))).unwrap().is_some());
+        assert!(db.get(&db_key(DBKey::Microblock(2, 0))).unwrap().is_none());
+    }
+}
@@ -1,11 +1,12 @@
-use crate::datastore::blocks::{self, stacks_blocks_db_read};
-use crate::datastore::contracts::{self, contract_db_delete_all, contract_db_write, db_key};
+use crate::datastore::blocks::{self, stacks_blocks_db};
+use crate::datastore::contracts::{contract_db, contract_db_delete_all, contract_db_namespace};
+use crate::datastore::keys::{db_key, decode_block_index, DBKey};
 use crate::types::{
     DataMapDeleteEventValue, DataMapEventStoredValue, DataMapInsertEventValue, DataMapStoredEntry,
-    DataMapUpdateEventValue, DataVarSetEventValue, DataVarStoredValue, FTBurnEventValue,
-    FTEventStoredValue, FTMintEventValue, FTTransferEventValue, NFTBurnEventValue,
-    NFTEventStoredValue, NFTMintEventValue, NFTStoredEntry, NFTTransferEventValue,
-    SmartContractEventValue,
+    DataMapUpdateEventValue, DataVarSetEventValue, DataVarStoredValue, FTBalanceInconsistency,
+    FTBurnEventValue, FTEventStoredValue, FTMintEventValue, FTStoredBalance, FTTransferEventValue,
+    NFTBurnEventValue, NFTEventStoredValue, NFTMintEventValue, NFTStoredEntry,
+    NFTTransferEventValue, SmartContractEventValue, StoredVersion,
 };
 use clarinet_lib::clarity_repl::clarity::analysis::contract_interface_builder::build_contract_interface;
 use clarinet_lib::clarity_repl::clarity::analysis::contract_interface_builder::{
@@ -26,25 +27,40 @@ use clarinet_lib::types::{
 use kompact::prelude::*;
 use opentelemetry::global;
 use opentelemetry::trace::{Span, Tracer};
-use rocksdb::{Options, DB};
+use serde::Serialize;
 
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::mpsc::Sender;
 
-use crate::datastore::StorageDriver;
+use crate::datastore::journal::{self, JournaledDatastore};
+use crate::datastore::{Datastore, ScanDirection, StorageDriver, WriteBatch, MAX_REORG_DEPTH};
 
 use super::block_store_manager::ContractInstanciation;
 
+pub mod node;
+
 #[derive(Clone, Debug)]
 pub enum ContractProcessorMessage {
-    RebuildState,
-    ProcessTransactionsBatch(BlockIdentifier, Vec<StacksTransactionData>),
-    RollbackTransactionsBatch(BlockIdentifier, Vec<StacksTransactionData>),
+    RebuildState(RebuildStateRequest),
+    /// Batch of a block, along with the anchored block of the microblock
+    /// batches (None for anchored blocks).
+    ProcessTransactionsBatch(
+        BlockIdentifier,
+        Option<BlockIdentifier>,
+        Vec<StacksTransactionData>,
+    ),
+    RollbackTransactionsBatch(
+        BlockIdentifier,
+        Option<BlockIdentifier>,
+        Vec<StacksTransactionData>,
+    ),
     Exit,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ContractProcessorEvent {
     TransactionsBatchProcessed(String, Vec<(TransactionIdentifier, SmartContractEventData)>),
+    BalanceInconsistency(String, FTBalanceInconsistency),
 }
 
 pub struct ContractProcessorPort;
@@ -64,6 +80,11 @@ pub struct ContractProcessor {
     block_identifier: BlockIdentifier,
     analysis: ContractAnalysis,
     ast: ContractAST,
+    stacks_node_rpc_url: Option<String>,
+    /// Set when the blocks preceding the deployment are missing: unknown
+    /// balances are then seeded from the node. Persisted along with the
+    /// contract state (`DBKey::PartialHistory`).
+    partial_history: bool,
 }
 pub enum Changes<'a> {
     UpdateDataVar(&'a str, &'a str, &'a str),
@@ -72,6 +93,8 @@ pub enum Changes<'a> {
     UpdateDataMapEntry(&'a str, (&'a str, &'a str), &'a str),
     SendTokens(&'a str, (&'a str, u128), &'a str),
     ReceiveTokens(&'a str, (&'a str, u128), &'a str),
+    IncreaseSupply(&'a str, u128, &'a str),
+    DecreaseSupply(&'a str, u128, &'a str),
     SendNFT(&'a str, (&'a str, &'a str), &'a str),
     ReceiveNFT(&'a str, (&'a str, &'a str), &'a str),
 }
@@ -86,6 +109,7 @@ impl ContractProcessor {
         analysis: ContractAnalysis,
         ast: ContractAST,
         block_identifier: BlockIdentifier,
+        stacks_node_rpc_url: Option<String>,
     ) -> Self {
         global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
         Self {
@@ -97,47 +121,180 @@ impl ContractProcessor {
             block_identifier,
             analysis,
             ast,
+            stacks_node_rpc_url,
+            partial_history: false,
         }
     }
 
-    fn db_key(&self, key: contracts::DBKey) -> Vec<u8> {
-        db_key(key, &self.contract_id)
+    pub fn build_state(&mut self) {
+        let block_db = stacks_blocks_db(&self.storage_driver);
+        self.partial_history = self.load_partial_history();
+        let start = match self.last_applied_block_index(&*block_db) {
+            Some(index) => {
+                info!(
+                    self.ctx().log(),
+                    "Resuming contract state after block {}", index
+                );
+                index + 1
+            }
+            None => self.reset_state(),
+        };
+        self.replay_blocks(&*block_db, start, None);
     }
 
-    pub fn build_state(&mut self) {
-        {
-            contract_db_delete_all(&self.storage_driver, &self.contract_id);
-            let db = contract_db_write(&self.storage_driver, &self.contract_id);
-            let interface = build_contract_interface(&self.analysis);
-            let interface_bytes =
-                serde_json::to_vec(&interface).expect("Unable to serialize block");
-            db.put(&self.db_key(contracts::DBKey::Interface), interface_bytes)
-                .unwrap();
+    /// Rebuilds the state from the deployment of the contract, or from
+    /// `request.from_block_index`, reporting its progress to `request.tx`.
+    /// Falls back to the deployment when the journals needed to rewind the
+    /// state were pruned.
+    pub fn rebuild_state(&mut self, request: RebuildStateRequest) {
+        let block_db = stacks_blocks_db(&self.storage_driver);
+        let start = match request.from_block_index {
+            Some(index) if index > self.block_identifier.index => {
+                if self.can_rewind_to(&*block_db, index) {
+                    if let Err(e) = self.rewind_state(index) {
+                        let _ = request.tx.send(RebuildStateProgress::Failed(e));
+                        return;
+                    }
+                    index
+                } else {
+                    info!(
+                        self.ctx().log(),
+                        "Journals of block {} pruned, rebuilding state of {} from its deployment",
+                        index,
+                        self.contract_id
+                    );
+                    self.reset_state()
+                }
+            }
+            _ => self.reset_state(),
+        };
+        self.replay_blocks(&*block_db, start, Some(&request.tx));
+        let _ = request.tx.send(RebuildStateProgress::Completed);
+
+        if request.verify {
+            let progress = match self.verify_state() {
+                Ok(mismatches) => RebuildStateProgress::Verified(mismatches),
+                Err(e) => RebuildStateProgress::Failed(e),
+            };
+            let _ = request.tx.send(progress);
         }
-        let block_db = stacks_blocks_db_read(&self.storage_driver);
-        let start = self.block_identifier.index;
-        let end = u64::from_be_bytes(
-            block_db
-                .get("tip".as_bytes())
-                .unwrap()
-                .unwrap()
-                .try_into()
-                .unwrap(),
+    }
+
+    /// Drops the state, and returns the height of the deployment of the
+    /// contract, to replay the blocks from.
+    fn reset_state(&mut self) -> u64 {
+        contract_db_delete_all(&self.storage_driver, &self.contract_id);
+        let db = contract_db(&self.storage_driver, &self.contract_id);
+        let interface = build_contract_interface(&self.analysis);
+        let interface_bytes = serde_json::to_vec(&interface).expect("Unable to serialize block");
+        db.put(&db_key(DBKey::Interface), &interface_bytes).unwrap();
+        self.partial_history = false;
+        self.block_identifier.index
+    }
+
+    fn load_partial_history(&self) -> bool {
+        let db = contract_db(&self.storage_driver, &self.contract_id);
+        match db.get(&db_key(DBKey::PartialHistory)) {
+            Ok(value) => value.is_some(),
+            Err(e) => {
+                warn!(self.ctx().log(), "{}", e);
+                false
+            }
+        }
+    }
+
+    /// Records that the blocks preceding `index` are missing, so that the
+    /// balances keep being seeded from the node after a restart.
+    fn mark_partial_history(&mut self, index: u64) {
+        if self.partial_history {
+            return;
+        }
+        self.partial_history = true;
+        info!(
+            self.ctx().log(),
+            "Block {} missing, contract history is partial", index
         );
+        let db = contract_db(&self.storage_driver, &self.contract_id);
+        if let Err(e) = db.put(&db_key(DBKey::PartialHistory), &[1]) {
+            warn!(self.ctx().log(), "{}", e);
+        }
+    }
 
-        warn!(
+    /// Returns true if the journals of every batch applied at or after
+    /// `block_index` are kept: the ones deeper than MAX_REORG_DEPTH below the
+    /// last applied block are pruned (see `journal::prune`).
+    fn can_rewind_to(&self, block_db: &dyn Datastore, block_index: u64) -> bool {
+        match self.last_applied_block_index(block_db) {
+            Some(last_index) => block_index.saturating_add(MAX_REORG_DEPTH) >= last_index,
+            None => false,
+        }
+    }
+
+    /// Rolls back the batches of the blocks at or after `block_index`, most
+    /// recent first, using their undo journals.
+    fn rewind_state(&mut self, block_index: u64) -> Result<(), String> {
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch.open(&contract_db_namespace(&self.contract_id))?;
+        let prefix = db_key(DBKey::UndoJournalScan);
+        let mut journal_keys = vec![];
+        db.visit(&prefix, None, ScanDirection::Reverse, &mut |key, _| {
+            let index = decode_block_index(&key[prefix.len()..])?;
+            if index < block_index {
+                return Ok(false);
+            }
+            journal_keys.push(key.to_vec());
+            Ok(true)
+        })?;
+        for journal_key in journal_keys.iter() {
+            journal::rollback(&*db, journal_key)?;
+        }
+        batch.commit()
+    }
+
+    fn replay_blocks(
+        &mut self,
+        block_db: &dyn Datastore,
+        start: u64,
+        progress_tx: Option<&Sender<RebuildStateProgress>>,
+    ) {
+        let end = match blocks::get_tip(block_db) {
+            Ok(Some(end)) => end,
+            Ok(None) => {
+                debug!(self.ctx().log(), "No stacks block archived yet");
+                return;
+            }
+            Err(e) => {
+                error!(self.ctx().log(), "{}", e);
+                return;
+            }
+        };
+
+        debug!(
             self.ctx().log(),
             "Will be looking for stacks blocks in range {:?}",
             start..=end
         );
+        if let Some(tx) = progress_tx {
+            let _ = tx.send(RebuildStateProgress::Started {
+                from_block_index: start,
+                tip: end,
+            });
+        }
         for index in start..=end {
-            let block_hash = block_db.get(index.to_be_bytes()).unwrap().unwrap();
-            let key = format!("hash:{}", String::from_utf8(block_hash).unwrap());
-            warn!(self.ctx().log(), "Getting {}", key);
-
-            let block_bytes = block_db.get(&key.as_bytes()).unwrap().unwrap();
-            let block = serde_json::from_slice::<StacksBlockData>(&block_bytes)
-                .expect("Unable to deserialize contract");
+            // Blocks archived before the working dir was created are missing
+            let block = match blocks::get_stacks_block_at_index(block_db, index) {
+                Ok(Some(block)) => block,
+                Ok(None) => {
+                    self.mark_partial_history(index);
+                    continue;
+                }
+                Err(e) => {
+                    warn!(self.ctx().log(), "{}", e);
+                    self.mark_partial_history(index);
+                    continue;
+                }
+            };
+            debug!(self.ctx().log(), "Getting {}", block.block_identifier.hash);
 
             let mut transactions = vec![];
             for transaction in block.transactions.iter() {
@@ -151,20 +308,185 @@ impl ContractProcessor {
                 }
             }
             if !transactions.is_empty() {
-                self.handle_transactions_batch(block.block_identifier.clone(), transactions);
+                self.handle_transactions_batch(block.block_identifier.clone(), None, transactions);
+            }
+            if let Some(tx) = progress_tx {
+                let _ = tx.send(RebuildStateProgress::BlockReplayed {
+                    block_index: index,
+                    tip: end,
+                });
             }
         }
     }
 
+    /// Compares the latest state with the one reported by the node: vars
+    /// written at least once, map entries, and the balances and supply of
+    /// SIP-010 tokens.
+    fn verify_state(&self) -> Result<Vec<StateMismatch>, String> {
+        let url = self
+            .stacks_node_rpc_url
+            .as_ref()
+            .ok_or("unable to verify state: no stacks node configured".to_string())?;
+        let db = contract_db(&self.storage_driver, &self.contract_id);
+        let mut mismatches = vec![];
+
+        for var in self.contract_interface.variables.iter() {
+            let rebuilt_value = match db.get(&db_key(DBKey::Var(&var.name)))? {
+                Some(bytes) => {
+                    serde_json::from_slice::<DataVarStoredValue>(&bytes)
+                        .map_err(|e| format!("unable to verify state: {}", e))?
+                        .hex_value
+                }
+                None => continue,
+            };
+            let node_value = node::get_data_var_from_node(url, &self.contract_id, &var.name)?;
+            if rebuilt_value != node_value {
+                mismatches.push(StateMismatch {
+                    field_name: var.name.clone(),
+                    key: None,
+                    rebuilt_value: Some(rebuilt_value),
+                    node_value: Some(node_value),
+                });
+            }
+        }
+
+        for map in self.contract_interface.maps.iter() {
+            for (_, bytes) in db.scan_prefix(&db_key(DBKey::MapScan(&map.name)))? {
+                let entry = serde_json::from_slice::<DataMapStoredEntry>(&bytes)
+                    .map_err(|e| format!("unable to verify state: {}", e))?;
+                let node_value = node::get_map_entry_from_node(
+                    url,
+                    &self.contract_id,
+                    &map.name,
+                    &entry.hex_key,
+                )?;
+                if node_value.as_ref() != Some(&entry.hex_value) {
+                    mismatches.push(StateMismatch {
+                        field_name: map.name.clone(),
+                        key: Some(entry.hex_key),
+                        rebuilt_value: Some(entry.hex_value),
+                        node_value,
+                    });
+                }
+            }
+        }
+
+        // SIP-010 read-only functions only cover contracts defining one token
+        if let [ft] = self.contract_interface.fungible_tokens.as_slice() {
+            let asset_id = format!("{}::{}", self.contract_id, ft.name);
+            let prefix = db_key(DBKey::FTScan(&asset_id));
+            for (key, bytes) in db.scan_prefix(&prefix)? {
+                let owner = String::from_utf8_lossy(&key[prefix.len()..]).to_string();
+                let rebuilt_value = String::from_utf8_lossy(&bytes).to_string();
+                let node_value = node::get_balance_from_node(url, &self.contract_id, &owner, None)?;
+                if rebuilt_value != node_value.to_string() {
+                    mismatches.push(StateMismatch {
+                        field_name: ft.name.clone(),
+                        key: Some(owner),
+                        rebuilt_value: Some(rebuilt_value),
+                        node_value: Some(node_value.to_string()),
+                    });
+                }
+            }
+            let rebuilt_value = db
+                .get(&db_key(DBKey::FTSupply(&asset_id)))?
+                .map(|bytes| String::from_utf8_lossy(&bytes).to_string());
+            let node_value = node::get_total_supply_from_node(url, &self.contract_id, None)?;
+            if rebuilt_value.as_deref().unwrap_or("0") != node_value.to_string() {
+                mismatches.push(StateMismatch {
+                    field_name: ft.name.clone(),
+                    key: None,
+                    rebuilt_value,
+                    node_value: Some(node_value.to_string()),
+                });
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Returns the height of the last block applied to the contract state,
+    /// unless that block is no longer part of the canonical chain. Errors are
+    /// logged, and the state is then rebuilt from scratch.
+    fn last_applied_block_index(&self, block_db: &dyn Datastore) -> Option<u64> {
+        let db = contract_db(&self.storage_driver, &self.contract_id);
+        let bytes = match db.get(&db_key(DBKey::LastAppliedBlock)) {
+            Ok(bytes) => bytes?,
+            Err(e) => {
+                error!(self.log(), "Unable to read last applied block: {}", e);
+                return None;
+            }
+        };
+        let block_identifier = match serde_json::from_slice::<BlockIdentifier>(&bytes) {
+            Ok(block_identifier) => block_identifier,
+            Err(e) => {
+                error!(self.log(), "Unable to read last applied block: {}", e);
+                return None;
+            }
+        };
+        match blocks::is_archived_block(block_db, &block_identifier) {
+            Ok(true) => Some(block_identifier.index),
+            Ok(false) => None,
+            Err(e) => {
+                error!(self.log(), "Unable to read last applied block: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Returns true if `block_identifier` is an archived block, applied
+    /// already. Microblocks, indexed by sequence, never are.
+    fn is_applied_block(&self, block_identifier: &BlockIdentifier) -> bool {
+        let block_db = stacks_blocks_db(&self.storage_driver);
+        match self.last_applied_block_index(&*block_db) {
+            Some(index) if block_identifier.index <= index => {}
+            _ => return false,
+        }
+        match blocks::is_archived_block(&*block_db, block_identifier) {
+            Ok(archived) => archived,
+            Err(e) => {
+                warn!(self.log(), "{}", e);
+                false
+            }
+        }
+    }
+
+    /// Applies the batch of `block_identifier`. Microblocks, with an
+    /// `anchor_block_identifier`, are written at the height of their anchored
+    /// block, after the batches written there already, and never become the
+    /// last applied block: the anchored block confirming them does.
     fn handle_transactions_batch(
         &mut self,
         block_identifier: BlockIdentifier,
+        anchor_block_identifier: Option<BlockIdentifier>,
         transactions: Vec<StacksTransactionData>,
     ) -> Vec<(TransactionIdentifier, SmartContractEventData)> {
+        // Batches are delivered again when catching up after a restart
+        if self.is_applied_block(&block_identifier) {
+            debug!(
+                self.ctx.log(),
+                "Skipping block {}, already applied to contract {}",
+                block_identifier.index,
+                self.contract_id
+            );
+            return vec![];
+        }
+
         let mut changes = vec![];
         let mut custom_events = vec![];
-        let mut event_index = 0;
-        let db = contract_db_write(&self.storage_driver, &self.contract_id);
+        // Every write is journaled, so that the batch can be rolled back on
+        // reorgs, and applied at once along with the journal.
+        let batch = WriteBatch::new(&self.storage_driver);
+        let contract_db = batch
+            .open(&contract_db_namespace(&self.contract_id))
+            .expect("Unable to open contract datastore");
+        let db = JournaledDatastore::new(&*contract_db);
+        let block_index = match anchor_block_identifier {
+            Some(ref anchor_block_identifier) => anchor_block_identifier.index,
+            None => block_identifier.index,
+        };
+        let first_position =
+            journal::first_position(&db, block_index).expect("Unable to read batch position");
+        let mut event_index = first_position;
         for tx in transactions.iter() {
             for event_wrapper in tx.metadata.receipt.events.iter() {
                 match event_wrapper {
@@ -172,11 +494,7 @@ impl ContractProcessor {
                         if event.contract_identifier == self.contract_id {
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::VarEvent(
-                                    &event.var,
-                                    block_identifier.index,
-                                    event_index,
-                                )),
+                                &db_key(DBKey::VarEvent(&event.var, block_index, event_index)),
                                 json!(DataVarSetEventValue {
                                     hex_value: event.hex_new_value.to_string(),
                                 })
@@ -195,11 +513,7 @@ impl ContractProcessor {
                         if event.contract_identifier == self.contract_id {
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::MapEvent(
-                                    &event.map,
-                                    block_identifier.index,
-                                    event_index,
-                                )),
+                                &db_key(DBKey::MapEvent(&event.map, block_index, event_index)),
                                 json!(DataMapEventStoredValue::Insert(DataMapInsertEventValue {
                                     hex_inserted_key: event.hex_inserted_key.to_string(),
                                     hex_inserted_value: event.hex_inserted_value.to_string(),
@@ -219,11 +533,7 @@ impl ContractProcessor {
                         if event.contract_identifier == self.contract_id {
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::MapEvent(
-                                    &event.map,
-                                    block_identifier.index,
-                                    event_index,
-                                )),
+                                &db_key(DBKey::MapEvent(&event.map, block_index, event_index)),
                                 json!(DataMapEventStoredValue::Update(DataMapUpdateEventValue {
                                     hex_key: event.hex_key.to_string(),
                                     hex_updated_value: event.hex_new_value.to_string(),
@@ -243,11 +553,7 @@ impl ContractProcessor {
                         if event.contract_identifier == self.contract_id {
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::MapEvent(
-                                    &event.map,
-                                    block_identifier.index,
-                                    event_index,
-                                )),
+                                &db_key(DBKey::MapEvent(&event.map, block_index, event_index)),
                                 json!(DataMapEventStoredValue::Delete(DataMapDeleteEventValue {
                                     hex_deleted_key: event.hex_deleted_key.to_string(),
                                 }))
@@ -264,11 +570,17 @@ impl ContractProcessor {
                     }
                     StacksTransactionEvent::FTMintEvent(event) => {
                         if event.asset_class_identifier.starts_with(&self.contract_id) {
+                            let amount = match self
+                                .parse_event_amount(&event.amount, &tx.transaction_identifier)
+                            {
+                                Some(amount) => amount,
+                                None => continue,
+                            };
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::FTEvent(
+                                &db_key(DBKey::FTEvent(
                                     &event.asset_class_identifier,
-                                    block_identifier.index,
+                                    block_index,
                                     event_index,
                                 )),
                                 json!(FTEventStoredValue::Mint(FTMintEventValue {
@@ -279,22 +591,31 @@ impl ContractProcessor {
                                 .as_bytes(),
                             )
                             .expect("Unable to write");
-                            let amount = u128::from_str_radix(&event.amount, 10)
-                                .expect("unable to parse amount");
                             changes.push(Changes::ReceiveTokens(
                                 &event.asset_class_identifier,
                                 (&event.recipient, amount),
                                 &tx.transaction_identifier.hash,
+                            ));
+                            changes.push(Changes::IncreaseSupply(
+                                &event.asset_class_identifier,
+                                amount,
+                                &tx.transaction_identifier.hash,
                             ))
                         }
                     }
                     StacksTransactionEvent::FTBurnEvent(event) => {
                         if event.asset_class_identifier.starts_with(&self.contract_id) {
+                            let amount = match self
+                                .parse_event_amount(&event.amount, &tx.transaction_identifier)
+                            {
+                                Some(amount) => amount,
+                                None => continue,
+                            };
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::FTEvent(
+                                &db_key(DBKey::FTEvent(
                                     &event.asset_class_identifier,
-                                    block_identifier.index,
+                                    block_index,
                                     event_index,
                                 )),
                                 json!(FTEventStoredValue::Burn(FTBurnEventValue {
@@ -305,22 +626,31 @@ impl ContractProcessor {
                                 .as_bytes(),
                             )
                             .expect("Unable to write");
-                            let amount = u128::from_str_radix(&event.amount, 10)
-                                .expect("unable to parse amount");
                             changes.push(Changes::SendTokens(
                                 &event.asset_class_identifier,
                                 (&event.sender, amount),
                                 &tx.transaction_identifier.hash,
+                            ));
+                            changes.push(Changes::DecreaseSupply(
+                                &event.asset_class_identifier,
+                                amount,
+                                &tx.transaction_identifier.hash,
                             ))
                         }
                     }
                     StacksTransactionEvent::FTTransferEvent(event) => {
                         if event.asset_class_identifier.starts_with(&self.contract_id) {
+                            let amount = match self
+                                .parse_event_amount(&event.amount, &tx.transaction_identifier)
+                            {
+                                Some(amount) => amount,
+                                None => continue,
+                            };
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::FTEvent(
+                                &db_key(DBKey::FTEvent(
                                     &event.asset_class_identifier,
-                                    block_identifier.index,
+                                    block_index,
                                     event_index,
                                 )),
                                 json!(FTEventStoredValue::Transfer(FTTransferEventValue {
@@ -332,8 +662,6 @@ impl ContractProcessor {
                                 .as_bytes(),
                             )
                             .expect("Unable to write");
-                            let amount = u128::from_str_radix(&event.amount, 10)
-                                .expect("unable to parse amount");
                             changes.push(Changes::SendTokens(
                                 &event.asset_class_identifier,
                                 (&event.sender, amount),
@@ -350,9 +678,9 @@ impl ContractProcessor {
                         if event.asset_class_identifier.starts_with(&self.contract_id) {
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::NFTEvent(
+                                &db_key(DBKey::NFTEvent(
                                     &event.asset_class_identifier,
-                                    block_identifier.index,
+                                    block_index,
                                     event_index,
                                 )),
                                 json!(NFTEventStoredValue::Mint(NFTMintEventValue {
@@ -374,9 +702,9 @@ impl ContractProcessor {
                         if event.asset_class_identifier.starts_with(&self.contract_id) {
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::NFTEvent(
+                                &db_key(DBKey::NFTEvent(
                                     &event.asset_class_identifier,
-                                    block_identifier.index,
+                                    block_index,
                                     event_index,
                                 )),
                                 json!(NFTEventStoredValue::Burn(NFTBurnEventValue {
@@ -398,9 +726,9 @@ impl ContractProcessor {
                         if event.asset_class_identifier.starts_with(&self.contract_id) {
                             event_index += 1;
                             db.put(
-                                &self.db_key(contracts::DBKey::NFTEvent(
+                                &db_key(DBKey::NFTEvent(
                                     &event.asset_class_identifier,
-                                    block_identifier.index,
+                                    block_index,
                                     event_index,
                                 )),
                                 json!(NFTEventStoredValue::Transfer(NFTTransferEventValue {
@@ -437,12 +765,38 @@ impl ContractProcessor {
             }
         }
 
+        // Net credits and debits of the block, per balance (and per supply,
+        // with no owner), used to seed the amounts read from the node.
+        let mut block_deltas: HashMap<(&str, Option<&str>), (u128, u128)> = HashMap::new();
+        for change in changes.iter() {
+            match change {
+                Changes::ReceiveTokens(asset_id, (owner, value), _) => {
+                    let delta = block_deltas.entry((asset_id, Some(owner))).or_default();
+                    delta.0 = delta.0.saturating_add(*value);
+                }
+                Changes::SendTokens(asset_id, (owner, value), _) => {
+                    let delta = block_deltas.entry((asset_id, Some(owner))).or_default();
+                    delta.1 = delta.1.saturating_add(*value);
+                }
+                Changes::IncreaseSupply(asset_id, value, _) => {
+                    let delta = block_deltas.entry((asset_id, None)).or_default();
+                    delta.0 = delta.0.saturating_add(*value);
+                }
+                Changes::DecreaseSupply(asset_id, value, _) => {
+                    let delta = block_deltas.entry((asset_id, None)).or_default();
+                    delta.1 = delta.1.saturating_add(*value);
+                }
+                _ => {}
+            }
+        }
+
         {
-            for change in changes.iter() {
+            for (position, change) in changes.iter().enumerate() {
+                let position = first_position + position as u32;
                 match change {
                     Changes::UpdateDataVar(var, new_value, txid) => {
                         db.put(
-                            &self.db_key(contracts::DBKey::Var(var)),
+                            &db_key(DBKey::Var(var)),
                             json!(DataVarStoredValue {
                                 hex_value: new_value.to_string(),
                             })
@@ -450,10 +804,19 @@ impl ContractProcessor {
                             .as_bytes(),
                         )
                         .expect("Unable to write");
+                        put_version(
+                            &db,
+                            DBKey::VarVersion(var, block_index, position),
+                            Some(DataVarStoredValue {
+                                hex_value: new_value.to_string(),
+                            }),
+                            &block_identifier,
+                            txid,
+                        );
                     }
                     Changes::InsertDataMapEntry(map, (new_key, new_value), txid) => {
                         db.put(
-                            &self.db_key(contracts::DBKey::MapEntry(map, new_key)),
+                            &db_key(DBKey::MapEntry(map, new_key)),
                             json!(DataMapStoredEntry {
                                 hex_key: new_key.to_string(),
                                 hex_value: new_value.to_string()
@@ -462,14 +825,31 @@ impl ContractProcessor {
                             .as_bytes(),
                         )
                         .expect("Unable to write");
+                        put_version(
+                            &db,
+                            DBKey::MapEntryVersion(map, new_key, block_index, position),
+                            Some(DataMapStoredEntry {
+                                hex_key: new_key.to_string(),
+                                hex_value: new_value.to_string(),
+                            }),
+                            &block_identifier,
+                            txid,
+                        );
                     }
                     Changes::DeleteDataMapEntry(map, deleted_key, txid) => {
-                        db.delete(&self.db_key(contracts::DBKey::MapEntry(map, deleted_key)))
+                        db.delete(&db_key(DBKey::MapEntry(map, deleted_key)))
                             .expect("Unable to write");
+                        put_version::<DataMapStoredEntry>(
+                            &db,
+                            DBKey::MapEntryVersion(map, deleted_key, block_index, position),
+                            None,
+                            &block_identifier,
+                            txid,
+                        );
                     }
                     Changes::UpdateDataMapEntry(map, (key, new_value), txid) => {
                         db.put(
-                            &self.db_key(contracts::DBKey::MapEntry(map, key)),
+                            &db_key(DBKey::MapEntry(map, key)),
                             json!(DataMapStoredEntry {
                                 hex_key: key.to_string(),
                                 hex_value: new_value.to_string()
@@ -478,56 +858,185 @@ impl ContractProcessor {
                             .as_bytes(),
                         )
                         .expect("Unable to write");
+                        put_version(
+                            &db,
+                            DBKey::MapEntryVersion(map, key, block_index, position),
+                            Some(DataMapStoredEntry {
+                                hex_key: key.to_string(),
+                                hex_value: new_value.to_string(),
+                            }),
+                            &block_identifier,
+                            txid,
+                        );
                     }
                     Changes::SendTokens(asset_id, (sender, value), txid) => {
-                        let balance =
-                            match db.get(&self.db_key(contracts::DBKey::FT(asset_id, sender))) {
-                                Ok(Some(value)) => {
-                                    u128::from_str_radix(&String::from_utf8(value).unwrap(), 10)
-                                        .unwrap()
-                                }
-                                Ok(None) => 0,
-                                Err(e) => panic!("Operational problem encountered: {}", e),
-                            };
+                        let balance = self.get_ft_balance(
+                            &db,
+                            asset_id,
+                            sender,
+                            &block_identifier,
+                            block_deltas.get(&(*asset_id, Some(*sender))),
+                        );
                         info!(
                             self.log(),
                             "{} will send {} (balance={})", sender, value, balance
                         );
+                        let balance = match balance.checked_sub(value) {
+                            Some(balance) => balance,
+                            None => {
+                                self.report_ft_inconsistency(
+                                    asset_id,
+                                    Some(sender),
+                                    balance,
+                                    value,
+                                    &block_identifier,
+                                    txid,
+                                );
+                                0
+                            }
+                        };
 
                         db.put(
-                            &self.db_key(contracts::DBKey::FT(asset_id, sender)),
-                            (balance - value).to_string(),
+                            &db_key(DBKey::FT(asset_id, sender)),
+                            balance.to_string().as_bytes(),
                         )
                         .expect("Unable to write");
+                        put_version(
+                            &db,
+                            DBKey::FTVersion(asset_id, sender, block_index, position),
+                            Some(FTStoredBalance {
+                                owner: sender.to_string(),
+                                balance: balance.to_string(),
+                            }),
+                            &block_identifier,
+                            txid,
+                        );
                     }
                     Changes::ReceiveTokens(asset_id, (recipient, value), txid) => {
-                        let balance =
-                            match db.get(&self.db_key(contracts::DBKey::FT(asset_id, recipient))) {
-                                Ok(Some(value)) => {
-                                    u128::from_str_radix(&String::from_utf8(value).unwrap(), 10)
-                                        .unwrap()
-                                }
-                                Ok(None) => 0,
-                                Err(e) => panic!("Operational problem encountered: {}", e),
-                            };
+                        let balance = self.get_ft_balance(
+                            &db,
+                            asset_id,
+                            recipient,
+                            &block_identifier,
+                            block_deltas.get(&(*asset_id, Some(*recipient))),
+                        );
                         info!(
                             self.log(),
                             "{} will receive {} (balance={})", recipient, value, balance
                         );
+                        let balance = match balance.checked_add(value) {
+                            Some(balance) => balance,
+                            None => {
+                                self.report_ft_inconsistency(
+                                    asset_id,
+                                    Some(recipient),
+                                    balance,
+                                    value,
+                                    &block_identifier,
+                                    txid,
+                                );
+                                u128::MAX
+                            }
+                        };
 
                         db.put(
-                            &self.db_key(contracts::DBKey::FT(asset_id, recipient)),
-                            (balance + value).to_string(),
+                            &db_key(DBKey::FT(asset_id, recipient)),
+                            balance.to_string().as_bytes(),
+                        )
+                        .expect("Unable to write");
+                        put_version(
+                            &db,
+                            DBKey::FTVersion(asset_id, recipient, block_index, position),
+                            Some(FTStoredBalance {
+                                owner: recipient.to_string(),
+                                balance: balance.to_string(),
+                            }),
+                            &block_identifier,
+                            txid,
+                        );
+                    }
+                    Changes::IncreaseSupply(asset_id, value, txid) => {
+                        let supply = self.get_ft_supply(
+                            &db,
+                            asset_id,
+                            &block_identifier,
+                            block_deltas.get(&(*asset_id, None)),
+                        );
+                        let supply = match supply.checked_add(value) {
+                            Some(supply) => supply,
+                            None => {
+                                self.report_ft_inconsistency(
+                                    asset_id,
+                                    None,
+                                    supply,
+                                    value,
+                                    &block_identifier,
+                                    txid,
+                                );
+                                u128::MAX
+                            }
+                        };
+                        db.put(
+                            &db_key(DBKey::FTSupply(asset_id)),
+                            supply.to_string().as_bytes(),
+                        )
+                        .expect("Unable to write");
+                        put_version(
+                            &db,
+                            DBKey::FTSupplyVersion(asset_id, block_index, position),
+                            Some(supply.to_string()),
+                            &block_identifier,
+                            txid,
+                        );
+                    }
+                    Changes::DecreaseSupply(asset_id, value, txid) => {
+                        let supply = self.get_ft_supply(
+                            &db,
+                            asset_id,
+                            &block_identifier,
+                            block_deltas.get(&(*asset_id, None)),
+                        );
+                        let supply = match supply.checked_sub(value) {
+                            Some(supply) => supply,
+                            None => {
+                                self.report_ft_inconsistency(
+                                    asset_id,
+                                    None,
+                                    supply,
+                                    value,
+                                    &block_identifier,
+                                    txid,
+                                );
+                                0
+                            }
+                        };
+                        db.put(
+                            &db_key(DBKey::FTSupply(asset_id)),
+                            supply.to_string().as_bytes(),
                         )
                         .expect("Unable to write");
+                        put_version(
+                            &db,
+                            DBKey::FTSupplyVersion(asset_id, block_index, position),
+                            Some(supply.to_string()),
+                            &block_identifier,
+                            txid,
+                        );
                     }
                     Changes::SendNFT(asset_class_id, (asset_id, sender), txid) => {
-                        db.delete(&self.db_key(contracts::DBKey::NFT(asset_class_id, asset_id)))
+                        db.delete(&db_key(DBKey::NFT(asset_class_id, asset_id)))
                             .expect("Unable to write");
+                        put_version::<NFTStoredEntry>(
+                            &db,
+                            DBKey::NFTVersion(asset_class_id, asset_id, block_index, position),
+                            None,
+                            &block_identifier,
+                            txid,
+                        );
                     }
                     Changes::ReceiveNFT(asset_class_id, (asset_id, recipient), txid) => {
                         db.put(
-                            &self.db_key(contracts::DBKey::NFT(asset_class_id, asset_id)),
+                            &db_key(DBKey::NFT(asset_class_id, asset_id)),
                             json!(NFTStoredEntry {
                                 hex_asset_identifier: asset_id.to_string(),
                                 owner: recipient.to_string(),
@@ -536,12 +1045,237 @@ impl ContractProcessor {
                             .as_bytes(),
                         )
                         .expect("Unable to write");
+                        put_version(
+                            &db,
+                            DBKey::NFTVersion(asset_class_id, asset_id, block_index, position),
+                            Some(NFTStoredEntry {
+                                hex_asset_identifier: asset_id.to_string(),
+                                owner: recipient.to_string(),
+                            }),
+                            &block_identifier,
+                            txid,
+                        );
                     }
                 }
             }
         }
+        let next_position = event_index.max(first_position + changes.len() as u32);
+        journal::set_next_position(&db, block_index, next_position).expect("Unable to write");
+        if anchor_block_identifier.is_none() {
+            db.put(
+                &db_key(DBKey::LastAppliedBlock),
+                &serde_json::to_vec(&block_identifier)
+                    .expect("Unable to serialize block identifier"),
+            )
+            .expect("Unable to write");
+        }
+        db.commit(&db_key(DBKey::UndoJournal(
+            block_index,
+            &block_identifier.hash,
+        )))
+        .expect("Unable to write journal");
+        if anchor_block_identifier.is_none() {
+            journal::prune(&*contract_db, block_index).expect("Unable to prune journals");
+        }
+        batch.commit().expect("Unable to write transactions batch");
         custom_events
     }
+
+    /// Restores the state preceding the batch of `block_identifier`, events
+    /// history and field versions included.
+    fn rollback_transactions_batch(
+        &mut self,
+        block_identifier: &BlockIdentifier,
+        anchor_block_identifier: Option<&BlockIdentifier>,
+    ) {
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch
+            .open(&contract_db_namespace(&self.contract_id))
+            .expect("Unable to open contract datastore");
+        let block_index = anchor_block_identifier.unwrap_or(block_identifier).index;
+        let journal_key = db_key(DBKey::UndoJournal(block_index, &block_identifier.hash));
+        let rolled_back = journal::rollback(&*db, &journal_key).expect("Unable to rollback");
+        batch.commit().expect("Unable to write rollback");
+        if !rolled_back {
+            warn!(
+                self.ctx().log(),
+                "No journal found for block {}, nothing to rollback", block_identifier.hash
+            );
+        }
+    }
+}
+
+impl ContractProcessor {
+    /// Node to seed the unknown amounts from: only when the history is
+    /// partial, and when the contract defines a single token, the one covered
+    /// by the SIP-010 read-only functions (see `verify_state`).
+    fn seeding_node(&self) -> Option<&str> {
+        match self.contract_interface.fungible_tokens.len() {
+            1 if self.partial_history => self.stacks_node_rpc_url.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the balance of `owner` tracked so far. Balances of owners never
+    /// seen are 0, unless they are seeded from the node (see `seeding_node`):
+    /// the balance is then read as of `block_identifier`, and the credits and
+    /// debits of the block (`block_delta`) are reverted.
+    fn get_ft_balance(
+        &self,
+        db: &dyn Datastore,
+        asset_id: &str,
+        owner: &str,
+        block_identifier: &BlockIdentifier,
+        block_delta: Option<&(u128, u128)>,
+    ) -> u128 {
+        match db.get(&db_key(DBKey::FT(asset_id, owner))) {
+            Ok(Some(bytes)) => return self.parse_amount(&bytes),
+            Ok(None) => {}
+            Err(e) => {
+                warn!(self.log(), "{}", e);
+                return 0;
+            }
+        }
+        let url = match self.seeding_node() {
+            Some(url) => url,
+            None => return 0,
+        };
+        match node::get_balance_from_node(
+            url,
+            &self.contract_id,
+            owner,
+            Some(&block_identifier.hash),
+        ) {
+            Ok(balance) => revert_block_delta(balance, block_delta),
+            Err(e) => {
+                warn!(self.log(), "{}", e);
+                0
+            }
+        }
+    }
+
+    /// Returns the total supply tracked so far, seeded like the balances.
+    fn get_ft_supply(
+        &self,
+        db: &dyn Datastore,
+        asset_id: &str,
+        block_identifier: &BlockIdentifier,
+        block_delta: Option<&(u128, u128)>,
+    ) -> u128 {
+        match db.get(&db_key(DBKey::FTSupply(asset_id))) {
+            Ok(Some(bytes)) => return self.parse_amount(&bytes),
+            Ok(None) => {}
+            Err(e) => {
+                warn!(self.log(), "{}", e);
+                return 0;
+            }
+        }
+        let url = match self.seeding_node() {
+            Some(url) => url,
+            None => return 0,
+        };
+        match node::get_total_supply_from_node(url, &self.contract_id, Some(&block_identifier.hash))
+        {
+            Ok(supply) => revert_block_delta(supply, block_delta),
+            Err(e) => {
+                warn!(self.log(), "{}", e);
+                0
+            }
+        }
+    }
+
+    fn parse_event_amount(&self, amount: &str, txid: &TransactionIdentifier) -> Option<u128> {
+        match amount.parse::<u128>() {
+            Ok(amount) => Some(amount),
+            Err(e) => {
+                warn!(
+                    self.log(),
+                    "Skipping event of transaction {} with malformed amount {}: {}",
+                    txid.hash,
+                    amount,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn parse_amount(&self, bytes: &[u8]) -> u128 {
+        match std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|amount| amount.parse::<u128>().ok())
+        {
+            Some(amount) => amount,
+            None => {
+                warn!(self.log(), "Malformed amount {:?}, reset to 0", bytes);
+                0
+            }
+        }
+    }
+
+    fn report_ft_inconsistency(
+        &mut self,
+        asset_id: &str,
+        owner: Option<&str>,
+        balance: u128,
+        amount: u128,
+        block_identifier: &BlockIdentifier,
+        txid: &str,
+    ) {
+        warn!(
+            self.log(),
+            "Inconsistent balance for {} ({:?}): {} can not absorb {}",
+            asset_id,
+            owner,
+            balance,
+            amount
+        );
+        self.contract_processor_port
+            .trigger(ContractProcessorEvent::BalanceInconsistency(
+                self.contract_id.clone(),
+                FTBalanceInconsistency {
+                    asset_class_identifier: asset_id.to_string(),
+                    owner: owner.map(|owner| owner.to_string()),
+                    balance: balance.to_string(),
+                    amount: amount.to_string(),
+                    block_identifier: block_identifier.clone(),
+                    transaction_identifier: TransactionIdentifier {
+                        hash: txid.to_string(),
+                    },
+                },
+            ));
+    }
+}
+
+/// Returns the amount preceding a block, given the amount once the block was
+/// applied and the `(credits, debits)` of the block.
+fn revert_block_delta(amount: u128, block_delta: Option<&(u128, u128)>) -> u128 {
+    match block_delta {
+        Some((credits, debits)) => amount.saturating_add(*debits).saturating_sub(*credits),
+        None => amount,
+    }
+}
+
+/// Records a write to a field, so that its state can be read as of any block.
+fn put_version<T: Serialize>(
+    db: &dyn Datastore,
+    key: DBKey,
+    value: Option<T>,
+    block_identifier: &BlockIdentifier,
+    txid: &str,
+) {
+    let version = StoredVersion {
+        value,
+        block_identifier: block_identifier.clone(),
+        transaction_identifier: TransactionIdentifier {
+            hash: txid.to_string(),
+        },
+    };
+    db.put(
+        &db_key(key),
+        &serde_json::to_vec(&version).expect("Unable to serialize version"),
+    )
+    .expect("Unable to write");
 }
 
 impl ComponentLifecycle for ContractProcessor {
@@ -565,14 +1299,25 @@ impl Actor for ContractProcessor {
         let mut span = tracer.start("handle message");
 
         match msg {
-            ContractProcessorMessage::RebuildState => {}
-            ContractProcessorMessage::ProcessTransactionsBatch(block_identifier, transactions) => {
+            ContractProcessorMessage::RebuildState(request) => {
+                info!(self.ctx.log(), "ContractProcessor rebuilding state");
+                self.rebuild_state(request);
+            }
+            ContractProcessorMessage::ProcessTransactionsBatch(
+                block_identifier,
+                anchor_block_identifier,
+                transactions,
+            ) => {
                 info!(
                     self.ctx.log(),
                     "ContractProcessor processed transaction batch"
                 );
 
-                let custom_events = self.handle_transactions_batch(block_identifier, transactions);
+                let custom_events = self.handle_transactions_batch(
+                    block_identifier,
+                    anchor_block_identifier,
+                    transactions,
+                );
 
                 self.contract_processor_port.trigger(
                     ContractProcessorEvent::TransactionsBatchProcessed(
@@ -581,7 +1326,19 @@ impl Actor for ContractProcessor {
                     ),
                 )
             }
-            ContractProcessorMessage::RollbackTransactionsBatch(block_identifier, transactions) => {
+            ContractProcessorMessage::RollbackTransactionsBatch(
+                block_identifier,
+                anchor_block_identifier,
+                _,
+            ) => {
+                info!(
+                    self.ctx.log(),
+                    "ContractProcessor rolling back transaction batch"
+                );
+                self.rollback_transactions_batch(
+                    &block_identifier,
+                    anchor_block_identifier.as_ref(),
+                );
             }
             ContractProcessorMessage::Exit => {}
         };
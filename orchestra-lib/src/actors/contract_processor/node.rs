@@ -0,0 +1,131 @@
+use clarity_repl::clarity::codec::StacksMessageCodec;
+use clarity_repl::clarity::types::{OptionalData, PrincipalData, ResponseData, Value};
+use clarity_repl::clarity::util::hash::{bytes_to_hex, hex_bytes};
+use stacks_rpc_client::StacksRpc;
+use std::io::Cursor;
+
+// Reads of the live state of a contract, used to seed the balances of the
+// tokens indexed mid-history and to verify rebuilt states. Values are
+// exchanged hex encoded, 0x prefixed, as they are stored.
+
+/// Returns the balance of `owner` reported by the node, through the SIP-010
+/// `get-balance` read-only function of the token contract (a wrapper around
+/// `ft-get-balance`). Read as of the block `tip` (an index block hash) when
+/// given, as of the node's tip otherwise.
+pub fn get_balance_from_node(
+    stacks_node_rpc_url: &str,
+    contract_id: &str,
+    owner: &str,
+    tip: Option<&str>,
+) -> Result<u128, String> {
+    let principal = PrincipalData::parse(owner)
+        .map_err(|e| format!("unable to seed balance of {}: {:?}", owner, e))?;
+    read_uint(
+        stacks_node_rpc_url,
+        contract_id,
+        "get-balance",
+        vec![Value::Principal(principal)],
+        tip,
+    )
+    .map_err(|e| format!("unable to seed balance of {}: {}", owner, e))
+}
+
+/// Returns the total supply reported by the node, through the SIP-010
+/// `get-total-supply` read-only function of the token contract.
+pub fn get_total_supply_from_node(
+    stacks_node_rpc_url: &str,
+    contract_id: &str,
+    tip: Option<&str>,
+) -> Result<u128, String> {
+    read_uint(
+        stacks_node_rpc_url,
+        contract_id,
+        "get-total-supply",
+        vec![],
+        tip,
+    )
+    .map_err(|e| format!("unable to seed total supply: {}", e))
+}
+
+/// Returns the value of the data var `var`.
+pub fn get_data_var_from_node(
+    stacks_node_rpc_url: &str,
+    contract_id: &str,
+    var: &str,
+) -> Result<String, String> {
+    let (contract_addr, contract_name) = split_contract_id(contract_id)?;
+    let value = StacksRpc::new(stacks_node_rpc_url)
+        .get_data_var(contract_addr, contract_name, var)
+        .map_err(|e| format!("unable to read var {}: {}", var, e))?;
+    Ok(encode_value(&value))
+}
+
+/// Returns the value of the entry `hex_key` of the map `map`, if any.
+pub fn get_map_entry_from_node(
+    stacks_node_rpc_url: &str,
+    contract_id: &str,
+    map: &str,
+    hex_key: &str,
+) -> Result<Option<String>, String> {
+    let (contract_addr, contract_name) = split_contract_id(contract_id)?;
+    let key = decode_value(hex_key)?;
+    let value = StacksRpc::new(stacks_node_rpc_url)
+        .get_map_entry(contract_addr, contract_name, map, &key)
+        .map_err(|e| format!("unable to read map {}: {}", map, e))?;
+    match value {
+        Value::Optional(OptionalData { data }) => Ok(data.map(|value| encode_value(&value))),
+        value => Err(format!(
+            "unable to read map {}: unexpected value {}",
+            map, value
+        )),
+    }
+}
+
+fn split_contract_id(contract_id: &str) -> Result<(&str, &str), String> {
+    contract_id
+        .split_once('.')
+        .ok_or(format!("malformed contract identifier {}", contract_id))
+}
+
+fn encode_value(value: &Value) -> String {
+    format!("0x{}", bytes_to_hex(&value.serialize_to_vec()))
+}
+
+fn decode_value(hex_value: &str) -> Result<Value, String> {
+    let bytes = hex_bytes(hex_value.strip_prefix("0x").unwrap_or(hex_value))
+        .map_err(|e| format!("malformed value {}: {:?}", hex_value, e))?;
+    Value::consensus_deserialize(&mut Cursor::new(&bytes))
+        .map_err(|e| format!("malformed value {}: {:?}", hex_value, e))
+}
+
+/// Calls a read-only function returning a `uint` or a `(response uint _)`.
+fn read_uint(
+    stacks_node_rpc_url: &str,
+    contract_id: &str,
+    method: &str,
+    args: Vec<Value>,
+    tip: Option<&str>,
+) -> Result<u128, String> {
+    let (contract_addr, contract_name) = split_contract_id(contract_id)?;
+    let value = StacksRpc::new(stacks_node_rpc_url)
+        .call_read_only_fn_at(
+            contract_addr,
+            contract_name,
+            method,
+            args,
+            contract_addr,
+            tip,
+        )
+        .map_err(|e| e.to_string())?;
+    match value {
+        Value::UInt(amount) => Ok(amount),
+        Value::Response(ResponseData {
+            committed: true,
+            data,
+        }) => match *data {
+            Value::UInt(amount) => Ok(amount),
+            value => Err(format!("unexpected value {}", value)),
+        },
+        value => Err(format!("unexpected value {}", value)),
+    }
+}
@@ -0,0 +1,24 @@
+use reqwest::blocking::Client;
+use std::time::Duration;
+
+/// Longest time an action can run before being considered failed.
+pub const ACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts `payload` to `url`, and returns the status of the response.
+pub fn execute_http_post(url: &str, payload: &[u8]) -> Result<String, String> {
+    let client = Client::builder()
+        .timeout(ACTION_TIMEOUT)
+        .build()
+        .map_err(|e| format!("unable to build http client: {}", e))?;
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(payload.to_vec())
+        .send()
+        .map_err(|e| format!("unable to post to {}: {}", url, e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("{} responded with status {}", url, status));
+    }
+    Ok(format!("{} responded with status {}", url, status))
+}
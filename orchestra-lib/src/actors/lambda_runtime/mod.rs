@@ -0,0 +1,342 @@
+use crate::types::{
+    Action, FiredTrigger, Lambda, LambdaLog, LambdaReport, ProtocolObserverId, User,
+};
+use clarinet_lib::types::{BitcoinBlockData, BlockIdentifier, StacksBlockData};
+use kompact::prelude::*;
+use opentelemetry::global;
+use opentelemetry::trace::{Span, Tracer};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{channel, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod executor;
+
+/// Logs kept per lambda, the oldest being dropped first.
+const MAX_LOGS_PER_LAMBDA: usize = 50;
+
+#[derive(Clone, Debug)]
+pub enum LambdaRuntimeMessage {
+    RegisterLambdas(ProtocolObserverId, Vec<Lambda>),
+    /// Blocks along with the triggers matched by the supervisor.
+    ProcessStacksBlock(StacksBlockData, Vec<FiredTrigger>),
+    ProcessBitcoinBlock(BitcoinBlockData, Vec<FiredTrigger>),
+    RevertStacksBlock(StacksBlockData, Vec<FiredTrigger>),
+    RevertBitcoinBlock(BitcoinBlockData, Vec<FiredTrigger>),
+    ExecutionCompleted(LambdaExecution, Result<String, String>),
+    GetReports(Sender<Vec<LambdaReport>>),
+    Exit,
+}
+
+/// Action of a lambda triggered by a block, along with its payload.
+#[derive(Clone, Debug)]
+pub struct LambdaExecution {
+    pub protocol_id: ProtocolObserverId,
+    pub lambda_id: u64,
+    pub action: User,
+    pub block_identifier: BlockIdentifier,
+    pub payload: Vec<u8>,
+}
+
+#[derive(ComponentDefinition)]
+pub struct LambdaRuntime {
+    ctx: ComponentContext<Self>,
+    lambdas: BTreeMap<ProtocolObserverId, Vec<Lambda>>,
+    reports: BTreeMap<(ProtocolObserverId, u64), LambdaReport>,
+    /// Executions are performed off the actor's thread, by a worker per
+    /// lambda: in order for a lambda, without a slow lambda delaying the
+    /// others. None until the runtime is started.
+    executors: Option<BTreeMap<(ProtocolObserverId, u64), Sender<LambdaExecution>>>,
+}
+
+impl LambdaRuntime {
+    pub fn new() -> Self {
+        global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
+        Self {
+            ctx: ComponentContext::uninitialised(),
+            lambdas: BTreeMap::new(),
+            reports: BTreeMap::new(),
+            executors: None,
+        }
+    }
+
+    pub fn register_lambdas(&mut self, protocol_id: ProtocolObserverId, lambdas: Vec<Lambda>) {
+        self.reports
+            .retain(|(report_protocol_id, _), _| report_protocol_id != &protocol_id);
+        // The workers of the previous lambdas stop once done with their queue
+        if let Some(ref mut executors) = self.executors {
+            executors.retain(|(executor_protocol_id, _), _| executor_protocol_id != &protocol_id);
+        }
+        for lambda in lambdas.iter() {
+            self.reports.insert(
+                (protocol_id.clone(), lambda.lambda_id),
+                LambdaReport {
+                    protocol_id: protocol_id.0,
+                    lambda_id: lambda.lambda_id,
+                    name: lambda.name.clone(),
+                    executions: 0,
+                    failures: 0,
+                    consecutive_failures: 0,
+                    last_error: None,
+                    logs: VecDeque::new(),
+                },
+            );
+        }
+        self.lambdas.insert(protocol_id, lambdas);
+    }
+
+    /// Notifies the lambdas fired by a new block, under "apply".
+    pub fn apply_stacks_block(&mut self, block: StacksBlockData, triggers: Vec<FiredTrigger>) {
+        self.trigger_lambdas(&block.block_identifier, &triggers, |transactions| {
+            stacks_payload(&block, transactions, "apply")
+        });
+    }
+
+    pub fn apply_bitcoin_block(&mut self, block: BitcoinBlockData, triggers: Vec<FiredTrigger>) {
+        self.trigger_lambdas(&block.block_identifier, &triggers, |transactions| {
+            bitcoin_payload(&block, transactions, "apply")
+        });
+    }
+
+    /// Notifies the lambdas fired by an orphaned block, with the same payload
+    /// under "rollback", so that they can undo their side effects.
+    pub fn revert_stacks_block(&mut self, block: StacksBlockData, triggers: Vec<FiredTrigger>) {
+        self.trigger_lambdas(&block.block_identifier, &triggers, |transactions| {
+            stacks_payload(&block, transactions, "rollback")
+        });
+    }
+
+    pub fn revert_bitcoin_block(&mut self, block: BitcoinBlockData, triggers: Vec<FiredTrigger>) {
+        self.trigger_lambdas(&block.block_identifier, &triggers, |transactions| {
+            bitcoin_payload(&block, transactions, "rollback")
+        });
+    }
+
+    /// Schedules the action of the lambdas of `triggers`, with the payload
+    /// built by `payload` out of the transactions matched by their trigger.
+    fn trigger_lambdas<F>(
+        &mut self,
+        block_identifier: &BlockIdentifier,
+        triggers: &[FiredTrigger],
+        payload: F,
+    ) where
+        F: Fn(&[usize]) -> serde_json::Value,
+    {
+        let runtime_ref = self.actor_ref();
+        let executors = match self.executors {
+            Some(ref mut executors) => executors,
+            None => {
+                warn!(
+                    self.ctx.log(),
+                    "LambdaRuntime not started, dropping lambdas of block {}",
+                    block_identifier.index
+                );
+                return;
+            }
+        };
+        for (protocol_id, lambdas) in self.lambdas.iter() {
+            for lambda in lambdas.iter() {
+                let trigger = triggers.iter().find(|trigger| {
+                    trigger.trigger_id.pid.0 == protocol_id.0
+                        && trigger.trigger_id.lambda_id == lambda.lambda_id
+                });
+                let trigger = match trigger {
+                    Some(trigger) => trigger,
+                    None => continue,
+                };
+                let action = match lambda.action {
+                    Action::User(ref action) => action.clone(),
+                    // Platform actions are carried out by the protocol observers
+                    Action::Platform(_) => continue,
+                };
+                let mut payload = payload(&trigger.transactions);
+                payload["lambda_id"] = json!(lambda.lambda_id);
+                payload["name"] = json!(lambda.name);
+                let payload = match serde_json::to_vec(&payload) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        warn!(
+                            self.ctx.log(),
+                            "Unable to serialize payload of lambda {}: {}", lambda.lambda_id, e
+                        );
+                        continue;
+                    }
+                };
+                let execution = LambdaExecution {
+                    protocol_id: protocol_id.clone(),
+                    lambda_id: lambda.lambda_id,
+                    action,
+                    block_identifier: block_identifier.clone(),
+                    payload,
+                };
+                let executor_key = (protocol_id.clone(), lambda.lambda_id);
+                let executions_tx = executors
+                    .entry(executor_key.clone())
+                    .or_insert_with(|| spawn_executor(runtime_ref.clone()));
+                if let Err(e) = executions_tx.send(execution) {
+                    // The worker is gone: a new one is spawned for the next block
+                    error!(
+                        self.ctx.log(),
+                        "Unable to schedule execution of lambda {}: {}", lambda.lambda_id, e
+                    );
+                    executors.remove(&executor_key);
+                }
+            }
+        }
+    }
+
+    pub fn record_execution(&mut self, execution: LambdaExecution, result: Result<String, String>) {
+        let report = match self
+            .reports
+            .get_mut(&(execution.protocol_id.clone(), execution.lambda_id))
+        {
+            Some(report) => report,
+            // The lambdas were registered again in the meantime
+            None => return,
+        };
+        report.executions += 1;
+        let (success, output) = match result {
+            Ok(output) => {
+                report.consecutive_failures = 0;
+                (true, output)
+            }
+            Err(e) => {
+                warn!(
+                    self.ctx.log(),
+                    "Lambda {} ({}) failed on block {}: {}",
+                    report.name,
+                    report.lambda_id,
+                    execution.block_identifier.index,
+                    e
+                );
+                report.failures += 1;
+                report.consecutive_failures += 1;
+                report.last_error = Some(e.clone());
+                (false, e)
+            }
+        };
+        if report.logs.len() == MAX_LOGS_PER_LAMBDA {
+            report.logs.pop_front();
+        }
+        report.logs.push_back(LambdaLog {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            block_identifier: execution.block_identifier,
+            success,
+            output,
+        });
+    }
+}
+
+/// Returns the payload of a lambda, that is `block` trimmed down to the
+/// transactions matched by its predicate (their positions in the block), under
+/// `operation` ("apply" or "rollback").
+fn stacks_payload(
+    block: &StacksBlockData,
+    transactions: &[usize],
+    operation: &str,
+) -> serde_json::Value {
+    let mut block = block.clone();
+    block.transactions = select_transactions(&block.transactions, transactions);
+    json!({ "chain": "stacks", operation: [block] })
+}
+
+fn bitcoin_payload(
+    block: &BitcoinBlockData,
+    transactions: &[usize],
+    operation: &str,
+) -> serde_json::Value {
+    let mut block = block.clone();
+    block.transactions = select_transactions(&block.transactions, transactions);
+    json!({ "chain": "bitcoin", operation: [block] })
+}
+
+fn select_transactions<T: Clone>(transactions: &[T], positions: &[usize]) -> Vec<T> {
+    positions
+        .iter()
+        .filter_map(|position| transactions.get(*position).cloned())
+        .collect()
+}
+
+fn execute(execution: &LambdaExecution) -> Result<String, String> {
+    match execution.action {
+        User::HTTPPost(ref url) => executor::execute_http_post(url, &execution.payload),
+    }
+}
+
+/// Spawns the worker carrying out the executions of a lambda, in order, and
+/// reporting their results to the runtime.
+fn spawn_executor(runtime_ref: ActorRef<LambdaRuntimeMessage>) -> Sender<LambdaExecution> {
+    let (executions_tx, executions_rx) = channel::<LambdaExecution>();
+    std::thread::spawn(move || {
+        while let Ok(execution) = executions_rx.recv() {
+            let result = execute(&execution);
+            runtime_ref.tell(LambdaRuntimeMessage::ExecutionCompleted(execution, result));
+        }
+    });
+    executions_tx
+}
+
+impl ComponentLifecycle for LambdaRuntime {
+    fn on_start(&mut self) -> Handled {
+        info!(self.log(), "LambdaRuntime starting");
+        self.executors = Some(BTreeMap::new());
+        Handled::Ok
+    }
+
+    fn on_stop(&mut self) -> Handled {
+        // Dropping the senders lets the workers terminate
+        self.executors = None;
+        Handled::Ok
+    }
+}
+
+impl Actor for LambdaRuntime {
+    type Message = LambdaRuntimeMessage;
+
+    fn receive_local(&mut self, msg: LambdaRuntimeMessage) -> Handled {
+        let tracer = opentelemetry_jaeger::new_pipeline()
+            .with_service_name("LambdaRuntime")
+            .install_simple()
+            .unwrap();
+        let mut span = tracer.start("handle message");
+
+        match msg {
+            LambdaRuntimeMessage::RegisterLambdas(protocol_id, lambdas) => {
+                info!(
+                    self.log(),
+                    "LambdaRuntime registering {} lambdas for protocol {}",
+                    lambdas.len(),
+                    protocol_id.0
+                );
+                self.register_lambdas(protocol_id, lambdas);
+            }
+            LambdaRuntimeMessage::ProcessStacksBlock(block, triggers) => {
+                self.apply_stacks_block(block, triggers);
+            }
+            LambdaRuntimeMessage::ProcessBitcoinBlock(block, triggers) => {
+                self.apply_bitcoin_block(block, triggers);
+            }
+            LambdaRuntimeMessage::RevertStacksBlock(block, triggers) => {
+                self.revert_stacks_block(block, triggers);
+            }
+            LambdaRuntimeMessage::RevertBitcoinBlock(block, triggers) => {
+                self.revert_bitcoin_block(block, triggers);
+            }
+            LambdaRuntimeMessage::ExecutionCompleted(execution, result) => {
+                self.record_execution(execution, result);
+            }
+            LambdaRuntimeMessage::GetReports(tx) => {
+                let _ = tx.send(self.reports.values().cloned().collect());
+            }
+            LambdaRuntimeMessage::Exit => {}
+        };
+        span.end();
+        Handled::Ok
+    }
+
+    fn receive_network(&mut self, _: NetMessage) -> Handled {
+        unimplemented!()
+    }
+}
@@ -1,12 +1,16 @@
 mod block_store_manager;
 mod contract_processor;
+mod lambda_runtime;
 mod protocol_observer;
 mod supervisor;
+mod wallet_processor;
 
 pub use block_store_manager::{BlockStoreManager, BlockStoreManagerMessage};
 pub use contract_processor::{ContractProcessor, ContractProcessorMessage};
+pub use lambda_runtime::{LambdaRuntime, LambdaRuntimeMessage};
 pub use protocol_observer::{ProtocolObserver, ProtocolObserverMessage};
-pub use supervisor::{OrchestraSupervisor, OrchestraSupervisorMessage};
+pub use supervisor::{OrchestraSupervisor, OrchestraSupervisorMessage, SupervisedActor};
+pub use wallet_processor::{WalletProcessor, WalletProcessorMessage};
 
 use kompact::prelude::*;
 use std::sync::mpsc::Receiver;
@@ -164,16 +168,17 @@ mod test {
     }
 
     fn block_with_transactions(
+        index: u64,
         transactions: Vec<StacksTransactionData>,
     ) -> ChainUpdatedWithBlockData {
         let block = StacksBlockData {
             block_identifier: BlockIdentifier {
-                index: 1,
-                hash: "1".into(),
+                index,
+                hash: index.to_string(),
             },
             parent_block_identifier: BlockIdentifier {
-                index: 0,
-                hash: "0".into(),
+                index: index - 1,
+                hash: (index - 1).to_string(),
             },
             timestamp: 0,
             transactions,
@@ -222,7 +227,7 @@ mod test {
         contracts.insert(test_contract_id.clone(), test_contract_settings);
 
         let (tx, rx) = channel();
-        let storage_driver = StorageDriver::tmpfs();
+        let storage_driver = StorageDriver::in_memory();
         let storage_driver_moved = storage_driver.clone();
         let handle = std::thread::spawn(|| run_supervisor(storage_driver_moved, rx));
 
@@ -238,12 +243,16 @@ mod test {
             lambdas: vec![],
             contracts,
             manifest_path: PathBuf::new(),
+            stacks_node_rpc_url: None,
         };
 
-        let block = block_with_transactions(vec![transaction_contract_deployment(
-            test_contract_id.to_string(),
-            "(print \"hello world\")",
-        )]);
+        let block = block_with_transactions(
+            1,
+            vec![transaction_contract_deployment(
+                test_contract_id.to_string(),
+                "(print \"hello world\")",
+            )],
+        );
         tx.send(OrchestraSupervisorMessage::ProcessStacksChainEvent(
             StacksChainEvent::ChainUpdatedWithBlock(block),
         ))
@@ -347,7 +356,7 @@ mod test {
                 hex_asset_identifier: "A".into(),
             }),
         ]);
-        let block = block_with_transactions(vec![transaction]);
+        let block = block_with_transactions(2, vec![transaction]);
         tx.send(OrchestraSupervisorMessage::ProcessStacksChainEvent(
             StacksChainEvent::ChainUpdatedWithBlock(block),
         ))
@@ -364,72 +373,148 @@ mod test {
         let _res = handle.join().unwrap();
 
         {
-            use crate::datastore::contracts::{contract_db_write, db_key, DBKey};
+            use crate::datastore::contracts::contract_db;
+            use crate::datastore::keys::{db_key, DBKey};
 
-            let db = contract_db_write(&storage_driver, &test_contract_id.to_string());
             let other_contract_id = "S1G2081040G2081040G2081040G208105NK8P91.test";
-            db.put(
-                &db_key(
-                    DBKey::MapEntry("my-map", "01"),
-                    &other_contract_id.to_string(),
-                ),
-                "junk".as_bytes(),
-            )
-            .unwrap();
+            let db = contract_db(&storage_driver, other_contract_id);
+            db.put(&db_key(DBKey::MapEntry("my-map", "01")), "junk".as_bytes())
+                .unwrap();
         }
 
         {
-            use crate::datastore::contracts::{contract_db_read, db_key, DBKey};
-            use rocksdb::{Direction, IteratorMode};
+            use crate::datastore::contracts::contract_db;
+            use crate::datastore::keys::{db_key, DBKey};
 
-            let db = contract_db_read(&storage_driver, &test_contract_id.to_string());
+            let db = contract_db(&storage_driver, &test_contract_id.to_string());
 
             let res = db
-                .get(&db_key(
-                    DBKey::MapEntry("my-map", "01"),
-                    &test_contract_id.to_string(),
-                ))
+                .get(&db_key(DBKey::MapEntry("my-map", "01")))
                 .unwrap()
                 .unwrap();
             assert_eq!(res, vec![17]);
 
             let res = db
-                .get(&db_key(
-                    DBKey::MapEntry("my-map", "k2"),
-                    &test_contract_id.to_string(),
-                ))
+                .get(&db_key(DBKey::MapEntry("my-map", "k2")))
                 .unwrap()
                 .unwrap();
             assert_eq!(String::from_utf8(res).unwrap(), "v4".to_string());
 
-            let mut iter = db.iterator(IteratorMode::Start); // Always iterates forward
-            println!("1");
-            for (key, value) in iter {
-                println!("Saw {:?}", String::from_utf8(key.to_vec()).unwrap());
-            }
-            iter = db.iterator(IteratorMode::End); // Always iterates backward
-            println!("2");
-            for (key, value) in iter {
-                println!("Saw {:?}", String::from_utf8(key.to_vec()).unwrap());
-            }
-            iter = db.iterator(IteratorMode::From(
-                b"S1G2081040G2081040G2081040G208105NK8PE5.test::var",
-                Direction::Forward,
-            )); // From a key in Direction::{forward,reverse}
-            println!("3");
-            for (key, value) in iter {
-                println!("Saw {:?}", String::from_utf8(key.to_vec()).unwrap());
-            }
+            let keys = db
+                .scan_prefix(&db_key(DBKey::MapScan("my-map")))
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>();
+            assert!(keys.contains(&db_key(DBKey::MapEntry("my-map", "01"))));
+            assert!(keys.contains(&db_key(DBKey::MapEntry("my-map", "02"))));
+            assert!(!keys.contains(&db_key(DBKey::MapEntry("my-map", "03"))));
+        }
+    }
 
-            // You can seek with an existing Iterator instance, too
-            iter = db.prefix_iterator(
-                b"map::S1G2081040G2081040G2081040G208105NK8PE5.test::my-map::entry(",
-            );
-            // iter.set_mode(IteratorMode::From(b"S1G2081040G2081040G2081040G208105NK8PE5.test::var", Direction::Forward));
-            println!("4");
-            for (key, value) in iter {
-                println!("Saw {:?}", String::from_utf8(key.to_vec()).unwrap());
+    #[test]
+    fn blocks_delivered_again_after_restart_are_not_applied_twice() {
+        use crate::actors::run_supervisor;
+        use crate::datastore::contracts::contract_db;
+        use crate::datastore::keys::{db_key, DBKey};
+        use crate::types::{
+            ContractSettings, ProjectMetadata, ProtocolObserverConfig, ProtocolObserverId,
+        };
+        use clarinet_lib::clarity_repl::clarity::types::{
+            QualifiedContractIdentifier, StandardPrincipalData,
+        };
+        use clarinet_lib::types::events::*;
+        use clarinet_lib::types::StacksChainEvent;
+        use std::collections::BTreeMap;
+        use std::convert::TryInto;
+        use std::sync::mpsc::channel;
+        use std::{thread, time};
+
+        let test_contract_id = QualifiedContractIdentifier::new(
+            StandardPrincipalData::transient(),
+            "token".try_into().unwrap(),
+        );
+        let mut contracts = BTreeMap::new();
+        contracts.insert(
+            test_contract_id.clone(),
+            ContractSettings {
+                state_explorer_enabled: true,
+                api_generator_enabled: vec![],
+            },
+        );
+        let orchestra_manifest = ProtocolObserverConfig {
+            identifier: ProtocolObserverId(0),
+            project: ProjectMetadata {
+                name: "test".into(),
+                authors: vec![],
+                homepage: "".into(),
+                license: "".into(),
+                description: "".into(),
+            },
+            lambdas: vec![],
+            contracts,
+            manifest_path: PathBuf::new(),
+            stacks_node_rpc_url: None,
+        };
+        let deployment = block_with_transactions(
+            1,
+            vec![transaction_contract_deployment(
+                test_contract_id.to_string(),
+                "(define-fungible-token my-ft)",
+            )],
+        );
+        let mut transaction =
+            transaction_contract_call_impacting_contract_id(test_contract_id.to_string(), true);
+        transaction
+            .metadata
+            .receipt
+            .events
+            .push(StacksTransactionEvent::FTMintEvent(FTMintEventData {
+                asset_class_identifier: format!("{}::my-ft", test_contract_id),
+                recipient: "S1G2081040G2081040G2081040G208105NK8P01".into(),
+                amount: "100".into(),
+            }));
+        let mint = block_with_transactions(2, vec![transaction]);
+
+        let storage_driver = StorageDriver::in_memory();
+        let delay = time::Duration::from_millis(100);
+        for run in 0..2 {
+            let (tx, rx) = channel();
+            let storage_driver_moved = storage_driver.clone();
+            let handle = std::thread::spawn(|| run_supervisor(storage_driver_moved, rx));
+
+            if run == 0 {
+                tx.send(OrchestraSupervisorMessage::ProcessStacksChainEvent(
+                    StacksChainEvent::ChainUpdatedWithBlock(deployment.clone()),
+                ))
+                .unwrap();
+                thread::sleep(delay);
             }
+            tx.send(OrchestraSupervisorMessage::RegisterProtocolObserver(
+                orchestra_manifest.clone(),
+            ))
+            .unwrap();
+            thread::sleep(delay);
+
+            // Delivered again after the restart, once caught up
+            tx.send(OrchestraSupervisorMessage::ProcessStacksChainEvent(
+                StacksChainEvent::ChainUpdatedWithBlock(mint.clone()),
+            ))
+            .unwrap();
+            thread::sleep(delay);
+
+            tx.send(OrchestraSupervisorMessage::Exit).unwrap();
+            let _res = handle.join().unwrap();
         }
+
+        let db = contract_db(&storage_driver, &test_contract_id.to_string());
+        let balance = db
+            .get(&db_key(DBKey::FT(
+                &format!("{}::my-ft", test_contract_id),
+                "S1G2081040G2081040G2081040G208105NK8P01",
+            )))
+            .unwrap()
+            .unwrap();
+        assert_eq!(String::from_utf8(balance).unwrap(), "100");
     }
 }
@@ -10,16 +10,20 @@ pub use update_state_explorer::UpdateStateExplorer;
 
 use super::block_store_manager::ContractInstanciation;
 use crate::datastore::blocks;
-use crate::datastore::contracts::{contract_db_read, db_key, DBKey};
-use crate::datastore::StorageDriver;
+use crate::datastore::contracts::{
+    contract_db, latest_version, scan_events_page, scan_versions_page,
+};
+use crate::datastore::keys::{db_key, DBKey};
+use crate::datastore::{Datastore, StorageDriver};
 use crate::types::{
     self, DataMapEventStoredValue, DataMapStoredEntry, DataVarSetEventFormattedValue,
-    DataVarSetEventValue, DataVarStoredValue, FTEventStoredValue, NFTEventStoredValue,
-    NFTStoredEntry,
+    DataVarSetEventValue, DataVarStoredValue, FTEventStoredValue, FTStoredBalance,
+    NFTEventStoredValue, NFTStoredEntry, StoredVersion,
 };
 use crate::types::{
     Contract, FieldValues, FieldValuesRequest, FieldValuesResponse, FtValues, MapValues, NftValues,
-    ProtocolObserverConfig, ProtocolObserverId, ProtocolRegistration, VarValues,
+    ProtocolObserverConfig, ProtocolObserverId, ProtocolRegistration, RequestError, TriggerId,
+    VarValues,
 };
 use clarinet_lib::clarity_repl::clarity::analysis::contract_interface_builder::build_contract_interface;
 use clarinet_lib::clarity_repl::clarity::analysis::contract_interface_builder::{
@@ -28,18 +32,18 @@ use clarinet_lib::clarity_repl::clarity::analysis::contract_interface_builder::{
 use clarinet_lib::clarity_repl::clarity::types::{
     QualifiedContractIdentifier, StandardPrincipalData,
 };
-use clarinet_lib::clarity_repl::clarity::util::hash::hex_bytes;
+use clarinet_lib::clarity_repl::clarity::util::hash::{hex_bytes, to_hex};
 use clarinet_lib::clarity_repl::repl::settings::InitialContract;
 use clarinet_lib::clarity_repl::repl::{ClarityInterpreter, Session, SessionSettings};
 use clarinet_lib::types::events::SmartContractEventData;
 use clarinet_lib::types::events::StacksTransactionEvent;
-use clarinet_lib::types::{BitcoinBlockData, StacksBlockData};
-use clarinet_lib::types::{BlockIdentifier, StacksTransactionData, TransactionIdentifier};
+use clarinet_lib::types::BitcoinBlockData;
+use clarinet_lib::types::{BlockIdentifier, StacksTransactionData};
 
 use kompact::prelude::*;
 use opentelemetry::global;
 use opentelemetry::trace::{Span, Tracer};
-use rocksdb::{Options, DB};
+use serde::de::DeserializeOwned;
 use serde_json::map::Map;
 use std::collections::{BTreeMap, VecDeque};
 use std::io::Cursor;
@@ -49,8 +53,12 @@ use std::sync::mpsc::Sender;
 pub enum ProtocolObserverMessage {
     ProcessTransaction(StacksTransactionData),
     RollbackTransaction(StacksTransactionData),
+    /// Lambdas of the protocol whose predicates fired on a block.
+    ProcessTriggers(BlockIdentifier, Vec<TriggerId>),
+    /// Triggers fired by a block since orphaned.
+    RevertTriggers(BlockIdentifier, Vec<TriggerId>),
     RequestFieldValues(FieldValuesRequest),
-    GetInterfaces(Sender<ProtocolRegistration>),
+    GetInterfaces(Sender<Result<ProtocolRegistration, RequestError>>),
     Exit,
 }
 
@@ -100,24 +108,31 @@ impl ProtocolObserver {
 
     pub fn build_state(&mut self) {
         let (contracts) = {
-            let mut working_dir = match self.storage_driver {
-                StorageDriver::Filesystem(ref config) => config.working_dir.clone(),
-            };
-            working_dir.push("stacks");
-            let mut options = Options::default();
-            options.create_if_missing(true);
-            // Todo: re-approach this
-            let db = DB::open_for_read_only(&options, working_dir, true).unwrap();
+            let db = blocks::contract_deployments_db(&self.storage_driver);
 
+            // Contracts not deployed yet, or unreadable, are left out of the
+            // analysis instead of taking the observer down.
             let mut contracts: Vec<(String, ContractInstanciation)> = Vec::new();
             for (contract_id, _) in self.config.contracts.iter() {
-                let bytes = db
-                    .get(&contract_id.to_string().as_bytes())
-                    .expect("Unable to hit contract storage")
-                    .expect(&format!("Unable to retrieve contract {}", contract_id));
-                let contract_instance = serde_json::from_slice::<ContractInstanciation>(&bytes)
-                    .expect("Unable to deserialize contract");
-                contracts.push((contract_id.to_string(), contract_instance));
+                let key = db_key(DBKey::ContractDeployment(&contract_id.to_string()));
+                let bytes = match db.get(&key) {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => {
+                        warn!(
+                            self.log(),
+                            "Contract {} not deployed, skipping", contract_id
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(self.log(), "Unable to retrieve contract: {}", e);
+                        continue;
+                    }
+                };
+                match serde_json::from_slice::<ContractInstanciation>(&bytes) {
+                    Ok(instance) => contracts.push((contract_id.to_string(), instance)),
+                    Err(e) => error!(self.log(), "Unable to deserialize contract: {}", e),
+                }
             }
             contracts
         };
@@ -138,7 +153,13 @@ impl ProtocolObserver {
             let mut diagnostics = vec![];
             // Extract the AST, and try to move to the next contract if we throw an error:
             // we're trying to get as many errors as possible
-            let contract_identifier = QualifiedContractIdentifier::parse(&contract_id).unwrap();
+            let contract_identifier = match QualifiedContractIdentifier::parse(&contract_id) {
+                Ok(contract_identifier) => contract_identifier,
+                Err(e) => {
+                    error!(self.log(), "Unable to parse contract: {:?}", e);
+                    continue;
+                }
+            };
             let (mut ast, mut diags, success) = incremental_session.interpreter.build_ast(
                 contract_identifier.clone(),
                 contract_instanciation.code.clone(),
@@ -209,6 +230,39 @@ impl ProtocolObserver {
     }
 }
 
+/// Number of entries and events returned when the request does not specify it.
+const DEFAULT_PAGE_SIZE: u16 = 50;
+
+fn decode_cursor(cursor: &Option<String>) -> Option<Vec<u8>> {
+    cursor.as_ref().and_then(|cursor| hex_bytes(cursor).ok())
+}
+
+fn encode_cursor(cursor: Option<Vec<u8>>) -> Option<String> {
+    cursor.map(|cursor| to_hex(&cursor))
+}
+
+/// Returns a page of events, most recent first, along with the cursor of the next page.
+fn read_events_page<T: DeserializeOwned>(
+    db: &dyn Datastore,
+    prefix: &[u8],
+    at_block_index: u64,
+    cursor: Option<&[u8]>,
+    page_size: u16,
+) -> (Vec<(T, u64, u64)>, Option<String>) {
+    let page = scan_events_page(db, prefix, at_block_index, cursor, page_size as usize)
+        .expect("Unable to read contract");
+    let events = page
+        .items
+        .into_iter()
+        .map(|(block_index, event_index, value)| {
+            let event =
+                serde_json::from_slice::<T>(&value).expect("Unable to deserialize contract");
+            (event, block_index, event_index as u64)
+        })
+        .collect();
+    (events, encode_cursor(page.next_cursor))
+}
+
 impl ComponentLifecycle for ProtocolObserver {
     fn on_start(&mut self) -> Handled {
         info!(self.log(), "ProtocolObserver starting");
@@ -232,17 +286,60 @@ impl Actor for ProtocolObserver {
         match msg {
             ProtocolObserverMessage::ProcessTransaction(tx) => {}
             ProtocolObserverMessage::RollbackTransaction(tx) => {}
+            ProtocolObserverMessage::ProcessTriggers(block_identifier, triggers) => {
+                for trigger in triggers.iter() {
+                    let lambda = self
+                        .config
+                        .lambdas
+                        .iter()
+                        .find(|lambda| lambda.lambda_id == trigger.lambda_id);
+                    match lambda {
+                        Some(lambda) => info!(
+                            self.log(),
+                            "Lambda {} triggered by block {} - {}",
+                            lambda.name,
+                            block_identifier.index,
+                            block_identifier.hash
+                        ),
+                        None => warn!(
+                            self.log(),
+                            "Unknown lambda {} triggered by block {}",
+                            trigger.lambda_id,
+                            block_identifier.index
+                        ),
+                    }
+                }
+            }
+            ProtocolObserverMessage::RevertTriggers(block_identifier, triggers) => {
+                for trigger in triggers.iter() {
+                    info!(
+                        self.log(),
+                        "Lambda {} reverted, block {} - {} orphaned",
+                        trigger.lambda_id,
+                        block_identifier.index,
+                        block_identifier.hash
+                    );
+                }
+            }
             ProtocolObserverMessage::GetInterfaces(tx) => {
                 let mut contracts = vec![];
+                let mut unknown_contract = None;
                 for (contract_id, _) in self.config.contracts.iter() {
                     let contract_id = contract_id.to_string();
 
-                    let db = contract_db_read(&self.storage_driver, &contract_id);
-
-                    let bytes = db
-                        .get(db_key(DBKey::Interface, &contract_id))
-                        .unwrap()
-                        .unwrap();
+                    let db = contract_db(&self.storage_driver, &contract_id);
+
+                    // Contracts are only known once their processor stored their interface
+                    let bytes = match db
+                        .get(&db_key(DBKey::Interface))
+                        .expect("Unable to read contract")
+                    {
+                        Some(bytes) => bytes,
+                        None => {
+                            unknown_contract = Some(contract_id);
+                            break;
+                        }
+                    };
                     let interface = serde_json::from_slice::<ContractInterface>(&bytes)
                         .expect("Unable to deserialize contract");
 
@@ -251,72 +348,87 @@ impl Actor for ProtocolObserver {
                         interface,
                     })
                 }
-                tx.send(ProtocolRegistration { contracts }).unwrap()
+                let response = match unknown_contract {
+                    Some(contract_id) => Err(RequestError::UnknownContract(contract_id)),
+                    None => Ok(ProtocolRegistration { contracts }),
+                };
+                let _ = tx.send(response);
             }
             ProtocolObserverMessage::RequestFieldValues(request) => {
-                let db = contract_db_read(&self.storage_driver, &request.contract_identifier);
-
-                let bytes = db
-                    .get(db_key(DBKey::Interface, &request.contract_identifier))
-                    .unwrap()
-                    .unwrap();
+                let db = contract_db(&self.storage_driver, &request.contract_identifier);
+
+                let bytes = match db
+                    .get(&db_key(DBKey::Interface))
+                    .expect("Unable to read contract")
+                {
+                    Some(bytes) => bytes,
+                    None => {
+                        let _ = request.tx.send(Err(RequestError::UnknownContract(
+                            request.contract_identifier.clone(),
+                        )));
+                        span.end();
+                        return Handled::Ok;
+                    }
+                };
                 let interface = serde_json::from_slice::<ContractInterface>(&bytes)
                     .expect("Unable to deserialize contract");
 
+                // Values are read as of the requested block, latest state by default
+                let at_block_index = match request.at_block_identifier {
+                    Some(ref block_identifier) => block_identifier.index,
+                    None => u64::MAX,
+                };
+                let page_size = match request.page_size {
+                    0 => DEFAULT_PAGE_SIZE,
+                    page_size => page_size,
+                };
+                let entries_cursor = decode_cursor(&request.entries_cursor);
+                let events_cursor = decode_cursor(&request.events_cursor);
+
                 let mut field = None;
                 // Iterate over every declared variable present in the contract interface
                 for var in interface.variables.iter() {
                     // We found the variable we're looking for:
                     if var.name == request.field_name {
                         // Retrieve the last value
-                        let value = match db
-                            .get(db_key(DBKey::Var(&var.name), &request.contract_identifier))
-                        {
-                            Ok(None) => Value::none(),
-                            Ok(Some(value)) => {
-                                let value = serde_json::from_slice::<DataVarStoredValue>(&value)
-                                    .expect("Unable to deserialize contract");
+                        let version = latest_version::<DataVarStoredValue>(
+                            &*db,
+                            &db_key(DBKey::VarVersionScan(&var.name)),
+                            &db_key(DBKey::VarVersion(&var.name, at_block_index, u32::MAX)),
+                        )
+                        .expect("Unable to read contract");
+                        let value = match version {
+                            Some(StoredVersion { value: Some(value), .. }) => {
                                 value.get_decoded_value()
                             }
                             _ => Value::none(),
                         };
 
                         // Retrieve the latest events
-                        let events_key =
-                            db_key(DBKey::VarEventScan(&var.name), &request.contract_identifier);
-                        let key = String::from_utf8(events_key.to_vec()).unwrap();
-                        let iter = db.prefix_iterator(&events_key);
-                        let mut events = vec![];
-                        for (key, value) in iter {
-                            if key.starts_with(&events_key) {
-                                let remainder =
-                                    String::from_utf8(key[events_key.len()..].to_vec()).unwrap();
-                                let order = remainder.split("/").collect::<Vec<&str>>();
-                                let block_index = u64::from_str_radix(order[0], 10).unwrap();
-                                let event_index = u64::from_str_radix(order[1], 10).unwrap();
-
-                                let event = serde_json::from_slice::<DataVarStoredValue>(&value)
-                                    .expect("Unable to deserialize contract");
-                                events.push(DataVarSetEventFormattedValue {
+                        let (events, events_next_cursor) = read_events_page::<DataVarStoredValue>(
+                            &*db,
+                            &db_key(DBKey::VarEventScan(&var.name)),
+                            at_block_index,
+                            events_cursor.as_deref(),
+                            page_size,
+                        );
+                        let events = events
+                            .into_iter()
+                            .map(|(event, block_index, event_index)| {
+                                DataVarSetEventFormattedValue {
                                     value: event.get_formatted_decoded_value(),
                                     block_index,
                                     event_index,
-                                });
-                            }
-                        }
-
-                        // Sort events using their tx index, event index
-                        events.sort_by(|b, a| {
-                            (a.block_index * 100 + a.event_index)
-                                .cmp(&(b.block_index * 100 + b.event_index))
-                        });
+                                }
+                            })
+                            .collect();
 
                         field = Some(FieldValues::Var(VarValues {
                             value: format!("{}", value),
                             value_type: var.type_f.clone(),
                             events,
-                            events_page_index: 0,
-                            events_page_size: 0,
+                            events_page_size: page_size,
+                            events_next_cursor,
                         }));
                     }
                 }
@@ -325,58 +437,36 @@ impl Actor for ProtocolObserver {
                     // Is the field that we're looking for a map?
                     for map in interface.maps.iter() {
                         if map.name == request.field_name {
-                            let value_key =
-                                db_key(DBKey::MapScan(&map.name), &request.contract_identifier);
-                            let iter = db.prefix_iterator(&value_key);
+                            let versions = scan_versions_page::<DataMapStoredEntry>(
+                                &*db,
+                                &db_key(DBKey::MapVersionScan(&map.name)),
+                                at_block_index,
+                                entries_cursor.as_deref(),
+                                page_size as usize,
+                            )
+                            .expect("Unable to read contract");
                             let mut entries = vec![];
-                            for (key, value) in iter {
-                                if key.starts_with(&value_key) {
-                                    let entry =
-                                        serde_json::from_slice::<DataMapStoredEntry>(&value)
-                                            .expect("Unable to deserialize contract");
-
+                            for version in versions.items.into_iter() {
+                                if let Some(entry) = version.value {
                                     entries.push((
                                         (
                                             entry.get_formatted_decoded_key(),
                                             entry.get_formatted_decoded_value(),
                                         ),
-                                        BlockIdentifier {
-                                            hash: "0".into(),
-                                            index: 0,
-                                        },
-                                        TransactionIdentifier { hash: "0".into() },
+                                        version.block_identifier,
+                                        version.transaction_identifier,
                                     ))
                                 }
                             }
 
-                            let events_key = db_key(
-                                DBKey::MapEventScan(&map.name),
-                                &request.contract_identifier,
-                            );
-                            let key = String::from_utf8(events_key.clone()).unwrap();
-                            warn!(self.ctx().log(), "Events key: {:?}", key);
-
-                            let iter = db.prefix_iterator(&events_key);
-                            let mut events = vec![];
-                            for (key, value) in iter {
-                                if key.starts_with(&events_key) {
-                                    let remainder =
-                                        String::from_utf8(key[events_key.len()..].to_vec())
-                                            .unwrap();
-                                    let order = remainder.split("/").collect::<Vec<&str>>();
-                                    let block_index = u64::from_str_radix(order[0], 10).unwrap();
-                                    let event_index = u64::from_str_radix(order[1], 10).unwrap();
-
-                                    let event =
-                                        serde_json::from_slice::<DataMapEventStoredValue>(&value)
-                                            .expect("Unable to deserialize contract");
-                                    events.push((event, block_index, event_index));
-                                }
-                            }
-                            events.sort_by(|(_, b1, b2), (_, a1, a2)| {
-                                (a1 * 100 + a2).cmp(&(b1 * 100 + b2))
-                            });
-
+                            let (events, events_next_cursor) =
+                                read_events_page::<DataMapEventStoredValue>(
+                                    &*db,
+                                    &db_key(DBKey::MapEventScan(&map.name)),
+                                    at_block_index,
+                                    events_cursor.as_deref(),
+                                    page_size,
+                                );
                             let events = events
                                 .into_iter()
                                 .map(|(e, block_index, event_index)| {
@@ -386,13 +476,13 @@ impl Actor for ProtocolObserver {
 
                             field = Some(FieldValues::Map(MapValues {
                                 entries,
-                                entries_page_size: 0,
-                                entries_page_index: 0,
+                                entries_page_size: page_size,
+                                entries_next_cursor: encode_cursor(versions.next_cursor),
                                 key_type: map.key.clone(),
                                 value_type: map.value.clone(),
                                 events,
-                                events_page_index: 0,
-                                events_page_size: 0,
+                                events_page_size: page_size,
+                                events_next_cursor,
                             }));
                         }
                     }
@@ -401,58 +491,36 @@ impl Actor for ProtocolObserver {
                     for nft in interface.non_fungible_tokens.iter() {
                         if nft.name == request.field_name {
                             let asset_id = format!("{}::{}", request.contract_identifier, nft.name);
-                            let values_key =
-                                db_key(DBKey::NFTScan(&asset_id), &request.contract_identifier);
-
-                            let iter = db.prefix_iterator(&values_key);
+                            let versions = scan_versions_page::<NFTStoredEntry>(
+                                &*db,
+                                &db_key(DBKey::NFTVersionScan(&asset_id)),
+                                at_block_index,
+                                entries_cursor.as_deref(),
+                                page_size as usize,
+                            )
+                            .expect("Unable to read contract");
                             let mut tokens = vec![];
-                            for (key, value) in iter {
-                                if key.starts_with(&values_key) {
-                                    let value = serde_json::from_slice::<NFTStoredEntry>(&value)
-                                        .expect("Unable to deserialize NFT");
-
+                            for version in versions.items.into_iter() {
+                                if let Some(value) = version.value {
                                     let decoded_asset_identifier =
                                         types::decode_value(&value.hex_asset_identifier);
 
                                     tokens.push((
                                         (decoded_asset_identifier, value.owner.to_string()),
-                                        BlockIdentifier {
-                                            hash: "0".into(),
-                                            index: 0,
-                                        },
-                                        TransactionIdentifier { hash: "0".into() },
+                                        version.block_identifier,
+                                        version.transaction_identifier,
                                     ))
                                 }
                             }
 
-                            let events_key = db_key(
-                                DBKey::NFTEventScan(&asset_id),
-                                &request.contract_identifier,
-                            );
-                            let key = String::from_utf8(events_key.clone()).unwrap();
-                            warn!(self.ctx().log(), "Events key: {:?}", key);
-
-                            let iter = db.prefix_iterator(&events_key);
-                            let mut events = vec![];
-                            for (key, value) in iter {
-                                if key.starts_with(&events_key) {
-                                    let remainder =
-                                        String::from_utf8(key[events_key.len()..].to_vec())
-                                            .unwrap();
-                                    let order = remainder.split("/").collect::<Vec<&str>>();
-                                    let block_index = u64::from_str_radix(order[0], 10).unwrap();
-                                    let event_index = u64::from_str_radix(order[1], 10).unwrap();
-
-                                    let event =
-                                        serde_json::from_slice::<NFTEventStoredValue>(&value)
-                                            .expect("Unable to deserialize contract");
-                                    events.push((event, block_index, event_index));
-                                }
-                            }
-                            events.sort_by(|(_, b1, b2), (_, a1, a2)| {
-                                (a1 * 100 + a2).cmp(&(b1 * 100 + b2))
-                            });
-
+                            let (events, events_next_cursor) =
+                                read_events_page::<NFTEventStoredValue>(
+                                    &*db,
+                                    &db_key(DBKey::NFTEventScan(&asset_id)),
+                                    at_block_index,
+                                    events_cursor.as_deref(),
+                                    page_size,
+                                );
                             let events = events
                                 .into_iter()
                                 .map(|(e, block_index, event_index)| {
@@ -462,12 +530,12 @@ impl Actor for ProtocolObserver {
 
                             field = Some(FieldValues::Nft(NftValues {
                                 tokens,
-                                tokens_page_size: 0,
-                                tokens_page_index: 0,
+                                tokens_page_size: page_size,
+                                tokens_next_cursor: encode_cursor(versions.next_cursor),
                                 token_type: nft.type_f.clone(),
                                 events,
-                                events_page_index: 0,
-                                events_page_size: 0,
+                                events_page_size: page_size,
+                                events_next_cursor,
                             }));
                         }
                     }
@@ -476,53 +544,45 @@ impl Actor for ProtocolObserver {
                     for ft in interface.fungible_tokens.iter() {
                         if ft.name == request.field_name {
                             let asset_id = format!("{}::{}", request.contract_identifier, ft.name);
-                            let values_key =
-                                db_key(DBKey::FTScan(&asset_id), &request.contract_identifier);
-
-                            let iter = db.prefix_iterator(&values_key);
+                            let versions = scan_versions_page::<FTStoredBalance>(
+                                &*db,
+                                &db_key(DBKey::FTVersionScan(&asset_id)),
+                                at_block_index,
+                                entries_cursor.as_deref(),
+                                page_size as usize,
+                            )
+                            .expect("Unable to read contract");
                             let mut balances = vec![];
-                            for (key, value) in iter {
-                                if key.starts_with(&values_key) {
-                                    let owner = String::from_utf8(key[values_key.len()..].to_vec())
-                                        .unwrap();
-                                    let balance = String::from_utf8(value.to_vec()).unwrap();
-
+                            for version in versions.items.into_iter() {
+                                if let Some(value) = version.value {
                                     balances.push((
-                                        (owner, balance),
-                                        BlockIdentifier {
-                                            hash: "0".into(),
-                                            index: 0,
-                                        },
-                                        TransactionIdentifier { hash: "0".into() },
+                                        (value.owner, value.balance),
+                                        version.block_identifier,
+                                        version.transaction_identifier,
                                     ))
                                 }
                             }
-                            let events_key =
-                                db_key(DBKey::FTEventScan(&asset_id), &request.contract_identifier);
-                            let key = String::from_utf8(events_key.clone()).unwrap();
-                            warn!(self.ctx().log(), "Events key: {:?}", key);
-
-                            let iter = db.prefix_iterator(&events_key);
-                            let mut events = vec![];
-                            for (key, value) in iter {
-                                if key.starts_with(&events_key) {
-                                    let remainder =
-                                        String::from_utf8(key[events_key.len()..].to_vec())
-                                            .unwrap();
-                                    let order = remainder.split("/").collect::<Vec<&str>>();
-                                    let block_index = u64::from_str_radix(order[0], 10).unwrap();
-                                    let event_index = u64::from_str_radix(order[1], 10).unwrap();
-
-                                    let event =
-                                        serde_json::from_slice::<FTEventStoredValue>(&value)
-                                            .expect("Unable to deserialize contract");
-                                    events.push((event, block_index, event_index));
-                                }
-                            }
-                            events.sort_by(|(_, b1, b2), (_, a1, a2)| {
-                                (a1 * 100 + a2).cmp(&(b1 * 100 + b2))
-                            });
 
+                            let total_supply = latest_version::<String>(
+                                &*db,
+                                &db_key(DBKey::FTSupplyVersionScan(&asset_id)),
+                                &db_key(DBKey::FTSupplyVersion(
+                                    &asset_id,
+                                    at_block_index,
+                                    u32::MAX,
+                                )),
+                            )
+                            .expect("Unable to read contract")
+                            .and_then(|version| version.value);
+
+                            let (events, events_next_cursor) =
+                                read_events_page::<FTEventStoredValue>(
+                                    &*db,
+                                    &db_key(DBKey::FTEventScan(&asset_id)),
+                                    at_block_index,
+                                    events_cursor.as_deref(),
+                                    page_size,
+                                );
                             let events = events
                                 .into_iter()
                                 .map(|(e, block_index, event_index)| {
@@ -532,57 +592,45 @@ impl Actor for ProtocolObserver {
 
                             field = Some(FieldValues::Ft(FtValues {
                                 balances,
-                                balances_page_size: 0,
-                                balances_page_index: 0,
+                                balances_page_size: page_size,
+                                balances_next_cursor: encode_cursor(versions.next_cursor),
+                                total_supply,
                                 events,
-                                events_page_index: 0,
-                                events_page_size: 0,
+                                events_page_size: page_size,
+                                events_next_cursor,
                             }));
                         }
                     }
                 }
 
                 // Get eventual latest blocks (bitcoin + stacks)
-                let stacks_db = blocks::stacks_blocks_db_read(&self.storage_driver);
-                let stacks_tip = u64::from_be_bytes(
-                    stacks_db
-                        .get("tip".as_bytes())
-                        .unwrap()
-                        .unwrap()
-                        .try_into()
-                        .unwrap(),
-                );
+                // Nothing to return while no block was archived
+                let stacks_db = blocks::stacks_blocks_db(&self.storage_driver);
+                let stacks_tip = match blocks::get_tip(&*stacks_db) {
+                    Ok(tip) => tip,
+                    Err(e) => {
+                        warn!(self.ctx().log(), "{}", e);
+                        None
+                    }
+                };
                 let mut stacks_blocks = vec![];
-                warn!(
-                    self.ctx().log(),
-                    "Will be looking for stacks blocks in range {:?}",
-                    request.stacks_block_identifier.index..stacks_tip
-                );
-                for missing_block in request.stacks_block_identifier.index..stacks_tip {
-                    let hash = stacks_db
-                        .get(&missing_block.to_be_bytes())
-                        .unwrap()
-                        .unwrap();
-                    let block_bytes = stacks_db
-                        .get(&format!("hash:{}", String::from_utf8(hash).unwrap()))
-                        .unwrap()
-                        .unwrap();
-                    let block = serde_json::from_slice::<StacksBlockData>(&block_bytes)
-                        .expect("Unable to deserialize contract");
-                    stacks_blocks.push(block);
+                if let Some(stacks_tip) = stacks_tip {
+                    debug!(
+                        self.ctx().log(),
+                        "Will be looking for stacks blocks in range {:?}",
+                        request.stacks_block_identifier.index..stacks_tip
+                    );
+                    for missing_block in request.stacks_block_identifier.index..stacks_tip {
+                        match blocks::get_stacks_block_at_index(&*stacks_db, missing_block) {
+                            Ok(Some(block)) => stacks_blocks.push(block),
+                            Ok(None) => {}
+                            Err(e) => warn!(self.ctx().log(), "{}", e),
+                        }
+                    }
                 }
-                warn!(self.ctx().log(), "Found {:?}", stacks_blocks);
-
-                let bitcoin_db = blocks::bitcoin_blocks_db_read(&self.storage_driver);
-                let bitcoin_tip = u64::from_be_bytes(
-                    bitcoin_db
-                        .get("tip".as_bytes())
-                        .unwrap()
-                        .unwrap()
-                        .try_into()
-                        .unwrap(),
-                );
-                let mut bitcoin_blocks = vec![];
+
+                // Bitcoin blocks are not served yet
+                let bitcoin_blocks = vec![];
                 // for missing_block in request..index..bitcoin_tip {
                 //     let hash = stacks_db.get(&missing_block.to_be_bytes()).unwrap().unwrap();
                 //     let block_bytes = stacks_db.get(&format!("hash:{}", String::from_utf8(hash).unwrap())).unwrap().unwrap();
@@ -591,14 +639,20 @@ impl Actor for ProtocolObserver {
                 //     bitcoin_blocks.push(block);
                 // }
 
-                let response = FieldValuesResponse {
-                    bitcoin_blocks,
-                    stacks_blocks,
-                    contract_identifier: request.contract_identifier.clone(),
-                    field_name: request.field_name.clone(),
-                    values: field.unwrap(),
+                let response = match field {
+                    Some(values) => Ok(FieldValuesResponse {
+                        bitcoin_blocks,
+                        stacks_blocks,
+                        contract_identifier: request.contract_identifier.clone(),
+                        field_name: request.field_name.clone(),
+                        values,
+                    }),
+                    None => Err(RequestError::UnknownField(
+                        request.contract_identifier.clone(),
+                        request.field_name.clone(),
+                    )),
                 };
-                request.tx.send(response).unwrap();
+                let _ = request.tx.send(response);
             }
             ProtocolObserverMessage::Exit => {}
         };
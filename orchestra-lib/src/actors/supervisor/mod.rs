@@ -1,25 +1,31 @@
 use crate::actors::{
     BlockStoreManager, BlockStoreManagerMessage, ContractProcessor, ContractProcessorMessage,
-    ProtocolObserver, ProtocolObserverMessage,
+    LambdaRuntime, LambdaRuntimeMessage, ProtocolObserver, ProtocolObserverMessage,
+    WalletProcessor, WalletProcessorMessage,
 };
-use crate::datastore::StorageDriver;
+use crate::datastore::{self, StorageDriver};
 use crate::types::{
-    BitcoinPredicate, FieldValues, FieldValuesRequest, ProtocolObserverConfig, ProtocolObserverId,
-    ProtocolRegistration, StacksChainPredicates, TriggerId,
+    BitcoinPredicate, FieldValues, FieldValuesRequest, FiredTrigger, Lambda, LambdaReport,
+    OrchestraPid, Predicate, ProtocolObserverConfig, ProtocolObserverId, ProtocolRegistration,
+    RebuildStateProgress, RebuildStateRequest, RequestError, StacksPredicate, TriggerId,
+    WalletStateRequest,
 };
 use clarinet_lib::clarity_repl::clarity::analysis::contract_interface_builder::ContractInterface;
 use clarinet_lib::clarity_repl::clarity::analysis::ContractAnalysis;
 use clarinet_lib::clarity_repl::clarity::diagnostic::Diagnostic;
+use clarinet_lib::clarity_repl::clarity::types::PrincipalData;
 use clarinet_lib::clarity_repl::repl::ast::ContractAST;
+use clarinet_lib::types::events::StacksTransactionEvent;
 use clarinet_lib::types::{
     BitcoinBlockData, BitcoinChainEvent, BlockIdentifier, StacksBlockData, StacksChainEvent,
-    StacksTransactionData, StacksTransactionReceipt,
+    StacksTransactionData,
 };
 use kompact::prelude::*;
-use rocksdb::DB;
 use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use opentelemetry::trace::Tracer;
 use opentelemetry::{global, trace::Span};
@@ -28,13 +34,52 @@ use super::contract_processor::{ContractProcessorEvent, ContractProcessorPort};
 
 use super::protocol_observer::{ProtocolObserverEvent, ProtocolObserverPort};
 
+/// Delay before restarting a faulted actor, doubled on each consecutive fault.
+const RESTART_BASE_DELAY: Duration = Duration::from_millis(500);
+const RESTART_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Faults further apart than this are not considered consecutive.
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+const MAX_RESTART_ATTEMPTS: u32 = 5;
+
+/// Actors restarted by the supervisor when their component faults.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SupervisedActor {
+    ContractProcessor(String),
+    ProtocolObserver(ProtocolObserverId),
+    WalletProcessor(String),
+    BlockStoreManager,
+    LambdaRuntime,
+}
+
+/// Arguments a contract processor is started with, kept to restart it.
+#[derive(Clone)]
+struct ContractProcessorArgs {
+    interface: ContractInterface,
+    analysis: ContractAnalysis,
+    ast: ContractAST,
+    block_identifier: BlockIdentifier,
+    stacks_node_rpc_url: Option<String>,
+}
+
+struct RestartState {
+    attempts: u32,
+    last_fault: Instant,
+}
+
 #[derive(Clone, Debug)]
 pub enum OrchestraSupervisorMessage {
     RegisterProtocolObserver(ProtocolObserverConfig),
-    GetProtocolInterfaces(ProtocolObserverId, Sender<ProtocolRegistration>),
+    GetProtocolInterfaces(
+        ProtocolObserverId,
+        Sender<Result<ProtocolRegistration, RequestError>>,
+    ),
     ProcessStacksChainEvent(StacksChainEvent),
     ProcessBitcoinChainEvent(BitcoinChainEvent),
     GetFieldValues(FieldValuesRequest),
+    RebuildContractState(RebuildStateRequest),
+    GetLambdaReports(Sender<Vec<LambdaReport>>),
+    GetWalletState(WalletStateRequest),
+    ActorFaulted(SupervisedActor),
     Exit,
 }
 
@@ -42,16 +87,22 @@ pub enum OrchestraSupervisorMessage {
 pub struct OrchestraSupervisor {
     ctx: ComponentContext<Self>,
     active_contracts_processors: HashMap<String, ActorRef<ContractProcessorMessage>>,
+    active_wallets_processors: HashMap<String, ActorRef<WalletProcessorMessage>>,
     active_protocol_observers: HashMap<ProtocolObserverId, ActorRef<ProtocolObserverMessage>>,
     contracts_processors_subscriptions: BTreeMap<String, BTreeSet<ProtocolObserverId>>,
     block_store_manager: Option<ActorRef<BlockStoreManagerMessage>>, // Todo: switch to event instead
+    lambda_runtime: Option<ActorRef<LambdaRuntimeMessage>>,
     registered_contracts: HashSet<String>,
+    stacks_nodes_rpc_urls: HashMap<ProtocolObserverId, String>,
     storage_driver: StorageDriver,
-    stacks_predicates: StacksChainPredicates,
+    stacks_predicates: HashMap<StacksPredicate, Vec<TriggerId>>,
     contract_processor_port: RequiredPort<ContractProcessorPort>,
     protocol_observer_port: RequiredPort<ProtocolObserverPort>,
     bitcoin_predicates: HashMap<BitcoinPredicate, Vec<TriggerId>>,
-    trigger_history: VecDeque<(String, HashSet<TriggerId>)>,
+    contracts_processors_args: HashMap<String, ContractProcessorArgs>,
+    protocol_observers_configs: HashMap<ProtocolObserverId, ProtocolObserverConfig>,
+    wallets_processors_rpc_urls: HashMap<String, Option<String>>,
+    restarts: HashMap<SupervisedActor, RestartState>,
 }
 
 // ignore_indications!(SetOffset, DynamicManager);
@@ -62,16 +113,10 @@ impl ComponentLifecycle for OrchestraSupervisor {
     fn on_start(&mut self) -> Handled {
         info!(self.log(), "OrchestraSupervisor starting");
 
-        // Ensure that we have access to storage by opening early connections
-        match self.storage_driver {
-            StorageDriver::Filesystem(ref config) => {
-                let mut bitcoin_path = config.working_dir.clone();
-                bitcoin_path.push("bitcoin");
-                let _db = DB::open_default(bitcoin_path).unwrap();
-                let mut stacks_path = config.working_dir.clone();
-                stacks_path.push("stacks");
-                let _db = DB::open_default(stacks_path).unwrap();
-            }
+        // Upgrade the working dir before any actor reads or writes it
+        if let Err(e) = datastore::run_migrations(&self.storage_driver) {
+            error!(self.log(), "Unable to migrate datastore: {}", e);
+            self.ctx.system().shutdown_async();
         }
 
         Handled::Ok
@@ -110,11 +155,12 @@ impl Actor for OrchestraSupervisor {
             }
             OrchestraSupervisorMessage::GetProtocolInterfaces(protocol_id, tx) => {
                 let mut span = tracer.start("register_local_contracts_observer");
-                let worker = match self.active_protocol_observers.get(&protocol_id) {
-                    Some(entry) => entry,
-                    None => unreachable!(),
-                };
-                let res = worker.tell(ProtocolObserverMessage::GetInterfaces(tx));
+                match self.active_protocol_observers.get(&protocol_id) {
+                    Some(worker) => worker.tell(ProtocolObserverMessage::GetInterfaces(tx)),
+                    None => {
+                        let _ = tx.send(Err(self.protocol_observer_error(&protocol_id)));
+                    }
+                }
                 span
             }
             OrchestraSupervisorMessage::GetFieldValues(request) => {
@@ -124,14 +170,54 @@ impl Actor for OrchestraSupervisor {
                     "Contracts observers registered: {:?}", self.active_protocol_observers
                 );
 
-                let worker = match self
-                    .active_protocol_observers
-                    .get(&ProtocolObserverId(request.protocol_id))
+                let protocol_id = ProtocolObserverId(request.protocol_id);
+                match self.active_protocol_observers.get(&protocol_id) {
+                    Some(worker) => {
+                        worker.tell(ProtocolObserverMessage::RequestFieldValues(request))
+                    }
+                    None => {
+                        let _ = request
+                            .tx
+                            .send(Err(self.protocol_observer_error(&protocol_id)));
+                    }
+                }
+                span
+            }
+            OrchestraSupervisorMessage::RebuildContractState(request) => {
+                let mut span = tracer.start("rebuild_contract_state");
+                match self
+                    .active_contracts_processors
+                    .get(&request.contract_identifier)
                 {
-                    Some(entry) => entry,
-                    None => unreachable!(),
-                };
-                let res = worker.tell(ProtocolObserverMessage::RequestFieldValues(request));
+                    Some(worker) => worker.tell(ContractProcessorMessage::RebuildState(request)),
+                    None => {
+                        let error = self.contract_processor_error(&request.contract_identifier);
+                        let _ = request.tx.send(RebuildStateProgress::Failed(format!(
+                            "unable to rebuild state: {}",
+                            error
+                        )));
+                    }
+                }
+                span
+            }
+            OrchestraSupervisorMessage::GetLambdaReports(tx) => {
+                let mut span = tracer.start("get_lambda_reports");
+                match self.lambda_runtime {
+                    Some(ref worker) => worker.tell(LambdaRuntimeMessage::GetReports(tx)),
+                    None => {
+                        let _ = tx.send(vec![]);
+                    }
+                }
+                span
+            }
+            OrchestraSupervisorMessage::GetWalletState(request) => {
+                let mut span = tracer.start("get_wallet_state");
+                self.handle_wallet_state_request(request);
+                span
+            }
+            OrchestraSupervisorMessage::ActorFaulted(actor) => {
+                let mut span = tracer.start("handle_actor_fault");
+                self.handle_actor_fault(actor);
                 span
             }
             OrchestraSupervisorMessage::Exit => {
@@ -157,7 +243,7 @@ impl Require<ContractProcessorPort> for OrchestraSupervisor {
                 let subscriptions = match self.contracts_processors_subscriptions.get(&contract_id)
                 {
                     Some(entry) => entry,
-                    None => unreachable!(),
+                    None => return Handled::Ok,
                 };
 
                 for protocol_id in subscriptions.iter() {
@@ -170,6 +256,14 @@ impl Require<ContractProcessorPort> for OrchestraSupervisor {
                     // worker.tell(ProtocolObserverMessage::ProcessChain);
                 }
             }
+            ContractProcessorEvent::BalanceInconsistency(contract_id, inconsistency) => {
+                warn!(
+                    self.ctx.log(),
+                    "Contract {} reported an inconsistent balance: {:?}",
+                    contract_id,
+                    inconsistency
+                );
+            }
         }
         Handled::Ok
     }
@@ -184,19 +278,19 @@ impl Require<ProtocolObserverPort> for OrchestraSupervisor {
                 {
                     if !self.registered_contracts.contains(&contract_id) {
                         self.registered_contracts.insert(contract_id.clone());
+                        let stacks_node_rpc_url = self
+                            .stacks_nodes_rpc_urls
+                            .get(&protocol_identifier)
+                            .cloned();
                         self.start_contract_processor(
                             contract_id.clone(),
                             interface,
                             analysis,
                             ast,
                             block_identifier,
+                            stacks_node_rpc_url,
                         );
                     }
-                    let worker = match self.active_contracts_processors.get(&contract_id) {
-                        Some(worker) => worker,
-                        None => unreachable!(),
-                    };
-
                     match self.contracts_processors_subscriptions.entry(contract_id) {
                         Entry::Occupied(observers) => {
                             observers.into_mut().insert(protocol_identifier.clone());
@@ -222,12 +316,18 @@ impl OrchestraSupervisor {
             contract_processor_port: RequiredPort::uninitialised(),
             protocol_observer_port: RequiredPort::uninitialised(),
             registered_contracts: HashSet::new(),
+            stacks_nodes_rpc_urls: HashMap::new(),
             bitcoin_predicates: HashMap::new(),
-            stacks_predicates: StacksChainPredicates::new(),
-            trigger_history: VecDeque::new(),
+            stacks_predicates: HashMap::new(),
+            contracts_processors_args: HashMap::new(),
+            protocol_observers_configs: HashMap::new(),
+            wallets_processors_rpc_urls: HashMap::new(),
+            restarts: HashMap::new(),
             storage_driver,
             block_store_manager: None,
+            lambda_runtime: None,
             active_contracts_processors: HashMap::new(),
+            active_wallets_processors: HashMap::new(),
             active_protocol_observers: HashMap::new(),
             contracts_processors_subscriptions: BTreeMap::new(),
         }
@@ -244,6 +344,22 @@ impl OrchestraSupervisor {
             return;
         }
 
+        if let Some(ref url) = observer_config.stacks_node_rpc_url {
+            self.stacks_nodes_rpc_urls
+                .insert(protocol_identifier.clone(), url.clone());
+        }
+        if self.lambda_runtime.is_none() {
+            self.start_lambda_runtime();
+        }
+        if let Some(ref worker) = self.lambda_runtime {
+            worker.tell(LambdaRuntimeMessage::RegisterLambdas(
+                protocol_identifier.clone(),
+                observer_config.lambdas.clone(),
+            ));
+        }
+        self.register_lambdas_predicates(protocol_identifier, &observer_config.lambdas);
+        self.protocol_observers_configs
+            .insert(protocol_identifier.clone(), observer_config.clone());
         self.start_protocol_observer(&observer_config);
     }
 
@@ -254,29 +370,121 @@ impl OrchestraSupervisor {
         analysis: ContractAnalysis,
         ast: ContractAST,
         block_identifier: BlockIdentifier,
+        stacks_node_rpc_url: Option<String>,
     ) {
+        self.contracts_processors_args.insert(
+            contract_id.clone(),
+            ContractProcessorArgs {
+                interface,
+                analysis,
+                ast,
+                block_identifier,
+                stacks_node_rpc_url,
+            },
+        );
+        self.spawn_contract_processor(contract_id);
+    }
+
+    fn spawn_contract_processor(&mut self, contract_id: String) {
+        let args = match self.contracts_processors_args.get(&contract_id) {
+            Some(args) => args.clone(),
+            None => return,
+        };
         let system = self.ctx.system();
         let worker = system.create(|| {
             ContractProcessor::new(
                 self.storage_driver.clone(),
                 contract_id.clone(),
-                interface,
-                analysis,
-                ast,
-                block_identifier,
+                args.interface,
+                args.analysis,
+                args.ast,
+                args.block_identifier,
+                args.stacks_node_rpc_url,
             )
         });
         worker.connect_to_required(self.contract_processor_port.share());
+        self.supervise(
+            &worker,
+            SupervisedActor::ContractProcessor(contract_id.clone()),
+        );
         system.start(&worker);
         self.active_contracts_processors
             .insert(contract_id, worker.actor_ref());
     }
 
+    /// Starts indexing the wallet of `principal`, from the archived blocks.
+    pub fn start_wallet_processor(
+        &mut self,
+        principal: String,
+        stacks_node_rpc_url: Option<String>,
+    ) {
+        self.wallets_processors_rpc_urls
+            .insert(principal.clone(), stacks_node_rpc_url);
+        self.spawn_wallet_processor(principal);
+    }
+
+    fn spawn_wallet_processor(&mut self, principal: String) {
+        let stacks_node_rpc_url = match self.wallets_processors_rpc_urls.get(&principal) {
+            Some(stacks_node_rpc_url) => stacks_node_rpc_url.clone(),
+            None => return,
+        };
+        let system = self.ctx.system();
+        let worker = system.create(|| {
+            WalletProcessor::new(
+                self.storage_driver.clone(),
+                principal.clone(),
+                stacks_node_rpc_url,
+            )
+        });
+        self.supervise(&worker, SupervisedActor::WalletProcessor(principal.clone()));
+        system.start(&worker);
+        self.active_wallets_processors
+            .insert(principal, worker.actor_ref());
+    }
+
+    /// Serves the state of a wallet, indexing it from the first request.
+    fn handle_wallet_state_request(&mut self, request: WalletStateRequest) {
+        let protocol_id = ProtocolObserverId(request.protocol_id);
+        if !self.protocol_observers_configs.contains_key(&protocol_id) {
+            let _ = request
+                .tx
+                .send(Err(RequestError::UnknownProtocol(protocol_id.0)));
+            return;
+        }
+        if !self
+            .wallets_processors_rpc_urls
+            .contains_key(&request.principal)
+        {
+            if PrincipalData::parse(&request.principal).is_err() {
+                let _ = request
+                    .tx
+                    .send(Err(RequestError::UnknownWallet(request.principal)));
+                return;
+            }
+            let stacks_node_rpc_url = self.stacks_nodes_rpc_urls.get(&protocol_id).cloned();
+            self.start_wallet_processor(request.principal.clone(), stacks_node_rpc_url);
+        }
+        match self.active_wallets_processors.get(&request.principal) {
+            Some(worker) => worker.tell(WalletProcessorMessage::GetWalletState(request)),
+            None => {
+                let error = RequestError::ActorUnavailable(format!(
+                    "wallet processor {}",
+                    request.principal
+                ));
+                let _ = request.tx.send(Err(error));
+            }
+        }
+    }
+
     pub fn start_protocol_observer(&mut self, observer_config: &ProtocolObserverConfig) {
         let system = self.ctx.system();
         let worker = system
             .create(|| ProtocolObserver::new(self.storage_driver.clone(), observer_config.clone()));
         worker.connect_to_required(self.protocol_observer_port.share());
+        self.supervise(
+            &worker,
+            SupervisedActor::ProtocolObserver(observer_config.identifier.clone()),
+        );
         system.start(&worker);
         self.active_protocol_observers
             .insert(observer_config.identifier.clone(), worker.actor_ref());
@@ -285,10 +493,143 @@ impl OrchestraSupervisor {
     pub fn start_block_store_manager(&mut self) {
         let system = self.ctx.system();
         let worker = system.create(|| BlockStoreManager::new(self.storage_driver.clone()));
+        self.supervise(&worker, SupervisedActor::BlockStoreManager);
         system.start(&worker);
         self.block_store_manager = Some(worker.actor_ref());
     }
 
+    /// Gets the supervisor notified when `worker` faults, instead of the
+    /// fault being swallowed by the system.
+    fn supervise<C: ComponentDefinition>(
+        &self,
+        worker: &Arc<Component<C>>,
+        actor: SupervisedActor,
+    ) {
+        let supervisor = self.actor_ref();
+        worker.on_definition(|definition| {
+            definition.ctx_mut().set_recovery_function(move |fault| {
+                fault.recover_with(move |_, _, _| {
+                    supervisor.tell(OrchestraSupervisorMessage::ActorFaulted(actor));
+                })
+            });
+        });
+    }
+
+    /// Schedules the restart of a faulted actor, backing off on consecutive
+    /// faults and giving up after MAX_RESTART_ATTEMPTS.
+    fn handle_actor_fault(&mut self, actor: SupervisedActor) {
+        match actor {
+            SupervisedActor::ContractProcessor(ref contract_id) => {
+                self.active_contracts_processors.remove(contract_id);
+            }
+            SupervisedActor::ProtocolObserver(ref protocol_id) => {
+                self.active_protocol_observers.remove(protocol_id);
+            }
+            SupervisedActor::WalletProcessor(ref principal) => {
+                self.active_wallets_processors.remove(principal);
+            }
+            SupervisedActor::BlockStoreManager => {
+                self.block_store_manager = None;
+            }
+            SupervisedActor::LambdaRuntime => {
+                self.lambda_runtime = None;
+            }
+        }
+
+        let now = Instant::now();
+        let restart = self.restarts.entry(actor.clone()).or_insert(RestartState {
+            attempts: 0,
+            last_fault: now,
+        });
+        if now.duration_since(restart.last_fault) > RESTART_WINDOW {
+            restart.attempts = 0;
+        }
+        restart.last_fault = now;
+        restart.attempts += 1;
+        let attempts = restart.attempts;
+        if attempts > MAX_RESTART_ATTEMPTS {
+            error!(
+                self.log(),
+                "{:?} faulted {} times in a row, not restarting it", actor, attempts
+            );
+            return;
+        }
+
+        let delay = restart_delay(attempts);
+        warn!(self.log(), "{:?} faulted, restarting in {:?}", actor, delay);
+        self.schedule_once(delay, move |supervisor, _| {
+            supervisor.restart_actor(actor);
+            Handled::Ok
+        });
+    }
+
+    /// Starts a faulted actor again, its state being rebuilt on start.
+    fn restart_actor(&mut self, actor: SupervisedActor) {
+        info!(self.log(), "Restarting {:?}", actor);
+        match actor {
+            SupervisedActor::ContractProcessor(contract_id) => {
+                if !self.active_contracts_processors.contains_key(&contract_id) {
+                    self.spawn_contract_processor(contract_id);
+                }
+            }
+            SupervisedActor::ProtocolObserver(protocol_id) => {
+                if self.active_protocol_observers.contains_key(&protocol_id) {
+                    return;
+                }
+                if let Some(config) = self.protocol_observers_configs.get(&protocol_id).cloned() {
+                    self.start_protocol_observer(&config);
+                }
+            }
+            SupervisedActor::WalletProcessor(principal) => {
+                if !self.active_wallets_processors.contains_key(&principal) {
+                    self.spawn_wallet_processor(principal);
+                }
+            }
+            // Also started on the next chain event
+            SupervisedActor::BlockStoreManager => {
+                if self.block_store_manager.is_none() {
+                    self.start_block_store_manager();
+                }
+            }
+            SupervisedActor::LambdaRuntime => {
+                if self.lambda_runtime.is_some() {
+                    return;
+                }
+                self.start_lambda_runtime();
+                if let Some(ref worker) = self.lambda_runtime {
+                    for (protocol_id, config) in self.protocol_observers_configs.iter() {
+                        worker.tell(LambdaRuntimeMessage::RegisterLambdas(
+                            protocol_id.clone(),
+                            config.lambdas.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    fn protocol_observer_error(&self, protocol_id: &ProtocolObserverId) -> RequestError {
+        match self.protocol_observers_configs.contains_key(protocol_id) {
+            true => RequestError::ActorUnavailable(format!("protocol observer {}", protocol_id.0)),
+            false => RequestError::UnknownProtocol(protocol_id.0),
+        }
+    }
+
+    fn contract_processor_error(&self, contract_id: &str) -> RequestError {
+        match self.contracts_processors_args.contains_key(contract_id) {
+            true => RequestError::ActorUnavailable(format!("contract processor {}", contract_id)),
+            false => RequestError::UnknownContract(contract_id.to_string()),
+        }
+    }
+
+    pub fn start_lambda_runtime(&mut self) {
+        let system = self.ctx.system();
+        let worker = system.create(LambdaRuntime::new);
+        self.supervise(&worker, SupervisedActor::LambdaRuntime);
+        system.start(&worker);
+        self.lambda_runtime = Some(worker.actor_ref());
+    }
+
     pub fn handle_stacks_chain_event(
         &mut self,
         chain_event: StacksChainEvent,
@@ -299,118 +640,347 @@ impl OrchestraSupervisor {
         }
 
         let worker = match self.block_store_manager {
-            Some(ref worker_ref) => worker_ref,
-            None => unreachable!(),
+            Some(ref worker_ref) => worker_ref.clone(),
+            None => {
+                error!(
+                    self.log(),
+                    "Block store manager unavailable, dropping stacks chain event"
+                );
+                return;
+            }
         };
 
+        // Anchored blocks, handed to the lambdas along with their triggers
+        let mut anchored_blocks = vec![];
+        let mut orphaned_blocks = vec![];
         let blocks = match chain_event {
             StacksChainEvent::ChainUpdatedWithBlock(update) => {
                 worker.tell(BlockStoreManagerMessage::ArchiveStacksBlock(
                     update.new_block.clone(),
                     update.anchored_trail.clone(),
                 ));
+                anchored_blocks.push(update.new_block.clone());
                 vec![(
                     update.new_block.block_identifier,
+                    None,
                     update.new_block.transactions,
                 )]
             }
             StacksChainEvent::ChainUpdatedWithReorg(update) => {
-                let blocks_ids_to_rollback = update
-                    .old_blocks
-                    .into_iter()
-                    .map(|(old_trail, old_block)| old_block.block_identifier)
-                    .collect::<Vec<_>>();
+                // Contracts states are rolled back from the tip, before the new
+                // blocks get processed: each block, then the microblocks it
+                // confirmed.
+                let mut blocks_ids_to_rollback = vec![];
+                let mut microblocks_to_rollback = vec![];
+                for (anchored_trail, old_block) in update.old_blocks.into_iter().rev() {
+                    self.rollback_transactions(
+                        &old_block.block_identifier,
+                        None,
+                        &old_block.transactions,
+                    );
+                    blocks_ids_to_rollback.push(old_block.block_identifier.clone());
+                    orphaned_blocks.push((old_block.block_identifier.clone(), Some(old_block)));
+                    let old_microblocks = anchored_trail
+                        .map(|trail| trail.microblocks)
+                        .unwrap_or_default();
+                    for microblock in old_microblocks.into_iter().rev() {
+                        self.rollback_transactions(
+                            &microblock.block_identifier,
+                            Some(&microblock.parent_block_identifier),
+                            &microblock.transactions,
+                        );
+                        orphaned_blocks.push((microblock.block_identifier.clone(), None));
+                        microblocks_to_rollback.push(microblock);
+                    }
+                }
 
                 worker.tell(BlockStoreManagerMessage::RollbackStacksBlocks(
                     blocks_ids_to_rollback,
                 ));
+                if !microblocks_to_rollback.is_empty() {
+                    worker.tell(BlockStoreManagerMessage::RollbackStacksMicroblocks(
+                        microblocks_to_rollback,
+                    ));
+                }
+                // The new blocks are processed after the microblocks they confirm
                 let mut batches = vec![];
                 for (anchored_trail, new_block) in update.new_blocks.into_iter() {
                     worker.tell(BlockStoreManagerMessage::ArchiveStacksBlock(
                         new_block.clone(),
                         anchored_trail.clone(),
                     ));
-                    batches.push((new_block.block_identifier, new_block.transactions));
+                    anchored_blocks.push(new_block.clone());
+                    let new_microblocks = anchored_trail
+                        .map(|trail| trail.microblocks)
+                        .unwrap_or_default();
+                    for microblock in new_microblocks.into_iter() {
+                        batches.push((
+                            microblock.block_identifier,
+                            Some(microblock.parent_block_identifier),
+                            microblock.transactions,
+                        ));
+                    }
+                    batches.push((new_block.block_identifier, None, new_block.transactions));
                 }
                 batches
             }
             StacksChainEvent::ChainUpdatedWithMicroblock(update) => {
-                let micro_tip = update.current_trail.microblocks.last().unwrap();
+                let micro_tip = match update.current_trail.microblocks.last() {
+                    Some(micro_tip) => micro_tip,
+                    None => {
+                        warn!(
+                            self.log(),
+                            "Empty microblocks trail received for block {}, ignoring",
+                            update.anchored_block.block_identifier.index
+                        );
+                        return;
+                    }
+                };
                 worker.tell(BlockStoreManagerMessage::ArchiveStacksMicroblock(
                     micro_tip.clone(),
                 ));
                 vec![(
                     micro_tip.block_identifier.clone(),
+                    Some(micro_tip.parent_block_identifier.clone()),
                     micro_tip.transactions.clone(),
                 )]
             }
-            StacksChainEvent::ChainUpdatedWithMicroblockReorg(_) => {
-                unreachable!()
+            StacksChainEvent::ChainUpdatedWithMicroblockReorg(update) => {
+                // The microblocks of the orphaned trail are rolled back from
+                // the tip, before the new block and its trail get processed.
+                let old_microblocks = update
+                    .old_trail
+                    .map(|trail| trail.microblocks)
+                    .unwrap_or_default();
+                for microblock in old_microblocks.iter().rev() {
+                    self.rollback_transactions(
+                        &microblock.block_identifier,
+                        Some(&microblock.parent_block_identifier),
+                        &microblock.transactions,
+                    );
+                }
+                if !old_microblocks.is_empty() {
+                    worker.tell(BlockStoreManagerMessage::RollbackStacksMicroblocks(
+                        old_microblocks.clone(),
+                    ));
+                }
+                orphaned_blocks = old_microblocks
+                    .into_iter()
+                    .rev()
+                    .map(|microblock| (microblock.block_identifier, None))
+                    .collect::<Vec<_>>();
+
+                worker.tell(BlockStoreManagerMessage::ArchiveStacksBlock(
+                    update.new_block.clone(),
+                    update.new_anchored_trail.clone(),
+                ));
+                anchored_blocks.push(update.new_block.clone());
+                let mut batches = update
+                    .new_anchored_trail
+                    .map(|trail| trail.microblocks)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|microblock| {
+                        (
+                            microblock.block_identifier,
+                            Some(microblock.parent_block_identifier),
+                            microblock.transactions,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                batches.push((
+                    update.new_block.block_identifier,
+                    None,
+                    update.new_block.transactions,
+                ));
+                batches
             }
         };
 
-        for (block_identifier, transactions) in blocks.iter() {
-            // Send message BlockStoreManagerMessage::ArchiveStacksBlock(block)
-
-            let mut transactions_batches: BTreeMap<&str, Vec<StacksTransactionData>> =
-                BTreeMap::new();
-            for tx in transactions.iter() {
-                let intersect = tx
-                    .metadata
-                    .receipt
-                    .mutated_contracts_radius
-                    .intersection(&self.registered_contracts);
-                for mutated_contract_id in intersect {
-                    match transactions_batches.entry(mutated_contract_id) {
-                        Entry::Occupied(transactions) => {
-                            transactions.into_mut().push(tx.clone());
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert(vec![tx.clone()]);
-                        }
-                    };
-                }
+        // Lambdas are notified of the orphaned blocks before the new ones,
+        // microblocks never being handed to the lambdas.
+        for (block_identifier, block) in orphaned_blocks.into_iter() {
+            let triggers = self.take_triggers(datastore::STACKS_TRIGGERS, &block_identifier);
+            if triggers.is_empty() {
+                continue;
             }
-
+            self.dispatch_triggers(&triggers, |triggers| {
+                ProtocolObserverMessage::RevertTriggers(block_identifier.clone(), triggers)
+            });
+            if let (Some(worker), Some(block)) = (&self.lambda_runtime, block) {
+                worker.tell(LambdaRuntimeMessage::RevertStacksBlock(block, triggers));
+            }
+        }
+        for (block_identifier, anchor_block_identifier, transactions) in blocks.iter() {
+            let transactions_batches = self.split_transactions_batches(transactions);
             for (contract_id, batch) in transactions_batches.into_iter() {
+                // Processors being restarted rebuild their state from the archive
                 let worker = match self.active_contracts_processors.get(contract_id) {
                     Some(worker) => worker,
-                    None => unreachable!(),
+                    None => continue,
                 };
                 info!(self.log(), "Spawning batch");
                 worker.tell(ContractProcessorMessage::ProcessTransactionsBatch(
                     block_identifier.clone(),
+                    anchor_block_identifier.clone(),
                     batch,
                 ));
             }
-            // todo: keep track of trigger_history.
+            let wallets_batches = self.split_wallets_batches(transactions);
+            for (principal, batch) in wallets_batches.into_iter() {
+                let worker = match self.active_wallets_processors.get(principal) {
+                    Some(worker) => worker,
+                    None => continue,
+                };
+                worker.tell(WalletProcessorMessage::ProcessTransactionsBatch(
+                    block_identifier.clone(),
+                    anchor_block_identifier.clone(),
+                    batch,
+                ));
+            }
+            let triggers = self.handle_new_stacks_block(transactions);
+            self.dispatch_triggers(&triggers, |triggers| {
+                ProtocolObserverMessage::ProcessTriggers(block_identifier.clone(), triggers)
+            });
+            if let Some(ref worker) = self.lambda_runtime {
+                let anchored_block = anchored_blocks
+                    .iter()
+                    .find(|block| block.block_identifier.hash == block_identifier.hash);
+                if let (Some(block), false) = (anchored_block, triggers.is_empty()) {
+                    worker.tell(LambdaRuntimeMessage::ProcessStacksBlock(
+                        block.clone(),
+                        triggers.clone(),
+                    ));
+                }
+            }
+            // Microblocks are identified by their sequence, not by a height:
+            // only the anchored blocks are tracked for reorgs.
+            if anchor_block_identifier.is_none() {
+                self.record_triggers(datastore::STACKS_TRIGGERS, block_identifier, triggers);
+            }
+        }
+    }
+
+    /// Rolls back the transactions of an orphaned block from the processors
+    /// they were applied to. Microblocks come with their anchored block.
+    fn rollback_transactions(
+        &self,
+        block_identifier: &BlockIdentifier,
+        anchor_block_identifier: Option<&BlockIdentifier>,
+        transactions: &[StacksTransactionData],
+    ) {
+        let transactions_batches = self.split_transactions_batches(transactions);
+        for (contract_id, batch) in transactions_batches.into_iter() {
+            // Processors being restarted rebuild their state from the archive
+            let worker = match self.active_contracts_processors.get(contract_id) {
+                Some(worker) => worker,
+                None => continue,
+            };
+            worker.tell(ContractProcessorMessage::RollbackTransactionsBatch(
+                block_identifier.clone(),
+                anchor_block_identifier.cloned(),
+                batch,
+            ));
+        }
+        let wallets_batches = self.split_wallets_batches(transactions);
+        for (principal, batch) in wallets_batches.into_iter() {
+            let worker = match self.active_wallets_processors.get(principal) {
+                Some(worker) => worker,
+                None => continue,
+            };
+            worker.tell(WalletProcessorMessage::RollbackTransactionsBatch(
+                block_identifier.clone(),
+                anchor_block_identifier.cloned(),
+                batch,
+            ));
         }
     }
 
+    /// Groups the transactions of a block by the registered contracts they mutate.
+    fn split_transactions_batches(
+        &self,
+        transactions: &[StacksTransactionData],
+    ) -> BTreeMap<&str, Vec<StacksTransactionData>> {
+        let mut transactions_batches: BTreeMap<&str, Vec<StacksTransactionData>> = BTreeMap::new();
+        for tx in transactions.iter() {
+            let intersect = tx
+                .metadata
+                .receipt
+                .mutated_contracts_radius
+                .intersection(&self.registered_contracts);
+            for mutated_contract_id in intersect {
+                match transactions_batches.entry(mutated_contract_id) {
+                    Entry::Occupied(transactions) => {
+                        transactions.into_mut().push(tx.clone());
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(vec![tx.clone()]);
+                    }
+                };
+            }
+        }
+        transactions_batches
+    }
+
+    /// Groups the transactions by watched principal involved.
+    fn split_wallets_batches(
+        &self,
+        transactions: &[StacksTransactionData],
+    ) -> BTreeMap<&str, Vec<StacksTransactionData>> {
+        let mut wallets_batches: BTreeMap<&str, Vec<StacksTransactionData>> = BTreeMap::new();
+        if self.active_wallets_processors.is_empty() {
+            return wallets_batches;
+        }
+        for tx in transactions.iter() {
+            let mut principals = get_involved_principals(tx);
+            if let Some(ref sponsor) = tx.metadata.sponsor {
+                principals.push(sponsor);
+            }
+            principals.sort();
+            principals.dedup();
+            for principal in principals.into_iter() {
+                if let Some((principal, _)) =
+                    self.active_wallets_processors.get_key_value(principal)
+                {
+                    wallets_batches
+                        .entry(principal.as_str())
+                        .or_default()
+                        .push(tx.clone());
+                }
+            }
+        }
+        wallets_batches
+    }
+
     pub fn handle_bitcoin_chain_event(&mut self, chain_event: BitcoinChainEvent) {
         if self.block_store_manager.is_none() {
             self.start_block_store_manager();
         }
 
         let worker = match self.block_store_manager {
-            Some(ref worker_ref) => worker_ref,
-            None => unreachable!(),
+            Some(ref worker_ref) => worker_ref.clone(),
+            None => {
+                error!(
+                    self.log(),
+                    "Block store manager unavailable, dropping bitcoin chain event"
+                );
+                return;
+            }
         };
 
-        let blocks = match chain_event {
-            BitcoinChainEvent::ChainUpdatedWithBlock(block) => vec![block],
+        let (blocks, orphaned_blocks) = match chain_event {
+            BitcoinChainEvent::ChainUpdatedWithBlock(block) => (vec![block], vec![]),
             BitcoinChainEvent::ChainUpdatedWithReorg(old_segment, new_segment) => {
                 let blocks_ids_to_rollback = old_segment
-                    .into_iter()
-                    .map(|b| b.block_identifier)
+                    .iter()
+                    .map(|b| b.block_identifier.clone())
                     .collect::<Vec<_>>();
 
                 worker.tell(BlockStoreManagerMessage::RollbackBitcoinBlocks(
                     blocks_ids_to_rollback,
                 ));
 
-                // todo: use trigger_history to Rollback previous changes.
-                new_segment
+                (new_segment, old_segment.into_iter().rev().collect())
             }
         };
 
@@ -418,116 +988,393 @@ impl OrchestraSupervisor {
             // Send message BlockStoreManagerMessage::ArchiveStacksBlock(block)
             worker.tell(BlockStoreManagerMessage::ArchiveBitcoinBlock(block.clone()));
         }
-    }
 
-    fn handle_new_bitcoin_block(&self, block: BitcoinBlockData) -> HashSet<&TriggerId> {
-        let instances_to_trigger: HashSet<&TriggerId> = HashSet::new();
-        instances_to_trigger
+        // Lambdas are notified of the orphaned blocks before the new ones
+        for block in orphaned_blocks.into_iter() {
+            let triggers = self.take_triggers(datastore::BITCOIN_TRIGGERS, &block.block_identifier);
+            if triggers.is_empty() {
+                continue;
+            }
+            self.dispatch_triggers(&triggers, |triggers| {
+                ProtocolObserverMessage::RevertTriggers(block.block_identifier.clone(), triggers)
+            });
+            if let Some(ref worker) = self.lambda_runtime {
+                worker.tell(LambdaRuntimeMessage::RevertBitcoinBlock(block, triggers));
+            }
+        }
+        for block in blocks.iter() {
+            let triggers = self.handle_new_bitcoin_block(block);
+            self.dispatch_triggers(&triggers, |triggers| {
+                ProtocolObserverMessage::ProcessTriggers(block.block_identifier.clone(), triggers)
+            });
+            if let (Some(worker), false) = (&self.lambda_runtime, triggers.is_empty()) {
+                worker.tell(LambdaRuntimeMessage::ProcessBitcoinBlock(
+                    block.clone(),
+                    triggers.clone(),
+                ));
+            }
+            self.record_triggers(
+                datastore::BITCOIN_TRIGGERS,
+                &block.block_identifier,
+                triggers,
+            );
+        }
     }
 
-    pub fn register_predicates(&mut self, mut predicates: StacksChainPredicates) {
-        for (k, v) in predicates.watching_contract_id_activity.drain() {
-            self.stacks_predicates
-                .watching_contract_id_activity
-                .insert(k, v);
+    fn handle_new_bitcoin_block(&self, block: &BitcoinBlockData) -> Vec<FiredTrigger> {
+        let mut fired_triggers = vec![];
+        for (predicate, triggers) in self.bitcoin_predicates.iter() {
+            let matching_transactions = block
+                .transactions
+                .iter()
+                .enumerate()
+                .filter(|(_, tx)| predicate.evaluate_transaction(tx))
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>();
+            let activated = match predicate {
+                BitcoinPredicate::AnyBlock => true,
+                _ => !matching_transactions.is_empty(),
+            };
+            if activated {
+                fire_triggers(&mut fired_triggers, triggers, &matching_transactions);
+            }
         }
+        sort_fired_triggers(&mut fired_triggers);
+        fired_triggers
+    }
 
-        for (k, v) in predicates.watching_contract_data_mutation_activity.drain() {
-            self.stacks_predicates
-                .watching_contract_data_mutation_activity
-                .insert(k, v);
+    /// Sends the message built by `message` to the protocol observers of
+    /// `triggers`, with their own triggers.
+    fn dispatch_triggers<F>(&self, triggers: &[FiredTrigger], message: F)
+    where
+        F: Fn(Vec<TriggerId>) -> ProtocolObserverMessage,
+    {
+        let mut triggers_by_protocol: BTreeMap<ProtocolObserverId, Vec<TriggerId>> =
+            BTreeMap::new();
+        for trigger in triggers.iter() {
+            triggers_by_protocol
+                .entry(ProtocolObserverId(trigger.trigger_id.pid.0))
+                .or_default()
+                .push(trigger.trigger_id.clone());
         }
-
-        for (k, v) in predicates.watching_principal_activity.drain() {
-            self.stacks_predicates
-                .watching_principal_activity
-                .insert(k, v);
+        for (protocol_id, mut triggers) in triggers_by_protocol.into_iter() {
+            let worker = match self.active_protocol_observers.get(&protocol_id) {
+                Some(worker) => worker,
+                None => {
+                    warn!(
+                        self.log(),
+                        "Protocol observer {:?} unavailable, dropping triggers {:?}",
+                        protocol_id,
+                        triggers
+                    );
+                    continue;
+                }
+            };
+            triggers.sort_by_key(|trigger| trigger.lambda_id);
+            worker.tell(message(triggers));
         }
+    }
 
-        for (k, v) in predicates.watching_ft_move_activity.drain() {
-            self.stacks_predicates
-                .watching_ft_move_activity
-                .insert(k, v);
+    /// Records the triggers fired by a block of the chain `namespace`, to
+    /// notify them if the block gets orphaned, and forgets the ones fired by
+    /// the blocks that no longer can be.
+    fn record_triggers(
+        &mut self,
+        namespace: &str,
+        block_identifier: &BlockIdentifier,
+        triggers: Vec<FiredTrigger>,
+    ) {
+        let result =
+            datastore::triggers::triggers_db(&self.storage_driver, namespace).and_then(|db| {
+                if !triggers.is_empty() {
+                    datastore::triggers::record_fired_triggers(&*db, block_identifier, &triggers)?;
+                }
+                datastore::triggers::prune(&*db, block_identifier.index)
+            });
+        if let Err(e) = result {
+            error!(
+                self.log(),
+                "Unable to record triggers fired by block {}: {}", block_identifier.index, e
+            );
         }
+    }
 
-        for (k, v) in predicates.watching_nft_activity.drain() {
-            self.stacks_predicates.watching_nft_activity.insert(k, v);
-        }
+    /// Returns the triggers fired by a block of the chain `namespace`, and
+    /// forgets them.
+    fn take_triggers(
+        &mut self,
+        namespace: &str,
+        block_identifier: &BlockIdentifier,
+    ) -> Vec<FiredTrigger> {
+        let result = datastore::triggers::triggers_db(&self.storage_driver, namespace)
+            .and_then(|db| datastore::triggers::take_fired_triggers(&*db, block_identifier));
+        let mut triggers = match result {
+            Ok(triggers) => triggers,
+            Err(e) => {
+                error!(
+                    self.log(),
+                    "Unable to read triggers fired by block {}: {}", block_identifier.index, e
+                );
+                vec![]
+            }
+        };
+        sort_fired_triggers(&mut triggers);
+        triggers
+    }
 
-        for v in predicates.watching_any_block_activity.drain() {
-            self.stacks_predicates.watching_any_block_activity.insert(v);
+    /// Registers the predicates of the lambdas of a protocol, so that they get
+    /// evaluated on every block.
+    fn register_lambdas_predicates(
+        &mut self,
+        protocol_identifier: &ProtocolObserverId,
+        lambdas: &[Lambda],
+    ) {
+        for lambda in lambdas.iter() {
+            let trigger_id = TriggerId {
+                pid: OrchestraPid(protocol_identifier.0),
+                lambda_id: lambda.lambda_id,
+            };
+            match lambda.predicate {
+                Predicate::BitcoinPredicate(ref predicate) => {
+                    self.bitcoin_predicates
+                        .entry(predicate.clone())
+                        .or_default()
+                        .push(trigger_id);
+                }
+                Predicate::StacksPredicate(ref predicate) => {
+                    self.stacks_predicates
+                        .entry(predicate.clone())
+                        .or_default()
+                        .push(trigger_id);
+                }
+            }
         }
     }
 
+    /// Returns the triggers of the predicates matching a block, evaluated by
+    /// the predicates themselves, along with the transactions they matched:
+    /// the lambdas get their payload out of them, without evaluating again.
     pub fn handle_new_stacks_block(
         &self,
-        block: StacksBlockData,
-        span: &mut dyn Span,
-    ) -> HashSet<&TriggerId> {
-        let mut instances_to_trigger: HashSet<&TriggerId> = HashSet::new();
-
-        // Start by adding the predicates looking for any new block
-        instances_to_trigger.extend(&self.stacks_predicates.watching_any_block_activity);
-
-        for tx in block.transactions.iter() {
-            if tx.metadata.success {
-                let contract_id_based_predicates = self
-                    .evaluate_predicates_watching_contract_mutations_activity(&tx.metadata.receipt);
-                instances_to_trigger.extend(&contract_id_based_predicates);
+        transactions: &[StacksTransactionData],
+    ) -> Vec<FiredTrigger> {
+        let mut fired_triggers = vec![];
+        for (predicate, triggers) in self.stacks_predicates.iter() {
+            let matching_transactions = transactions
+                .iter()
+                .enumerate()
+                .filter(|(_, tx)| predicate.evaluate_transaction(tx))
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>();
+            let activated = match predicate {
+                StacksPredicate::AnyBlock => true,
+                _ => !matching_transactions.is_empty(),
+            };
+            if activated {
+                fire_triggers(&mut fired_triggers, triggers, &matching_transactions);
             }
         }
+        sort_fired_triggers(&mut fired_triggers);
+        fired_triggers
+    }
+}
 
-        instances_to_trigger
+fn fire_triggers(
+    fired_triggers: &mut Vec<FiredTrigger>,
+    triggers: &[TriggerId],
+    matching_transactions: &[usize],
+) {
+    for trigger_id in triggers.iter() {
+        fired_triggers.push(FiredTrigger {
+            trigger_id: trigger_id.clone(),
+            transactions: matching_transactions.to_vec(),
+        });
     }
+}
 
-    fn evaluate_predicates_watching_contract_mutations_activity(
-        &self,
-        transaction_receipt: &StacksTransactionReceipt,
-    ) -> HashSet<&TriggerId> {
-        let mut activated_triggers = HashSet::new();
+/// Orders triggers by protocol and lambda, a lambda being fired once.
+fn sort_fired_triggers(fired_triggers: &mut Vec<FiredTrigger>) {
+    fired_triggers.sort_by_key(|trigger| (trigger.trigger_id.pid.0, trigger.trigger_id.lambda_id));
+    fired_triggers.dedup_by(|a, b| a.trigger_id == b.trigger_id);
+}
 
-        for contract_id in transaction_receipt.mutated_contracts_radius.iter() {
-            if let Some(triggers) = self
-                .stacks_predicates
-                .watching_contract_id_activity
-                .get(contract_id)
-            {
-                activated_triggers.extend(triggers);
+/// Exponential backoff, from RESTART_BASE_DELAY up to RESTART_MAX_DELAY.
+fn restart_delay(attempts: u32) -> Duration {
+    let factor = 1u32 << attempts.saturating_sub(1).min(16);
+    RESTART_BASE_DELAY
+        .saturating_mul(factor)
+        .min(RESTART_MAX_DELAY)
+}
+
+/// Returns the sender of `tx`, and the principals of its assets events.
+fn get_involved_principals(tx: &StacksTransactionData) -> Vec<&String> {
+    let mut principals = vec![&tx.metadata.sender];
+    for event in tx.metadata.receipt.events.iter() {
+        match event {
+            StacksTransactionEvent::STXTransferEvent(data) => {
+                principals.extend([&data.sender, &data.recipient])
+            }
+            StacksTransactionEvent::STXMintEvent(data) => principals.push(&data.recipient),
+            StacksTransactionEvent::STXLockEvent(data) => principals.push(&data.locked_address),
+            StacksTransactionEvent::STXBurnEvent(data) => principals.push(&data.sender),
+            StacksTransactionEvent::FTTransferEvent(data) => {
+                principals.extend([&data.sender, &data.recipient])
             }
+            StacksTransactionEvent::FTMintEvent(data) => principals.push(&data.recipient),
+            StacksTransactionEvent::FTBurnEvent(data) => principals.push(&data.sender),
+            StacksTransactionEvent::NFTTransferEvent(data) => {
+                principals.extend([&data.sender, &data.recipient])
+            }
+            StacksTransactionEvent::NFTMintEvent(data) => principals.push(&data.recipient),
+            StacksTransactionEvent::NFTBurnEvent(data) => principals.push(&data.sender),
+            _ => {}
         }
-
-        activated_triggers
     }
+    principals
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::types::{OrchestraPid, StacksChainPredicates, TriggerId};
+    use super::{restart_delay, OrchestraSupervisor, RESTART_BASE_DELAY, RESTART_MAX_DELAY};
+    use crate::datastore::StorageDriver;
+    use crate::types::{
+        Action, Lambda, OrchestraPid, Predicate, ProtocolObserverId, StacksFtEventPredicate,
+        StacksPredicate, StacksPrintEventPredicate, TokenAction, TriggerId, User,
+    };
+    use clarinet_lib::types::events::{FTTransferEventData, StacksTransactionEvent};
+    use clarinet_lib::types::{
+        StacksTransactionData, StacksTransactionKind, StacksTransactionMetadata,
+        StacksTransactionReceipt, TransactionIdentifier,
+    };
     use std::collections::HashSet;
 
-    // #[test]
-    // fn test_predicate_watching_contract_id_activity_integration() {
-
-    //     let mut predicates = StacksChainPredicates::new();
-    //     let contract_id: String = "STX.contract_id".into();
-    //     let mut triggers = HashSet::new();
-    //     let trigger_101 = TriggerId { pid: OrchestraPid(1), lambda_id: 1 };
-    //     triggers.insert(trigger_101.clone());
-    //     predicates.watching_contract_id_activity.insert(contract_id.clone(), triggers);
-
-    //     let mut supervisor = OrchestraSupervisor::new();
-    //     supervisor.register_predicates(predicates);
-
-    //     let block = block_with_transactions(vec![
-    //         transaction_contract_call_impacting_contract_id(contract_id.clone(), true)
-    //     ]);
-    //     let res = supervisor.handle_new_stacks_block(block, &mut MockedSpan::new());
-    //     assert!(res.contains(&trigger_101));
-
-    //     let block = block_with_transactions(vec![
-    //         transaction_contract_call_impacting_contract_id(contract_id.clone(), false)
-    //     ]);
-    //     let res = supervisor.handle_new_stacks_block(block, &mut MockedSpan::new());
-    //     assert!(res.is_empty());
-    // }
+    fn transaction_impacting_contract_id(
+        contract_id: String,
+        events: Vec<StacksTransactionEvent>,
+        success: bool,
+    ) -> StacksTransactionData {
+        let mut mutated_contracts_radius = HashSet::new();
+        mutated_contracts_radius.insert(contract_id);
+        StacksTransactionData {
+            transaction_identifier: TransactionIdentifier { hash: "0".into() },
+            operations: vec![],
+            metadata: StacksTransactionMetadata {
+                success,
+                result: "".into(),
+                raw_tx: "0x00".to_string(),
+                execution_cost: None,
+                sender: "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM".into(),
+                fee: 0,
+                sponsor: None,
+                kind: StacksTransactionKind::ContractCall,
+                receipt: StacksTransactionReceipt {
+                    mutated_contracts_radius,
+                    mutated_assets_radius: HashSet::new(),
+                    events,
+                },
+                description: "".into(),
+            },
+        }
+    }
+
+    fn triggers(lambda_ids: &[u64]) -> HashSet<TriggerId> {
+        lambda_ids
+            .iter()
+            .map(|lambda_id| TriggerId {
+                pid: OrchestraPid(1),
+                lambda_id: *lambda_id,
+            })
+            .collect()
+    }
+
+    fn lambda(lambda_id: u64, predicate: StacksPredicate) -> Lambda {
+        Lambda {
+            lambda_id,
+            name: format!("lambda-{}", lambda_id),
+            predicate: Predicate::StacksPredicate(predicate),
+            action: Action::User(User::HTTPPost("http://localhost:3000".into())),
+        }
+    }
+
+    #[test]
+    fn test_predicates_evaluated_on_new_stacks_block() {
+        let contract_id: String = "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.token".into();
+        let asset_id = format!("{}::token", contract_id);
+        let ft_event = |actions: Vec<TokenAction>, principal: Option<&str>| {
+            StacksPredicate::FtEvent(StacksFtEventPredicate {
+                asset_identifier: asset_id.clone(),
+                actions,
+                principal: principal.map(|principal| principal.to_string()),
+            })
+        };
+        let lambdas = vec![
+            lambda(1, StacksPredicate::AnyBlock),
+            lambda(2, ft_event(vec![], None)),
+            lambda(
+                3,
+                ft_event(
+                    vec![TokenAction::Transfer],
+                    Some("ST2CY5V39NHDPWSXMW9QDT3HC3GD6Q6XX4CFRK9AG"),
+                ),
+            ),
+            // Same asset, but neither the action nor the principal match
+            lambda(4, ft_event(vec![TokenAction::Mint], None)),
+            lambda(
+                5,
+                ft_event(vec![], Some("ST2JHG361ZXG51QTKY2NQCVBPPRRE2KZB1HR05NNC")),
+            ),
+            // Same contract, but no print event
+            lambda(
+                6,
+                StacksPredicate::PrintEvent(StacksPrintEventPredicate {
+                    contract_identifier: contract_id.clone(),
+                    topic: None,
+                    value: None,
+                }),
+            ),
+        ];
+
+        let mut supervisor = OrchestraSupervisor::new(StorageDriver::in_memory());
+        supervisor.register_lambdas_predicates(&ProtocolObserverId(1), &lambdas);
+
+        let transfer = StacksTransactionEvent::FTTransferEvent(FTTransferEventData {
+            asset_class_identifier: asset_id.clone(),
+            sender: "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM".into(),
+            recipient: "ST2CY5V39NHDPWSXMW9QDT3HC3GD6Q6XX4CFRK9AG".into(),
+            amount: "10".into(),
+        });
+        let transactions = vec![transaction_impacting_contract_id(
+            contract_id.clone(),
+            vec![transfer.clone()],
+            true,
+        )];
+        let res = supervisor.handle_new_stacks_block(&transactions);
+        assert!(res.iter().all(|trigger| trigger.transactions == vec![0]));
+        let res = res
+            .into_iter()
+            .map(|trigger| trigger.trigger_id)
+            .collect::<HashSet<_>>();
+        assert_eq!(res, triggers(&[1, 2, 3]));
+
+        let transactions = vec![transaction_impacting_contract_id(
+            contract_id,
+            vec![transfer],
+            false,
+        )];
+        let res = supervisor
+            .handle_new_stacks_block(&transactions)
+            .into_iter()
+            .map(|trigger| trigger.trigger_id)
+            .collect::<HashSet<_>>();
+        assert_eq!(res, triggers(&[1]));
+    }
+
+    #[test]
+    fn test_restart_delay_backs_off_exponentially() {
+        assert_eq!(restart_delay(1), RESTART_BASE_DELAY);
+        assert_eq!(restart_delay(2), RESTART_BASE_DELAY * 2);
+        assert_eq!(restart_delay(4), RESTART_BASE_DELAY * 8);
+        assert_eq!(restart_delay(10), RESTART_MAX_DELAY);
+        assert_eq!(restart_delay(u32::MAX), RESTART_MAX_DELAY);
+    }
 }
@@ -1,221 +1,679 @@
-use crate::types::ProtocolObserverConfig;
-use clarinet_lib::clarity_repl::clarity::types::{StandardPrincipalData, QualifiedContractIdentifier};
-use clarinet_lib::clarity_repl::repl::settings::InitialContract;
-use clarinet_lib::clarity_repl::repl::{ClarityInterpreter, Session, SessionSettings};
-use clarinet_lib::types::{StacksTransactionData, TransactionIdentifier};
-use clarinet_lib::types::events::{StacksTransactionEvent, SmartContractEventData};
+use crate::actors::contract_processor::node;
+use crate::datastore::blocks::{self, stacks_blocks_db};
+use crate::datastore::contracts::scan_events_page;
+use crate::datastore::journal::{self, JournaledDatastore};
+use crate::datastore::keys::{db_key, DBKey};
+use crate::datastore::wallets::{wallet_db, wallet_db_delete_all, wallet_db_namespace};
+use crate::types::{
+    FTBurnEventValue, FTEventStoredValue, FTMintEventValue, FTTransferEventValue,
+    NFTBurnEventValue, NFTEventStoredValue, NFTMintEventValue, NFTTransferEventValue, RequestError,
+    STXBurnEventValue, STXLockEventValue, STXMintEventValue, STXTransferEventValue,
+    WalletActivityStoredValue, WalletEventStoredValue, WalletStateRequest, WalletStateResponse,
+    WalletStoredBalance, WalletStoredNFT, WalletTransactionValue, STX_ASSET_IDENTIFIER,
+};
+use clarinet_lib::clarity_repl::clarity::util::hash::{hex_bytes, to_hex};
+use clarinet_lib::types::events::StacksTransactionEvent;
+use clarinet_lib::types::{BlockIdentifier, StacksTransactionData};
 use kompact::prelude::*;
 use opentelemetry::global;
 use opentelemetry::trace::{Span, Tracer};
-use rocksdb::{Options, DB};
-use crate::datastore::contracts::{db_key, DBKey, contract_db};
+use stacks_rpc_client::StacksRpc;
+use std::collections::BTreeMap;
 
-use std::collections::{BTreeMap, VecDeque};
+use crate::datastore::{Datastore, StorageDriver, WriteBatch};
 
-use crate::datastore::StorageDriver;
-
-use super::block_store_manager::ContractInstanciation;
+/// Number of activity entries returned when the request does not specify it.
+const DEFAULT_PAGE_SIZE: u16 = 50;
 
 #[derive(Clone, Debug)]
 pub enum WalletProcessorMessage {
-    RebuildState,
-    ProcessTransactionsBatch(Vec<StacksTransactionData>),
-    RollbackTransactionsBatch(Vec<StacksTransactionData>),
+    /// Batch of a block, along with the anchored block of the microblock
+    /// batches (None for anchored blocks).
+    ProcessTransactionsBatch(
+        BlockIdentifier,
+        Option<BlockIdentifier>,
+        Vec<StacksTransactionData>,
+    ),
+    RollbackTransactionsBatch(
+        BlockIdentifier,
+        Option<BlockIdentifier>,
+        Vec<StacksTransactionData>,
+    ),
+    GetWalletState(WalletStateRequest),
     Exit,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum WalletProcessorEvent {
-    TransactionsBatchProcessed(String, Vec<(SmartContractEventData, TransactionIdentifier)>),
-}
-
-pub struct WalletProcessorPort;
-
-impl Port for WalletProcessorPort {
-    type Indication = WalletProcessorEvent;
-    type Request = Never;
-}
-
+/// Indexes the balances, tokens and activity of a watched principal.
 #[derive(ComponentDefinition)]
 pub struct WalletProcessor {
     ctx: ComponentContext<Self>,
-    contract_processor_port: ProvidedPort<WalletProcessorPort>,
-    contract_id: String,
+    principal: String,
     storage_driver: StorageDriver,
-}
-pub enum Changes <'a> {
-    UpdateDataVar(&'a str, &'a str, &'a str),
-    InsertDataMapEntry(&'a str, (&'a str, &'a str), &'a str),
-    DeleteDataMapEntry(&'a str, &'a str, &'a str),
-    UpdateDataMapEntry(&'a str, (&'a str, &'a str), &'a str),
-    SendTokens(&'a str, (&'a str, u128), &'a str),
-    ReceiveTokens(&'a str, (&'a str, u128), &'a str),
-    SendNFT(&'a str, (&'a str, &'a str), &'a str),
-    ReceiveNFT(&'a str, (&'a str, &'a str), &'a str),
+    stacks_node_rpc_url: Option<String>,
 }
 
-ignore_requests!(WalletProcessorPort, WalletProcessor);
-
 impl WalletProcessor {
-    pub fn new(storage_driver: StorageDriver, contract_id: String) -> Self {
+    pub fn new(
+        storage_driver: StorageDriver,
+        principal: String,
+        stacks_node_rpc_url: Option<String>,
+    ) -> Self {
         global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
         Self {
             ctx: ComponentContext::uninitialised(),
-            contract_processor_port: ProvidedPort::uninitialised(),
+            principal,
             storage_driver,
-            contract_id,
+            stacks_node_rpc_url,
         }
     }
 
-    fn db_key(&self, key: DBKey) -> Vec<u8> {
-        db_key(key, &self.contract_id)
+    /// Catches up with the archived blocks, from the last block applied, or
+    /// from the genesis for a principal never watched before.
+    pub fn build_state(&mut self) {
+        let block_db = stacks_blocks_db(&self.storage_driver);
+        let tip = match blocks::get_tip(&*block_db) {
+            Ok(Some(tip)) => tip,
+            Ok(None) => return,
+            Err(e) => {
+                error!(self.ctx().log(), "{}", e);
+                return;
+            }
+        };
+        let start = match self.last_applied_block_index(&*block_db) {
+            Some(index) => index + 1,
+            None => {
+                if let Err(e) = wallet_db_delete_all(&self.storage_driver, &self.principal) {
+                    error!(self.ctx().log(), "{}", e);
+                    return;
+                }
+                0
+            }
+        };
+        info!(
+            self.ctx().log(),
+            "Indexing wallet {} in range {:?}",
+            self.principal,
+            start..=tip
+        );
+        for index in start..=tip {
+            // Blocks archived before the working dir was created are missing
+            let block = match blocks::get_stacks_block_at_index(&*block_db, index) {
+                Ok(Some(block)) => block,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(self.ctx().log(), "{}", e);
+                    continue;
+                }
+            };
+
+            let transactions = block
+                .transactions
+                .into_iter()
+                .filter(|tx| !wallet_events(&self.principal, tx).is_empty())
+                .collect::<Vec<_>>();
+            if !transactions.is_empty() {
+                self.handle_transactions_batch(block.block_identifier, None, transactions);
+            }
+        }
     }
 
-    pub fn build_state(&mut self) {
+    /// Returns the height of the last block applied to the wallet, unless
+    /// that block is no longer part of the canonical chain. Errors are logged,
+    /// and the wallet is then indexed from scratch.
+    fn last_applied_block_index(&self, block_db: &dyn Datastore) -> Option<u64> {
+        let last_applied_block = wallet_db(&self.storage_driver, &self.principal)
+            .and_then(|db| self.last_applied_block(&*db));
+        let block_identifier = match last_applied_block {
+            Ok(block_identifier) => block_identifier?,
+            Err(e) => {
+                error!(self.log(), "{}", e);
+                return None;
+            }
+        };
+        match blocks::is_archived_block(block_db, &block_identifier) {
+            Ok(true) => Some(block_identifier.index),
+            Ok(false) => None,
+            Err(e) => {
+                error!(self.log(), "Unable to read last applied block: {}", e);
+                None
+            }
+        }
+    }
 
-        let (contracts, dependencies) = {
-            let mut working_dir = match self.storage_driver {
-                StorageDriver::Filesystem(ref config) => config.working_dir.clone(),
-            };
-            working_dir.push("stacks");
-            let options = Options::default();
-            let contract_id = self.contract_id.clone();
-            let db = DB::open_for_read_only(&options, working_dir, true).unwrap();
-    
-            // Get dependencies
-            let mut interpreter =
-                ClarityInterpreter::new(StandardPrincipalData::transient(), 2, vec![]);
-    
-            let mut contracts: BTreeMap<String, ContractInstanciation> = BTreeMap::new();
-            let mut dependencies: BTreeMap<String, Vec<String>> = BTreeMap::new();
-            let mut queue = VecDeque::new();
-    
-            queue.push_front(contract_id.clone());
-    
-            while let Some(contract_id) = queue.pop_front() {
-                let (_, deps) = match contracts.get(&contract_id) {
-                    Some(entry) => (entry.clone(), Vec::new()),
-                    None => {
-                        let bytes = db
-                            .get(&contract_id.as_bytes())
-                            .expect("Unable to hit contract storage")
-                            .expect("Unable to retrieve contract");
-                        let contract_instance = serde_json::from_slice::<ContractInstanciation>(&bytes)
-                            .expect("Unable to deserialize contract");
-    
-                        let deps = interpreter
-                            .detect_dependencies(
-                                contract_id.to_string(),
-                                contract_instance.code.clone(),
-                                2,
-                            )
-                            .expect("Unable to retrieve contract dependencies");
-    
-                        contracts.insert(contract_id.to_string(), contract_instance.clone());
-                        (contract_instance, deps)
-                    }
-                };
-    
-                if deps.len() > 0 {
-                    dependencies.insert(
-                        contract_id.to_string(),
-                        deps.clone().into_iter().map(|c| c.to_string()).collect(),
-                    );
-                    for contract_id in deps.into_iter() {
-                        queue.push_back(contract_id.to_string());
-                    }
-                    queue.push_back(contract_id.to_string());
-                } else {
-                    dependencies.insert(contract_id.to_string(), vec![]);
-                }
+    fn last_applied_block(&self, db: &dyn Datastore) -> Result<Option<BlockIdentifier>, String> {
+        match db.get(&db_key(DBKey::LastAppliedBlock))? {
+            Some(bytes) => serde_json::from_slice::<BlockIdentifier>(&bytes)
+                .map(Some)
+                .map_err(|e| format!("unable to read last applied block: {}", e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns true if `block_identifier` is an archived block, applied
+    /// already. Microblocks, indexed by sequence, never are.
+    fn is_applied_block(&self, block_identifier: &BlockIdentifier) -> bool {
+        let block_db = stacks_blocks_db(&self.storage_driver);
+        match self.last_applied_block_index(&*block_db) {
+            Some(index) if block_identifier.index <= index => {}
+            _ => return false,
+        }
+        match blocks::is_archived_block(&*block_db, block_identifier) {
+            Ok(archived) => archived,
+            Err(e) => {
+                warn!(self.log(), "{}", e);
+                false
             }
-            (contracts, dependencies)
+        }
+    }
+
+    /// Applies the batch of `block_identifier`. Microblocks are written at the
+    /// height of their anchored block, and never become the last applied
+    /// block (see `ContractProcessor::handle_transactions_batch`).
+    fn handle_transactions_batch(
+        &mut self,
+        block_identifier: BlockIdentifier,
+        anchor_block_identifier: Option<BlockIdentifier>,
+        transactions: Vec<StacksTransactionData>,
+    ) {
+        // Batches are delivered again when catching up after a restart
+        if self.is_applied_block(&block_identifier) {
+            debug!(
+                self.ctx.log(),
+                "Skipping block {}, already applied to wallet {}",
+                block_identifier.index,
+                self.principal
+            );
+            return;
+        }
+
+        // Every write is journaled, so that the batch can be rolled back on
+        // reorgs, and applied at once along with the journal.
+        let batch = WriteBatch::new(&self.storage_driver);
+        let wallet_db = batch
+            .open(&wallet_db_namespace(&self.principal))
+            .expect("Unable to open wallet datastore");
+        let db = JournaledDatastore::new(&*wallet_db);
+        let block_index = match anchor_block_identifier {
+            Some(ref anchor_block_identifier) => anchor_block_identifier.index,
+            None => block_identifier.index,
         };
+        let events = transactions
+            .iter()
+            .map(|tx| (tx, wallet_events(&self.principal, tx)))
+            .collect::<Vec<_>>();
+        self.seed_balances(&db, &block_identifier, &events);
+        let mut event_index =
+            journal::first_position(&db, block_index).expect("Unable to read batch position");
+        for (tx, events) in events.into_iter() {
+            for event in events.into_iter() {
+                event_index += 1;
+                self.apply_event(&db, &event);
+                db.put(
+                    &db_key(DBKey::WalletActivity(block_index, event_index)),
+                    json!(WalletActivityStoredValue {
+                        event,
+                        block_identifier: block_identifier.clone(),
+                        transaction_identifier: tx.transaction_identifier.clone(),
+                    })
+                    .to_string()
+                    .as_bytes(),
+                )
+                .expect("Unable to write");
+            }
+        }
+        journal::set_next_position(&db, block_index, event_index).expect("Unable to write");
+        if anchor_block_identifier.is_none() {
+            db.put(
+                &db_key(DBKey::LastAppliedBlock),
+                &serde_json::to_vec(&block_identifier)
+                    .expect("Unable to serialize block identifier"),
+            )
+            .expect("Unable to write");
+        }
+        db.commit(&db_key(DBKey::UndoJournal(
+            block_index,
+            &block_identifier.hash,
+        )))
+        .expect("Unable to write journal");
+        if anchor_block_identifier.is_none() {
+            journal::prune(&*wallet_db, block_index).expect("Unable to prune journals");
+        }
+        batch.commit().expect("Unable to write transactions batch");
+    }
 
-        // Order the graph
-        let ordered_contracts_ids = clarinet_lib::utils::order_contracts(&dependencies);
-
-        // Build a SessionSettings struct from the contracts
-        let mut settings = SessionSettings::default();
-        settings.include_boot_contracts = vec!["costs-v2".to_string()];
-        settings.costs_version = 2;
-        settings.analysis = vec!["all".into()];
-
-        let mut incremental_session = Session::new(settings);
-        let mut full_analysis = BTreeMap::new();
-        for contract_id in ordered_contracts_ids.into_iter() {
-            let contract_instanciation = contracts.get(&contract_id).unwrap();
-            let mut diagnostics = vec![];
-            // Extract the AST, and try to move to the next contract if we throw an error:
-            // we're trying to get as many errors as possible
-            let contract_identifier = QualifiedContractIdentifier::parse(&contract_id).unwrap();
-            let mut ast = match incremental_session
-                .interpreter
-                .build_ast(contract_identifier.clone(), 
-                contract_instanciation.code.clone())
-            {
-                Ok(ast) => ast,
-                Err((_, Some(diagnostic), _)) => {
-                    diagnostics.push(diagnostic);
+    /// Seeds the balances of the fungible assets never seen from the node, as
+    /// genesis allocations, and movements preceding the indexed blocks, do not
+    /// emit events. Balances are read as of the block being applied, and the
+    /// movements of `events` are reverted.
+    fn seed_balances(
+        &self,
+        db: &dyn Datastore,
+        block_identifier: &BlockIdentifier,
+        events: &[(&StacksTransactionData, Vec<WalletEventStoredValue>)],
+    ) {
+        let url = match self.stacks_node_rpc_url {
+            Some(ref url) => url,
+            None => return,
+        };
+        let mut movements: BTreeMap<&str, (u128, u128)> = BTreeMap::new();
+        for event in events.iter().flat_map(|(_, events)| events.iter()) {
+            if let Some((asset_id, credited, debited)) = self.fungible_movement(event) {
+                let movement = movements.entry(asset_id).or_default();
+                movement.0 = movement.0.saturating_add(credited);
+                movement.1 = movement.1.saturating_add(debited);
+            }
+        }
+        for (asset_id, (credited, debited)) in movements.into_iter() {
+            if credited == 0 && debited == 0 {
+                continue;
+            }
+            match db.get(&db_key(DBKey::WalletBalance(asset_id))) {
+                Ok(None) => {}
+                Ok(Some(_)) => continue,
+                Err(e) => {
+                    warn!(self.log(), "{}", e);
                     continue;
                 }
-                _ => {
+            }
+            let balance = match self.get_balance_from_node(url, asset_id, &block_identifier.hash) {
+                Ok(balance) => balance,
+                Err(e) => {
+                    warn!(self.log(), "{}", e);
                     continue;
                 }
             };
+            let balance = balance.saturating_add(debited).saturating_sub(credited);
+            self.put_balance(db, asset_id, balance);
+        }
+    }
 
-            // Run the analysis, and try to move to the next contract if we throw an error:
-            // we're trying to get as many errors as possible
-            let (annotations, mut annotation_diagnostics) = incremental_session
-                .interpreter
-                .collect_annotations(&ast, &contract_instanciation.code);
-            diagnostics.append(&mut annotation_diagnostics);
-
-            let (analysis, mut analysis_diagnostics) = match incremental_session.interpreter.run_analysis(
-                contract_identifier.clone(),
-                &mut ast,
-                &annotations,
-            ) {
-                Ok(analysis) => analysis,
-                Err((_, Some(diagnostic), _)) => {
-                    diagnostics.push(diagnostic);
-                    continue;
+    /// Returns the balance of `asset_id` reported by the node as of the block
+    /// `block_hash`: locked STX included, and tokens through the SIP-010
+    /// `get-balance` function of their contract, which is assumed to define
+    /// that single token.
+    fn get_balance_from_node(
+        &self,
+        url: &str,
+        asset_id: &str,
+        block_hash: &str,
+    ) -> Result<u128, String> {
+        if asset_id == STX_ASSET_IDENTIFIER {
+            let account = StacksRpc::new(url)
+                .get_account_at(&self.principal, Some(block_hash))
+                .map_err(|e| format!("unable to seed balance of {}: {}", self.principal, e))?;
+            return Ok(account.balance.saturating_add(account.locked));
+        }
+        let contract_id = match asset_id.split_once("::") {
+            Some((contract_id, _)) => contract_id,
+            None => asset_id,
+        };
+        node::get_balance_from_node(url, contract_id, &self.principal, Some(block_hash))
+    }
+
+    /// Returns the fungible asset moved by `event`, along with the amounts
+    /// `(credited, debited)` to the principal. None for non fungible tokens.
+    fn fungible_movement<'e>(
+        &self,
+        event: &'e WalletEventStoredValue,
+    ) -> Option<(&'e str, u128, u128)> {
+        let (asset_id, credited, debited) = match event {
+            WalletEventStoredValue::Transaction(data) => {
+                (STX_ASSET_IDENTIFIER, 0, data.fee as u128)
+            }
+            WalletEventStoredValue::STXTransfer(data) => (
+                STX_ASSET_IDENTIFIER,
+                self.own_amount(&data.recipient, &data.amount),
+                self.own_amount(&data.sender, &data.amount),
+            ),
+            WalletEventStoredValue::STXMint(data) => {
+                (STX_ASSET_IDENTIFIER, self.parse_amount(&data.amount), 0)
+            }
+            WalletEventStoredValue::STXBurn(data) => {
+                (STX_ASSET_IDENTIFIER, 0, self.parse_amount(&data.amount))
+            }
+            // Locked STX remain part of the balance
+            WalletEventStoredValue::STXLock(_) => (STX_ASSET_IDENTIFIER, 0, 0),
+            WalletEventStoredValue::FT(asset_id, FTEventStoredValue::Mint(data)) => {
+                (asset_id.as_str(), self.parse_amount(&data.amount), 0)
+            }
+            WalletEventStoredValue::FT(asset_id, FTEventStoredValue::Transfer(data)) => (
+                asset_id.as_str(),
+                self.own_amount(&data.recipient, &data.amount),
+                self.own_amount(&data.sender, &data.amount),
+            ),
+            WalletEventStoredValue::FT(asset_id, FTEventStoredValue::Burn(data)) => {
+                (asset_id.as_str(), 0, self.parse_amount(&data.amount))
+            }
+            WalletEventStoredValue::NFT(..) => return None,
+        };
+        Some((asset_id, credited, debited))
+    }
+
+    /// Returns `amount` if `principal` is the watched principal, 0 otherwise.
+    fn own_amount(&self, principal: &str, amount: &str) -> u128 {
+        match principal == self.principal {
+            true => self.parse_amount(amount),
+            false => 0,
+        }
+    }
+
+    fn apply_event(&self, db: &dyn Datastore, event: &WalletEventStoredValue) {
+        if let Some((asset_id, credited, debited)) = self.fungible_movement(event) {
+            if debited > 0 {
+                self.debit(db, asset_id, debited);
+            }
+            if credited > 0 {
+                self.credit(db, asset_id, credited);
+            }
+            return;
+        }
+        match event {
+            WalletEventStoredValue::NFT(asset_id, NFTEventStoredValue::Mint(data)) => {
+                self.receive_nft(db, asset_id, &data.hex_asset_identifier);
+            }
+            WalletEventStoredValue::NFT(asset_id, NFTEventStoredValue::Transfer(data)) => {
+                if data.sender == self.principal {
+                    db.delete(&db_key(DBKey::WalletNFT(
+                        asset_id,
+                        &data.hex_asset_identifier,
+                    )))
+                    .expect("Unable to write");
                 }
-                _ => {
-                    continue;
+                if data.recipient == self.principal {
+                    self.receive_nft(db, asset_id, &data.hex_asset_identifier);
                 }
-            };
-            diagnostics.append(&mut analysis_diagnostics);
-
-            let _ = incremental_session.interpreter.save_contract(
-                contract_identifier.clone(),
-                &mut ast,
-                contract_instanciation.code.clone(),
-                analysis.clone(),
-                false,
-            );
+            }
+            WalletEventStoredValue::NFT(asset_id, NFTEventStoredValue::Burn(data)) => {
+                db.delete(&db_key(DBKey::WalletNFT(
+                    asset_id,
+                    &data.hex_asset_identifier,
+                )))
+                .expect("Unable to write");
+            }
+            // Fungible movements, applied above
+            _ => {}
+        }
+    }
 
-            full_analysis.insert(
-                contract_id.clone(),
-                (diagnostics, analysis, ast),
+    fn credit(&self, db: &dyn Datastore, asset_id: &str, amount: u128) {
+        let balance = self.get_balance(db, asset_id);
+        let balance = match balance.checked_add(amount) {
+            Some(balance) => balance,
+            None => {
+                warn!(
+                    self.log(),
+                    "Inconsistent {} balance for {}: {} can not absorb {}",
+                    asset_id,
+                    self.principal,
+                    balance,
+                    amount
+                );
+                u128::MAX
+            }
+        };
+        self.put_balance(db, asset_id, balance);
+    }
+
+    fn debit(&self, db: &dyn Datastore, asset_id: &str, amount: u128) {
+        let balance = self.get_balance(db, asset_id);
+        let balance = match balance.checked_sub(amount) {
+            Some(balance) => balance,
+            None => {
+                warn!(
+                    self.log(),
+                    "Inconsistent {} balance for {}: {} can not absorb -{}",
+                    asset_id,
+                    self.principal,
+                    balance,
+                    amount
+                );
+                0
+            }
+        };
+        self.put_balance(db, asset_id, balance);
+    }
+
+    /// Returns the balance tracked so far (see `seed_balances`).
+    fn get_balance(&self, db: &dyn Datastore, asset_id: &str) -> u128 {
+        match db
+            .get(&db_key(DBKey::WalletBalance(asset_id)))
+            .expect("Unable to read balance")
+        {
+            Some(bytes) => {
+                let balance = serde_json::from_slice::<WalletStoredBalance>(&bytes)
+                    .expect("Unable to deserialize balance");
+                self.parse_amount(&balance.balance)
+            }
+            None => 0,
+        }
+    }
+
+    fn put_balance(&self, db: &dyn Datastore, asset_id: &str, balance: u128) {
+        db.put(
+            &db_key(DBKey::WalletBalance(asset_id)),
+            json!(WalletStoredBalance {
+                asset_class_identifier: asset_id.to_string(),
+                balance: balance.to_string(),
+            })
+            .to_string()
+            .as_bytes(),
+        )
+        .expect("Unable to write");
+    }
+
+    fn receive_nft(&self, db: &dyn Datastore, asset_id: &str, hex_asset_identifier: &str) {
+        db.put(
+            &db_key(DBKey::WalletNFT(asset_id, hex_asset_identifier)),
+            json!(WalletStoredNFT {
+                asset_class_identifier: asset_id.to_string(),
+                hex_asset_identifier: hex_asset_identifier.to_string(),
+            })
+            .to_string()
+            .as_bytes(),
+        )
+        .expect("Unable to write");
+    }
+
+    fn parse_amount(&self, amount: &str) -> u128 {
+        match amount.parse::<u128>() {
+            Ok(amount) => amount,
+            Err(_) => {
+                warn!(self.log(), "Malformed amount {:?}, reset to 0", amount);
+                0
+            }
+        }
+    }
+
+    /// Restores the state preceding the batch of `block_identifier`,
+    /// activity included.
+    fn rollback_transactions_batch(
+        &mut self,
+        block_identifier: &BlockIdentifier,
+        anchor_block_identifier: Option<&BlockIdentifier>,
+    ) {
+        let batch = WriteBatch::new(&self.storage_driver);
+        let db = batch
+            .open(&wallet_db_namespace(&self.principal))
+            .expect("Unable to open wallet datastore");
+        let block_index = anchor_block_identifier.unwrap_or(block_identifier).index;
+        let journal_key = db_key(DBKey::UndoJournal(block_index, &block_identifier.hash));
+        let rolled_back = journal::rollback(&*db, &journal_key).expect("Unable to rollback");
+        batch.commit().expect("Unable to write rollback");
+        if !rolled_back {
+            warn!(
+                self.ctx().log(),
+                "No journal found for block {}, nothing to rollback", block_identifier.hash
             );
         }
+    }
 
-        info!(self.log(), "WalletProcessor performed analysis {:?}", full_analysis);
+    fn get_wallet_state(
+        &self,
+        request: &WalletStateRequest,
+    ) -> Result<WalletStateResponse, String> {
+        let db = wallet_db(&self.storage_driver, &self.principal)?;
+        let mut balances = vec![];
+        for (_, bytes) in db.scan_prefix(&db_key(DBKey::WalletBalanceScan))? {
+            let balance = serde_json::from_slice::<WalletStoredBalance>(&bytes)
+                .map_err(|e| format!("unable to read balance: {}", e))?;
+            balances.push(balance);
+        }
+        let mut nfts = vec![];
+        for (_, bytes) in db.scan_prefix(&db_key(DBKey::WalletNFTScan))? {
+            let nft = serde_json::from_slice::<WalletStoredNFT>(&bytes)
+                .map_err(|e| format!("unable to read token: {}", e))?;
+            nfts.push(nft);
+        }
 
-        // Store artifacts
-        {
-            let db = contract_db(&self.storage_driver, &self.contract_id);
-            let full_analysis_bytes = serde_json::to_vec(&full_analysis).expect("Unable to serialize block");
-            db.put(&self.db_key(DBKey::FullAnalysis), full_analysis_bytes).unwrap();
-            // todo(ludo): finer granularity
-            // db.put(format!("{}::ast", contract_id).as_bytes(), full_analysis_bytes).unwrap();
-            // db.put(format!("{}::diags", contract_id).as_bytes(), full_analysis_bytes).unwrap();
-            // db.put(format!("{}::abi", contract_id).as_bytes(), full_analysis_bytes).unwrap();
+        let page_size = match request.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => page_size,
+        };
+        let cursor = request
+            .activity_cursor
+            .as_ref()
+            .and_then(|cursor| hex_bytes(cursor).ok());
+        let page = scan_events_page(
+            &*db,
+            &db_key(DBKey::WalletActivityScan),
+            u64::MAX,
+            cursor.as_deref(),
+            page_size as usize,
+        )?;
+        let mut activity = vec![];
+        for (_, _, bytes) in page.items.into_iter() {
+            let entry = serde_json::from_slice::<WalletActivityStoredValue>(&bytes)
+                .map_err(|e| format!("unable to read activity: {}", e))?;
+            activity.push(entry);
         }
+
+        Ok(WalletStateResponse {
+            principal: self.principal.clone(),
+            block_identifier: self.last_applied_block(&*db)?,
+            balances,
+            nfts,
+            activity,
+            activity_page_size: page_size,
+            activity_next_cursor: page.next_cursor.map(|cursor| to_hex(&cursor)),
+        })
     }
 }
 
+/// Returns the events of `tx` involving `principal`, preceded by the
+/// transaction itself when `principal` sent or sponsored it.
+pub fn wallet_events(principal: &str, tx: &StacksTransactionData) -> Vec<WalletEventStoredValue> {
+    let mut events = vec![];
+    let fee_payer = tx.metadata.sponsor.as_ref().unwrap_or(&tx.metadata.sender);
+    if tx.metadata.sender == principal || fee_payer == principal {
+        events.push(WalletEventStoredValue::Transaction(
+            WalletTransactionValue {
+                description: tx.metadata.description.clone(),
+                success: tx.metadata.success,
+                fee: if fee_payer == principal {
+                    tx.metadata.fee
+                } else {
+                    0
+                },
+            },
+        ));
+    }
+    for event in tx.metadata.receipt.events.iter() {
+        let event = match event {
+            StacksTransactionEvent::STXTransferEvent(data)
+                if data.sender == principal || data.recipient == principal =>
+            {
+                WalletEventStoredValue::STXTransfer(STXTransferEventValue {
+                    sender: data.sender.to_string(),
+                    recipient: data.recipient.to_string(),
+                    amount: data.amount.to_string(),
+                })
+            }
+            StacksTransactionEvent::STXMintEvent(data) if data.recipient == principal => {
+                WalletEventStoredValue::STXMint(STXMintEventValue {
+                    recipient: data.recipient.to_string(),
+                    amount: data.amount.to_string(),
+                })
+            }
+            StacksTransactionEvent::STXLockEvent(data) if data.locked_address == principal => {
+                WalletEventStoredValue::STXLock(STXLockEventValue {
+                    locked_amount: data.locked_amount.to_string(),
+                    unlock_height: data.unlock_height.to_string(),
+                    locked_address: data.locked_address.to_string(),
+                })
+            }
+            StacksTransactionEvent::STXBurnEvent(data) if data.sender == principal => {
+                WalletEventStoredValue::STXBurn(STXBurnEventValue {
+                    sender: data.sender.to_string(),
+                    amount: data.amount.to_string(),
+                })
+            }
+            StacksTransactionEvent::FTTransferEvent(data)
+                if data.sender == principal || data.recipient == principal =>
+            {
+                WalletEventStoredValue::FT(
+                    data.asset_class_identifier.to_string(),
+                    FTEventStoredValue::Transfer(FTTransferEventValue {
+                        sender: data.sender.to_string(),
+                        recipient: data.recipient.to_string(),
+                        amount: data.amount.to_string(),
+                    }),
+                )
+            }
+            StacksTransactionEvent::FTMintEvent(data) if data.recipient == principal => {
+                WalletEventStoredValue::FT(
+                    data.asset_class_identifier.to_string(),
+                    FTEventStoredValue::Mint(FTMintEventValue {
+                        recipient: data.recipient.to_string(),
+                        amount: data.amount.to_string(),
+                    }),
+                )
+            }
+            StacksTransactionEvent::FTBurnEvent(data) if data.sender == principal => {
+                WalletEventStoredValue::FT(
+                    data.asset_class_identifier.to_string(),
+                    FTEventStoredValue::Burn(FTBurnEventValue {
+                        sender: data.sender.to_string(),
+                        amount: data.amount.to_string(),
+                    }),
+                )
+            }
+            StacksTransactionEvent::NFTTransferEvent(data)
+                if data.sender == principal || data.recipient == principal =>
+            {
+                WalletEventStoredValue::NFT(
+                    data.asset_class_identifier.to_string(),
+                    NFTEventStoredValue::Transfer(NFTTransferEventValue {
+                        sender: data.sender.to_string(),
+                        recipient: data.recipient.to_string(),
+                        hex_asset_identifier: data.hex_asset_identifier.to_string(),
+                    }),
+                )
+            }
+            StacksTransactionEvent::NFTMintEvent(data) if data.recipient == principal => {
+                WalletEventStoredValue::NFT(
+                    data.asset_class_identifier.to_string(),
+                    NFTEventStoredValue::Mint(NFTMintEventValue {
+                        recipient: data.recipient.to_string(),
+                        hex_asset_identifier: data.hex_asset_identifier.to_string(),
+                    }),
+                )
+            }
+            StacksTransactionEvent::NFTBurnEvent(data) if data.sender == principal => {
+                WalletEventStoredValue::NFT(
+                    data.asset_class_identifier.to_string(),
+                    NFTEventStoredValue::Burn(NFTBurnEventValue {
+                        sender: data.sender.to_string(),
+                        hex_asset_identifier: data.hex_asset_identifier.to_string(),
+                    }),
+                )
+            }
+            _ => continue,
+        };
+        events.push(event);
+    }
+    events
+}
+
 impl ComponentLifecycle for WalletProcessor {
     fn on_start(&mut self) -> Handled {
         info!(self.log(), "WalletProcessor starting and building state");
@@ -237,144 +695,42 @@ impl Actor for WalletProcessor {
         let mut span = tracer.start("handle message");
 
         match msg {
-            WalletProcessorMessage::RebuildState => {}
-            WalletProcessorMessage::ProcessTransactionsBatch(transactions) => {
+            WalletProcessorMessage::ProcessTransactionsBatch(
+                block_identifier,
+                anchor_block_identifier,
+                transactions,
+            ) => {
                 info!(
                     self.ctx.log(),
                     "WalletProcessor processed transaction batch"
                 );
-                let mut changes = vec![];
-                let mut custom_events = vec![];
-
-                for tx in transactions.iter() {
-                    for event in tx.metadata.receipt.events.iter() {
-                        match event {
-                            StacksTransactionEvent::DataVarSetEvent(event) => {
-                                if event.contract_identifier == self.contract_id {
-                                    changes.push(Changes::UpdateDataVar(&event.var, &event.new_value, &tx.transaction_identifier.hash))
-                                }
-                            },
-                            StacksTransactionEvent::DataMapInsertEvent(event) => {
-                                if event.contract_identifier == self.contract_id {
-                                    changes.push(Changes::InsertDataMapEntry(&event.map, (&event.inserted_key, &event.inserted_value), &tx.transaction_identifier.hash))
-                                }
-                            },
-                            StacksTransactionEvent::DataMapUpdateEvent(event) => {
-                                if event.contract_identifier == self.contract_id {
-                                    changes.push(Changes::UpdateDataMapEntry(&event.map, (&event.key, &event.new_value), &tx.transaction_identifier.hash))
-                                }
-                            },
-                            StacksTransactionEvent::DataMapDeleteEvent(event) => {
-                                if event.contract_identifier == self.contract_id {
-                                    changes.push(Changes::DeleteDataMapEntry(&event.map, &event.deleted_key, &tx.transaction_identifier.hash))
-                                }
-                            },
-                            StacksTransactionEvent::FTMintEvent(event) => {
-                                if event.asset_class_identifier.starts_with(&self.contract_id) {
-                                    let amount = u128::from_str_radix(&event.amount, 10)
-                                        .expect("unable to parse amount");
-                                    changes.push(Changes::ReceiveTokens(&event.asset_class_identifier, (&event.recipient, amount), &tx.transaction_identifier.hash))
-                                }
-                            }
-                            StacksTransactionEvent::FTBurnEvent(event) => {
-                                if event.asset_class_identifier.starts_with(&self.contract_id) {
-                                    let amount = u128::from_str_radix(&event.amount, 10)
-                                        .expect("unable to parse amount");
-                                    changes.push(Changes::SendTokens(&event.asset_class_identifier, (&event.sender, amount), &tx.transaction_identifier.hash))
-                                }
-                            }
-                            StacksTransactionEvent::FTTransferEvent(event) => {
-                                if event.asset_class_identifier.starts_with(&self.contract_id) {
-                                    let amount = u128::from_str_radix(&event.amount, 10)
-                                        .expect("unable to parse amount");
-                                    changes.push(Changes::SendTokens(&event.asset_class_identifier, (&event.sender, amount), &tx.transaction_identifier.hash));
-                                    changes.push(Changes::ReceiveTokens(&event.asset_class_identifier, (&event.recipient, amount), &tx.transaction_identifier.hash))
-                                }
-                            }
-                            StacksTransactionEvent::NFTMintEvent(event) => {
-                                if event.asset_class_identifier.starts_with(&self.contract_id) {
-                                    changes.push(Changes::ReceiveNFT(&event.asset_class_identifier, (&event.asset_identifier, &event.recipient), &tx.transaction_identifier.hash))
-                                }
-                            }
-                            StacksTransactionEvent::NFTBurnEvent(event) => {
-                                if event.asset_class_identifier.starts_with(&self.contract_id) {
-                                    changes.push(Changes::SendNFT(&event.asset_class_identifier, (&event.asset_identifier, &event.sender), &tx.transaction_identifier.hash))
-                                }
-                            }
-                            StacksTransactionEvent::NFTTransferEvent(event) => {
-                                if event.asset_class_identifier.starts_with(&self.contract_id) {
-                                    changes.push(Changes::SendNFT(&event.asset_class_identifier, (&event.asset_identifier, &event.sender), &tx.transaction_identifier.hash));
-                                    changes.push(Changes::ReceiveNFT(&event.asset_class_identifier, (&event.asset_identifier, &event.recipient), &tx.transaction_identifier.hash))
-                                }                        
-                            }
-                            StacksTransactionEvent::SmartContractEvent(event) => {
-                                if event.contract_identifier == self.contract_id {
-                                    custom_events.push((event.clone(), tx.transaction_identifier.clone()));
-                                }                   
-                            }
-                            StacksTransactionEvent::STXMintEvent(event) => {
-
-                            }
-                            StacksTransactionEvent::STXBurnEvent(event) => {
-                                                            
-                            }
-                            StacksTransactionEvent::STXTransferEvent(event) => {
-                                                            
-                            }
-                            StacksTransactionEvent::STXLockEvent(event) => {
-                                
-                            }
-                        }
-                    }
-                }
-
-                {
-                    let db = contract_db(&self.storage_driver, &self.contract_id);
-                    for change in changes.iter() {
-                        match change {
-                            Changes::UpdateDataVar(var, new_value, txid) => {
-                                db.put(&self.db_key(DBKey::Var(var)), new_value).expect("Unable to write");
-                            },
-                            Changes::InsertDataMapEntry(map, (new_key, new_value), txid) => {
-                                db.put(&self.db_key(DBKey::Map(map, new_key)), new_value).expect("Unable to write");
-                            },
-                            Changes::DeleteDataMapEntry(map, deleted_key, txid) => {
-                                db.delete(&self.db_key(DBKey::Map(map, deleted_key))).expect("Unable to write");
-                            },
-                            Changes::UpdateDataMapEntry(map, (key, new_value), txid) => {
-                                db.put(&self.db_key(DBKey::Map(map, key)), new_value).expect("Unable to write");
-                            },
-                            Changes::SendTokens(asset_id, (sender, value), txid) => {
-                                let balance = match db.get(&self.db_key(DBKey::FT(asset_id, sender))) {
-                                    Ok(Some(value)) => u128::from_str_radix(&String::from_utf8(value).unwrap(), 10).unwrap(),
-                                    Ok(None) => 0,
-                                    Err(e) => panic!("Operational problem encountered: {}", e),
-                                };
-                                db.put(&self.db_key(DBKey::FT(asset_id, sender)), (balance - value).to_string()).expect("Unable to write");
-                            },
-                            Changes::ReceiveTokens(asset_id, (recipient, value), txid) => {
-                                let balance = match db.get(&self.db_key(DBKey::FT(asset_id, recipient))) {
-                                    Ok(Some(value)) => u128::from_str_radix(&String::from_utf8(value).unwrap(), 10).unwrap(),
-                                    Ok(None) => 0,
-                                    Err(e) => panic!("Operational problem encountered: {}", e),
-                                };
-                                db.put(&self.db_key(DBKey::FT(asset_id, recipient)), (balance + value).to_string()).expect("Unable to write");
-                            },
-                            Changes::SendNFT(asset_class_id, (asset_id, sender), txid) => {
-                                db.delete(&self.db_key(DBKey::NFT(asset_class_id, asset_id))).expect("Unable to write");
-                            },
-                            Changes::ReceiveNFT(asset_class_id, (asset_id, recipient), txid) => {
-                                db.put(&self.db_key(DBKey::NFT(asset_class_id, asset_id)), recipient).expect("Unable to write");
-                            },
-                        }
-                    }    
-                }
-
-                self.contract_processor_port.trigger(
-                    WalletProcessorEvent::TransactionsBatchProcessed(self.contract_id.clone(), custom_events),
-                )
+                self.handle_transactions_batch(
+                    block_identifier,
+                    anchor_block_identifier,
+                    transactions,
+                );
+            }
+            WalletProcessorMessage::RollbackTransactionsBatch(
+                block_identifier,
+                anchor_block_identifier,
+                _,
+            ) => {
+                info!(
+                    self.ctx.log(),
+                    "WalletProcessor rolling back transaction batch"
+                );
+                self.rollback_transactions_batch(
+                    &block_identifier,
+                    anchor_block_identifier.as_ref(),
+                );
+            }
+            WalletProcessorMessage::GetWalletState(request) => {
+                let response = self.get_wallet_state(&request).map_err(|e| {
+                    error!(self.ctx.log(), "{}", e);
+                    RequestError::StorageError(e)
+                });
+                let _ = request.tx.send(response);
             }
-            WalletProcessorMessage::RollbackTransactionsBatch(transactions) => {}
             WalletProcessorMessage::Exit => {}
         };
         span.end();
@@ -385,3 +741,309 @@ impl Actor for WalletProcessor {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarinet_lib::types::events::{
+        FTMintEventData, FTTransferEventData, NFTMintEventData, STXTransferEventData,
+    };
+    use clarinet_lib::types::{
+        StacksTransactionKind, StacksTransactionMetadata, StacksTransactionReceipt,
+        TransactionIdentifier,
+    };
+    use std::collections::HashSet;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+
+    const ALICE: &str = "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM";
+    const BOB: &str = "ST1SJ3DTE5DN7X54YDH5D64R3BCB6A2AG2ZQ8YPD5";
+
+    fn token() -> String {
+        format!("{}.token::token", BOB)
+    }
+
+    /// Transaction sent by BOB, so that ALICE pays no fee.
+    fn transaction(txid: &str, events: Vec<StacksTransactionEvent>) -> StacksTransactionData {
+        StacksTransactionData {
+            transaction_identifier: TransactionIdentifier { hash: txid.into() },
+            operations: vec![],
+            metadata: StacksTransactionMetadata {
+                success: true,
+                result: "".into(),
+                raw_tx: "0x00".to_string(),
+                execution_cost: None,
+                sender: BOB.into(),
+                fee: 180,
+                sponsor: None,
+                kind: StacksTransactionKind::ContractCall,
+                receipt: StacksTransactionReceipt {
+                    mutated_contracts_radius: HashSet::new(),
+                    mutated_assets_radius: HashSet::new(),
+                    events,
+                },
+                description: "".into(),
+            },
+        }
+    }
+
+    fn stx_transfer(sender: &str, recipient: &str, amount: u64) -> StacksTransactionEvent {
+        StacksTransactionEvent::STXTransferEvent(STXTransferEventData {
+            sender: sender.into(),
+            recipient: recipient.into(),
+            amount: amount.to_string(),
+        })
+    }
+
+    fn ft_mint(recipient: &str, amount: u64) -> StacksTransactionEvent {
+        StacksTransactionEvent::FTMintEvent(FTMintEventData {
+            asset_class_identifier: token(),
+            recipient: recipient.into(),
+            amount: amount.to_string(),
+        })
+    }
+
+    fn block_identifier(index: u64) -> BlockIdentifier {
+        BlockIdentifier {
+            index,
+            hash: format!("0x{:02x}", index),
+        }
+    }
+
+    fn balance(processor: &WalletProcessor, asset_id: &str) -> u128 {
+        let db = wallet_db(&processor.storage_driver, ALICE).unwrap();
+        processor.get_balance(&*db, asset_id)
+    }
+
+    fn wallet_state(processor: &WalletProcessor, cursor: Option<String>) -> WalletStateResponse {
+        let (tx, _rx) = channel();
+        let request = WalletStateRequest {
+            tx,
+            principal: ALICE.into(),
+            protocol_id: 1,
+            page_size: 2,
+            activity_cursor: cursor,
+        };
+        processor.get_wallet_state(&request).unwrap()
+    }
+
+    /// Serves the STX balance of 1000 and the token balance of 75 of any
+    /// principal.
+    fn mock_node() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(len) = line.strip_prefix("content-length:") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                }
+                let mut request_body = vec![0u8; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                let body = if request_line.contains("/v2/accounts/") {
+                    r#"{"balance":"0x000000000000000000000000000003e8","locked":"0x00000000000000000000000000000000","unlock_height":0,"nonce":0,"balance_proof":"","nonce_proof":""}"#
+                } else {
+                    r#"{"okay":true,"result":"0x010000000000000000000000000000004b"}"#
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn balances_are_applied_and_rolled_back() {
+        let mut processor = WalletProcessor::new(StorageDriver::in_memory(), ALICE.into(), None);
+        processor.handle_transactions_batch(
+            block_identifier(1),
+            None,
+            vec![transaction(
+                "0x01",
+                vec![stx_transfer(BOB, ALICE, 100), ft_mint(ALICE, 50)],
+            )],
+        );
+        assert_eq!(balance(&processor, STX_ASSET_IDENTIFIER), 100);
+        assert_eq!(balance(&processor, &token()), 50);
+
+        processor.handle_transactions_batch(
+            block_identifier(2),
+            None,
+            vec![transaction("0x02", vec![stx_transfer(ALICE, BOB, 30)])],
+        );
+        assert_eq!(balance(&processor, STX_ASSET_IDENTIFIER), 70);
+        assert_eq!(
+            wallet_state(&processor, None).block_identifier,
+            Some(block_identifier(2))
+        );
+
+        processor.rollback_transactions_batch(&block_identifier(2), None);
+        assert_eq!(balance(&processor, STX_ASSET_IDENTIFIER), 100);
+        let state = wallet_state(&processor, None);
+        assert_eq!(state.block_identifier, Some(block_identifier(1)));
+        assert_eq!(state.activity.len(), 2);
+        assert_eq!(state.balances.len(), 2);
+    }
+
+    #[test]
+    fn microblocks_are_written_at_their_anchored_block_height() {
+        let mut processor = WalletProcessor::new(StorageDriver::in_memory(), ALICE.into(), None);
+        processor.handle_transactions_batch(
+            block_identifier(1),
+            None,
+            vec![transaction("0x01", vec![stx_transfer(BOB, ALICE, 100)])],
+        );
+        let microblock = BlockIdentifier {
+            index: 1,
+            hash: "0xm1".into(),
+        };
+        processor.handle_transactions_batch(
+            microblock.clone(),
+            Some(block_identifier(1)),
+            vec![transaction("0x02", vec![stx_transfer(BOB, ALICE, 10)])],
+        );
+
+        // The microblock follows its anchored block, and is not the last
+        // applied block
+        let state = wallet_state(&processor, None);
+        assert_eq!(state.block_identifier, Some(block_identifier(1)));
+        assert_eq!(state.activity.len(), 2);
+        assert_eq!(state.activity[0].transaction_identifier.hash, "0x02");
+        assert_eq!(balance(&processor, STX_ASSET_IDENTIFIER), 110);
+
+        processor.rollback_transactions_batch(&microblock, Some(&block_identifier(1)));
+        assert_eq!(balance(&processor, STX_ASSET_IDENTIFIER), 100);
+        assert_eq!(wallet_state(&processor, None).activity.len(), 1);
+    }
+
+    #[test]
+    fn balances_never_seen_are_seeded_from_node() {
+        let mut processor =
+            WalletProcessor::new(StorageDriver::in_memory(), ALICE.into(), Some(mock_node()));
+        processor.handle_transactions_batch(
+            block_identifier(1),
+            None,
+            vec![transaction(
+                "0x01",
+                vec![stx_transfer(BOB, ALICE, 100), ft_mint(ALICE, 50)],
+            )],
+        );
+        // The node reports the balances as of the block, its movements included
+        assert_eq!(balance(&processor, STX_ASSET_IDENTIFIER), 1000);
+        assert_eq!(balance(&processor, &token()), 75);
+
+        // Balances seen already are not read again
+        processor.handle_transactions_batch(
+            block_identifier(2),
+            None,
+            vec![transaction("0x02", vec![stx_transfer(ALICE, BOB, 30)])],
+        );
+        assert_eq!(balance(&processor, STX_ASSET_IDENTIFIER), 970);
+    }
+
+    #[test]
+    fn wallet_activity_is_paginated() {
+        let mut processor = WalletProcessor::new(StorageDriver::in_memory(), ALICE.into(), None);
+        for index in 1..=3 {
+            processor.handle_transactions_batch(
+                block_identifier(index),
+                None,
+                vec![transaction(
+                    &format!("0x{:02x}", index),
+                    vec![stx_transfer(BOB, ALICE, 10)],
+                )],
+            );
+        }
+
+        // Most recent first
+        let page = wallet_state(&processor, None);
+        let txids = page
+            .activity
+            .iter()
+            .map(|entry| entry.transaction_identifier.hash.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(txids, vec!["0x03", "0x02"]);
+        assert_eq!(page.activity_page_size, 2);
+
+        let page = wallet_state(&processor, page.activity_next_cursor);
+        assert_eq!(page.activity.len(), 1);
+        assert_eq!(page.activity[0].transaction_identifier.hash, "0x01");
+        assert_eq!(page.activity_next_cursor, None);
+    }
+
+    #[test]
+    fn wallet_events_keep_events_involving_principal() {
+        let tx = StacksTransactionData {
+            transaction_identifier: TransactionIdentifier { hash: "0".into() },
+            operations: vec![],
+            metadata: StacksTransactionMetadata {
+                success: true,
+                result: "".into(),
+                raw_tx: "0x00".to_string(),
+                execution_cost: None,
+                sender: ALICE.into(),
+                fee: 180,
+                sponsor: Some(BOB.into()),
+                kind: StacksTransactionKind::ContractCall,
+                receipt: StacksTransactionReceipt {
+                    mutated_contracts_radius: HashSet::new(),
+                    mutated_assets_radius: HashSet::new(),
+                    events: vec![
+                        StacksTransactionEvent::STXTransferEvent(STXTransferEventData {
+                            sender: ALICE.into(),
+                            recipient: BOB.into(),
+                            amount: "100".into(),
+                        }),
+                        StacksTransactionEvent::FTTransferEvent(FTTransferEventData {
+                            asset_class_identifier: format!("{}.token::token", BOB),
+                            sender: BOB.into(),
+                            recipient: format!("{}.vault", BOB),
+                            amount: "1".into(),
+                        }),
+                        StacksTransactionEvent::NFTMintEvent(NFTMintEventData {
+                            asset_class_identifier: format!("{}.nft::nft", BOB),
+                            recipient: ALICE.into(),
+                            hex_asset_identifier: "0x0100000000000000000000000000000001".into(),
+                        }),
+                    ],
+                },
+                description: "".into(),
+            },
+        };
+
+        // The fee is paid by the sponsor
+        let events = wallet_events(ALICE, &tx);
+        assert_eq!(events.len(), 3);
+        match &events[0] {
+            WalletEventStoredValue::Transaction(data) => assert_eq!(data.fee, 0),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(matches!(events[2], WalletEventStoredValue::NFT(..)));
+
+        let events = wallet_events(BOB, &tx);
+        assert_eq!(events.len(), 3);
+        match &events[0] {
+            WalletEventStoredValue::Transaction(data) => assert_eq!(data.fee, 180),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        assert!(wallet_events(&format!("{}.other", BOB), &tx).is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+use super::{Datastore, ScanDirection, StorageDriver};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+/// Pending writes, by namespace then by key. None stands for a deletion.
+pub type PendingWrites = BTreeMap<String, BTreeMap<Vec<u8>, Option<Vec<u8>>>>;
+
+/// Writes buffered across namespaces, and applied at once by `commit`: a
+/// crash can not leave a block half applied.
+pub struct WriteBatch {
+    storage_driver: StorageDriver,
+    pending: Arc<Mutex<PendingWrites>>,
+}
+
+impl WriteBatch {
+    pub fn new(storage_driver: &StorageDriver) -> WriteBatch {
+        WriteBatch {
+            storage_driver: storage_driver.clone(),
+            pending: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Opens the datastore `namespace` through the batch. Reads see the
+    /// pending writes, writes are held until `commit`.
+    pub fn open(&self, namespace: &str) -> Result<Box<dyn Datastore>, String> {
+        Ok(Box::new(BatchedDatastore {
+            db: self.storage_driver.open(namespace)?,
+            namespace: namespace.to_string(),
+            pending: self.pending.clone(),
+        }))
+    }
+
+    pub fn commit(self) -> Result<(), String> {
+        let pending = std::mem::take(
+            &mut *self
+                .pending
+                .lock()
+                .map_err(|_| "unable to commit batch: lock poisoned".to_string())?,
+        );
+        self.storage_driver.write(pending)
+    }
+}
+
+struct BatchedDatastore {
+    db: Box<dyn Datastore>,
+    namespace: String,
+    pending: Arc<Mutex<PendingWrites>>,
+}
+
+impl BatchedDatastore {
+    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<(), String> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| "unable to write batch: lock poisoned".to_string())?;
+        pending
+            .entry(self.namespace.clone())
+            .or_default()
+            .insert(key.to_vec(), value);
+        Ok(())
+    }
+}
+
+impl Datastore for BatchedDatastore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let pending = self
+            .pending
+            .lock()
+            .map_err(|_| "unable to read batch: lock poisoned".to_string())?;
+        if let Some(value) = pending
+            .get(&self.namespace)
+            .and_then(|writes| writes.get(key))
+        {
+            return Ok(value.clone());
+        }
+        self.db.get(key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.write(key, Some(value.to_vec()))
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.write(key, None)
+    }
+
+    fn visit(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        direction: ScanDirection,
+        visitor: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, String>,
+    ) -> Result<(), String> {
+        // Batches are short lived and seldom scanned: merge the pending writes
+        // with the stored entries of the whole range.
+        let mut entries = BTreeMap::new();
+        self.db
+            .visit(prefix, cursor, direction, &mut |key, value| {
+                entries.insert(key.to_vec(), value.to_vec());
+                Ok(true)
+            })?;
+        {
+            let pending = self
+                .pending
+                .lock()
+                .map_err(|_| "unable to read batch: lock poisoned".to_string())?;
+            if let Some(writes) = pending.get(&self.namespace) {
+                let range = match (direction, cursor) {
+                    (ScanDirection::Forward, Some(cursor)) => {
+                        (Bound::Excluded(cursor.to_vec()), Bound::Unbounded)
+                    }
+                    (ScanDirection::Reverse, Some(cursor)) => (
+                        Bound::Included(prefix.to_vec()),
+                        Bound::Excluded(cursor.to_vec()),
+                    ),
+                    (_, None) => (Bound::Included(prefix.to_vec()), Bound::Unbounded),
+                };
+                for (key, value) in writes
+                    .range::<Vec<u8>, _>(range)
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                {
+                    match value {
+                        Some(value) => entries.insert(key.clone(), value.clone()),
+                        None => entries.remove(key),
+                    };
+                }
+            }
+        }
+        let entries: Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)>> = match direction {
+            ScanDirection::Forward => Box::new(entries.into_iter()),
+            ScanDirection::Reverse => Box::new(entries.into_iter().rev()),
+        };
+        for (key, value) in entries {
+            if !visitor(&key, &value)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_is_applied_on_commit() {
+        let storage_driver = StorageDriver::in_memory();
+        let blocks_db = storage_driver.open("blocks").unwrap();
+        blocks_db.put(b"tip", b"1").unwrap();
+        blocks_db.put(b"stale", b"1").unwrap();
+
+        let batch = WriteBatch::new(&storage_driver);
+        let batched_blocks_db = batch.open("blocks").unwrap();
+        let batched_deployments_db = batch.open("deployments").unwrap();
+        batched_blocks_db.put(b"block", b"2").unwrap();
+        batched_blocks_db.put(b"tip", b"2").unwrap();
+        batched_blocks_db.delete(b"stale").unwrap();
+        batched_deployments_db.put(b"contract", b"2").unwrap();
+
+        // Pending writes are only visible through the batch
+        assert_eq!(batched_blocks_db.get(b"tip").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(
+            batched_blocks_db.scan_prefix(&[]).unwrap(),
+            vec![
+                (b"block".to_vec(), b"2".to_vec()),
+                (b"tip".to_vec(), b"2".to_vec())
+            ]
+        );
+        assert_eq!(blocks_db.get(b"tip").unwrap(), Some(b"1".to_vec()));
+
+        batch.commit().unwrap();
+        assert_eq!(
+            blocks_db.scan_prefix(&[]).unwrap(),
+            vec![
+                (b"block".to_vec(), b"2".to_vec()),
+                (b"tip".to_vec(), b"2".to_vec())
+            ]
+        );
+        let deployments_db = storage_driver.open("deployments").unwrap();
+        assert_eq!(
+            deployments_db.get(b"contract").unwrap(),
+            Some(b"2".to_vec())
+        );
+    }
+}
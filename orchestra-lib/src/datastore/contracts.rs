@@ -1,138 +1,303 @@
-use std::path::PathBuf;
-
-use super::StorageDriver;
-use clarinet_lib::clarity_repl::clarity::util::hash::hex_bytes;
-use ripemd::{Digest, Ripemd160, Ripemd320};
-use rocksdb::{Options, DB};
-
-pub enum DBKey<'a> {
-    FullAnalysis,
-    Interface,
-    Var(&'a str),
-    VarEvent(&'a str, u64, u32),
-    VarEventScanBlock(&'a str, u64),
-    VarEventScan(&'a str),
-    MapEntry(&'a str, &'a str),
-    MapScan(&'a str),
-    MapEvent(&'a str, u64, u32),
-    MapEventScanBlock(&'a str, u64),
-    MapEventScan(&'a str),
-    FT(&'a str, &'a str),
-    FTScan(&'a str),
-    FTEvent(&'a str, u64, u32),
-    FTEventScanBlock(&'a str, u64),
-    FTEventScan(&'a str),
-    NFT(&'a str, &'a str),
-    NFTScan(&'a str),
-    NFTEvent(&'a str, u64, u32),
-    NFTEventScan(&'a str),
-    NFTEventScanBlock(&'a str, u64),
+use super::keys::decode_event_position;
+use super::{Datastore, ScanDirection, StorageDriver};
+use crate::types::StoredVersion;
+use serde::de::DeserializeOwned;
+
+pub fn contract_db_namespace(contract_id: &str) -> String {
+    format!("contract:{}", contract_id)
 }
 
 pub fn contract_db_delete_all(storage_driver: &StorageDriver, contract_id: &str) {
-    let path = contract_db_path(storage_driver, contract_id);
-    let _ = std::fs::remove_dir_all(path);
+    storage_driver
+        .delete_all(&contract_db_namespace(contract_id))
+        .expect("unable to delete contract datastore")
 }
 
-pub fn contract_db_path(storage_driver: &StorageDriver, contract_id: &str) -> PathBuf {
-    let mut working_dir = match storage_driver {
-        StorageDriver::Filesystem(ref config) => config.working_dir.clone(),
-    };
-    working_dir.push("contracts");
-    working_dir.push(&contract_id);
-    working_dir
+pub fn contract_db(storage_driver: &StorageDriver, contract_id: &str) -> Box<dyn Datastore> {
+    storage_driver
+        .open(&contract_db_namespace(contract_id))
+        .expect("unable to open contract datastore")
 }
 
-pub fn contract_db_read(storage_driver: &StorageDriver, contract_id: &str) -> DB {
-    let working_dir = contract_db_path(storage_driver, contract_id);
-    let options = Options::default();
-    DB::open_for_read_only(&options, working_dir, true).unwrap()
+/// A page of a scan, along with the cursor to resume from, if any is left.
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Vec<u8>>,
 }
 
-pub fn contract_db_write(storage_driver: &StorageDriver, contract_id: &str) -> DB {
-    let working_dir = contract_db_path(storage_driver, contract_id);
-    let options = Options::default();
-    DB::open_default(working_dir).unwrap()
+fn split_position<'a>(prefix: &[u8], key: &'a [u8]) -> Result<(&'a [u8], u64, u32), String> {
+    if key.len() < prefix.len() + 12 {
+        return Err(format!("unable to read datastore: malformed key {:?}", key));
+    }
+    let (entry, position) = key[prefix.len()..].split_at(key.len() - prefix.len() - 12);
+    let (block_index, index) = decode_event_position(position)
+        .ok_or_else(|| format!("unable to read datastore: malformed key {:?}", key))?;
+    Ok((entry, block_index, index))
 }
 
-pub fn db_key(key: DBKey, contract_id: &str) -> Vec<u8> {
-    match key {
-        DBKey::FullAnalysis => format!("{}::#analysis", contract_id).as_bytes().to_vec(),
-        DBKey::Interface => format!("{}::#interface", contract_id).as_bytes().to_vec(),
-        DBKey::Var(var) => format!("var::{}::{}", contract_id, var).as_bytes().to_vec(),
-        DBKey::MapEntry(map, key) => {
-            let mut prefix = format!("map::{}::{}@", contract_id, map)
-                .as_bytes()
-                .to_vec();
-            let mut hasher = Ripemd160::new();
-            hasher.update(key);
-            let mut result = hasher.finalize().to_vec();
-            prefix.append(&mut result);
-            prefix
-        }
-        DBKey::MapScan(map) => format!("map::{}::{}@", contract_id, map)
-            .as_bytes()
-            .to_vec(),
-        DBKey::FT(asset_id, owner) => format!("ft::{}@{}", asset_id, owner).as_bytes().to_vec(),
-        DBKey::FTScan(asset_id) => format!("ft::{}@", asset_id).as_bytes().to_vec(),
-        DBKey::NFT(asset_id, key) => {
-            let mut prefix = format!("nft::{}::id@", asset_id).as_bytes().to_vec();
-            let mut hasher = Ripemd160::new();
-            hasher.update(key);
-            let mut result = hasher.finalize().to_vec();
-            prefix.append(&mut result);
-            prefix
+/// Returns the state of the entries under `prefix` (one of the `*VersionScan`
+/// keys) once the block `block_index` was processed: for each entry, its last
+/// version written at or before this block. Deleted entries are skipped.
+pub fn scan_versions<T: DeserializeOwned>(
+    db: &dyn Datastore,
+    prefix: &[u8],
+    block_index: u64,
+) -> Result<Vec<StoredVersion<T>>, String> {
+    Ok(scan_versions_page(db, prefix, block_index, None, usize::MAX)?.items)
+}
+
+/// Same as `scan_versions`, limited to `limit` entries, starting after the
+/// entry designated by `cursor`.
+pub fn scan_versions_page<T: DeserializeOwned>(
+    db: &dyn Datastore,
+    prefix: &[u8],
+    block_index: u64,
+    cursor: Option<&[u8]>,
+    limit: usize,
+) -> Result<Page<StoredVersion<T>>, String> {
+    // Versions of an entry are contiguous, sorted by block then by position:
+    // the last one at or before `block_index` is the state of the entry.
+    let mut current_entry: Option<Vec<u8>> = None;
+    let mut current_version: Option<Vec<u8>> = None;
+    let mut live_entries: Vec<(Vec<u8>, StoredVersion<T>)> = vec![];
+    let mut has_more = false;
+
+    db.visit(prefix, cursor, ScanDirection::Forward, &mut |key, value| {
+        let (entry, version_block_index, _) = split_position(prefix, key)?;
+        if current_entry.as_deref() != Some(entry) {
+            let previous_entry = current_entry.replace(entry.to_vec());
+            let version = current_version.take();
+            if !push_live_entry(previous_entry, version, limit, &mut live_entries)? {
+                has_more = true;
+                return Ok(false);
+            }
         }
-        DBKey::NFTScan(asset_id) => format!("nft::{}::id@", asset_id).as_bytes().to_vec(),
-        DBKey::VarEvent(var, block_index, event_index) => format!(
-            "var::{}::{}#events::{}/{}",
-            contract_id, var, block_index, event_index
-        )
-        .as_bytes()
-        .to_vec(),
-        DBKey::VarEventScanBlock(var, block_index) => {
-            format!("var::{}::{}#events::{}/", contract_id, var, block_index)
-                .as_bytes()
-                .to_vec()
+        if version_block_index <= block_index {
+            current_version = Some(value.to_vec());
         }
-        DBKey::VarEventScan(var) => format!("var::{}::{}#events::", contract_id, var)
-            .as_bytes()
-            .to_vec(),
-        DBKey::MapEvent(map, block_index, event_index) => format!(
-            "map::{}::{}#events::{}/{}",
-            contract_id, map, block_index, event_index
-        )
-        .as_bytes()
-        .to_vec(),
-        DBKey::MapEventScanBlock(map, block_index) => {
-            format!("map::{}::{}#events::{}/", contract_id, map, block_index)
-                .as_bytes()
-                .to_vec()
+        Ok(true)
+    })?;
+    if !has_more {
+        has_more = !push_live_entry(current_entry, current_version, limit, &mut live_entries)?;
+    }
+
+    let next_cursor = match (has_more, live_entries.last()) {
+        // Resume after every version of the last entry returned
+        (true, Some((entry, _))) => {
+            let mut cursor = prefix.to_vec();
+            cursor.extend_from_slice(entry);
+            cursor.extend_from_slice(&[0xff; 12]);
+            Some(cursor)
         }
-        DBKey::MapEventScan(map) => format!("map::{}::{}#events::", contract_id, map)
-            .as_bytes()
-            .to_vec(),
-        DBKey::FTEvent(asset_id, block_index, event_index) => {
-            format!("ft::{}#events::{}/{}", asset_id, block_index, event_index)
-                .as_bytes()
-                .to_vec()
+        _ => None,
+    };
+    let items = live_entries
+        .into_iter()
+        .map(|(_, version)| version)
+        .collect();
+    Ok(Page { items, next_cursor })
+}
+
+/// Keeps the last version of an entry, unless it was deleted. Returns false
+/// when the page is already full.
+fn push_live_entry<T: DeserializeOwned>(
+    entry: Option<Vec<u8>>,
+    version: Option<Vec<u8>>,
+    limit: usize,
+    live_entries: &mut Vec<(Vec<u8>, StoredVersion<T>)>,
+) -> Result<bool, String> {
+    if let (Some(entry), Some(version)) = (entry, version) {
+        let version = serde_json::from_slice::<StoredVersion<T>>(&version)
+            .map_err(|e| format!("unable to read version: {}", e))?;
+        if version.value.is_some() {
+            if live_entries.len() == limit {
+                return Ok(false);
+            }
+            live_entries.push((entry, version));
         }
-        DBKey::FTEventScanBlock(asset_id, block_index) => {
-            format!("ft::{}#events::{}/", asset_id, block_index)
-                .as_bytes()
-                .to_vec()
+    }
+    Ok(true)
+}
+
+/// Returns the last version of a single-valued entry (a data var, the supply
+/// of a fungible token) written before `seek_key`, found by seeking backward
+/// from it. `seek_key` is the version key of the entry at the requested block
+/// and position `u32::MAX`. Deletions are returned as a version with no value.
+pub fn latest_version<T: DeserializeOwned>(
+    db: &dyn Datastore,
+    prefix: &[u8],
+    seek_key: &[u8],
+) -> Result<Option<StoredVersion<T>>, String> {
+    let mut latest = None;
+    db.visit(
+        prefix,
+        Some(seek_key),
+        ScanDirection::Reverse,
+        &mut |_, value| {
+            latest = Some(value.to_vec());
+            Ok(false)
+        },
+    )?;
+    latest
+        .map(|version| {
+            serde_json::from_slice::<StoredVersion<T>>(&version)
+                .map_err(|e| format!("unable to read version: {}", e))
+        })
+        .transpose()
+}
+
+/// Returns at most `limit` events under `prefix` (one of the `*EventScan`
+/// keys), most recent first, skipping the events of the blocks after
+/// `block_index`. Items are `(block_index, event_index, value)`.
+pub fn scan_events_page(
+    db: &dyn Datastore,
+    prefix: &[u8],
+    block_index: u64,
+    cursor: Option<&[u8]>,
+    limit: usize,
+) -> Result<Page<(u64, u32, Vec<u8>)>, String> {
+    let mut items = vec![];
+    let mut next_cursor = None;
+    let mut last_key = None;
+    db.visit(prefix, cursor, ScanDirection::Reverse, &mut |key, value| {
+        let (_, event_block_index, event_index) = split_position(prefix, key)?;
+        if event_block_index > block_index {
+            return Ok(true);
         }
-        DBKey::FTEventScan(asset_id) => format!("ft::{}#events::", asset_id).as_bytes().to_vec(),
-        DBKey::NFTEvent(asset_id, block_index, event_index) => {
-            format!("nft::{}#events::{}/{}", asset_id, block_index, event_index)
-                .as_bytes()
-                .to_vec()
+        if items.len() == limit {
+            next_cursor = last_key.take();
+            return Ok(false);
         }
-        DBKey::NFTEventScanBlock(asset_id, block_index) => {
-            format!("nft::{}#events::{}/", asset_id, block_index)
-                .as_bytes()
-                .to_vec()
+        items.push((event_block_index, event_index, value.to_vec()));
+        last_key = Some(key.to_vec());
+        Ok(true)
+    })?;
+    Ok(Page { items, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::keys::{db_key, DBKey};
+    use super::*;
+    use crate::types::DataMapStoredEntry;
+    use clarinet_lib::types::{BlockIdentifier, TransactionIdentifier};
+
+    fn put_version(db: &dyn Datastore, key: &str, value: Option<&str>, block_index: u64) {
+        let version = StoredVersion {
+            value: value.map(|value| DataMapStoredEntry {
+                hex_key: key.to_string(),
+                hex_value: value.to_string(),
+            }),
+            block_identifier: BlockIdentifier {
+                index: block_index,
+                hash: format!("0x{:02x}", block_index),
+            },
+            transaction_identifier: TransactionIdentifier {
+                hash: format!("0x{:02x}{}", block_index, key),
+            },
+        };
+        db.put(
+            &db_key(DBKey::MapEntryVersion("my-map", key, block_index, 0)),
+            &serde_json::to_vec(&version).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn map_is_read_as_of_a_block() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = contract_db(
+            &storage_driver,
+            "S1G2081040G2081040G2081040G208105NK8P91.test",
+        );
+        put_version(&*db, "0x01", Some("0x0a"), 1);
+        put_version(&*db, "0x02", Some("0x0b"), 2);
+        put_version(&*db, "0x01", Some("0x0c"), 3);
+        put_version(&*db, "0x02", None, 4);
+
+        let prefix = db_key(DBKey::MapVersionScan("my-map"));
+        let read = |block_index| {
+            scan_versions::<DataMapStoredEntry>(&*db, &prefix, block_index)
+                .unwrap()
+                .into_iter()
+                .map(|version| {
+                    (
+                        version.value.unwrap().hex_value,
+                        version.block_identifier.index,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(read(0), vec![]);
+        assert_eq!(read(2), vec![("0x0a".into(), 1), ("0x0b".into(), 2)]);
+        assert_eq!(read(3), vec![("0x0c".into(), 3), ("0x0b".into(), 2)]);
+        assert_eq!(read(4), vec![("0x0c".into(), 3)]);
+
+        // Deleted entries do not take a slot in the page
+        put_version(&*db, "0x03", Some("0x0d"), 4);
+        let page = scan_versions_page::<DataMapStoredEntry>(&*db, &prefix, 4, None, 1).unwrap();
+        assert_eq!(page.items[0].value.as_ref().unwrap().hex_value, "0x0c");
+        let cursor = page.next_cursor.unwrap();
+        let page =
+            scan_versions_page::<DataMapStoredEntry>(&*db, &prefix, 4, Some(&cursor), 1).unwrap();
+        assert_eq!(page.items[0].value.as_ref().unwrap().hex_value, "0x0d");
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn latest_var_version_is_sought_backward() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = contract_db(
+            &storage_driver,
+            "S1G2081040G2081040G2081040G208105NK8P91.test",
+        );
+        for (block_index, position, value) in [(1, 0, "0x01"), (3, 0, "0x02"), (3, 1, "0x03")] {
+            let version = StoredVersion {
+                value: Some(value.to_string()),
+                block_identifier: BlockIdentifier {
+                    index: block_index,
+                    hash: format!("0x{:02x}", block_index),
+                },
+                transaction_identifier: TransactionIdentifier {
+                    hash: format!("0x{:02x}{}", block_index, position),
+                },
+            };
+            db.put(
+                &db_key(DBKey::VarVersion("my-var", block_index, position)),
+                &serde_json::to_vec(&version).unwrap(),
+            )
+            .unwrap();
         }
-        DBKey::NFTEventScan(asset_id) => format!("nft::{}#events::", asset_id).as_bytes().to_vec(),
+        // Versions of another var sorting right after must not be picked
+        db.put(
+            &db_key(DBKey::VarVersion("my-var-2", 0, 0)),
+            &serde_json::to_vec(&StoredVersion {
+                value: Some("0xff".to_string()),
+                block_identifier: BlockIdentifier {
+                    index: 0,
+                    hash: "0x00".into(),
+                },
+                transaction_identifier: TransactionIdentifier {
+                    hash: "0x00".into(),
+                },
+            })
+            .unwrap(),
+        )
+        .unwrap();
+
+        let prefix = db_key(DBKey::VarVersionScan("my-var"));
+        let read = |block_index| {
+            latest_version::<String>(
+                &*db,
+                &prefix,
+                &db_key(DBKey::VarVersion("my-var", block_index, u32::MAX)),
+            )
+            .unwrap()
+            .and_then(|version| version.value)
+        };
+        assert_eq!(read(0), None);
+        assert_eq!(read(2), Some("0x01".into()));
+        assert_eq!(read(3), Some("0x03".into()));
+        assert_eq!(read(u64::MAX), Some("0x03".into()));
     }
 }
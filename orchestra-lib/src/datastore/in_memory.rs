@@ -0,0 +1,208 @@
+use super::batch::PendingWrites;
+use super::{prefix_upper_bound, Datastore, ScanDirection};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock};
+
+type Entries = Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>;
+
+/// Namespaces kept in memory, shared by every actor holding a clone of the
+/// storage. Used by tests, and discarded with the process.
+#[derive(Clone, Default)]
+pub struct InMemoryStorage {
+    namespaces: Arc<Mutex<HashMap<String, Entries>>>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> InMemoryStorage {
+        InMemoryStorage::default()
+    }
+
+    pub fn open(&self, namespace: &str) -> InMemoryDatastore {
+        let mut namespaces = self.namespaces.lock().expect("storage lock poisoned");
+        let entries = namespaces
+            .entry(namespace.to_string())
+            .or_insert_with(|| Arc::new(RwLock::new(BTreeMap::new())));
+        InMemoryDatastore {
+            entries: entries.clone(),
+        }
+    }
+
+    pub fn write(&self, pending: PendingWrites) -> Result<(), String> {
+        let namespaces = pending
+            .keys()
+            .map(|namespace| self.open(namespace).entries)
+            .collect::<Vec<_>>();
+        // Every namespace is locked before the first write, in a stable order
+        let mut guards = vec![];
+        for entries in namespaces.iter() {
+            guards.push(
+                entries
+                    .write()
+                    .map_err(|_| "unable to write datastore: lock poisoned".to_string())?,
+            );
+        }
+        for (entries, (_, writes)) in guards.iter_mut().zip(pending.into_iter()) {
+            for (key, value) in writes.into_iter() {
+                match value {
+                    Some(value) => entries.insert(key, value),
+                    None => entries.remove(&key),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Clears a namespace in place, like `OnDiskStorage::delete_all`.
+    pub fn delete_all(&self, namespace: &str) {
+        let namespaces = self.namespaces.lock().expect("storage lock poisoned");
+        if let Some(entries) = namespaces.get(namespace) {
+            entries.write().expect("storage lock poisoned").clear();
+        }
+    }
+}
+
+impl fmt::Debug for InMemoryStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InMemoryStorage")
+    }
+}
+
+pub struct InMemoryDatastore {
+    entries: Entries,
+}
+
+impl Datastore for InMemoryDatastore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| "unable to read datastore: lock poisoned".to_string())?;
+        Ok(entries.get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| "unable to write datastore: lock poisoned".to_string())?;
+        entries.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        let mut entries = self
+            .entries
+            .write()
+            .map_err(|_| "unable to write datastore: lock poisoned".to_string())?;
+        entries.remove(key);
+        Ok(())
+    }
+
+    fn visit(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        direction: ScanDirection,
+        visitor: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, String>,
+    ) -> Result<(), String> {
+        let entries = self
+            .entries
+            .read()
+            .map_err(|_| "unable to read datastore: lock poisoned".to_string())?;
+        let range = match (direction, cursor) {
+            (ScanDirection::Forward, Some(cursor)) => {
+                (Bound::Excluded(cursor.to_vec()), Bound::Unbounded)
+            }
+            (ScanDirection::Reverse, Some(cursor)) => (
+                Bound::Included(prefix.to_vec()),
+                Bound::Excluded(cursor.to_vec()),
+            ),
+            (_, None) => match prefix_upper_bound(prefix) {
+                Some(upper_bound) => (
+                    Bound::Included(prefix.to_vec()),
+                    Bound::Excluded(upper_bound),
+                ),
+                None => (Bound::Included(prefix.to_vec()), Bound::Unbounded),
+            },
+        };
+        let mut range = entries.range::<Vec<u8>, _>(range);
+        loop {
+            let entry = match direction {
+                ScanDirection::Forward => range.next(),
+                ScanDirection::Reverse => range.next_back(),
+            };
+            match entry {
+                Some((key, value)) if key.starts_with(prefix) => {
+                    if !visitor(key, value)? {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces_are_shared_and_scanned_in_order() {
+        let storage = InMemoryStorage::new();
+        let writer = storage.open("stacks");
+        writer.put(b"map::b", b"2").unwrap();
+        writer.put(b"map::a", b"1").unwrap();
+        writer.put(b"var::a", b"3").unwrap();
+
+        let reader = storage.open("stacks");
+        let entries = reader.scan_prefix(b"map::").unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"map::a".to_vec(), b"1".to_vec()),
+                (b"map::b".to_vec(), b"2".to_vec())
+            ]
+        );
+        assert_eq!(storage.open("bitcoin").get(b"map::a").unwrap(), None);
+
+        storage.delete_all("stacks");
+        assert_eq!(storage.open("stacks").get(b"var::a").unwrap(), None);
+        // Datastores opened before the deletion keep working on the namespace.
+        writer.put(b"var::b", b"4").unwrap();
+        assert_eq!(reader.get(b"var::b").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(reader.get(b"map::a").unwrap(), None);
+    }
+
+    #[test]
+    fn pages_resume_after_cursor_in_both_directions() {
+        let storage = InMemoryStorage::new();
+        let db = storage.open("stacks");
+        for key in [b"a1", b"b1", b"b2", b"b3", b"c1"] {
+            db.put(key, b"").unwrap();
+        }
+        let keys = |entries: Vec<(Vec<u8>, Vec<u8>)>| {
+            entries
+                .into_iter()
+                .map(|(key, _)| String::from_utf8(key).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let page = db.scan_page(b"b", None, 2, ScanDirection::Forward).unwrap();
+        assert_eq!(keys(page), vec!["b1", "b2"]);
+        let page = db
+            .scan_page(b"b", Some(b"b2"), 2, ScanDirection::Forward)
+            .unwrap();
+        assert_eq!(keys(page), vec!["b3"]);
+
+        let page = db.scan_page(b"b", None, 2, ScanDirection::Reverse).unwrap();
+        assert_eq!(keys(page), vec!["b3", "b2"]);
+        let page = db
+            .scan_page(b"b", Some(b"b2"), 2, ScanDirection::Reverse)
+            .unwrap();
+        assert_eq!(keys(page), vec!["b1"]);
+    }
+}
@@ -0,0 +1,222 @@
+use super::keys::{db_key, DBKey};
+use super::{prune_below_reorg_depth, Datastore, ScanDirection};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Value of a key before a batch of writes, None if the key was missing.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct UndoEntry {
+    pub key: Vec<u8>,
+    pub previous_value: Option<Vec<u8>>,
+}
+
+/// Writes performed while processing a block, recorded so that they can be
+/// reverted if the block gets orphaned.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct UndoJournal {
+    pub entries: Vec<UndoEntry>,
+}
+
+#[derive(Default)]
+struct JournalState {
+    touched_keys: HashSet<Vec<u8>>,
+    journal: UndoJournal,
+}
+
+/// Datastore recording the previous value of every key it writes, the first
+/// time it writes it.
+pub struct JournaledDatastore<'a> {
+    db: &'a dyn Datastore,
+    state: Mutex<JournalState>,
+}
+
+impl<'a> JournaledDatastore<'a> {
+    pub fn new(db: &'a dyn Datastore) -> JournaledDatastore<'a> {
+        JournaledDatastore {
+            db,
+            state: Mutex::new(JournalState::default()),
+        }
+    }
+
+    fn record(&self, key: &[u8]) -> Result<(), String> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| "unable to write journal: lock poisoned".to_string())?;
+        if state.touched_keys.insert(key.to_vec()) {
+            let previous_value = self.db.get(key)?;
+            state.journal.entries.push(UndoEntry {
+                key: key.to_vec(),
+                previous_value,
+            });
+        }
+        Ok(())
+    }
+
+    /// Stores the journal under `journal_key`, in the underlying datastore.
+    pub fn commit(self, journal_key: &[u8]) -> Result<(), String> {
+        let state = self
+            .state
+            .into_inner()
+            .map_err(|_| "unable to write journal: lock poisoned".to_string())?;
+        let bytes = serde_json::to_vec(&state.journal)
+            .map_err(|e| format!("unable to write journal: {}", e))?;
+        self.db.put(journal_key, &bytes)
+    }
+}
+
+impl<'a> Datastore for JournaledDatastore<'a> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.db.get(key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.record(key)?;
+        self.db.put(key, value)
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.record(key)?;
+        self.db.delete(key)
+    }
+
+    fn visit(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        direction: ScanDirection,
+        visitor: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, String>,
+    ) -> Result<(), String> {
+        self.db.visit(prefix, cursor, direction, visitor)
+    }
+}
+
+/// Restores the values recorded in the journal stored under `journal_key`,
+/// and drops the journal. Returns false if there was no such journal.
+pub fn rollback(db: &dyn Datastore, journal_key: &[u8]) -> Result<bool, String> {
+    let bytes = match db.get(journal_key)? {
+        Some(bytes) => bytes,
+        None => return Ok(false),
+    };
+    let journal = serde_json::from_slice::<UndoJournal>(&bytes)
+        .map_err(|e| format!("unable to read journal: {}", e))?;
+    for entry in journal.entries.iter().rev() {
+        match entry.previous_value {
+            Some(ref value) => db.put(&entry.key, value)?,
+            None => db.delete(&entry.key)?,
+        }
+    }
+    db.delete(journal_key)?;
+    Ok(true)
+}
+
+/// Drops the journals of the blocks deeper than `MAX_REORG_DEPTH` below the
+/// block `tip_index`, which can no longer be orphaned.
+pub fn prune(db: &dyn Datastore, tip_index: u64) -> Result<(), String> {
+    prune_below_reorg_depth(db, &db_key(DBKey::UndoJournalScan), tip_index)
+}
+
+/// Returns the first position available at the height `block_index`, for
+/// the events and versions of a batch. Anchored blocks start at 0, and the
+/// microblocks built on them, written at the same height, follow.
+pub fn first_position(db: &dyn Datastore, block_index: u64) -> Result<u32, String> {
+    let bytes = match db.get(&db_key(DBKey::NextPosition))? {
+        Some(bytes) => bytes,
+        None => return Ok(0),
+    };
+    if bytes.len() != 12 {
+        return Err(format!(
+            "unable to read next position: malformed value {:?}",
+            bytes
+        ));
+    }
+    let mut index = [0u8; 8];
+    index.copy_from_slice(&bytes[..8]);
+    let mut position = [0u8; 4];
+    position.copy_from_slice(&bytes[8..]);
+    match u64::from_be_bytes(index) == block_index {
+        true => Ok(u32::from_be_bytes(position)),
+        false => Ok(0),
+    }
+}
+
+/// Records `position` as the next one available at the height `block_index`.
+/// Written along with the batch, so that rolling it back restores it.
+pub fn set_next_position(
+    db: &dyn Datastore,
+    block_index: u64,
+    position: u32,
+) -> Result<(), String> {
+    let mut bytes = block_index.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&position.to_be_bytes());
+    db.put(&db_key(DBKey::NextPosition), &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::{StorageDriver, MAX_REORG_DEPTH};
+
+    #[test]
+    fn rollback_restores_previous_state() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = storage_driver.open("contract").unwrap();
+        db.put(b"balance", b"10").unwrap();
+        db.put(b"nft", b"alice").unwrap();
+
+        let journaled = JournaledDatastore::new(&*db);
+        journaled.put(b"balance", b"7").unwrap();
+        journaled.put(b"balance", b"5").unwrap();
+        journaled.delete(b"nft").unwrap();
+        journaled.put(b"event", b"transfer").unwrap();
+        journaled.commit(b"journal").unwrap();
+        assert_eq!(db.get(b"balance").unwrap(), Some(b"5".to_vec()));
+
+        assert!(rollback(&*db, b"journal").unwrap());
+        assert_eq!(
+            db.scan_prefix(&[]).unwrap(),
+            vec![
+                (b"balance".to_vec(), b"10".to_vec()),
+                (b"nft".to_vec(), b"alice".to_vec())
+            ]
+        );
+        assert!(!rollback(&*db, b"journal").unwrap());
+    }
+
+    #[test]
+    fn journals_deeper_than_reorgs_are_pruned() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = storage_driver.open("contract").unwrap();
+        for index in [1, 2, 3] {
+            JournaledDatastore::new(&*db)
+                .commit(&db_key(DBKey::UndoJournal(index, "0x00")))
+                .unwrap();
+        }
+
+        prune(&*db, MAX_REORG_DEPTH + 2).unwrap();
+        let journals = db.scan_prefix(&db_key(DBKey::UndoJournalScan)).unwrap();
+        assert_eq!(journals.len(), 2);
+        assert!(!rollback(&*db, &db_key(DBKey::UndoJournal(1, "0x00"))).unwrap());
+        assert!(rollback(&*db, &db_key(DBKey::UndoJournal(2, "0x00"))).unwrap());
+    }
+
+    #[test]
+    fn microblocks_positions_follow_their_anchored_block() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = storage_driver.open("contract").unwrap();
+        assert_eq!(first_position(&*db, 5).unwrap(), 0);
+        set_next_position(&*db, 5, 3).unwrap();
+
+        let journaled = JournaledDatastore::new(&*db);
+        assert_eq!(first_position(&journaled, 5).unwrap(), 3);
+        set_next_position(&journaled, 5, 7).unwrap();
+        journaled
+            .commit(&db_key(DBKey::UndoJournal(5, "0xmicro")))
+            .unwrap();
+        assert_eq!(first_position(&*db, 5).unwrap(), 7);
+        assert_eq!(first_position(&*db, 6).unwrap(), 0);
+
+        assert!(rollback(&*db, &db_key(DBKey::UndoJournal(5, "0xmicro"))).unwrap());
+        assert_eq!(first_position(&*db, 5).unwrap(), 3);
+    }
+}
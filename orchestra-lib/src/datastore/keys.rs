@@ -0,0 +1,358 @@
+/// Version of the key layout described by `DBKey`. Bumped whenever the
+/// encoding changes, along with a new step in `migrations`.
+pub const SCHEMA_VERSION: u32 = 5;
+
+/// Keys of every namespace of a working dir.
+///
+/// A key is a tag byte followed by its components: strings are length
+/// prefixed (u32), and numbers are big-endian, so that a prefix scan returns
+/// the entries ordered by block height, then by event index. The last
+/// component of `MapEntry`, `FT`, `NFT`, `WalletBalance` and `WalletNFT` is
+/// written as-is, and can be read back from the remainder of the matching
+/// `*Scan` key.
+///
+/// `*Version` keys record every write to a field, ordered by entry, then by
+/// block height and position in the block (see `contracts::scan_versions`).
+/// `LastAppliedBlock` holds the identifier of the last anchored block written
+/// to a namespace, in the same batch as the block itself. `NextPosition` holds
+/// the height and position following the last batch written, microblocks
+/// being written at the height of their anchored block (see
+/// `journal::first_position`). `Microblock` keys are ordered by anchored block
+/// height, then by sequence. `PartialHistory` is set
+/// on contracts whose state was built without the blocks preceding it.
+/// `UndoJournal` keys hold the writes of a block, to revert on reorgs (see
+/// `journal`). `FiredTriggers` keys hold the triggers fired by a block, to
+/// notify on reorgs (see `triggers`).
+pub enum DBKey<'a> {
+    SchemaVersion,
+    LastAppliedBlock,
+    NextPosition,
+    // Blocks namespaces
+    Tip,
+    Block(&'a str),
+    BlockHash(u64),
+    Microblock(u64, u64),
+    MicroblockScan,
+    ContractDeployment(&'a str),
+    ContractDeploymentScan,
+    FiredTriggers(u64, &'a str),
+    FiredTriggersScan,
+    // Contracts namespaces
+    FullAnalysis,
+    Interface,
+    PartialHistory,
+    Var(&'a str),
+    VarEvent(&'a str, u64, u32),
+    VarEventScanBlock(&'a str, u64),
+    VarEventScan(&'a str),
+    VarVersion(&'a str, u64, u32),
+    VarVersionScan(&'a str),
+    MapEntry(&'a str, &'a str),
+    MapScan(&'a str),
+    MapEvent(&'a str, u64, u32),
+    MapEventScanBlock(&'a str, u64),
+    MapEventScan(&'a str),
+    MapEntryVersion(&'a str, &'a str, u64, u32),
+    MapVersionScan(&'a str),
+    FT(&'a str, &'a str),
+    FTScan(&'a str),
+    FTEvent(&'a str, u64, u32),
+    FTEventScanBlock(&'a str, u64),
+    FTEventScan(&'a str),
+    FTVersion(&'a str, &'a str, u64, u32),
+    FTVersionScan(&'a str),
+    FTSupply(&'a str),
+    FTSupplyVersion(&'a str, u64, u32),
+    FTSupplyVersionScan(&'a str),
+    NFT(&'a str, &'a str),
+    NFTScan(&'a str),
+    NFTEvent(&'a str, u64, u32),
+    NFTEventScanBlock(&'a str, u64),
+    NFTEventScan(&'a str),
+    NFTVersion(&'a str, &'a str, u64, u32),
+    NFTVersionScan(&'a str),
+    // Wallets namespaces
+    WalletBalance(&'a str),
+    WalletBalanceScan,
+    WalletNFT(&'a str, &'a str),
+    WalletNFTScan,
+    WalletActivity(u64, u32),
+    WalletActivityScan,
+    UndoJournal(u64, &'a str),
+    UndoJournalScan,
+}
+
+mod tags {
+    pub const SCHEMA_VERSION: u8 = 0x00;
+    pub const TIP: u8 = 0x01;
+    pub const BLOCK: u8 = 0x02;
+    pub const BLOCK_HASH: u8 = 0x03;
+    pub const MICROBLOCK: u8 = 0x04;
+    pub const CONTRACT_DEPLOYMENT: u8 = 0x05;
+    pub const LAST_APPLIED_BLOCK: u8 = 0x06;
+    pub const FIRED_TRIGGERS: u8 = 0x07;
+    pub const NEXT_POSITION: u8 = 0x08;
+    pub const FULL_ANALYSIS: u8 = 0x10;
+    pub const INTERFACE: u8 = 0x11;
+    pub const PARTIAL_HISTORY: u8 = 0x12;
+    pub const VAR: u8 = 0x20;
+    pub const VAR_EVENT: u8 = 0x21;
+    pub const VAR_VERSION: u8 = 0x22;
+    pub const MAP_ENTRY: u8 = 0x30;
+    pub const MAP_EVENT: u8 = 0x31;
+    pub const MAP_VERSION: u8 = 0x32;
+    pub const FT: u8 = 0x40;
+    pub const FT_EVENT: u8 = 0x41;
+    pub const FT_VERSION: u8 = 0x42;
+    pub const FT_SUPPLY: u8 = 0x43;
+    pub const FT_SUPPLY_VERSION: u8 = 0x44;
+    pub const NFT: u8 = 0x50;
+    pub const NFT_EVENT: u8 = 0x51;
+    pub const NFT_VERSION: u8 = 0x52;
+    pub const UNDO_JOURNAL: u8 = 0x60;
+    pub const WALLET_BALANCE: u8 = 0x70;
+    pub const WALLET_NFT: u8 = 0x71;
+    pub const WALLET_ACTIVITY: u8 = 0x72;
+}
+
+struct KeyBuilder {
+    bytes: Vec<u8>,
+}
+
+impl KeyBuilder {
+    fn new(tag: u8) -> KeyBuilder {
+        KeyBuilder { bytes: vec![tag] }
+    }
+
+    fn str(mut self, value: &str) -> KeyBuilder {
+        self.bytes
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.bytes.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> KeyBuilder {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> KeyBuilder {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn remainder(mut self, value: &str) -> KeyBuilder {
+        self.bytes.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    fn build(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub fn db_key(key: DBKey) -> Vec<u8> {
+    match key {
+        DBKey::SchemaVersion => KeyBuilder::new(tags::SCHEMA_VERSION).build(),
+        DBKey::LastAppliedBlock => KeyBuilder::new(tags::LAST_APPLIED_BLOCK).build(),
+        DBKey::NextPosition => KeyBuilder::new(tags::NEXT_POSITION).build(),
+        DBKey::Tip => KeyBuilder::new(tags::TIP).build(),
+        DBKey::Block(hash) => KeyBuilder::new(tags::BLOCK).str(hash).build(),
+        DBKey::BlockHash(block_index) => KeyBuilder::new(tags::BLOCK_HASH).u64(block_index).build(),
+        DBKey::Microblock(block_index, microblock_index) => KeyBuilder::new(tags::MICROBLOCK)
+            .u64(block_index)
+            .u64(microblock_index)
+            .build(),
+        DBKey::MicroblockScan => KeyBuilder::new(tags::MICROBLOCK).build(),
+        DBKey::ContractDeployment(contract_id) => KeyBuilder::new(tags::CONTRACT_DEPLOYMENT)
+            .str(contract_id)
+            .build(),
+        DBKey::ContractDeploymentScan => KeyBuilder::new(tags::CONTRACT_DEPLOYMENT).build(),
+        DBKey::FiredTriggers(block_index, block_hash) => KeyBuilder::new(tags::FIRED_TRIGGERS)
+            .u64(block_index)
+            .str(block_hash)
+            .build(),
+        DBKey::FiredTriggersScan => KeyBuilder::new(tags::FIRED_TRIGGERS).build(),
+        DBKey::FullAnalysis => KeyBuilder::new(tags::FULL_ANALYSIS).build(),
+        DBKey::Interface => KeyBuilder::new(tags::INTERFACE).build(),
+        DBKey::PartialHistory => KeyBuilder::new(tags::PARTIAL_HISTORY).build(),
+        DBKey::Var(var) => KeyBuilder::new(tags::VAR).str(var).build(),
+        DBKey::VarEvent(var, block_index, event_index) => KeyBuilder::new(tags::VAR_EVENT)
+            .str(var)
+            .u64(block_index)
+            .u32(event_index)
+            .build(),
+        DBKey::VarEventScanBlock(var, block_index) => KeyBuilder::new(tags::VAR_EVENT)
+            .str(var)
+            .u64(block_index)
+            .build(),
+        DBKey::VarEventScan(var) => KeyBuilder::new(tags::VAR_EVENT).str(var).build(),
+        DBKey::VarVersion(var, block_index, position) => KeyBuilder::new(tags::VAR_VERSION)
+            .str(var)
+            .u64(block_index)
+            .u32(position)
+            .build(),
+        DBKey::VarVersionScan(var) => KeyBuilder::new(tags::VAR_VERSION).str(var).build(),
+        DBKey::MapEntry(map, hex_key) => KeyBuilder::new(tags::MAP_ENTRY)
+            .str(map)
+            .remainder(hex_key)
+            .build(),
+        DBKey::MapScan(map) => KeyBuilder::new(tags::MAP_ENTRY).str(map).build(),
+        DBKey::MapEvent(map, block_index, event_index) => KeyBuilder::new(tags::MAP_EVENT)
+            .str(map)
+            .u64(block_index)
+            .u32(event_index)
+            .build(),
+        DBKey::MapEventScanBlock(map, block_index) => KeyBuilder::new(tags::MAP_EVENT)
+            .str(map)
+            .u64(block_index)
+            .build(),
+        DBKey::MapEventScan(map) => KeyBuilder::new(tags::MAP_EVENT).str(map).build(),
+        DBKey::MapEntryVersion(map, hex_key, block_index, position) => {
+            KeyBuilder::new(tags::MAP_VERSION)
+                .str(map)
+                .str(hex_key)
+                .u64(block_index)
+                .u32(position)
+                .build()
+        }
+        DBKey::MapVersionScan(map) => KeyBuilder::new(tags::MAP_VERSION).str(map).build(),
+        DBKey::FT(asset_id, owner) => KeyBuilder::new(tags::FT)
+            .str(asset_id)
+            .remainder(owner)
+            .build(),
+        DBKey::FTScan(asset_id) => KeyBuilder::new(tags::FT).str(asset_id).build(),
+        DBKey::FTEvent(asset_id, block_index, event_index) => KeyBuilder::new(tags::FT_EVENT)
+            .str(asset_id)
+            .u64(block_index)
+            .u32(event_index)
+            .build(),
+        DBKey::FTEventScanBlock(asset_id, block_index) => KeyBuilder::new(tags::FT_EVENT)
+            .str(asset_id)
+            .u64(block_index)
+            .build(),
+        DBKey::FTEventScan(asset_id) => KeyBuilder::new(tags::FT_EVENT).str(asset_id).build(),
+        DBKey::FTVersion(asset_id, owner, block_index, position) => {
+            KeyBuilder::new(tags::FT_VERSION)
+                .str(asset_id)
+                .str(owner)
+                .u64(block_index)
+                .u32(position)
+                .build()
+        }
+        DBKey::FTVersionScan(asset_id) => KeyBuilder::new(tags::FT_VERSION).str(asset_id).build(),
+        DBKey::FTSupply(asset_id) => KeyBuilder::new(tags::FT_SUPPLY).str(asset_id).build(),
+        DBKey::FTSupplyVersion(asset_id, block_index, position) => {
+            KeyBuilder::new(tags::FT_SUPPLY_VERSION)
+                .str(asset_id)
+                .u64(block_index)
+                .u32(position)
+                .build()
+        }
+        DBKey::FTSupplyVersionScan(asset_id) => KeyBuilder::new(tags::FT_SUPPLY_VERSION)
+            .str(asset_id)
+            .build(),
+        DBKey::NFT(asset_id, hex_asset_identifier) => KeyBuilder::new(tags::NFT)
+            .str(asset_id)
+            .remainder(hex_asset_identifier)
+            .build(),
+        DBKey::NFTScan(asset_id) => KeyBuilder::new(tags::NFT).str(asset_id).build(),
+        DBKey::NFTEvent(asset_id, block_index, event_index) => KeyBuilder::new(tags::NFT_EVENT)
+            .str(asset_id)
+            .u64(block_index)
+            .u32(event_index)
+            .build(),
+        DBKey::NFTEventScanBlock(asset_id, block_index) => KeyBuilder::new(tags::NFT_EVENT)
+            .str(asset_id)
+            .u64(block_index)
+            .build(),
+        DBKey::NFTEventScan(asset_id) => KeyBuilder::new(tags::NFT_EVENT).str(asset_id).build(),
+        DBKey::NFTVersion(asset_id, hex_asset_identifier, block_index, position) => {
+            KeyBuilder::new(tags::NFT_VERSION)
+                .str(asset_id)
+                .str(hex_asset_identifier)
+                .u64(block_index)
+                .u32(position)
+                .build()
+        }
+        DBKey::NFTVersionScan(asset_id) => KeyBuilder::new(tags::NFT_VERSION).str(asset_id).build(),
+        DBKey::WalletBalance(asset_id) => KeyBuilder::new(tags::WALLET_BALANCE)
+            .remainder(asset_id)
+            .build(),
+        DBKey::WalletBalanceScan => KeyBuilder::new(tags::WALLET_BALANCE).build(),
+        DBKey::WalletNFT(asset_id, hex_asset_identifier) => KeyBuilder::new(tags::WALLET_NFT)
+            .str(asset_id)
+            .remainder(hex_asset_identifier)
+            .build(),
+        DBKey::WalletNFTScan => KeyBuilder::new(tags::WALLET_NFT).build(),
+        DBKey::WalletActivity(block_index, event_index) => KeyBuilder::new(tags::WALLET_ACTIVITY)
+            .u64(block_index)
+            .u32(event_index)
+            .build(),
+        DBKey::WalletActivityScan => KeyBuilder::new(tags::WALLET_ACTIVITY).build(),
+        DBKey::UndoJournal(block_index, block_hash) => KeyBuilder::new(tags::UNDO_JOURNAL)
+            .u64(block_index)
+            .str(block_hash)
+            .build(),
+        DBKey::UndoJournalScan => KeyBuilder::new(tags::UNDO_JOURNAL).build(),
+    }
+}
+
+/// Decodes the `(block_index, event_index)` trailing an event key, given the
+/// remainder of the key after its `*EventScan` prefix. Also decodes the
+/// `(block_index, position)` trailing a version key.
+pub fn decode_event_position(remainder: &[u8]) -> Option<(u64, u32)> {
+    if remainder.len() != 12 {
+        return None;
+    }
+    let mut block_index = [0u8; 8];
+    block_index.copy_from_slice(&remainder[..8]);
+    let mut event_index = [0u8; 4];
+    event_index.copy_from_slice(&remainder[8..]);
+    Some((
+        u64::from_be_bytes(block_index),
+        u32::from_be_bytes(event_index),
+    ))
+}
+
+/// Decodes the block height of an undo journal or of fired triggers, given
+/// the remainder of their key after the `UndoJournalScan` or
+/// `FiredTriggersScan` prefix.
+pub fn decode_block_index(remainder: &[u8]) -> Result<u64, String> {
+    if remainder.len() < 8 {
+        return Err(format!("malformed block key {:?}", remainder));
+    }
+    let mut block_index = [0u8; 8];
+    block_index.copy_from_slice(&remainder[..8]);
+    Ok(u64::from_be_bytes(block_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_keys_sort_by_block_then_event() {
+        let mut keys = vec![
+            db_key(DBKey::VarEvent("counter", 10, 1)),
+            db_key(DBKey::VarEvent("counter", 9, 2)),
+            db_key(DBKey::VarEvent("counter", 9, 10)),
+            db_key(DBKey::VarEvent("counter", 100, 0)),
+        ];
+        keys.sort();
+
+        let prefix = db_key(DBKey::VarEventScan("counter"));
+        let positions = keys
+            .iter()
+            .map(|key| {
+                assert!(key.starts_with(&prefix));
+                decode_event_position(&key[prefix.len()..]).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![(9, 2), (9, 10), (10, 1), (100, 0)]);
+
+        // A var named after the prefix of another one does not leak in its scan
+        let other = db_key(DBKey::VarEvent("counter-2", 9, 2));
+        assert!(!other.starts_with(&prefix));
+    }
+}
@@ -0,0 +1,229 @@
+use super::keys::{db_key, DBKey, SCHEMA_VERSION};
+use super::{
+    contracts, StorageDriver, BITCOIN_BLOCKS, CONTRACT_DEPLOYMENTS, METADATA, STACKS_BLOCKS,
+    STACKS_MICROBLOCKS,
+};
+use clarinet_lib::types::StacksMicroblockData;
+
+/// Namespace of the fired triggers of both chains, up to v3.
+const LEGACY_TRIGGERS: &str = "triggers";
+
+type Migration = fn(&StorageDriver) -> Result<(), String>;
+
+/// Steps upgrading a working dir, indexed by the version they upgrade from.
+const MIGRATIONS: [Migration; 5] = [
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+    migrate_v4_to_v5,
+];
+
+/// Returns the schema version of the working dir, or None if it was never used.
+pub fn get_schema_version(storage_driver: &StorageDriver) -> Result<Option<u32>, String> {
+    let metadata = storage_driver.open(METADATA)?;
+    match metadata.get(&db_key(DBKey::SchemaVersion))? {
+        Some(bytes) => {
+            let bytes: [u8; 4] = bytes
+                .try_into()
+                .map_err(|_| "unable to read schema version: malformed value".to_string())?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+        None => {
+            // Working dirs populated before the schema was versioned.
+            for namespace in [BITCOIN_BLOCKS, STACKS_BLOCKS, STACKS_MICROBLOCKS] {
+                if !storage_driver.open(namespace)?.scan_prefix(&[])?.is_empty() {
+                    return Ok(Some(0));
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
+fn set_schema_version(storage_driver: &StorageDriver, version: u32) -> Result<(), String> {
+    let metadata = storage_driver.open(METADATA)?;
+    metadata.put(&db_key(DBKey::SchemaVersion), &version.to_be_bytes())
+}
+
+/// Upgrades the working dir to `SCHEMA_VERSION`, one step at a time. Must run
+/// before any actor reads or writes the datastore.
+pub fn run_migrations(storage_driver: &StorageDriver) -> Result<(), String> {
+    let mut version = match get_schema_version(storage_driver)? {
+        Some(version) => version,
+        None => return set_schema_version(storage_driver, SCHEMA_VERSION),
+    };
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "unable to open working dir: schema version {} is newer than {}",
+            version, SCHEMA_VERSION
+        ));
+    }
+    while version < SCHEMA_VERSION {
+        MIGRATIONS[version as usize](storage_driver)
+            .map_err(|e| format!("unable to migrate schema to v{}: {}", version + 1, e))?;
+        version += 1;
+        set_schema_version(storage_driver, version)?;
+    }
+    Ok(())
+}
+
+/// Moves the blocks namespaces from the ASCII keys ("hash:{}", "tip", "~:{}",
+/// "~tip") to the typed keys. Contracts states are not migrated but dropped:
+/// contract processors rebuild them from the stacks blocks on start.
+fn migrate_v0_to_v1(storage_driver: &StorageDriver) -> Result<(), String> {
+    for namespace in [BITCOIN_BLOCKS, STACKS_BLOCKS, STACKS_MICROBLOCKS] {
+        let db = storage_driver.open(namespace)?;
+        for (key, value) in db.scan_prefix(&[])? {
+            let new_key = if key == b"tip" || key == b"~tip" {
+                db_key(DBKey::Tip)
+            } else if let Some(hash) = key.strip_prefix(b"hash:") {
+                db_key(DBKey::Block(&String::from_utf8_lossy(hash)))
+            } else if let Some(index) = key.strip_prefix(b"~:") {
+                let index = String::from_utf8_lossy(index)
+                    .parse::<u64>()
+                    .map_err(|e| format!("malformed microblock key: {}", e))?;
+                legacy_microblock_key(index)
+            } else if key.len() == 8 {
+                let mut index = [0u8; 8];
+                index.copy_from_slice(&key);
+                db_key(DBKey::BlockHash(u64::from_be_bytes(index)))
+            } else {
+                continue;
+            };
+            db.put(&new_key, &value)?;
+            db.delete(&key)?;
+        }
+    }
+
+    let db = storage_driver.open(CONTRACT_DEPLOYMENTS)?;
+    for (key, value) in db.scan_prefix(&[])? {
+        let contract_id = String::from_utf8_lossy(&key).to_string();
+        db.put(&db_key(DBKey::ContractDeployment(&contract_id)), &value)?;
+        db.delete(&key)?;
+        storage_driver.delete_all(&contracts::contract_db_namespace(&contract_id))?;
+    }
+    Ok(())
+}
+
+/// Contracts states now keep every version of their fields: drop them, so
+/// that contract processors rebuild them, history included, on start.
+fn migrate_v1_to_v2(storage_driver: &StorageDriver) -> Result<(), String> {
+    drop_contracts_states(storage_driver)
+}
+
+/// Contracts states now track the total supply of their fungible tokens.
+fn migrate_v2_to_v3(storage_driver: &StorageDriver) -> Result<(), String> {
+    drop_contracts_states(storage_driver)
+}
+
+/// Fired triggers are now kept per chain, and keyed by block height so that
+/// they can be pruned: the ones recorded so far are dropped.
+fn migrate_v3_to_v4(storage_driver: &StorageDriver) -> Result<(), String> {
+    storage_driver.delete_all(LEGACY_TRIGGERS)
+}
+
+/// Microblocks are now keyed by the height of their anchored block, then by
+/// sequence: the sequence alone collided across anchored blocks. Microblocks
+/// that can not be read are dropped. Contracts states, which keyed the
+/// microblocks writes by sequence as well, are rebuilt.
+fn migrate_v4_to_v5(storage_driver: &StorageDriver) -> Result<(), String> {
+    let db = storage_driver.open(STACKS_MICROBLOCKS)?;
+    let prefix = db_key(DBKey::MicroblockScan);
+    for (key, value) in db.scan_prefix(&prefix)? {
+        if key.len() != prefix.len() + 8 {
+            continue;
+        }
+        db.delete(&key)?;
+        if let Ok(microblock) = serde_json::from_slice::<StacksMicroblockData>(&value) {
+            let new_key = db_key(DBKey::Microblock(
+                microblock.parent_block_identifier.index,
+                microblock.block_identifier.index,
+            ));
+            db.put(&new_key, &value)?;
+        }
+    }
+    drop_contracts_states(storage_driver)
+}
+
+/// Key of the microblocks up to v4, made of their sequence only.
+fn legacy_microblock_key(index: u64) -> Vec<u8> {
+    let mut key = db_key(DBKey::MicroblockScan);
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+fn drop_contracts_states(storage_driver: &StorageDriver) -> Result<(), String> {
+    let db = storage_driver.open(CONTRACT_DEPLOYMENTS)?;
+    let prefix = db_key(DBKey::ContractDeploymentScan);
+    for (key, _) in db.scan_prefix(&prefix)? {
+        // Skip the length prefix of the contract identifier
+        let contract_id = String::from_utf8_lossy(&key[prefix.len() + 4..]).to_string();
+        storage_driver.delete_all(&contracts::contract_db_namespace(&contract_id))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_working_dir_is_migrated() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = storage_driver.open(STACKS_BLOCKS).unwrap();
+        db.put(b"hash:0xaa", b"block").unwrap();
+        db.put(&2u64.to_be_bytes(), b"0xaa").unwrap();
+        db.put(b"tip", &2u64.to_be_bytes()).unwrap();
+        let microblocks_db = storage_driver.open(STACKS_MICROBLOCKS).unwrap();
+        let microblock = json!({
+            "block_identifier": { "hash": "0xbb", "index": 12 },
+            "parent_block_identifier": { "hash": "0xaa", "index": 2 },
+            "transactions": [],
+        })
+        .to_string();
+        microblocks_db.put(b"~:12", microblock.as_bytes()).unwrap();
+        assert_eq!(get_schema_version(&storage_driver).unwrap(), Some(0));
+
+        run_migrations(&storage_driver).unwrap();
+
+        assert_eq!(
+            get_schema_version(&storage_driver).unwrap(),
+            Some(SCHEMA_VERSION)
+        );
+        assert_eq!(
+            db.get(&db_key(DBKey::Block("0xaa"))).unwrap(),
+            Some(b"block".to_vec())
+        );
+        assert_eq!(
+            db.get(&db_key(DBKey::BlockHash(2))).unwrap(),
+            Some(b"0xaa".to_vec())
+        );
+        assert_eq!(
+            db.get(&db_key(DBKey::Tip)).unwrap(),
+            Some(2u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(db.get(b"tip").unwrap(), None);
+        assert_eq!(
+            microblocks_db
+                .get(&db_key(DBKey::Microblock(2, 12)))
+                .unwrap(),
+            Some(microblock.into_bytes())
+        );
+        assert_eq!(
+            microblocks_db.get(&legacy_microblock_key(12)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn fresh_working_dir_is_stamped() {
+        let storage_driver = StorageDriver::in_memory();
+        assert_eq!(get_schema_version(&storage_driver).unwrap(), None);
+        run_migrations(&storage_driver).unwrap();
+        assert_eq!(
+            get_schema_version(&storage_driver).unwrap(),
+            Some(SCHEMA_VERSION)
+        );
+    }
+}
@@ -1,33 +1,176 @@
+pub mod batch;
 pub mod blocks;
 pub mod contracts;
+mod in_memory;
+pub mod journal;
+pub mod keys;
+pub mod migrations;
+mod on_disk;
+pub mod triggers;
+pub mod wallets;
 
+pub use batch::WriteBatch;
+pub use in_memory::{InMemoryDatastore, InMemoryStorage};
+pub use migrations::run_migrations;
+pub use on_disk::{OnDiskDatastore, OnDiskStorage};
+
+use batch::PendingWrites;
 use std::path::PathBuf;
 
-pub trait Datastore {}
-// InMemory Datastore
-// OnDisk Datastore
-// Remote Datastore
+/// Namespaces shared by every working dir. Contracts states get their own
+/// namespace, named after the contract (see `contracts::contract_db_namespace`),
+/// and so do watched wallets (see `wallets::wallet_db_namespace`).
+pub const BITCOIN_BLOCKS: &str = "bitcoin_blocks";
+pub const STACKS_BLOCKS: &str = "stacks_blocks";
+pub const STACKS_MICROBLOCKS: &str = "stacks_microblocks";
+pub const CONTRACT_DEPLOYMENTS: &str = "contract_deployments";
+/// Triggers fired by the recent blocks of each chain (see `triggers`).
+pub const BITCOIN_TRIGGERS: &str = "bitcoin_triggers";
+pub const STACKS_TRIGGERS: &str = "stacks_triggers";
+/// Schema version of the working dir (see `migrations`).
+pub const METADATA: &str = "metadata";
+
+/// Number of blocks behind the tip that can still be orphaned. The data kept
+/// for reverting blocks (undo journals, fired triggers) is pruned past it.
+pub const MAX_REORG_DEPTH: u64 = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScanDirection {
+    Forward,
+    Reverse,
+}
+
+/// Key-value store backing the blocks archived by `BlockStoreManager` and the
+/// contracts states maintained by `ContractProcessor`.
+pub trait Datastore: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String>;
+
+    fn delete(&self, key: &[u8]) -> Result<(), String>;
+
+    /// Walks the entries whose key starts with `prefix`, in key order (or
+    /// reverse key order), starting right after `cursor` when given. Stops as
+    /// soon as `visitor` returns false. The visitor must not write to the
+    /// datastore.
+    fn visit(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        direction: ScanDirection,
+        visitor: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, String>,
+    ) -> Result<(), String>;
+
+    /// Returns the entries whose key starts with `prefix`, ordered by key.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        self.scan_page(prefix, None, usize::MAX, ScanDirection::Forward)
+    }
+
+    /// Returns at most `limit` entries whose key starts with `prefix`,
+    /// starting right after `cursor` when given.
+    fn scan_page(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        limit: usize,
+        direction: ScanDirection,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        let mut entries = vec![];
+        if limit == 0 {
+            return Ok(entries);
+        }
+        self.visit(prefix, cursor, direction, &mut |key, value| {
+            entries.push((key.to_vec(), value.to_vec()));
+            Ok(entries.len() < limit)
+        })?;
+        Ok(entries)
+    }
+}
+
+/// Returns the smallest key greater than every key starting with `prefix`,
+/// or None if there is no such key (empty prefix, or only 0xff bytes).
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper_bound = prefix.to_vec();
+    while let Some(byte) = upper_bound.pop() {
+        if byte < 0xff {
+            upper_bound.push(byte + 1);
+            return Some(upper_bound);
+        }
+    }
+    None
+}
 
+/// Drops the entries starting with `prefix` and keyed by block height (see
+/// `keys::decode_block_index`) deeper than `MAX_REORG_DEPTH` below the block
+/// `tip_index`, which can no longer be orphaned.
+pub fn prune_below_reorg_depth(
+    db: &dyn Datastore,
+    prefix: &[u8],
+    tip_index: u64,
+) -> Result<(), String> {
+    let mut expired_keys = vec![];
+    db.visit(prefix, None, ScanDirection::Forward, &mut |key, _| {
+        let index = keys::decode_block_index(&key[prefix.len()..])?;
+        if index.saturating_add(MAX_REORG_DEPTH) >= tip_index {
+            return Ok(false);
+        }
+        expired_keys.push(key.to_vec());
+        Ok(true)
+    })?;
+    for key in expired_keys.iter() {
+        db.delete(key)?;
+    }
+    Ok(())
+}
+
+/// Handle on the storage of a working dir. Clones share the same underlying
+/// database, so that writes are immediately visible to every actor.
 #[derive(Clone, Debug)]
 pub enum StorageDriver {
-    Filesystem(FilesystemConfig),
+    Filesystem(OnDiskStorage),
+    InMemory(InMemoryStorage),
 }
 
 impl StorageDriver {
     pub fn filesystem(working_dir: PathBuf) -> StorageDriver {
-        StorageDriver::Filesystem(FilesystemConfig { working_dir })
+        StorageDriver::Filesystem(OnDiskStorage::new(working_dir))
     }
 
     pub fn tmpfs() -> StorageDriver {
         let mut working_dir = std::env::temp_dir();
         working_dir.push("orchestra");
-        StorageDriver::Filesystem(FilesystemConfig { working_dir })
+        StorageDriver::Filesystem(OnDiskStorage::new(working_dir))
     }
-}
 
-#[derive(Clone, Debug)]
-pub struct FilesystemConfig {
-    pub working_dir: PathBuf,
+    pub fn in_memory() -> StorageDriver {
+        StorageDriver::InMemory(InMemoryStorage::new())
+    }
+
+    /// Opens the datastore `namespace`, creating it if missing.
+    pub fn open(&self, namespace: &str) -> Result<Box<dyn Datastore>, String> {
+        match self {
+            StorageDriver::Filesystem(storage) => Ok(Box::new(storage.open(namespace)?)),
+            StorageDriver::InMemory(storage) => Ok(Box::new(storage.open(namespace))),
+        }
+    }
+
+    /// Applies the writes of a `WriteBatch`, all or none of them.
+    pub fn write(&self, pending: PendingWrites) -> Result<(), String> {
+        match self {
+            StorageDriver::Filesystem(storage) => storage.write(pending),
+            StorageDriver::InMemory(storage) => storage.write(pending),
+        }
+    }
+
+    pub fn delete_all(&self, namespace: &str) -> Result<(), String> {
+        match self {
+            StorageDriver::Filesystem(storage) => storage.delete_all(namespace),
+            StorageDriver::InMemory(storage) => {
+                storage.delete_all(namespace);
+                Ok(())
+            }
+        }
+    }
 }
 
 pub enum DataField {
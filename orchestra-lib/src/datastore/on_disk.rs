@@ -0,0 +1,201 @@
+use super::batch::PendingWrites;
+use super::{
+    prefix_upper_bound, Datastore, ScanDirection, BITCOIN_BLOCKS, CONTRACT_DEPLOYMENTS, METADATA,
+    STACKS_BLOCKS, STACKS_MICROBLOCKS,
+};
+use rocksdb::{
+    BoundColumnFamily, DBWithThreadMode, Direction, IteratorMode, MultiThreaded, Options,
+    WriteBatch,
+};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+type SharedDB = DBWithThreadMode<MultiThreaded>;
+
+/// Database of a working dir, opened on first use and shared by every actor
+/// holding a clone of the storage. Each namespace is a column family.
+#[derive(Clone)]
+pub struct OnDiskStorage {
+    pub working_dir: PathBuf,
+    db: Arc<Mutex<Option<Arc<SharedDB>>>>,
+}
+
+impl OnDiskStorage {
+    pub fn new(working_dir: PathBuf) -> OnDiskStorage {
+        OnDiskStorage {
+            working_dir,
+            db: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn get_db(&self) -> Result<Arc<SharedDB>, String> {
+        let mut db = self
+            .db
+            .lock()
+            .map_err(|_| "unable to open database: lock poisoned".to_string())?;
+        if let Some(ref db) = *db {
+            return Ok(db.clone());
+        }
+
+        std::fs::create_dir_all(&self.working_dir)
+            .map_err(|e| format!("unable to create {}: {}", self.working_dir.display(), e))?;
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        // Contracts column families are created on the fly, and must be
+        // re-opened along with the static ones.
+        let mut column_families =
+            SharedDB::list_cf(&options, &self.working_dir).unwrap_or_default();
+        for name in [
+            BITCOIN_BLOCKS,
+            STACKS_BLOCKS,
+            STACKS_MICROBLOCKS,
+            CONTRACT_DEPLOYMENTS,
+            METADATA,
+        ] {
+            if !column_families.iter().any(|cf| cf == name) {
+                column_families.push(name.to_string());
+            }
+        }
+        let opened = SharedDB::open_cf(&options, &self.working_dir, column_families)
+            .map_err(|e| format!("unable to open {}: {}", self.working_dir.display(), e))?;
+        let opened = Arc::new(opened);
+        *db = Some(opened.clone());
+        Ok(opened)
+    }
+
+    pub fn open(&self, namespace: &str) -> Result<OnDiskDatastore, String> {
+        let db = self.get_db()?;
+        if db.cf_handle(namespace).is_none() {
+            db.create_cf(namespace, &Options::default())
+                .map_err(|e| format!("unable to create {}: {}", namespace, e))?;
+        }
+        Ok(OnDiskDatastore {
+            db,
+            column_family: namespace.to_string(),
+        })
+    }
+
+    pub fn write(&self, pending: PendingWrites) -> Result<(), String> {
+        let db = self.get_db()?;
+        let mut batch = WriteBatch::default();
+        for (namespace, writes) in pending.into_iter() {
+            self.open(&namespace)?;
+            let cf = db
+                .cf_handle(&namespace)
+                .ok_or(format!("column family {} dropped", namespace))?;
+            for (key, value) in writes.into_iter() {
+                match value {
+                    Some(value) => batch.put_cf(&cf, key, value),
+                    None => batch.delete_cf(&cf, key),
+                }
+            }
+        }
+        db.write(batch)
+            .map_err(|e| format!("unable to write datastore: {}", e))
+    }
+
+    /// Clears a namespace in place: the column family is kept, so that the
+    /// datastores already opened on it remain usable.
+    pub fn delete_all(&self, namespace: &str) -> Result<(), String> {
+        let db = self.get_db()?;
+        let cf = match db.cf_handle(namespace) {
+            Some(cf) => cf,
+            None => return Ok(()),
+        };
+        // Range deletions exclude their end: the last key is deleted on its own.
+        let last_key = match db.iterator_cf(&cf, IteratorMode::End).next() {
+            Some((key, _)) => key,
+            None => return Ok(()),
+        };
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(&cf, Vec::<u8>::new(), &last_key);
+        batch.delete_cf(&cf, &last_key);
+        db.write(batch)
+            .map_err(|e| format!("unable to clear {}: {}", namespace, e))
+    }
+}
+
+impl fmt::Debug for OnDiskStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OnDiskStorage({})", self.working_dir.display())
+    }
+}
+
+pub struct OnDiskDatastore {
+    db: Arc<SharedDB>,
+    column_family: String,
+}
+
+impl OnDiskDatastore {
+    fn column_family(&self) -> Result<Arc<BoundColumnFamily>, String> {
+        self.db
+            .cf_handle(&self.column_family)
+            .ok_or(format!("column family {} dropped", self.column_family))
+    }
+}
+
+impl Datastore for OnDiskDatastore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let cf = self.column_family()?;
+        self.db
+            .get_cf(&cf, key)
+            .map_err(|e| format!("unable to read datastore: {}", e))
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        let cf = self.column_family()?;
+        self.db
+            .put_cf(&cf, key, value)
+            .map_err(|e| format!("unable to write datastore: {}", e))
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        let cf = self.column_family()?;
+        self.db
+            .delete_cf(&cf, key)
+            .map_err(|e| format!("unable to write datastore: {}", e))
+    }
+
+    fn visit(
+        &self,
+        prefix: &[u8],
+        cursor: Option<&[u8]>,
+        direction: ScanDirection,
+        visitor: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, String>,
+    ) -> Result<(), String> {
+        let cf = self.column_family()?;
+        // No prefix extractor is configured: seek to the start of the range,
+        // and stop at the first key out of it. Seeks are inclusive, the cursor
+        // (or the upper bound, in reverse) is skipped.
+        let upper_bound = prefix_upper_bound(prefix);
+        let (mode, skipped) = match (direction, cursor) {
+            (ScanDirection::Forward, Some(cursor)) => {
+                (IteratorMode::From(cursor, Direction::Forward), Some(cursor))
+            }
+            (ScanDirection::Forward, None) => {
+                (IteratorMode::From(prefix, Direction::Forward), None)
+            }
+            (ScanDirection::Reverse, Some(cursor)) => {
+                (IteratorMode::From(cursor, Direction::Reverse), Some(cursor))
+            }
+            (ScanDirection::Reverse, None) => match upper_bound {
+                Some(ref upper_bound) => (
+                    IteratorMode::From(upper_bound, Direction::Reverse),
+                    Some(upper_bound.as_slice()),
+                ),
+                None => (IteratorMode::End, None),
+            },
+        };
+        for (key, value) in self.db.iterator_cf(&cf, mode) {
+            if Some(key.as_ref()) == skipped {
+                continue;
+            }
+            if !key.starts_with(prefix) || !visitor(&key, &value)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
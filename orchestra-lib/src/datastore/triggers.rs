@@ -0,0 +1,136 @@
+use super::keys::{db_key, DBKey};
+use super::{prune_below_reorg_depth, Datastore, StorageDriver};
+use crate::types::FiredTrigger;
+use clarinet_lib::types::BlockIdentifier;
+
+/// Opens the fired triggers of a chain, `STACKS_TRIGGERS` or `BITCOIN_TRIGGERS`.
+pub fn triggers_db(
+    storage_driver: &StorageDriver,
+    namespace: &str,
+) -> Result<Box<dyn Datastore>, String> {
+    storage_driver
+        .open(namespace)
+        .map_err(|e| format!("unable to open triggers datastore: {}", e))
+}
+
+/// Records the triggers fired by a block.
+pub fn record_fired_triggers(
+    db: &dyn Datastore,
+    block_identifier: &BlockIdentifier,
+    triggers: &[FiredTrigger],
+) -> Result<(), String> {
+    let bytes = serde_json::to_vec(triggers)
+        .map_err(|e| format!("unable to record fired triggers: {}", e))?;
+    db.put(
+        &db_key(DBKey::FiredTriggers(
+            block_identifier.index,
+            &block_identifier.hash,
+        )),
+        &bytes,
+    )
+}
+
+/// Returns the triggers fired by a block, and forgets them.
+pub fn take_fired_triggers(
+    db: &dyn Datastore,
+    block_identifier: &BlockIdentifier,
+) -> Result<Vec<FiredTrigger>, String> {
+    let key = db_key(DBKey::FiredTriggers(
+        block_identifier.index,
+        &block_identifier.hash,
+    ));
+    let triggers = match db.get(&key)? {
+        Some(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|e| format!("unable to read fired triggers: {}", e))?,
+        None => return Ok(vec![]),
+    };
+    db.delete(&key)?;
+    Ok(triggers)
+}
+
+/// Drops the triggers fired by the blocks deeper than `MAX_REORG_DEPTH` below
+/// the block `tip_index`, which can no longer be orphaned.
+pub fn prune(db: &dyn Datastore, tip_index: u64) -> Result<(), String> {
+    prune_below_reorg_depth(db, &db_key(DBKey::FiredTriggersScan), tip_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::{MAX_REORG_DEPTH, STACKS_TRIGGERS};
+    use crate::types::{OrchestraPid, TriggerId};
+
+    fn fired_triggers() -> Vec<FiredTrigger> {
+        vec![FiredTrigger {
+            trigger_id: TriggerId {
+                pid: OrchestraPid(1),
+                lambda_id: 2,
+            },
+            transactions: vec![0, 3],
+        }]
+    }
+
+    fn block_identifier(index: u64, hash: &str) -> BlockIdentifier {
+        BlockIdentifier {
+            index,
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn fired_triggers_are_taken_once() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = triggers_db(&storage_driver, STACKS_TRIGGERS).unwrap();
+        let triggers = fired_triggers();
+
+        record_fired_triggers(&*db, &block_identifier(1, "0xa1"), &triggers).unwrap();
+        assert_eq!(
+            take_fired_triggers(&*db, &block_identifier(1, "0xb2")).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            take_fired_triggers(&*db, &block_identifier(1, "0xa1")).unwrap(),
+            triggers
+        );
+        assert_eq!(
+            take_fired_triggers(&*db, &block_identifier(1, "0xa1")).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn triggers_recorded_without_transactions_are_read() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = triggers_db(&storage_driver, STACKS_TRIGGERS).unwrap();
+        db.put(
+            &db_key(DBKey::FiredTriggers(1, "0xa1")),
+            br#"[{"pid":1,"lambda_id":2}]"#,
+        )
+        .unwrap();
+
+        let triggers = take_fired_triggers(&*db, &block_identifier(1, "0xa1")).unwrap();
+        assert_eq!(triggers.len(), 1);
+        assert_eq!(triggers[0].trigger_id.lambda_id, 2);
+        assert!(triggers[0].transactions.is_empty());
+    }
+
+    #[test]
+    fn triggers_deeper_than_reorgs_are_pruned() {
+        let storage_driver = StorageDriver::in_memory();
+        let db = triggers_db(&storage_driver, STACKS_TRIGGERS).unwrap();
+        let triggers = fired_triggers();
+        record_fired_triggers(&*db, &block_identifier(1, "0xa1"), &triggers).unwrap();
+        record_fired_triggers(&*db, &block_identifier(2, "0xa2"), &triggers).unwrap();
+
+        prune(&*db, 2 + MAX_REORG_DEPTH).unwrap();
+
+        assert_eq!(
+            take_fired_triggers(&*db, &block_identifier(1, "0xa1")).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            take_fired_triggers(&*db, &block_identifier(2, "0xa2")).unwrap(),
+            triggers
+        );
+    }
+}
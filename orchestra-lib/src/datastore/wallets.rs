@@ -0,0 +1,20 @@
+use super::{Datastore, StorageDriver};
+
+pub fn wallet_db_namespace(principal: &str) -> String {
+    format!("wallet:{}", principal)
+}
+
+pub fn wallet_db_delete_all(storage_driver: &StorageDriver, principal: &str) -> Result<(), String> {
+    storage_driver
+        .delete_all(&wallet_db_namespace(principal))
+        .map_err(|e| format!("unable to delete wallet datastore: {}", e))
+}
+
+pub fn wallet_db(
+    storage_driver: &StorageDriver,
+    principal: &str,
+) -> Result<Box<dyn Datastore>, String> {
+    storage_driver
+        .open(&wallet_db_namespace(principal))
+        .map_err(|e| format!("unable to open wallet datastore: {}", e))
+}
@@ -8,23 +8,46 @@ use clarinet_lib::clarity_repl::clarity::util::hash::hex_bytes;
 use clarinet_lib::clarity_repl::clarity::Value;
 use clarinet_lib::types::events::StacksTransactionEvent;
 use clarinet_lib::types::{
-    AccountIdentifier, BitcoinBlockData, BlockIdentifier, StacksBlockData, TransactionIdentifier,
+    BitcoinBlockData, BlockIdentifier, StacksBlockData, TransactionIdentifier,
 };
 use serde_json::map::Map;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, VecDeque};
 use std::io::Cursor;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+mod predicates;
+
+pub use predicates::{
+    load_lambdas_from_manifest, parse_lambdas, BitcoinPredicate, ClarityValueMatcher, Predicate,
+    StacksContractCallPredicate, StacksContractDeploymentPredicate, StacksFtEventPredicate,
+    StacksNftEventPredicate, StacksPredicate, StacksPrintEventPredicate, StacksStxEventPredicate,
+    TokenAction,
+};
+
+/// Asset under which the STX movements are indexed.
+pub const STX_ASSET_IDENTIFIER: &str = "STX";
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct OrchestraPid(pub u64);
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct TriggerId {
     pub pid: OrchestraPid,
     pub lambda_id: u64,
 }
 
+/// Trigger fired by a block, with the positions in the block of the
+/// transactions matching the predicate of its lambda. Flattened, so that the
+/// triggers recorded without their positions can still be read.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct FiredTrigger {
+    #[serde(flatten)]
+    pub trigger_id: TriggerId,
+    #[serde(default)]
+    pub transactions: Vec<usize>,
+}
+
 #[derive(PartialEq, Eq, Hash, Clone, Debug, PartialOrd, Ord)]
 pub struct ProtocolObserverId(pub u64);
 
@@ -35,6 +58,8 @@ pub struct ProtocolObserverConfig {
     pub lambdas: Vec<Lambda>,
     pub contracts: Vec<(QualifiedContractIdentifier, ContractSettings)>,
     pub manifest_path: PathBuf,
+    /// Node queried for the balances of the tokens indexed mid-history.
+    pub stacks_node_rpc_url: Option<String>,
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
@@ -52,90 +77,35 @@ pub struct ContractSettings {
     pub api_generator_enabled: Vec<String>,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct Lambda {
-    lambda_id: u64,
-    name: String,
-    predicate: Predicate,
-    action: Action,
+    pub lambda_id: u64,
+    pub name: String,
+    pub predicate: Predicate,
+    pub action: Action,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Action {
-    User,
-    Platform,
+    User(User),
+    Platform(Platform),
 }
 
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub enum User {
+    /// Posts the payload, as JSON, to this url.
+    #[serde(rename = "http_post")]
     HTTPPost(String),
-    CodeExecution(String),
 }
 
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Platform {
     StateExplorer,
     ApiGenerator,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum Predicate {
-    BitcoinPredicate,
-    StacksPredicate,
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum BitcoinPredicate {
-    AnyBlock,
-    AnyOperation(AccountIdentifier),
-    AnyStacksOperation(CrossStacksChainOperation, AccountIdentifier),
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum CrossStacksChainOperation {
-    Any,
-    MineBlock,
-    TransferSTX,
-    StacksSTX,
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum StacksPredicate {
-    BitcoinPredicate,
-    StacksContractPredicate,
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum StacksContractBasedPredicate {
-    AnyCallToContract(QualifiedContractIdentifier),
-    AnyResultFromContractCall(QualifiedContractIdentifier, String),
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum StacksOperationPredicate {
-    AnyOperation(AccountIdentifier),
-}
-
-pub struct StacksChainPredicates {
-    pub watching_contract_id_activity: HashMap<String, HashSet<TriggerId>>,
-    pub watching_contract_data_mutation_activity: HashMap<String, HashSet<TriggerId>>,
-    pub watching_principal_activity: HashMap<String, HashSet<TriggerId>>,
-    pub watching_ft_move_activity: HashMap<String, HashSet<TriggerId>>,
-    pub watching_nft_activity: HashMap<String, HashSet<TriggerId>>,
-    pub watching_any_block_activity: HashSet<TriggerId>,
-}
-
-impl StacksChainPredicates {
-    pub fn new() -> Self {
-        Self {
-            watching_contract_id_activity: HashMap::new(),
-            watching_contract_data_mutation_activity: HashMap::new(),
-            watching_principal_activity: HashMap::new(),
-            watching_ft_move_activity: HashMap::new(),
-            watching_nft_activity: HashMap::new(),
-            watching_any_block_activity: HashSet::new(),
-        }
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum FieldValues {
     Var(VarValues),
@@ -150,50 +120,148 @@ pub struct VarValues {
     pub value_type: ContractInterfaceAtomType,
     pub events: Vec<DataVarSetEventFormattedValue>,
     pub events_page_size: u16,
-    pub events_page_index: u64,
+    pub events_next_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct MapValues {
     pub entries: Vec<((String, String), BlockIdentifier, TransactionIdentifier)>,
     pub entries_page_size: u16,
-    pub entries_page_index: u64,
+    pub entries_next_cursor: Option<String>,
     pub key_type: ContractInterfaceAtomType,
     pub value_type: ContractInterfaceAtomType,
     pub events: Vec<DataMapEventFormattedValue>,
     pub events_page_size: u16,
-    pub events_page_index: u64,
+    pub events_next_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct NftValues {
     pub tokens: Vec<((String, String), BlockIdentifier, TransactionIdentifier)>,
     pub tokens_page_size: u16,
-    pub tokens_page_index: u64,
+    pub tokens_next_cursor: Option<String>,
     pub token_type: ContractInterfaceAtomType,
     pub events: Vec<NFTEventFormattedValue>,
     pub events_page_size: u16,
-    pub events_page_index: u64,
+    pub events_next_cursor: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct FtValues {
     pub balances: Vec<((String, String), BlockIdentifier, TransactionIdentifier)>,
     pub balances_page_size: u16,
-    pub balances_page_index: u64,
-    // pub total_supply: Option<String>, ;; TODO: not present in ContractInterface :/
+    pub balances_next_cursor: Option<String>,
+    pub total_supply: Option<String>,
     pub events: Vec<FTEventFormattedValue>,
     pub events_page_size: u16,
-    pub events_page_index: u64,
+    pub events_next_cursor: Option<String>,
 }
 
 #[derive(Clone, Debug)]
 pub struct FieldValuesRequest {
-    pub tx: Sender<FieldValuesResponse>,
+    pub tx: Sender<Result<FieldValuesResponse, RequestError>>,
     pub contract_identifier: String,
     pub field_name: String,
     pub protocol_id: u64,
     pub stacks_block_identifier: BlockIdentifier,
+    /// Read the field as it was once this block was processed, instead of its latest state.
+    pub at_block_identifier: Option<BlockIdentifier>,
+    /// Number of entries and events to return, 0 for the default.
+    pub page_size: u16,
+    /// Cursors returned by a previous response, to read the next pages.
+    pub entries_cursor: Option<String>,
+    pub events_cursor: Option<String>,
+}
+
+/// Error sent back to the requester when a request can not be served.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RequestError {
+    UnknownProtocol(u64),
+    UnknownContract(String),
+    UnknownField(String, String),
+    /// The principal of a wallet request is not a valid principal.
+    UnknownWallet(String),
+    /// The actor serving the request faulted, and is being restarted.
+    ActorUnavailable(String),
+    /// The datastore serving the request could not be read.
+    StorageError(String),
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RequestError::UnknownProtocol(protocol_id) => {
+                write!(f, "protocol {} not registered", protocol_id)
+            }
+            RequestError::UnknownContract(contract_id) => {
+                write!(f, "contract {} not observed", contract_id)
+            }
+            RequestError::UnknownField(contract_id, field_name) => {
+                write!(f, "field {} not found in {}", field_name, contract_id)
+            }
+            RequestError::UnknownWallet(principal) => {
+                write!(f, "wallet {} is not a valid principal", principal)
+            }
+            RequestError::ActorUnavailable(actor) => {
+                write!(f, "{} unavailable, restarting", actor)
+            }
+            RequestError::StorageError(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RebuildStateRequest {
+    pub tx: Sender<RebuildStateProgress>,
+    pub contract_identifier: String,
+    /// Replay the blocks from this height, instead of the deployment of the
+    /// contract. The state recorded after this height is rolled back first.
+    pub from_block_index: Option<u64>,
+    /// Compare the rebuilt state with the live state of the node.
+    pub verify: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RebuildStateProgress {
+    Started { from_block_index: u64, tip: u64 },
+    BlockReplayed { block_index: u64, tip: u64 },
+    Verified(Vec<StateMismatch>),
+    Completed,
+    Failed(String),
+}
+
+/// Entry whose rebuilt value differs from the node's. Values are hex
+/// encoded (decimal for balances), None when the entry is missing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StateMismatch {
+    pub field_name: String,
+    pub key: Option<String>,
+    pub rebuilt_value: Option<String>,
+    pub node_value: Option<String>,
+}
+
+/// Executions of a lambda, along with its most recent logs.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LambdaReport {
+    pub protocol_id: u64,
+    pub lambda_id: u64,
+    pub name: String,
+    pub executions: u64,
+    pub failures: u64,
+    /// Reset by the first successful execution.
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+    pub logs: VecDeque<LambdaLog>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LambdaLog {
+    /// Seconds since the epoch.
+    pub timestamp: u64,
+    pub block_identifier: BlockIdentifier,
+    pub success: bool,
+    /// Response of the endpoint, or error.
+    pub output: String,
 }
 
 #[derive(Clone, Debug)]
@@ -205,6 +273,33 @@ pub struct FieldValuesResponse {
     pub stacks_blocks: Vec<StacksBlockData>,
 }
 
+#[derive(Clone, Debug)]
+pub struct WalletStateRequest {
+    pub tx: Sender<Result<WalletStateResponse, RequestError>>,
+    /// Starts being indexed by the first request.
+    pub principal: String,
+    pub protocol_id: u64,
+    /// Number of activity entries to return, 0 for the default.
+    pub page_size: u16,
+    /// Cursor returned by a previous response, to read the next page.
+    pub activity_cursor: Option<String>,
+}
+
+/// Balances, tokens and activity of a principal, as of `block_identifier`.
+/// STX balances are listed under `STX_ASSET_IDENTIFIER`, NFT identifiers are
+/// hex encoded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WalletStateResponse {
+    pub principal: String,
+    pub block_identifier: Option<BlockIdentifier>,
+    pub balances: Vec<WalletStoredBalance>,
+    pub nfts: Vec<WalletStoredNFT>,
+    /// Most recent first.
+    pub activity: Vec<WalletActivityStoredValue>,
+    pub activity_page_size: u16,
+    pub activity_next_cursor: Option<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProtocolRegistration {
     pub contracts: Vec<Contract>,
@@ -461,6 +556,76 @@ pub struct NFTStoredEntry {
     pub owner: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FTStoredBalance {
+    pub owner: String,
+    pub balance: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WalletStoredBalance {
+    pub asset_class_identifier: String,
+    pub balance: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WalletStoredNFT {
+    pub asset_class_identifier: String,
+    pub hex_asset_identifier: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WalletActivityStoredValue {
+    pub event: WalletEventStoredValue,
+    pub block_identifier: BlockIdentifier,
+    pub transaction_identifier: TransactionIdentifier,
+}
+
+/// Event of a transaction involving a watched principal. Token events are
+/// keyed by their asset class identifier.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum WalletEventStoredValue {
+    Transaction(WalletTransactionValue),
+    STXTransfer(STXTransferEventValue),
+    STXMint(STXMintEventValue),
+    STXLock(STXLockEventValue),
+    STXBurn(STXBurnEventValue),
+    FT(String, FTEventStoredValue),
+    NFT(String, NFTEventStoredValue),
+}
+
+/// Transaction sent or sponsored by a watched principal.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WalletTransactionValue {
+    pub description: String,
+    pub success: bool,
+    /// Fee debited from the principal, 0 when paid by a sponsor.
+    pub fee: u64,
+}
+
+/// Transfer or burn of more tokens than the balance tracked for `owner` (or
+/// than the total supply, when `owner` is None), or credit overflowing it:
+/// the balance is clamped instead.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FTBalanceInconsistency {
+    pub asset_class_identifier: String,
+    pub owner: Option<String>,
+    pub balance: String,
+    pub amount: String,
+    pub block_identifier: BlockIdentifier,
+    pub transaction_identifier: TransactionIdentifier,
+}
+
+/// A write to a var, a map entry, a balance or a token, along with the block
+/// and transaction that performed it. `value` is None once the entry was
+/// deleted (or the token burnt / sent away).
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StoredVersion<T> {
+    pub value: Option<T>,
+    pub block_identifier: BlockIdentifier,
+    pub transaction_identifier: TransactionIdentifier,
+}
+
 impl DataMapStoredEntry {
     pub fn get_formatted_decoded_key(&self) -> String {
         let value = self.hex_key.clone();
@@ -0,0 +1,547 @@
+use super::{Action, Lambda, User};
+use clarinet_lib::clarity_repl::clarity::codec::transaction::{
+    StacksTransaction, TransactionPayload,
+};
+use clarinet_lib::clarity_repl::clarity::codec::StacksMessageCodec;
+use clarinet_lib::clarity_repl::clarity::types::QualifiedContractIdentifier;
+use clarinet_lib::clarity_repl::clarity::util::hash::hex_bytes;
+use clarinet_lib::clarity_repl::clarity::Value;
+use clarinet_lib::clarity_repl::repl::{Session, SessionSettings};
+use clarinet_lib::types::events::StacksTransactionEvent;
+use clarinet_lib::types::{
+    AccountIdentifier, BitcoinTransactionData, StacksTransactionData, StacksTransactionKind,
+};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub enum Predicate {
+    #[serde(rename = "bitcoin")]
+    BitcoinPredicate(BitcoinPredicate),
+    #[serde(rename = "stacks")]
+    StacksPredicate(StacksPredicate),
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BitcoinPredicate {
+    AnyBlock,
+    /// Operations crediting or debiting this account.
+    AnyOperation(AccountIdentifier),
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StacksPredicate {
+    AnyBlock,
+    ContractCall(StacksContractCallPredicate),
+    PrintEvent(StacksPrintEventPredicate),
+    FtEvent(StacksFtEventPredicate),
+    NftEvent(StacksNftEventPredicate),
+    StxEvent(StacksStxEventPredicate),
+    ContractDeployment(StacksContractDeploymentPredicate),
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct StacksContractCallPredicate {
+    pub contract_identifier: String,
+    /// Any public function of the contract if None.
+    pub method: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct StacksPrintEventPredicate {
+    pub contract_identifier: String,
+    pub topic: Option<String>,
+    /// Matcher applied to the printed value.
+    pub value: Option<ClarityValueMatcher>,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct StacksFtEventPredicate {
+    /// Such as `ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.token::token`.
+    pub asset_identifier: String,
+    /// Any action if empty.
+    #[serde(default)]
+    pub actions: Vec<TokenAction>,
+    /// Sender or recipient of the tokens.
+    pub principal: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct StacksNftEventPredicate {
+    pub asset_identifier: String,
+    #[serde(default)]
+    pub actions: Vec<TokenAction>,
+    pub principal: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct StacksStxEventPredicate {
+    #[serde(default)]
+    pub actions: Vec<TokenAction>,
+    pub principal: Option<String>,
+    /// Smallest amount moved, in microstacks.
+    pub min_amount: Option<u64>,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct StacksContractDeploymentPredicate {
+    pub deployer: Option<String>,
+    /// Fully qualified identifier of a trait the contract must implement.
+    pub implements_trait: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenAction {
+    Mint,
+    Transfer,
+    Burn,
+    /// Only emitted for STX.
+    Lock,
+}
+
+/// Matches Clarity values, through their Clarity syntax (`u1`, `"transfer"`,
+/// `'ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM`, ...).
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClarityValueMatcher {
+    Equals(String),
+    Contains(String),
+    TupleField {
+        name: String,
+        matcher: Box<ClarityValueMatcher>,
+    },
+}
+
+impl ClarityValueMatcher {
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            ClarityValueMatcher::Equals(expected) => &format!("{}", value) == expected,
+            ClarityValueMatcher::Contains(expected) => format!("{}", value).contains(expected),
+            ClarityValueMatcher::TupleField { name, matcher } => match value {
+                Value::Tuple(tuple) => tuple
+                    .data_map
+                    .iter()
+                    .any(|(key, value)| &key.to_string() == name && matcher.matches(value)),
+                _ => false,
+            },
+        }
+    }
+}
+
+impl StacksPredicate {
+    /// Returns true if `transaction` is relevant to the predicate. Failed
+    /// transactions are only relevant to `AnyBlock`.
+    pub fn evaluate_transaction(&self, transaction: &StacksTransactionData) -> bool {
+        match self {
+            StacksPredicate::AnyBlock => true,
+            _ if !transaction.metadata.success => false,
+            StacksPredicate::ContractCall(predicate) => {
+                match transaction.metadata.kind {
+                    StacksTransactionKind::ContractCall => {}
+                    _ => return false,
+                }
+                match get_contract_call(&transaction.metadata.raw_tx) {
+                    Some((contract_identifier, method)) => {
+                        contract_identifier == predicate.contract_identifier
+                            && match predicate.method {
+                                Some(ref expected) => &method == expected,
+                                None => true,
+                            }
+                    }
+                    None => false,
+                }
+            }
+            StacksPredicate::PrintEvent(predicate) => transaction
+                .metadata
+                .receipt
+                .events
+                .iter()
+                .any(|event| match event {
+                    StacksTransactionEvent::SmartContractEvent(data) => {
+                        data.contract_identifier == predicate.contract_identifier
+                            && match predicate.topic {
+                                Some(ref topic) => &data.topic == topic,
+                                None => true,
+                            }
+                            && match predicate.value {
+                                Some(ref matcher) => match decode_clarity_value(&data.hex_value) {
+                                    Some(value) => matcher.matches(&value),
+                                    None => false,
+                                },
+                                None => true,
+                            }
+                    }
+                    _ => false,
+                }),
+            StacksPredicate::FtEvent(predicate) => {
+                transaction.metadata.receipt.events.iter().any(|event| {
+                    let (asset_identifier, action, principals) = match event {
+                        StacksTransactionEvent::FTMintEvent(data) => (
+                            &data.asset_class_identifier,
+                            TokenAction::Mint,
+                            vec![&data.recipient],
+                        ),
+                        StacksTransactionEvent::FTTransferEvent(data) => (
+                            &data.asset_class_identifier,
+                            TokenAction::Transfer,
+                            vec![&data.sender, &data.recipient],
+                        ),
+                        StacksTransactionEvent::FTBurnEvent(data) => (
+                            &data.asset_class_identifier,
+                            TokenAction::Burn,
+                            vec![&data.sender],
+                        ),
+                        _ => return false,
+                    };
+                    asset_identifier == &predicate.asset_identifier
+                        && matches_action(&predicate.actions, action)
+                        && matches_principal(&predicate.principal, &principals)
+                })
+            }
+            StacksPredicate::NftEvent(predicate) => {
+                transaction.metadata.receipt.events.iter().any(|event| {
+                    let (asset_identifier, action, principals) = match event {
+                        StacksTransactionEvent::NFTMintEvent(data) => (
+                            &data.asset_class_identifier,
+                            TokenAction::Mint,
+                            vec![&data.recipient],
+                        ),
+                        StacksTransactionEvent::NFTTransferEvent(data) => (
+                            &data.asset_class_identifier,
+                            TokenAction::Transfer,
+                            vec![&data.sender, &data.recipient],
+                        ),
+                        StacksTransactionEvent::NFTBurnEvent(data) => (
+                            &data.asset_class_identifier,
+                            TokenAction::Burn,
+                            vec![&data.sender],
+                        ),
+                        _ => return false,
+                    };
+                    asset_identifier == &predicate.asset_identifier
+                        && matches_action(&predicate.actions, action)
+                        && matches_principal(&predicate.principal, &principals)
+                })
+            }
+            StacksPredicate::StxEvent(predicate) => {
+                transaction.metadata.receipt.events.iter().any(|event| {
+                    let (action, amount, principals) = match event {
+                        StacksTransactionEvent::STXMintEvent(data) => {
+                            (TokenAction::Mint, &data.amount, vec![&data.recipient])
+                        }
+                        StacksTransactionEvent::STXTransferEvent(data) => (
+                            TokenAction::Transfer,
+                            &data.amount,
+                            vec![&data.sender, &data.recipient],
+                        ),
+                        StacksTransactionEvent::STXBurnEvent(data) => {
+                            (TokenAction::Burn, &data.amount, vec![&data.sender])
+                        }
+                        StacksTransactionEvent::STXLockEvent(data) => (
+                            TokenAction::Lock,
+                            &data.locked_amount,
+                            vec![&data.locked_address],
+                        ),
+                        _ => return false,
+                    };
+                    matches_action(&predicate.actions, action)
+                        && matches_principal(&predicate.principal, &principals)
+                        && match predicate.min_amount {
+                            Some(min_amount) => match amount.parse::<u128>() {
+                                Ok(amount) => amount >= min_amount as u128,
+                                Err(_) => false,
+                            },
+                            None => true,
+                        }
+                })
+            }
+            StacksPredicate::ContractDeployment(predicate) => {
+                let deployment = match transaction.metadata.kind {
+                    StacksTransactionKind::ContractDeployment(ref deployment) => deployment,
+                    _ => return false,
+                };
+                let deployer = deployment
+                    .contract_identifier
+                    .split('.')
+                    .next()
+                    .unwrap_or_default();
+                match predicate.deployer {
+                    Some(ref expected) if expected != deployer => return false,
+                    _ => {}
+                }
+                match predicate.implements_trait {
+                    Some(ref trait_identifier) => {
+                        get_implemented_traits(&deployment.contract_identifier, &deployment.code)
+                            .iter()
+                            .any(|implemented| implemented == trait_identifier)
+                    }
+                    None => true,
+                }
+            }
+        }
+    }
+}
+
+impl BitcoinPredicate {
+    pub fn evaluate_transaction(&self, transaction: &BitcoinTransactionData) -> bool {
+        match self {
+            BitcoinPredicate::AnyBlock => true,
+            BitcoinPredicate::AnyOperation(account) => transaction
+                .operations
+                .iter()
+                .any(|operation| operation.account.address == account.address),
+        }
+    }
+}
+
+fn matches_action(actions: &[TokenAction], action: TokenAction) -> bool {
+    actions.is_empty() || actions.contains(&action)
+}
+
+fn matches_principal(expected: &Option<String>, principals: &[&String]) -> bool {
+    match expected {
+        Some(expected) => principals.iter().any(|principal| *principal == expected),
+        None => true,
+    }
+}
+
+/// Returns the contract and the function invoked by a contract call, decoded
+/// from the raw transaction.
+fn get_contract_call(raw_tx: &str) -> Option<(String, String)> {
+    let bytes = hex_bytes(raw_tx.strip_prefix("0x").unwrap_or(raw_tx)).ok()?;
+    let transaction = StacksTransaction::consensus_deserialize(&mut Cursor::new(&bytes)).ok()?;
+    match transaction.payload {
+        TransactionPayload::ContractCall(call) => Some((
+            format!("{}.{}", call.address, call.contract_name),
+            call.function_name.to_string(),
+        )),
+        _ => None,
+    }
+}
+
+thread_local! {
+    /// Session only used for parsing contracts, built once per thread rather
+    /// than for every deployment evaluated.
+    static PARSER_SESSION: RefCell<Session> =
+        RefCell::new(Session::new(SessionSettings::default()));
+}
+
+/// Returns the fully qualified identifiers of the traits implemented by the
+/// contract, as resolved by the Clarity parser: the analysis records the same
+/// traits, but can't run without the traits being deployed in the session.
+fn get_implemented_traits(contract_identifier: &str, code: &str) -> Vec<String> {
+    let contract_identifier = match QualifiedContractIdentifier::parse(contract_identifier) {
+        Ok(contract_identifier) => contract_identifier,
+        Err(_) => return vec![],
+    };
+    let (ast, _, success) = PARSER_SESSION.with(|session| {
+        session
+            .borrow_mut()
+            .interpreter
+            .build_ast(contract_identifier, code.to_string(), 2)
+    });
+    if !success {
+        return vec![];
+    }
+    ast.implemented_traits
+        .iter()
+        .map(|trait_identifier| {
+            format!(
+                "{}.{}",
+                trait_identifier.contract_identifier, trait_identifier.name
+            )
+        })
+        .collect()
+}
+
+fn decode_clarity_value(hex_value: &str) -> Option<Value> {
+    let bytes = hex_bytes(hex_value.strip_prefix("0x").unwrap_or(hex_value)).ok()?;
+    Value::consensus_deserialize(&mut Cursor::new(&bytes)).ok()
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct ClarinetManifestFile {
+    orchestra: Option<OrchestraManifestFile>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct OrchestraManifestFile {
+    lambdas: Option<Vec<LambdaManifestFile>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct LambdaManifestFile {
+    lambda_id: Option<u64>,
+    name: String,
+    predicate: Predicate,
+    action: LambdaManifestAction,
+}
+
+/// Actions declarable in a manifest, some of them being rejected.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+enum LambdaManifestAction {
+    HttpPost(String),
+    /// Scripts can't be run isolated from the observer: rejected.
+    CodeExecution(String),
+}
+
+impl LambdaManifestAction {
+    fn into_action(self) -> Result<User, String> {
+        match self {
+            LambdaManifestAction::HttpPost(url) => Ok(User::HTTPPost(url)),
+            LambdaManifestAction::CodeExecution(_) => {
+                Err("code_execution actions are not supported, use http_post".into())
+            }
+        }
+    }
+}
+
+/// Reads the lambdas declared in the `[[orchestra.lambdas]]` sections of a
+/// Clarinet manifest.
+pub fn load_lambdas_from_manifest(manifest_path: &Path) -> Result<Vec<Lambda>, String> {
+    let mut file = File::open(manifest_path)
+        .map_err(|e| format!("unable to open manifest {:?}: {}", manifest_path, e))?;
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer)
+        .map_err(|e| format!("unable to read manifest {:?}: {}", manifest_path, e))?;
+    parse_lambdas(&buffer)
+}
+
+/// Lambdas without a `lambda_id` are numbered after their position.
+pub fn parse_lambdas(content: &str) -> Result<Vec<Lambda>, String> {
+    let manifest: ClarinetManifestFile =
+        toml::from_str(content).map_err(|e| format!("unable to parse lambdas: {}", e))?;
+    let lambdas = manifest
+        .orchestra
+        .and_then(|orchestra| orchestra.lambdas)
+        .unwrap_or_default();
+    lambdas
+        .into_iter()
+        .enumerate()
+        .map(|(position, lambda)| {
+            let action = lambda
+                .action
+                .into_action()
+                .map_err(|e| format!("unable to load lambda {}: {}", lambda.name, e))?;
+            Ok(Lambda {
+                lambda_id: lambda.lambda_id.unwrap_or(position as u64 + 1),
+                name: lambda.name,
+                predicate: lambda.predicate,
+                action: Action::User(action),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lambdas_are_loaded_from_manifest() {
+        let manifest = r#"
+[project]
+name = "counter"
+
+[[orchestra.lambdas]]
+name = "large-stx-transfers"
+action = { http_post = "http://localhost:3000/transfers" }
+predicate.stacks.stx_event = { actions = ["transfer"], min_amount = 1000000 }
+
+[[orchestra.lambdas]]
+lambda_id = 7
+name = "sip-010-deployments"
+action = { http_post = "http://localhost:3000/deployments" }
+
+[orchestra.lambdas.predicate.stacks.contract_deployment]
+implements_trait = "SP3FBR2AGK5H9QBDH3EEN6DF8EK8JY7RX8QJ5SVTE.sip-010-trait-ft-standard.sip-010-trait"
+"#;
+        let lambdas = parse_lambdas(manifest).unwrap();
+        assert_eq!(lambdas.len(), 2);
+        assert_eq!(lambdas[0].lambda_id, 1);
+        assert_eq!(
+            lambdas[0].predicate,
+            Predicate::StacksPredicate(StacksPredicate::StxEvent(StacksStxEventPredicate {
+                actions: vec![TokenAction::Transfer],
+                principal: None,
+                min_amount: Some(1000000),
+            }))
+        );
+        assert_eq!(
+            lambdas[0].action,
+            Action::User(User::HTTPPost("http://localhost:3000/transfers".into()))
+        );
+        assert_eq!(lambdas[1].lambda_id, 7);
+
+        assert_eq!(
+            parse_lambdas("[project]\nname = \"counter\"").unwrap(),
+            vec![]
+        );
+
+        let manifest = r#"
+[[orchestra.lambdas]]
+name = "stacking"
+action = { http_post = "http://localhost:3000/stacking" }
+predicate.bitcoin.any_stacks_operation = ["stack_stx", { address = "mqZWn3Bfa2yQrTeP5EBFnSJqs8ZgBmMfYA" }]
+"#;
+        assert!(parse_lambdas(manifest)
+            .unwrap_err()
+            .contains("unknown variant `any_stacks_operation`"));
+
+        let manifest = r#"
+[[orchestra.lambdas]]
+name = "deployments"
+action = { code_execution = "cat >> deployments.json" }
+predicate.stacks = "any_block"
+"#;
+        assert!(parse_lambdas(manifest)
+            .unwrap_err()
+            .contains("code_execution actions are not supported"));
+    }
+
+    #[test]
+    fn implemented_traits_are_resolved() {
+        let code = "(impl-trait 'SP3FBR2AGK5H9QBDH3EEN6DF8EK8JY7RX8QJ5SVTE.sip-010-trait-ft-standard.sip-010-trait)\n(impl-trait .nft-trait.nft-trait)";
+        assert_eq!(
+            get_implemented_traits("ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.token", code),
+            vec![
+                "SP3FBR2AGK5H9QBDH3EEN6DF8EK8JY7RX8QJ5SVTE.sip-010-trait-ft-standard.sip-010-trait"
+                    .to_string(),
+                "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.nft-trait.nft-trait".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn contract_calls_are_decoded_from_raw_transactions() {
+        use clarinet_lib::clarity_repl::clarity::util::hash::to_hex;
+        use stacks_rpc_client::transactions::{TransactionBuilder, TransactionKeys};
+
+        let secret_key =
+            hex_bytes("753b7cc01a1a2e86221266a154af739463fce51219d97e4f856cd7200c3bd2a601")
+                .unwrap();
+        let transaction = TransactionBuilder::contract_call(
+            "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.counter",
+            "increment",
+            vec![Value::UInt(1)],
+        )
+        .unwrap()
+        .build(&TransactionKeys::singlesig(&secret_key).unwrap())
+        .unwrap();
+        let raw_tx = format!("0x{}", to_hex(&transaction.serialize_to_vec()));
+        assert_eq!(
+            get_contract_call(&raw_tx),
+            Some((
+                "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM.counter".to_string(),
+                "increment".to_string()
+            ))
+        );
+        assert_eq!(get_contract_call("0x00"), None);
+    }
+}
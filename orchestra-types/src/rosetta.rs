@@ -93,13 +93,20 @@ pub struct StacksTransactionData {
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum StacksTransactionKind {
-    ContractCall,
+    ContractCall(StacksContractCallData),
     ContractDeployment(StacksContractDeploymentData),
     NativeTokenTransfer,
     Coinbase,
     Other,
 }
 
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StacksContractCallData {
+    pub contract_identifier: String,
+    pub method: String,
+    pub args: Vec<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct StacksContractDeploymentData {
     pub contract_identifier: String,
@@ -131,6 +138,19 @@ pub struct StacksTransactionExecutionCost {
     pub runtime: u64,
 }
 
+/// Fees paid by recently mined transactions sharing the same payload (calls
+/// to a given contract function, STX transfers, contract deployments).
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StacksFeeEstimate {
+    pub samples: usize,
+    /// 25th, 50th and 75th percentiles of the fees paid, in µSTX.
+    pub fee_low: u64,
+    pub fee_medium: u64,
+    pub fee_high: u64,
+    /// Median of each execution cost dimension.
+    pub execution_cost: Option<StacksTransactionExecutionCost>,
+}
+
 /// Extra event data for Transaction
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct StacksTransactionReceipt {
@@ -392,6 +412,27 @@ pub enum BitcoinChainEvent {
     ChainUpdatedWithReorg(Vec<BitcoinBlockData>, Vec<BitcoinBlockData>),
 }
 
+/// Lifecycle of the bitcoin transactions broadcasted through the observer's
+/// bitcoin RPC proxy.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BitcoinTransactionEvent {
+    TransactionBroadcast(BitcoinTransactionBroadcastData),
+    TransactionConfirmed(BitcoinTransactionConfirmedData),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BitcoinTransactionBroadcastData {
+    pub transaction_identifier: TransactionIdentifier,
+    /// Consensus-encoded transaction, hex encoded, as submitted to bitcoind.
+    pub raw_tx: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BitcoinTransactionConfirmedData {
+    pub transaction_identifier: TransactionIdentifier,
+    pub block_identifier: BlockIdentifier,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum StacksChainEvent {
@@ -436,14 +477,47 @@ pub enum StacksNetwork {
     Mainnet,
 }
 
+impl StacksNetwork {
+    pub fn is_mainnet(&self) -> bool {
+        self == &StacksNetwork::Mainnet
+    }
+
+    /// Chain id committed in every transaction, preventing replays across networks.
+    pub fn chain_id(&self) -> u32 {
+        match self {
+            StacksNetwork::Mainnet => 0x00000001,
+            StacksNetwork::Testnet | StacksNetwork::Devnet => 0x80000000,
+        }
+    }
+
+    pub fn get_bitcoin_network(&self) -> BitcoinNetwork {
+        match self {
+            StacksNetwork::Devnet => BitcoinNetwork::Regtest,
+            StacksNetwork::Testnet => BitcoinNetwork::Testnet,
+            StacksNetwork::Mainnet => BitcoinNetwork::Mainnet,
+        }
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum BitcoinNetwork {
     Regtest,
     Testnet,
     Mainnet,
 }
 
+impl BitcoinNetwork {
+    /// Name of the chain, as reported by bitcoind's `getblockchaininfo`.
+    pub fn get_chain_name(&self) -> &'static str {
+        match self {
+            BitcoinNetwork::Regtest => "regtest",
+            BitcoinNetwork::Testnet => "test",
+            BitcoinNetwork::Mainnet => "main",
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum ChainsCoordinatorCommand {
@@ -0,0 +1,270 @@
+use crate::rpc_client::{
+    decode_consensus, decode_error_response, decode_post_transaction_result,
+    decode_read_only_call_result, decode_transaction_rejection, AccountInfo, AccountResponse,
+    Balance, ClarityDataResponse, Contract, ContractInterface, FeeEstimation, NodeInfo,
+    NodeRequest, PostTransactionResult, PoxInfo, ReadOnlyCallResult, RetryPolicy, RpcError,
+    TraitImplementationResponse, DEFAULT_TIMEOUT,
+};
+use clarity_repl::clarity::codec::transaction::TransactionPayload;
+use clarity_repl::clarity::codec::{StacksBlock, StacksMicroblock, StacksTransaction};
+use clarity_repl::clarity::types::Value;
+use reqwest::{Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+/// Non-blocking counterpart of `StacksRpc`, for callers running on an async
+/// runtime (Rocket handlers, tokio tasks spawned by actors).
+#[derive(Clone)]
+pub struct AsyncStacksRpc {
+    pub url: String,
+    pub client: Client,
+    pub retry_policy: RetryPolicy,
+}
+
+impl AsyncStacksRpc {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.into(),
+            client: Client::builder().timeout(DEFAULT_TIMEOUT).build().unwrap(),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = Client::builder().timeout(timeout).build().unwrap();
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    fn build_request(&self, request: &NodeRequest) -> RequestBuilder {
+        match request {
+            NodeRequest::Get(url) => self.client.get(url),
+            NodeRequest::PostJson(url, body) => self.client.post(url).json(body),
+            NodeRequest::PostOctets(url, body) => self
+                .client
+                .post(url)
+                .header("Content-Type", "application/octet-stream")
+                .body(body.clone()),
+        }
+    }
+
+    async fn send(
+        &self,
+        request: &NodeRequest,
+        retry_policy: &RetryPolicy,
+    ) -> Result<Response, RpcError> {
+        let mut attempt = 1;
+        loop {
+            let result = match self.build_request(request).send().await {
+                Ok(res) if res.status().is_success() => Ok(res),
+                Ok(res) => {
+                    let status = res.status().as_u16();
+                    let body = res.text().await.unwrap_or_default();
+                    Err(decode_error_response(status, body))
+                }
+                Err(e) => Err(RpcError::from(e)),
+            };
+            match result {
+                Err(e) if e.is_retryable() => match retry_policy.backoff(attempt) {
+                    Some(backoff) => tokio::time::sleep(backoff).await,
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn execute<T: DeserializeOwned>(&self, request: NodeRequest) -> Result<T, RpcError> {
+        self.send(&request, &self.retry_policy)
+            .await?
+            .json::<T>()
+            .await
+            .map_err(|e| RpcError::Decode(e.to_string()))
+    }
+
+    async fn execute_raw(&self, request: NodeRequest) -> Result<Vec<u8>, RpcError> {
+        self.send(&request, &self.retry_policy)
+            .await?
+            .bytes()
+            .await
+            .map(|bytes| bytes.to_vec())
+            .map_err(RpcError::from)
+    }
+
+    /// Broadcasts are sent once, see `StacksRpc::post_transaction`.
+    pub async fn post_transaction(
+        &self,
+        transaction: StacksTransaction,
+    ) -> Result<PostTransactionResult, RpcError> {
+        let request = NodeRequest::post_transaction(&self.url, &transaction);
+        let txid: String = self
+            .send(&request, &RetryPolicy::none())
+            .await
+            .map_err(decode_transaction_rejection)?
+            .json()
+            .await
+            .map_err(|e| RpcError::Decode(e.to_string()))?;
+        Ok(decode_post_transaction_result(txid))
+    }
+
+    pub async fn get_nonce(&self, address: &str) -> Result<u64, RpcError> {
+        let res: Balance = self.execute(NodeRequest::nonce(&self.url, address)).await?;
+        Ok(res.nonce)
+    }
+
+    pub async fn get_account(&self, principal: &str) -> Result<AccountInfo, RpcError> {
+        self.get_account_at(principal, None).await
+    }
+
+    /// Same as `get_account`, as of the block `tip` (an index block hash)
+    /// when given.
+    pub async fn get_account_at(
+        &self,
+        principal: &str,
+        tip: Option<&str>,
+    ) -> Result<AccountInfo, RpcError> {
+        let request = NodeRequest::account(&self.url, principal, tip);
+        let res: AccountResponse = self.execute(request).await?;
+        res.decode()
+    }
+
+    pub async fn get_pox_info(&self) -> Result<PoxInfo, RpcError> {
+        self.execute(NodeRequest::pox_info(&self.url)).await
+    }
+
+    pub async fn get_info(&self) -> Result<NodeInfo, RpcError> {
+        self.execute(NodeRequest::info(&self.url)).await
+    }
+
+    pub async fn get_contract_source(
+        &self,
+        principal: &str,
+        contract_name: &str,
+    ) -> Result<Contract, RpcError> {
+        let request = NodeRequest::contract_source(&self.url, principal, contract_name);
+        self.execute(request).await
+    }
+
+    pub async fn call_read_only_fn(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        method: &str,
+        args: Vec<Value>,
+        sender: &str,
+    ) -> Result<Value, RpcError> {
+        self.call_read_only_fn_at(contract_addr, contract_name, method, args, sender, None)
+            .await
+    }
+
+    /// Same as `call_read_only_fn`, evaluated as of the block `tip`.
+    pub async fn call_read_only_fn_at(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        method: &str,
+        args: Vec<Value>,
+        sender: &str,
+        tip: Option<&str>,
+    ) -> Result<Value, RpcError> {
+        let request = NodeRequest::read_only_call(
+            &self.url,
+            contract_addr,
+            contract_name,
+            method,
+            &args,
+            sender,
+            tip,
+        );
+        let response: ReadOnlyCallResult = self.execute(request).await?;
+        decode_read_only_call_result(response)
+    }
+
+    pub async fn get_contract_interface(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+    ) -> Result<ContractInterface, RpcError> {
+        let request = NodeRequest::contract_interface(&self.url, contract_addr, contract_name);
+        self.execute(request).await
+    }
+
+    pub async fn get_map_entry(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        map_name: &str,
+        key: &Value,
+    ) -> Result<Value, RpcError> {
+        let request =
+            NodeRequest::map_entry(&self.url, contract_addr, contract_name, map_name, key);
+        let res: ClarityDataResponse = self.execute(request).await?;
+        res.decode()
+    }
+
+    pub async fn get_data_var(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        var_name: &str,
+    ) -> Result<Value, RpcError> {
+        let request = NodeRequest::data_var(&self.url, contract_addr, contract_name, var_name);
+        let res: ClarityDataResponse = self.execute(request).await?;
+        res.decode()
+    }
+
+    pub async fn estimate_transaction_fee(
+        &self,
+        payload: &TransactionPayload,
+        estimated_len: Option<u64>,
+    ) -> Result<FeeEstimation, RpcError> {
+        let request = NodeRequest::fee_estimation(&self.url, payload, estimated_len);
+        self.execute(request).await
+    }
+
+    pub async fn get_block(&self, index_block_hash: &str) -> Result<StacksBlock, RpcError> {
+        let request = NodeRequest::block(&self.url, index_block_hash);
+        decode_consensus(&self.execute_raw(request).await?)
+    }
+
+    pub async fn get_microblock(
+        &self,
+        microblock_hash: &str,
+    ) -> Result<StacksMicroblock, RpcError> {
+        let request = NodeRequest::microblock(&self.url, microblock_hash);
+        decode_consensus(&self.execute_raw(request).await?)
+    }
+
+    pub async fn get_confirmed_microblocks(
+        &self,
+        index_block_hash: &str,
+    ) -> Result<Vec<StacksMicroblock>, RpcError> {
+        let request = NodeRequest::confirmed_microblocks(&self.url, index_block_hash);
+        decode_consensus(&self.execute_raw(request).await?)
+    }
+
+    pub async fn is_trait_implemented(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        trait_contract_addr: &str,
+        trait_contract_name: &str,
+        trait_name: &str,
+    ) -> Result<bool, RpcError> {
+        let request = NodeRequest::trait_implementation(
+            &self.url,
+            contract_addr,
+            contract_name,
+            trait_contract_addr,
+            trait_contract_name,
+            trait_name,
+        );
+        let res: TraitImplementationResponse = self.execute(request).await?;
+        Ok(res.is_implemented)
+    }
+}
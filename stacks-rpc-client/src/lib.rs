@@ -8,10 +8,19 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 
+pub mod async_rpc_client;
+pub mod nonce_manager;
+pub mod observer_client;
 pub mod rpc_client;
 pub mod transactions;
 
-pub use rpc_client::{PoxInfo, StacksRpc};
+pub use async_rpc_client::AsyncStacksRpc;
+pub use nonce_manager::{NonceManager, TransactionStatus, TransactionTracker, TransactionUpdate};
+pub use observer_client::ObserverFeeClient;
+pub use rpc_client::{
+    AccountInfo, ContractInterface, FeeEstimation, NodeInfo, PoxInfo, RetryPolicy, RpcError,
+    StacksRpc,
+};
 
 #[cfg(test)]
 mod tests {
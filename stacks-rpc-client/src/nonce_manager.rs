@@ -0,0 +1,721 @@
+use crate::rpc_client::{normalize_txid, RpcError, StacksRpc};
+use clarity_repl::clarity::codec::StacksTransaction;
+use orchestra_types::{BlockIdentifier, StacksBlockData, StacksChainEvent, StacksMicroblocksTrail};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Rejection reasons returned by the node when the nonce of a transaction
+/// does not line up with the state of the account or of the mempool.
+const NONCE_REJECTION_REASONS: [&str; 2] = ["BadNonce", "ConflictingNonceInMempool"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionStatus {
+    /// Accepted by the node, waiting in the mempool.
+    Pending,
+    /// Included in a microblock streamed on top of `anchored_block`.
+    MinedInMicroblock {
+        anchored_block: BlockIdentifier,
+        microblock: BlockIdentifier,
+    },
+    /// Included in an anchored block, or in a microblock confirmed by it.
+    Anchored { block: BlockIdentifier },
+    /// Evicted from the mempool, or superseded by another transaction
+    /// spending the same nonce.
+    Dropped { reason: String },
+    /// Replaced by a transaction with the same nonce and a higher fee.
+    Replaced { by: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionUpdate {
+    pub txid: String,
+    pub sender: String,
+    pub nonce: u64,
+    pub status: TransactionStatus,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackedTransaction {
+    pub txid: String,
+    pub sender: String,
+    pub nonce: u64,
+    pub fee: u64,
+    pub status: TransactionStatus,
+}
+
+/// Follows the transactions broadcasted by a `NonceManager` through the
+/// chain events emitted by the observer.
+#[derive(Default)]
+pub struct TransactionTracker {
+    transactions: HashMap<String, TrackedTransaction>,
+    nonces: BTreeMap<(String, u64), Vec<String>>,
+}
+
+impl TransactionTracker {
+    pub fn new() -> TransactionTracker {
+        TransactionTracker::default()
+    }
+
+    pub fn track(&mut self, txid: &str, sender: &str, nonce: u64, fee: u64) {
+        self.transactions.insert(
+            txid.to_string(),
+            TrackedTransaction {
+                txid: txid.to_string(),
+                sender: sender.to_string(),
+                nonce,
+                fee,
+                status: TransactionStatus::Pending,
+            },
+        );
+        self.nonces
+            .entry((sender.to_string(), nonce))
+            .or_insert_with(Vec::new)
+            .push(txid.to_string());
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&TrackedTransaction> {
+        self.transactions.get(txid)
+    }
+
+    /// Returns the transactions of `sender` still waiting to be anchored.
+    pub fn get_unconfirmed(&self, sender: &str) -> Vec<&TrackedTransaction> {
+        let mut transactions = self
+            .transactions
+            .values()
+            .filter(|tx| tx.sender == sender)
+            .filter(|tx| {
+                matches!(
+                    tx.status,
+                    TransactionStatus::Pending | TransactionStatus::MinedInMicroblock { .. }
+                )
+            })
+            .collect::<Vec<_>>();
+        transactions.sort_by_key(|tx| tx.nonce);
+        transactions
+    }
+
+    /// Stops following the transactions of `sender` anchored below `nonce`.
+    pub fn prune(&mut self, sender: &str, nonce: u64) {
+        let keys = self
+            .nonces
+            .range((sender.to_string(), 0)..(sender.to_string(), nonce))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in keys.into_iter() {
+            for txid in self.nonces.remove(&key).unwrap_or_default() {
+                self.transactions.remove(&txid);
+            }
+        }
+    }
+
+    pub fn mark_replaced(&mut self, txid: &str, by: &str) -> Option<TransactionUpdate> {
+        self.update(txid, TransactionStatus::Replaced { by: by.to_string() })
+    }
+
+    /// Handles the transactions dropped from the node's mempool, which stop
+    /// being followed.
+    pub fn process_dropped_transactions(
+        &mut self,
+        txids: &[String],
+        reason: &str,
+    ) -> Vec<TransactionUpdate> {
+        let updates = txids
+            .iter()
+            .filter_map(|txid| {
+                self.update(
+                    txid,
+                    TransactionStatus::Dropped {
+                        reason: reason.to_string(),
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        for update in updates.iter() {
+            self.forget(&update.txid);
+        }
+        updates
+    }
+
+    fn forget(&mut self, txid: &str) {
+        let tx = match self.transactions.remove(txid) {
+            Some(tx) => tx,
+            None => return,
+        };
+        let key = (tx.sender, tx.nonce);
+        if let Some(txids) = self.nonces.get_mut(&key) {
+            txids.retain(|other| other != txid);
+            if txids.is_empty() {
+                self.nonces.remove(&key);
+            }
+        }
+    }
+
+    /// Stops following the transactions of the senders of the transactions
+    /// included in a confirmed block, up to their nonce: they can't be
+    /// reverted anymore.
+    fn prune_confirmed(
+        &mut self,
+        confirmed_block: &(StacksBlockData, Option<StacksMicroblocksTrail>),
+    ) {
+        let (block, trail) = confirmed_block;
+        for txid in block_txids(block, trail).iter() {
+            let (sender, nonce) = match self.transactions.get(txid) {
+                Some(tx) => (tx.sender.clone(), tx.nonce),
+                None => continue,
+            };
+            self.prune(&sender, nonce + 1);
+        }
+    }
+
+    /// Returns the status changes carried by a chain update. Transactions
+    /// included in blocks or microblocks rolled back by a reorg are pending
+    /// again, unless they are included in the new fork.
+    pub fn process_chain_event(
+        &mut self,
+        chain_event: &StacksChainEvent,
+    ) -> Vec<TransactionUpdate> {
+        let mut updates = vec![];
+        match chain_event {
+            StacksChainEvent::ChainUpdatedWithBlock(data) => {
+                updates.append(&mut self.process_block(&data.new_block, &data.anchored_trail));
+                self.prune_confirmed(&data.confirmed_block);
+            }
+            StacksChainEvent::ChainUpdatedWithReorg(data) => {
+                for (trail, block) in data.old_blocks.iter() {
+                    updates.append(&mut self.revert_block(block, trail));
+                }
+                for (trail, block) in data.new_blocks.iter() {
+                    updates.append(&mut self.process_block(block, trail));
+                }
+                self.prune_confirmed(&data.confirmed_block);
+            }
+            StacksChainEvent::ChainUpdatedWithMicroblock(data) => {
+                updates.append(
+                    &mut self.process_microblocks(&data.anchored_block, &data.current_trail),
+                );
+            }
+            StacksChainEvent::ChainUpdatedWithMicroblockReorg(data) => {
+                if let Some(ref trail) = data.old_trail {
+                    updates.append(&mut self.revert_microblocks(trail));
+                }
+                updates.append(&mut self.process_block(&data.new_block, &data.new_anchored_trail));
+            }
+        }
+        // A transaction reverted and re-included by the same event only
+        // reports its final status.
+        let mut latest: Vec<TransactionUpdate> = vec![];
+        for update in updates.into_iter() {
+            latest.retain(|u| u.txid != update.txid);
+            latest.push(update);
+        }
+        latest
+    }
+
+    fn process_block(
+        &mut self,
+        block: &StacksBlockData,
+        anchored_trail: &Option<StacksMicroblocksTrail>,
+    ) -> Vec<TransactionUpdate> {
+        let txids = block_txids(block, anchored_trail);
+        let mut updates = vec![];
+        for txid in txids.iter() {
+            let (sender, nonce) = match self.transactions.get(txid) {
+                Some(tx) => (tx.sender.clone(), tx.nonce),
+                None => continue,
+            };
+            if let Some(update) = self.update(
+                txid,
+                TransactionStatus::Anchored {
+                    block: block.block_identifier.clone(),
+                },
+            ) {
+                updates.push(update);
+            }
+            // Any other transaction spending this nonce can't be mined anymore.
+            let conflicting = self
+                .nonces
+                .get(&(sender, nonce))
+                .cloned()
+                .unwrap_or_default();
+            for other in conflicting.iter().filter(|other| *other != txid) {
+                let superseded = TransactionStatus::Dropped {
+                    reason: format!("nonce consumed by {}", txid),
+                };
+                if let Some(update) = self.update(other, superseded) {
+                    updates.push(update);
+                }
+            }
+        }
+        updates
+    }
+
+    fn process_microblocks(
+        &mut self,
+        anchored_block: &StacksBlockData,
+        trail: &StacksMicroblocksTrail,
+    ) -> Vec<TransactionUpdate> {
+        let mut updates = vec![];
+        for microblock in trail.microblocks.iter() {
+            for tx in microblock.transactions.iter() {
+                let status = TransactionStatus::MinedInMicroblock {
+                    anchored_block: anchored_block.block_identifier.clone(),
+                    microblock: microblock.block_identifier.clone(),
+                };
+                if let Some(update) = self.update(&tx.transaction_identifier.hash, status) {
+                    updates.push(update);
+                }
+            }
+        }
+        updates
+    }
+
+    fn revert_block(
+        &mut self,
+        block: &StacksBlockData,
+        anchored_trail: &Option<StacksMicroblocksTrail>,
+    ) -> Vec<TransactionUpdate> {
+        let mut updates = vec![];
+        let txids = block_txids(block, anchored_trail);
+        for txid in txids.iter() {
+            let (sender, nonce) = match self.transactions.get(txid) {
+                Some(tx) => (tx.sender.clone(), tx.nonce),
+                None => continue,
+            };
+            if let Some(update) = self.update(txid, TransactionStatus::Pending) {
+                updates.push(update);
+            }
+            // Transactions superseded by the reverted one are valid again.
+            let conflicting = self
+                .nonces
+                .get(&(sender, nonce))
+                .cloned()
+                .unwrap_or_default();
+            for other in conflicting.iter().filter(|other| *other != txid) {
+                let superseded = matches!(
+                    self.transactions.get(other).map(|tx| &tx.status),
+                    Some(TransactionStatus::Dropped { reason }) if reason.ends_with(txid.as_str())
+                );
+                if superseded {
+                    if let Some(update) = self.update(other, TransactionStatus::Pending) {
+                        updates.push(update);
+                    }
+                }
+            }
+        }
+        updates
+    }
+
+    fn revert_microblocks(&mut self, trail: &StacksMicroblocksTrail) -> Vec<TransactionUpdate> {
+        let mut updates = vec![];
+        for microblock in trail.microblocks.iter() {
+            for tx in microblock.transactions.iter() {
+                let txid = &tx.transaction_identifier.hash;
+                let was_streamed = matches!(
+                    self.transactions.get(txid).map(|tx| &tx.status),
+                    Some(TransactionStatus::MinedInMicroblock { .. })
+                );
+                if was_streamed {
+                    if let Some(update) = self.update(txid, TransactionStatus::Pending) {
+                        updates.push(update);
+                    }
+                }
+            }
+        }
+        updates
+    }
+
+    fn update(&mut self, txid: &str, status: TransactionStatus) -> Option<TransactionUpdate> {
+        let tx = self.transactions.get_mut(txid)?;
+        if tx.status == status {
+            return None;
+        }
+        tx.status = status.clone();
+        Some(TransactionUpdate {
+            txid: tx.txid.clone(),
+            sender: tx.sender.clone(),
+            nonce: tx.nonce,
+            status,
+        })
+    }
+}
+
+/// Returns the txids of an anchored block, followed by the ones of the
+/// microblocks it confirmed.
+fn block_txids(
+    block: &StacksBlockData,
+    anchored_trail: &Option<StacksMicroblocksTrail>,
+) -> Vec<String> {
+    let mut txids = block
+        .transactions
+        .iter()
+        .map(|tx| tx.transaction_identifier.hash.clone())
+        .collect::<Vec<_>>();
+    if let Some(trail) = anchored_trail {
+        for microblock in trail.microblocks.iter() {
+            txids.extend(
+                microblock
+                    .transactions
+                    .iter()
+                    .map(|tx| tx.transaction_identifier.hash.clone()),
+            );
+        }
+    }
+    txids
+}
+
+/// Hands out nonces to the transactions broadcasted on behalf of a set of
+/// senders, without waiting for the previous ones to be mined.
+pub struct NonceManager {
+    pub rpc: StacksRpc,
+    pub tracker: TransactionTracker,
+    next_nonces: HashMap<String, u64>,
+    /// Nonces below the next one, freed by dropped or rejected transactions:
+    /// handed out first, so that the later transactions can be mined.
+    free_nonces: HashMap<String, BTreeSet<u64>>,
+}
+
+impl NonceManager {
+    pub fn new(rpc: StacksRpc) -> NonceManager {
+        NonceManager {
+            rpc,
+            tracker: TransactionTracker::new(),
+            next_nonces: HashMap::new(),
+            free_nonces: HashMap::new(),
+        }
+    }
+
+    /// Returns the next nonce available for `sender`, fetching the account
+    /// from the node the first time the sender is seen.
+    pub fn next_nonce(&mut self, sender: &str) -> Result<u64, RpcError> {
+        if let Some(free_nonces) = self.free_nonces.get_mut(sender) {
+            if let Some(nonce) = free_nonces.iter().next().cloned() {
+                free_nonces.remove(&nonce);
+                return Ok(nonce);
+            }
+        }
+        let nonce = match self.next_nonces.get(sender) {
+            Some(nonce) => *nonce,
+            None => self.rpc.get_nonce(sender)?,
+        };
+        self.next_nonces.insert(sender.to_string(), nonce + 1);
+        Ok(nonce)
+    }
+
+    /// Forgets the nonces handed out to `sender`; the next one will be
+    /// fetched from the node.
+    pub fn resync(&mut self, sender: &str) {
+        self.next_nonces.remove(sender);
+        self.free_nonces.remove(sender);
+    }
+
+    /// Broadcasts a transaction built with a nonce obtained from `next_nonce`.
+    /// When the node disagrees with the nonce, the sender is resynced before
+    /// the rejection is returned.
+    pub fn broadcast(&mut self, transaction: StacksTransaction) -> Result<String, RpcError> {
+        let sender = transaction.origin_address().to_string();
+        let nonce = transaction.get_origin_nonce();
+        let fee = transaction.get_tx_fee();
+        match self.rpc.post_transaction(transaction) {
+            Ok(res) => {
+                self.tracker.track(&res.txid, &sender, nonce, fee);
+                Ok(res.txid)
+            }
+            Err(e) => {
+                self.release_nonce(&sender, nonce, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Broadcasts `replacement` in place of the transaction `txid`. The
+    /// replacement must spend the same nonce with a higher fee, so that the
+    /// node evicts the original from its mempool.
+    pub fn replace(
+        &mut self,
+        txid: &str,
+        replacement: StacksTransaction,
+    ) -> Result<String, RpcError> {
+        let txid = normalize_txid(txid);
+        let original = match self.tracker.get(&txid) {
+            Some(original) => original.clone(),
+            None => {
+                return Err(RpcError::InvalidReplacement(format!(
+                    "transaction {} not tracked",
+                    txid
+                )))
+            }
+        };
+        let sender = replacement.origin_address().to_string();
+        let nonce = replacement.get_origin_nonce();
+        let fee = replacement.get_tx_fee();
+        if sender != original.sender || nonce != original.nonce {
+            return Err(RpcError::InvalidReplacement(format!(
+                "replacement of {} must spend nonce {} of {}",
+                txid, original.nonce, original.sender
+            )));
+        }
+        if fee <= original.fee {
+            return Err(RpcError::InvalidReplacement(format!(
+                "replacement of {} must pay a fee higher than {}",
+                txid, original.fee
+            )));
+        }
+        let res = self.rpc.post_transaction(replacement)?;
+        self.tracker.track(&res.txid, &sender, nonce, fee);
+        self.tracker.mark_replaced(&txid, &res.txid);
+        Ok(res.txid)
+    }
+
+    /// Handles the transactions dropped from the node's mempool, making the
+    /// nonces they were holding available again.
+    pub fn process_dropped_transactions(
+        &mut self,
+        txids: &[String],
+        reason: &str,
+    ) -> Vec<TransactionUpdate> {
+        let updates = self.tracker.process_dropped_transactions(txids, reason);
+        for update in updates.iter() {
+            let still_spent = self
+                .tracker
+                .get_unconfirmed(&update.sender)
+                .iter()
+                .any(|tx| tx.nonce == update.nonce);
+            if !still_spent {
+                self.free_nonce(&update.sender, update.nonce);
+            }
+        }
+        updates
+    }
+
+    /// Returns the status changes carried by a chain update.
+    pub fn process_chain_event(
+        &mut self,
+        chain_event: &StacksChainEvent,
+    ) -> Vec<TransactionUpdate> {
+        self.tracker.process_chain_event(chain_event)
+    }
+
+    fn release_nonce(&mut self, sender: &str, nonce: u64, error: &RpcError) {
+        match error {
+            RpcError::TransactionRejected { reason, .. }
+                if NONCE_REJECTION_REASONS.contains(&reason.as_str()) =>
+            {
+                self.resync(sender)
+            }
+            // The nonce was not consumed: hand it out again
+            RpcError::TransactionRejected { .. } => self.free_nonce(sender, nonce),
+            _ => {}
+        }
+    }
+
+    /// Makes `nonce` available again. The next nonce only moves back when no
+    /// later nonce is in flight, the others being kept as gaps to fill.
+    fn free_nonce(&mut self, sender: &str, nonce: u64) {
+        let next_nonce = match self.next_nonces.get_mut(sender) {
+            Some(next_nonce) if nonce < *next_nonce => next_nonce,
+            _ => return,
+        };
+        let free_nonces = self.free_nonces.entry(sender.to_string()).or_default();
+        free_nonces.insert(nonce);
+        while *next_nonce > 0 && free_nonces.remove(&(*next_nonce - 1)) {
+            *next_nonce -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc_client::tests::{mock_node, signed_transaction};
+    use orchestra_types::{
+        ChainUpdatedWithBlockData, ChainUpdatedWithMicroblockData,
+        ChainUpdatedWithMicroblockReorgData, StacksMicroblockData, StacksTransactionData,
+    };
+
+    fn transaction(txid: &str) -> StacksTransactionData {
+        serde_json::from_value(json!({
+            "transaction_identifier": { "hash": txid },
+            "operations": [],
+            "metadata": {
+                "success": true,
+                "raw_tx": "0x00",
+                "result": "(ok true)",
+                "sender": "ST1PQHQKV0RJXZFY1DGX8MNSNYVE3VGZJSRTPGZGM",
+                "fee": 0,
+                "kind": "Coinbase",
+                "execution_cost": null,
+                "receipt": {
+                    "mutated_contracts_radius": [],
+                    "mutated_assets_radius": [],
+                    "events": []
+                },
+                "description": ""
+            }
+        }))
+        .unwrap()
+    }
+
+    fn block(index: u64, hash: &str, txids: &[&str]) -> StacksBlockData {
+        serde_json::from_value(json!({
+            "block_identifier": { "index": index, "hash": hash },
+            "parent_block_identifier": { "index": index - 1, "hash": format!("{}-parent", hash) },
+            "timestamp": 0,
+            "transactions": txids.iter().map(|txid| transaction(txid)).collect::<Vec<_>>(),
+            "metadata": {
+                "bitcoin_anchor_block_identifier": { "index": 0, "hash": "" },
+                "pox_cycle_index": 0,
+                "pox_cycle_position": 0,
+                "pox_cycle_length": 0
+            }
+        }))
+        .unwrap()
+    }
+
+    fn trail(hash: &str, txids: &[&str]) -> StacksMicroblocksTrail {
+        StacksMicroblocksTrail {
+            microblocks: vec![StacksMicroblockData {
+                block_identifier: BlockIdentifier {
+                    index: 0,
+                    hash: hash.into(),
+                },
+                parent_block_identifier: BlockIdentifier {
+                    index: 0,
+                    hash: "parent".into(),
+                },
+                transactions: txids.iter().map(|txid| transaction(txid)).collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn microblock_reorgs_revert_streamed_transactions() {
+        let mut tracker = TransactionTracker::new();
+        tracker.track("0xaa", "sender", 1, 200);
+
+        let updates = tracker.process_chain_event(&StacksChainEvent::ChainUpdatedWithMicroblock(
+            ChainUpdatedWithMicroblockData {
+                anchored_block: block(10, "a", &[]),
+                current_trail: trail("m1", &["0xaa"]),
+            },
+        ));
+        assert!(matches!(
+            updates[0].status,
+            TransactionStatus::MinedInMicroblock { .. }
+        ));
+
+        let updates =
+            tracker.process_chain_event(&StacksChainEvent::ChainUpdatedWithMicroblockReorg(
+                ChainUpdatedWithMicroblockReorgData {
+                    new_block: block(11, "b", &[]),
+                    new_anchored_trail: None,
+                    old_trail: Some(trail("m1", &["0xaa"])),
+                },
+            ));
+        assert_eq!(updates[0].status, TransactionStatus::Pending);
+        assert_eq!(tracker.get_unconfirmed("sender").len(), 1);
+    }
+
+    #[test]
+    fn broadcasts_are_tracked_under_the_observed_txid() {
+        let (url, _) = mock_node(
+            "200 OK",
+            r#""e1c5a6e5b7c1f8cbd2a1f9e8c84c1e2d3b5a69d4c7b8e2f1a0d9c8b7a6f5e4d3""#,
+        );
+        let mut manager = NonceManager::new(StacksRpc::new(&url));
+        let txid = manager.broadcast(signed_transaction(0)).unwrap();
+        let observed_txid = "0xe1c5a6e5b7c1f8cbd2a1f9e8c84c1e2d3b5a69d4c7b8e2f1a0d9c8b7a6f5e4d3";
+        assert_eq!(txid, observed_txid);
+
+        let new_block = block(10, "a", &[observed_txid]);
+        let updates = manager.process_chain_event(&StacksChainEvent::ChainUpdatedWithBlock(
+            ChainUpdatedWithBlockData {
+                new_block: new_block.clone(),
+                anchored_trail: None,
+                confirmed_block: (new_block, None),
+            },
+        ));
+        assert_eq!(updates.len(), 1);
+        assert!(matches!(
+            manager.tracker.get(observed_txid).unwrap().status,
+            TransactionStatus::Anchored { .. }
+        ));
+    }
+
+    #[test]
+    fn anchoring_drops_conflicting_transactions() {
+        let mut tracker = TransactionTracker::new();
+        tracker.track("0xaa", "sender", 1, 200);
+        tracker.track("0xbb", "sender", 1, 400);
+        tracker.mark_replaced("0xaa", "0xbb");
+
+        let new_block = block(10, "a", &["0xaa"]);
+        let updates = tracker.process_chain_event(&StacksChainEvent::ChainUpdatedWithBlock(
+            ChainUpdatedWithBlockData {
+                new_block: new_block.clone(),
+                anchored_trail: None,
+                confirmed_block: (new_block, None),
+            },
+        ));
+        assert_eq!(updates.len(), 2);
+        assert!(matches!(
+            tracker.get("0xaa").unwrap().status,
+            TransactionStatus::Anchored { .. }
+        ));
+        assert!(matches!(
+            tracker.get("0xbb").unwrap().status,
+            TransactionStatus::Dropped { .. }
+        ));
+
+        tracker.prune("sender", 2);
+        assert!(tracker.get("0xaa").is_none());
+    }
+
+    #[test]
+    fn dropped_nonces_are_handed_out_before_new_ones() {
+        let mut manager = NonceManager::new(StacksRpc::new("http://localhost:0"));
+        manager.next_nonces.insert("sender".into(), 0);
+        for (nonce, txid) in ["0xa0", "0xa1", "0xa2"].iter().enumerate() {
+            assert_eq!(manager.next_nonce("sender").unwrap(), nonce as u64);
+            manager.tracker.track(txid, "sender", nonce as u64, 200);
+        }
+
+        // Nonce 2 is still in flight: 1 is a gap, not the next nonce
+        let updates = manager.process_dropped_transactions(&["0xa1".into()], "expired");
+        assert_eq!(updates.len(), 1);
+        assert!(manager.tracker.get("0xa1").is_none());
+        assert_eq!(manager.next_nonce("sender").unwrap(), 1);
+        assert_eq!(manager.next_nonce("sender").unwrap(), 3);
+
+        // Nothing is in flight after the last nonce handed out
+        manager.process_dropped_transactions(&["0xa2".into()], "expired");
+        manager.free_nonce("sender", 3);
+        assert_eq!(manager.next_nonce("sender").unwrap(), 2);
+        assert_eq!(manager.next_nonce("sender").unwrap(), 3);
+    }
+
+    #[test]
+    fn confirmed_transactions_are_pruned() {
+        let mut tracker = TransactionTracker::new();
+        tracker.track("0xaa", "sender", 1, 200);
+        tracker.track("0xbb", "sender", 2, 200);
+
+        let new_block = block(10, "a", &["0xaa"]);
+        tracker.process_chain_event(&StacksChainEvent::ChainUpdatedWithBlock(
+            ChainUpdatedWithBlockData {
+                new_block: new_block.clone(),
+                anchored_trail: None,
+                confirmed_block: (block(4, "c", &[]), None),
+            },
+        ));
+        assert!(tracker.get("0xaa").is_some());
+
+        tracker.process_chain_event(&StacksChainEvent::ChainUpdatedWithBlock(
+            ChainUpdatedWithBlockData {
+                new_block: block(11, "b", &[]),
+                anchored_trail: None,
+                confirmed_block: (new_block, None),
+            },
+        ));
+        assert!(tracker.get("0xaa").is_none());
+        assert_eq!(tracker.get_unconfirmed("sender").len(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::rpc_client::{RpcError, DEFAULT_TIMEOUT};
+use clarity_repl::clarity::codec::transaction::TransactionPayload;
+use orchestra_types::StacksFeeEstimate;
+use reqwest::blocking::Client;
+
+#[derive(Deserialize, Debug)]
+struct ObserverResponse<T> {
+    result: T,
+}
+
+/// Client for the fee estimates served by the event observer, learned from
+/// the fees paid by recently mined transactions.
+pub struct ObserverFeeClient {
+    pub url: String,
+    pub client: Client,
+}
+
+impl ObserverFeeClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.into(),
+            client: Client::builder().timeout(DEFAULT_TIMEOUT).build().unwrap(),
+        }
+    }
+
+    /// Returns the fees paid by the transactions comparable to `payload`, or
+    /// None when the observer did not see any yet.
+    pub fn get_fee_estimate(
+        &self,
+        payload: &TransactionPayload,
+    ) -> Result<Option<StacksFeeEstimate>, RpcError> {
+        let path = match get_fee_estimate_path(payload) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let request_url = format!("{}/v1/fees/{}", self.url, path);
+        let res = self.client.get(&request_url).send()?;
+        let status = res.status().as_u16();
+        if status == 404 {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            let body = res.text().unwrap_or_default();
+            return Err(RpcError::HttpStatus { status, body });
+        }
+        let res: ObserverResponse<StacksFeeEstimate> =
+            res.json().map_err(|e| RpcError::Decode(e.to_string()))?;
+        Ok(Some(res.result))
+    }
+}
+
+fn get_fee_estimate_path(payload: &TransactionPayload) -> Option<String> {
+    match payload {
+        TransactionPayload::TokenTransfer(..) => Some("stx-transfer".into()),
+        TransactionPayload::SmartContract(..) => Some("contract-deploy".into()),
+        TransactionPayload::ContractCall(contract_call) => Some(format!(
+            "contract-call/{}.{}/{}",
+            contract_call.address, contract_call.contract_name, contract_call.function_name
+        )),
+        _ => None,
+    }
+}
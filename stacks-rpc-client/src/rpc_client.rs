@@ -1,17 +1,132 @@
-use clarity_repl::clarity::codec::{StacksMessageCodec, StacksTransaction};
+use clarity_repl::clarity::codec::transaction::TransactionPayload;
+use clarity_repl::clarity::codec::{
+    StacksBlock, StacksMessageCodec, StacksMicroblock, StacksTransaction,
+};
 use clarity_repl::clarity::types::Value;
 use clarity_repl::clarity::util::hash::{bytes_to_hex, hex_bytes};
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+use std::fmt;
 use std::io::Cursor;
+use std::time::Duration;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub enum RpcError {
-    Generic,
+    /// The node could not be reached (connection refused, DNS, TLS...).
+    Transport(String),
+    /// The node did not answer within the configured timeout.
+    Timeout,
+    /// The node answered with a non-2xx status.
+    HttpStatus { status: u16, body: String },
+    /// The node answered with a payload that could not be decoded.
+    Decode(String),
+    /// The node refused to admit a transaction in its mempool.
+    TransactionRejected {
+        reason: String,
+        reason_data: Option<JsonValue>,
+        txid: Option<String>,
+    },
+    /// A read-only function could not be evaluated by the node.
+    ReadOnlyCallFailed(String),
+    /// A replacement transaction was refused before being broadcasted.
+    InvalidReplacement(String),
+}
+
+impl RpcError {
+    /// Transport failures, timeouts, server errors and rate limiting are
+    /// transient: the same request can succeed later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            RpcError::Transport(_) | RpcError::Timeout => true,
+            RpcError::HttpStatus { status, .. } => *status >= 500 || *status == 429,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::Transport(e) => write!(f, "unable to reach stacks-node: {}", e),
+            RpcError::Timeout => write!(f, "stacks-node timed out"),
+            RpcError::HttpStatus { status, body } => {
+                write!(f, "stacks-node responded with status {}: {}", status, body)
+            }
+            RpcError::Decode(e) => write!(f, "unable to decode stacks-node response: {}", e),
+            RpcError::TransactionRejected { reason, txid, .. } => match txid {
+                Some(txid) => write!(f, "transaction {} rejected: {}", txid, reason),
+                None => write!(f, "transaction rejected: {}", reason),
+            },
+            RpcError::ReadOnlyCallFailed(e) => write!(f, "read-only call failed: {}", e),
+            RpcError::InvalidReplacement(e) => write!(f, "invalid replacement: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<reqwest::Error> for RpcError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            RpcError::Timeout
+        } else if e.is_decode() {
+            RpcError::Decode(e.to_string())
+        } else {
+            RpcError::Transport(e.to_string())
+        }
+    }
+}
+
+/// Retries applied to the requests failing with a retryable error, with an
+/// exponential backoff between attempts.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        }
+    }
+
+    /// Delay to observe after the given failed attempt (starting at 1), or
+    /// `None` once the attempts are exhausted.
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff);
+        Some(backoff.min(self.max_backoff))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
 }
 
 pub struct StacksRpc {
     pub url: String,
     pub client: Client,
+    pub retry_policy: RetryPolicy,
 }
 
 pub struct PostTransactionResult {
@@ -72,89 +187,510 @@ pub struct PoxCycle {
 }
 
 #[derive(Deserialize, Debug)]
-struct Balance {
-    balance: String,
-    nonce: u64,
-    balance_proof: String,
-    nonce_proof: String,
+pub(crate) struct Balance {
+    pub balance: String,
+    pub nonce: u64,
+    pub balance_proof: String,
+    pub nonce_proof: String,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Contract {
-    source: String,
-    publish_height: u64,
+    pub source: String,
+    pub publish_height: u64,
+}
+
+/// Balances of an account, in µSTX.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountInfo {
+    /// Spendable balance (locked STX excluded).
+    pub balance: u128,
+    pub locked: u128,
+    /// Burn block height at which the locked STX are released.
+    pub unlock_height: u64,
+    pub nonce: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct AccountResponse {
+    pub balance: String,
+    pub locked: String,
+    pub unlock_height: u64,
+    pub nonce: u64,
+}
+
+impl AccountResponse {
+    pub fn decode(self) -> Result<AccountInfo, RpcError> {
+        Ok(AccountInfo {
+            balance: decode_u128(&self.balance)?,
+            locked: decode_u128(&self.locked)?,
+            unlock_height: self.unlock_height,
+            nonce: self.nonce,
+        })
+    }
+}
+
+/// Public interface of a contract. Clarity types are left in their JSON
+/// representation.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractInterface {
+    pub functions: Vec<ContractInterfaceFunction>,
+    pub variables: Vec<ContractInterfaceVariable>,
+    pub maps: Vec<ContractInterfaceMap>,
+    pub fungible_tokens: Vec<ContractInterfaceFungibleToken>,
+    pub non_fungible_tokens: Vec<ContractInterfaceNonFungibleToken>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractInterfaceFunction {
+    pub name: String,
+    /// `public`, `private` or `read_only`.
+    pub access: String,
+    pub args: Vec<ContractInterfaceFunctionArg>,
+    pub outputs: ContractInterfaceFunctionOutput,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractInterfaceFunctionArg {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub arg_type: JsonValue,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractInterfaceFunctionOutput {
+    #[serde(rename = "type")]
+    pub output_type: JsonValue,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractInterfaceVariable {
+    pub name: String,
+    /// `variable` or `constant`.
+    pub access: String,
+    #[serde(rename = "type")]
+    pub variable_type: JsonValue,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractInterfaceMap {
+    pub name: String,
+    pub key: JsonValue,
+    pub value: JsonValue,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractInterfaceFungibleToken {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContractInterfaceNonFungibleToken {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub asset_type: JsonValue,
+}
+
+/// Hex encoded Clarity value, as returned by `/v2/map_entry` and `/v2/data_var`.
+#[derive(Deserialize, Debug)]
+pub(crate) struct ClarityDataResponse {
+    pub data: String,
+}
+
+impl ClarityDataResponse {
+    pub fn decode(self) -> Result<Value, RpcError> {
+        decode_clarity_value(&self.data)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FeeEstimation {
+    pub estimated_cost_scalar: u64,
+    pub cost_scalar_change_by_byte: Option<f64>,
+    /// Low, middle and high estimations, in that order.
+    pub estimations: Vec<FeeRateEstimation>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FeeRateEstimation {
+    pub fee_rate: f64,
+    /// Total fee, in µSTX.
+    pub fee: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct TraitImplementationResponse {
+    pub is_implemented: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ReadOnlyCallResult {
+    pub okay: bool,
+    pub result: Option<String>,
+    pub cause: Option<String>,
+}
+
+/// Turns a non-2xx response into an error.
+pub(crate) fn decode_error_response(status: u16, body: String) -> RpcError {
+    RpcError::HttpStatus { status, body }
+}
+
+/// Recognizes the rejections returned by `POST /v2/transactions`. Only applied
+/// to broadcasts: the errors of the other endpoints can carry a `reason` too.
+pub(crate) fn decode_transaction_rejection(error: RpcError) -> RpcError {
+    #[derive(Deserialize)]
+    struct TransactionRejection {
+        reason: String,
+        reason_data: Option<JsonValue>,
+        txid: Option<String>,
+    }
+
+    let rejection = match error {
+        RpcError::HttpStatus { ref body, .. } => {
+            serde_json::from_str::<TransactionRejection>(body).ok()
+        }
+        _ => None,
+    };
+    match rejection {
+        Some(rejection) => RpcError::TransactionRejected {
+            reason: rejection.reason,
+            reason_data: rejection.reason_data,
+            txid: rejection.txid,
+        },
+        None => error,
+    }
+}
+
+pub(crate) fn decode_post_transaction_result(txid: String) -> PostTransactionResult {
+    PostTransactionResult {
+        txid: normalize_txid(&txid),
+    }
+}
+
+pub(crate) fn decode_read_only_call_result(
+    response: ReadOnlyCallResult,
+) -> Result<Value, RpcError> {
+    if !response.okay {
+        return Err(RpcError::ReadOnlyCallFailed(
+            response.cause.unwrap_or("unknown cause".into()),
+        ));
+    }
+    let result = response
+        .result
+        .ok_or(RpcError::Decode("result missing".into()))?;
+    decode_clarity_value(&result)
+}
+
+pub(crate) fn decode_clarity_value(raw_value: &str) -> Result<Value, RpcError> {
+    // Removing the 0x prefix
+    let raw_value = match raw_value.strip_prefix("0x") {
+        Some(raw_value) => raw_value,
+        _ => return Err(RpcError::Decode(format!("malformed value {}", raw_value))),
+    };
+    let bytes = hex_bytes(&raw_value).map_err(|e| RpcError::Decode(format!("{:?}", e)))?;
+    let mut cursor = Cursor::new(&bytes);
+    Value::consensus_deserialize(&mut cursor).map_err(|e| RpcError::Decode(format!("{:?}", e)))
+}
+
+/// Decodes the hex encoded amounts (e.g. `0x0000000000000000000000003b9aca00`)
+/// returned by `/v2/accounts`.
+pub(crate) fn decode_u128(raw_value: &str) -> Result<u128, RpcError> {
+    let raw_value = raw_value.strip_prefix("0x").unwrap_or(raw_value);
+    u128::from_str_radix(raw_value, 16)
+        .map_err(|e| RpcError::Decode(format!("malformed amount {}: {}", raw_value, e)))
+}
+
+/// Decodes the consensus-encoded blocks and microblocks served by the node.
+pub(crate) fn decode_consensus<T: StacksMessageCodec>(bytes: &[u8]) -> Result<T, RpcError> {
+    let mut cursor = Cursor::new(bytes);
+    T::consensus_deserialize(&mut cursor).map_err(|e| RpcError::Decode(format!("{:?}", e)))
+}
+
+/// Txids are `0x` prefixed across the observer events, while the node
+/// answers broadcasts with the bare hex txid.
+pub fn normalize_txid(txid: &str) -> String {
+    match txid.strip_prefix("0x") {
+        Some(_) => txid.to_string(),
+        None => format!("0x{}", txid),
+    }
+}
+
+/// Request to the node, built by the same code for `StacksRpc` and
+/// `AsyncStacksRpc`, which only differ in the way they send it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum NodeRequest {
+    Get(String),
+    PostJson(String, JsonValue),
+    /// Consensus-encoded payload.
+    PostOctets(String, Vec<u8>),
+}
+
+impl NodeRequest {
+    pub fn post_transaction(url: &str, transaction: &StacksTransaction) -> NodeRequest {
+        NodeRequest::PostOctets(
+            format!("{}/v2/transactions", url),
+            transaction.serialize_to_vec(),
+        )
+    }
+
+    pub fn nonce(url: &str, address: &str) -> NodeRequest {
+        NodeRequest::Get(format!("{}/v2/accounts/{}", url, address))
+    }
+
+    pub fn account(url: &str, principal: &str, tip: Option<&str>) -> NodeRequest {
+        let path = format!("{}/v2/accounts/{}?proof=0", url, principal);
+        NodeRequest::Get(match tip {
+            Some(tip) => format!("{}&tip={}", path, tip.trim_start_matches("0x")),
+            None => path,
+        })
+    }
+
+    pub fn pox_info(url: &str) -> NodeRequest {
+        NodeRequest::Get(format!("{}/v2/pox", url))
+    }
+
+    pub fn info(url: &str) -> NodeRequest {
+        NodeRequest::Get(format!("{}/v2/info", url))
+    }
+
+    pub fn contract_source(url: &str, principal: &str, contract_name: &str) -> NodeRequest {
+        NodeRequest::Get(format!(
+            "{}/v2/contracts/source/{}/{}",
+            url, principal, contract_name
+        ))
+    }
+
+    pub fn read_only_call(
+        url: &str,
+        contract_addr: &str,
+        contract_name: &str,
+        method: &str,
+        args: &[Value],
+        sender: &str,
+        tip: Option<&str>,
+    ) -> NodeRequest {
+        let path = format!(
+            "{}/v2/contracts/call-read/{}/{}/{}",
+            url, contract_addr, contract_name, method
+        );
+        let path = match tip {
+            Some(tip) => format!("{}?tip={}", path, tip.trim_start_matches("0x")),
+            None => path,
+        };
+        let arguments = args
+            .iter()
+            .map(|a| bytes_to_hex(&a.serialize_to_vec()))
+            .collect::<Vec<_>>();
+        NodeRequest::PostJson(
+            path,
+            json!({
+                "sender": sender,
+                "arguments": arguments,
+            }),
+        )
+    }
+
+    pub fn contract_interface(url: &str, contract_addr: &str, contract_name: &str) -> NodeRequest {
+        NodeRequest::Get(format!(
+            "{}/v2/contracts/interface/{}/{}",
+            url, contract_addr, contract_name
+        ))
+    }
+
+    /// Map keys are posted as a JSON string holding the hex encoded Clarity value.
+    pub fn map_entry(
+        url: &str,
+        contract_addr: &str,
+        contract_name: &str,
+        map_name: &str,
+        key: &Value,
+    ) -> NodeRequest {
+        NodeRequest::PostJson(
+            format!(
+                "{}/v2/map_entry/{}/{}/{}?proof=0",
+                url, contract_addr, contract_name, map_name
+            ),
+            json!(format!("0x{}", bytes_to_hex(&key.serialize_to_vec()))),
+        )
+    }
+
+    pub fn data_var(
+        url: &str,
+        contract_addr: &str,
+        contract_name: &str,
+        var_name: &str,
+    ) -> NodeRequest {
+        NodeRequest::Get(format!(
+            "{}/v2/data_var/{}/{}/{}?proof=0",
+            url, contract_addr, contract_name, var_name
+        ))
+    }
+
+    pub fn fee_estimation(
+        url: &str,
+        payload: &TransactionPayload,
+        estimated_len: Option<u64>,
+    ) -> NodeRequest {
+        NodeRequest::PostJson(
+            format!("{}/v2/fees/transaction", url),
+            json!({
+                "transaction_payload": format!("0x{}", bytes_to_hex(&payload.serialize_to_vec())),
+                "estimated_len": estimated_len,
+            }),
+        )
+    }
+
+    pub fn block(url: &str, index_block_hash: &str) -> NodeRequest {
+        NodeRequest::Get(format!("{}/v2/blocks/{}", url, index_block_hash))
+    }
+
+    pub fn microblock(url: &str, microblock_hash: &str) -> NodeRequest {
+        NodeRequest::Get(format!("{}/v2/microblocks/{}", url, microblock_hash))
+    }
+
+    pub fn confirmed_microblocks(url: &str, index_block_hash: &str) -> NodeRequest {
+        NodeRequest::Get(format!(
+            "{}/v2/microblocks/confirmed/{}",
+            url, index_block_hash
+        ))
+    }
+
+    pub fn trait_implementation(
+        url: &str,
+        contract_addr: &str,
+        contract_name: &str,
+        trait_contract_addr: &str,
+        trait_contract_name: &str,
+        trait_name: &str,
+    ) -> NodeRequest {
+        NodeRequest::Get(format!(
+            "{}/v2/traits/{}/{}/{}/{}/{}",
+            url, contract_addr, contract_name, trait_contract_addr, trait_contract_name, trait_name
+        ))
+    }
 }
 
 impl StacksRpc {
     pub fn new(url: &str) -> Self {
         Self {
             url: url.into(),
-            client: Client::builder().build().unwrap(),
+            client: Client::builder().timeout(DEFAULT_TIMEOUT).build().unwrap(),
+            retry_policy: RetryPolicy::default(),
         }
     }
 
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = Client::builder().timeout(timeout).build().unwrap();
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    fn build_request(&self, request: &NodeRequest) -> RequestBuilder {
+        match request {
+            NodeRequest::Get(url) => self.client.get(url),
+            NodeRequest::PostJson(url, body) => self.client.post(url).json(body),
+            NodeRequest::PostOctets(url, body) => self
+                .client
+                .post(url)
+                .header("Content-Type", "application/octet-stream")
+                .body(body.clone()),
+        }
+    }
+
+    /// Sends `request`, retrying according to `retry_policy`, until the node
+    /// answers with a 2xx status.
+    fn send(
+        &self,
+        request: &NodeRequest,
+        retry_policy: &RetryPolicy,
+    ) -> Result<Response, RpcError> {
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .build_request(request)
+                .send()
+                .map_err(RpcError::from)
+                .and_then(|res| {
+                    let status = res.status();
+                    if !status.is_success() {
+                        let body = res.text().unwrap_or_default();
+                        return Err(decode_error_response(status.as_u16(), body));
+                    }
+                    Ok(res)
+                });
+            match result {
+                Err(e) if e.is_retryable() => match retry_policy.backoff(attempt) {
+                    Some(backoff) => std::thread::sleep(backoff),
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Sends `request` with the retry policy of the client, decoding the JSON
+    /// body of the response.
+    fn execute<T: DeserializeOwned>(&self, request: NodeRequest) -> Result<T, RpcError> {
+        self.send(&request, &self.retry_policy)?
+            .json::<T>()
+            .map_err(|e| RpcError::Decode(e.to_string()))
+    }
+
+    /// Same as `execute`, returning the raw body of the response.
+    fn execute_raw(&self, request: NodeRequest) -> Result<Vec<u8>, RpcError> {
+        self.send(&request, &self.retry_policy)?
+            .bytes()
+            .map(|bytes| bytes.to_vec())
+            .map_err(RpcError::from)
+    }
+
+    /// Broadcasts are sent once: a timed out broadcast may still have been
+    /// admitted by the node, and resending it would be rejected as a nonce
+    /// conflict.
     pub fn post_transaction(
         &self,
         transaction: StacksTransaction,
     ) -> Result<PostTransactionResult, RpcError> {
-        let tx = transaction.serialize_to_vec();
-        let path = format!("{}/v2/transactions", self.url);
-        let res = self
-            .client
-            .post(&path)
-            .header("Content-Type", "application/octet-stream")
-            .body(tx)
-            .send()
-            .unwrap();
-
-        if !res.status().is_success() {
-            println!("{}", res.text().unwrap());
-            return Err(RpcError::Generic);
-        }
-
-        let txid: String = res.json().unwrap();
-        let res = PostTransactionResult { txid };
-        Ok(res)
+        let request = NodeRequest::post_transaction(&self.url, &transaction);
+        let txid: String = self
+            .send(&request, &RetryPolicy::none())
+            .map_err(decode_transaction_rejection)?
+            .json()
+            .map_err(|e| RpcError::Decode(e.to_string()))?;
+        Ok(decode_post_transaction_result(txid))
     }
 
     pub fn get_nonce(&self, address: &str) -> Result<u64, RpcError> {
-        let request_url = format!("{}/v2/accounts/{addr}", self.url, addr = address,);
+        let res: Balance = self.execute(NodeRequest::nonce(&self.url, address))?;
+        Ok(res.nonce)
+    }
 
-        let res: Balance = self
-            .client
-            .get(&request_url)
-            .send()
-            .expect("Unable to retrieve account")
-            .json()
-            .expect("Unable to parse contract");
-        let nonce = res.nonce;
-        Ok(nonce)
+    pub fn get_account(&self, principal: &str) -> Result<AccountInfo, RpcError> {
+        self.get_account_at(principal, None)
     }
 
-    pub fn get_pox_info(&self) -> Result<PoxInfo, RpcError> {
-        let request_url = format!("{}/v2/pox", self.url);
+    /// Same as `get_account`, as of the block `tip` (an index block hash)
+    /// when given.
+    pub fn get_account_at(
+        &self,
+        principal: &str,
+        tip: Option<&str>,
+    ) -> Result<AccountInfo, RpcError> {
+        let res: AccountResponse = self.execute(NodeRequest::account(&self.url, principal, tip))?;
+        res.decode()
+    }
 
-        let res: PoxInfo = self
-            .client
-            .get(&request_url)
-            .send()
-            .expect("Unable to retrieve account")
-            .json()
-            .expect("Unable to parse contract");
-        Ok(res)
+    pub fn get_pox_info(&self) -> Result<PoxInfo, RpcError> {
+        self.execute(NodeRequest::pox_info(&self.url))
     }
 
     pub fn get_info(&self) -> Result<NodeInfo, RpcError> {
-        let request_url = format!("{}/v2/info", self.url);
-
-        let res: NodeInfo = self
-            .client
-            .get(&request_url)
-            .send()
-            .expect("Unable to retrieve account")
-            .json()
-            .expect("Unable to parse contract");
-        Ok(res)
+        self.execute(NodeRequest::info(&self.url))
     }
 
     pub fn get_contract_source(
@@ -162,20 +698,11 @@ impl StacksRpc {
         principal: &str,
         contract_name: &str,
     ) -> Result<Contract, RpcError> {
-        let request_url = format!(
-            "{}/v2/contracts/source/{}/{}",
-            self.url, principal, contract_name
-        );
-
-        let res = self.client.get(&request_url).send();
-
-        match res {
-            Ok(response) => match response.json() {
-                Ok(value) => Ok(value),
-                _ => Err(RpcError::Generic),
-            },
-            _ => Err(RpcError::Generic),
-        }
+        self.execute(NodeRequest::contract_source(
+            &self.url,
+            principal,
+            contract_name,
+        ))
     }
 
     pub fn call_read_only_fn(
@@ -186,49 +713,324 @@ impl StacksRpc {
         args: Vec<Value>,
         sender: &str,
     ) -> Result<Value, RpcError> {
-        let path = format!(
-            "{}/v2/contracts/call-read/{}/{}/{}",
-            self.url, contract_addr, contract_name, method
+        self.call_read_only_fn_at(contract_addr, contract_name, method, args, sender, None)
+    }
+
+    /// Same as `call_read_only_fn`, evaluated against the state of the chain
+    /// as of the block `tip` (an index block hash) when given.
+    pub fn call_read_only_fn_at(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        method: &str,
+        args: Vec<Value>,
+        sender: &str,
+        tip: Option<&str>,
+    ) -> Result<Value, RpcError> {
+        let request = NodeRequest::read_only_call(
+            &self.url,
+            contract_addr,
+            contract_name,
+            method,
+            &args,
+            sender,
+            tip,
+        );
+        let response: ReadOnlyCallResult = self.execute(request)?;
+        decode_read_only_call_result(response)
+    }
+
+    pub fn get_contract_interface(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+    ) -> Result<ContractInterface, RpcError> {
+        self.execute(NodeRequest::contract_interface(
+            &self.url,
+            contract_addr,
+            contract_name,
+        ))
+    }
+
+    /// Returns the entry of a data map, as a Clarity optional.
+    pub fn get_map_entry(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        map_name: &str,
+        key: &Value,
+    ) -> Result<Value, RpcError> {
+        let request =
+            NodeRequest::map_entry(&self.url, contract_addr, contract_name, map_name, key);
+        let res: ClarityDataResponse = self.execute(request)?;
+        res.decode()
+    }
+
+    pub fn get_data_var(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        var_name: &str,
+    ) -> Result<Value, RpcError> {
+        let request = NodeRequest::data_var(&self.url, contract_addr, contract_name, var_name);
+        let res: ClarityDataResponse = self.execute(request)?;
+        res.decode()
+    }
+
+    pub fn estimate_transaction_fee(
+        &self,
+        payload: &TransactionPayload,
+        estimated_len: Option<u64>,
+    ) -> Result<FeeEstimation, RpcError> {
+        self.execute(NodeRequest::fee_estimation(
+            &self.url,
+            payload,
+            estimated_len,
+        ))
+    }
+
+    pub fn get_block(&self, index_block_hash: &str) -> Result<StacksBlock, RpcError> {
+        decode_consensus(&self.execute_raw(NodeRequest::block(&self.url, index_block_hash))?)
+    }
+
+    pub fn get_microblock(&self, microblock_hash: &str) -> Result<StacksMicroblock, RpcError> {
+        decode_consensus(&self.execute_raw(NodeRequest::microblock(&self.url, microblock_hash))?)
+    }
+
+    /// Returns the stream of microblocks confirmed by an anchored block.
+    pub fn get_confirmed_microblocks(
+        &self,
+        index_block_hash: &str,
+    ) -> Result<Vec<StacksMicroblock>, RpcError> {
+        let request = NodeRequest::confirmed_microblocks(&self.url, index_block_hash);
+        decode_consensus(&self.execute_raw(request)?)
+    }
+
+    pub fn is_trait_implemented(
+        &self,
+        contract_addr: &str,
+        contract_name: &str,
+        trait_contract_addr: &str,
+        trait_contract_name: &str,
+        trait_name: &str,
+    ) -> Result<bool, RpcError> {
+        let request = NodeRequest::trait_implementation(
+            &self.url,
+            contract_addr,
+            contract_name,
+            trait_contract_addr,
+            trait_contract_name,
+            trait_name,
         );
+        let res: TraitImplementationResponse = self.execute(request)?;
+        Ok(res.is_implemented)
+    }
+}
 
-        let arguments = args
-            .iter()
-            .map(|a| bytes_to_hex(&a.serialize_to_vec()))
-            .collect::<Vec<_>>();
-        let res = self
-            .client
-            .post(&path)
-            .json(&json!({
-                "sender": sender,
-                "arguments": arguments,
-            }))
-            .send()
-            .unwrap();
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transactions::{TransactionBuilder, TransactionKeys};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
-        if !res.status().is_success() {
-            println!("{}", res.text().unwrap());
-            return Err(RpcError::Generic);
+    #[test]
+    fn rejected_transactions_are_decoded() {
+        let body = r#"{"error":"transaction rejected","reason":"BadNonce","reason_data":{"expected":3,"actual":2},"txid":"0x01"}"#;
+        match decode_transaction_rejection(decode_error_response(400, body.into())) {
+            RpcError::TransactionRejected {
+                reason,
+                reason_data,
+                txid,
+            } => {
+                assert_eq!(reason, "BadNonce");
+                assert_eq!(reason_data.unwrap()["expected"], 3);
+                assert_eq!(txid.unwrap(), "0x01");
+            }
+            e => panic!("unexpected error {:?}", e),
         }
 
-        #[derive(Deserialize, Debug)]
-        struct ReadOnlyCallResult {
-            okay: bool,
-            result: String,
+        let error = decode_error_response(503, "unavailable".into());
+        assert!(error.is_retryable());
+        assert!(!decode_error_response(404, "".into()).is_retryable());
+    }
+
+    #[test]
+    fn rejections_are_only_decoded_for_broadcasts() {
+        let (url, _) = mock_node(
+            "400 Bad Request",
+            r#"{"error":"transaction rejected","reason":"BadNonce","txid":"0x01"}"#,
+        );
+        let rpc = StacksRpc::new(&url);
+        assert!(matches!(
+            rpc.post_transaction(signed_transaction(0)),
+            Err(RpcError::TransactionRejected { .. })
+        ));
+        assert!(matches!(
+            rpc.get_pox_info(),
+            Err(RpcError::HttpStatus { status: 400, .. })
+        ));
+    }
+
+    #[test]
+    fn requests_are_built_for_the_tip() {
+        assert_eq!(
+            NodeRequest::account("http://node", "ST000000000000000000002AMW42H", Some("0xab")),
+            NodeRequest::Get(
+                "http://node/v2/accounts/ST000000000000000000002AMW42H?proof=0&tip=ab".into()
+            )
+        );
+        match NodeRequest::read_only_call(
+            "http://node",
+            "ST000000000000000000002AMW42H",
+            "pox",
+            "get-pox-info",
+            &[Value::UInt(1)],
+            "ST000000000000000000002AMW42H",
+            Some("0xab"),
+        ) {
+            NodeRequest::PostJson(path, body) => {
+                assert_eq!(
+                    path,
+                    "http://node/v2/contracts/call-read/ST000000000000000000002AMW42H/pox/get-pox-info?tip=ab"
+                );
+                assert_eq!(
+                    body["arguments"][0],
+                    bytes_to_hex(&Value::UInt(1).serialize_to_vec())
+                );
+            }
+            request => panic!("unexpected request {:?}", request),
         }
+    }
 
-        let response: ReadOnlyCallResult = res.json().unwrap();
-        if response.okay {
-            // Removing the 0x prefix
-            let raw_value = match response.result.strip_prefix("0x") {
-                Some(raw_value) => raw_value,
-                _ => panic!(),
-            };
-            let bytes = hex_bytes(&raw_value).unwrap();
-            let mut cursor = Cursor::new(&bytes);
-            let value = Value::consensus_deserialize(&mut cursor).unwrap();
-            Ok(value)
-        } else {
-            Err(RpcError::Generic)
+    #[test]
+    fn account_amounts_are_decoded() {
+        let res: AccountResponse = serde_json::from_str(
+            r#"{"balance":"0x0000000000000000000000003b9aca00","locked":"0x00000000000000000000000000000064","unlock_height":120,"nonce":4,"balance_proof":"","nonce_proof":""}"#,
+        )
+        .unwrap();
+        let account = res.decode().unwrap();
+        assert_eq!(account.balance, 1_000_000_000);
+        assert_eq!(account.locked, 100);
+        assert_eq!(account.unlock_height, 120);
+        assert_eq!(account.nonce, 4);
+    }
+
+    #[test]
+    fn truncated_blocks_are_rejected() {
+        assert!(matches!(
+            decode_consensus::<StacksBlock>(&[0x00, 0x01]),
+            Err(RpcError::Decode(_))
+        ));
+        assert!(matches!(
+            decode_consensus::<Vec<StacksMicroblock>>(&[0x00, 0x00, 0x00, 0x02]),
+            Err(RpcError::Decode(_))
+        ));
+        let empty_stream: Vec<StacksMicroblock> =
+            decode_consensus(&[0x00, 0x00, 0x00, 0x00]).unwrap();
+        assert!(empty_stream.is_empty());
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+        };
+        assert_eq!(policy.backoff(1), Some(Duration::from_millis(100)));
+        assert_eq!(policy.backoff(2), Some(Duration::from_millis(200)));
+        assert_eq!(policy.backoff(3), Some(Duration::from_millis(300)));
+        assert_eq!(policy.backoff(5), None);
+        assert_eq!(RetryPolicy::none().backoff(1), None);
+    }
+
+    #[test]
+    fn transaction_broadcasts_are_not_retried() {
+        let (url, requests) = mock_node("503 Service Unavailable", "");
+        let rpc = StacksRpc::new(&url).with_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        });
+        match rpc.post_transaction(signed_transaction(0)) {
+            Err(RpcError::HttpStatus { status, .. }) => assert_eq!(status, 503),
+            _ => panic!("broadcast should have failed"),
         }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn broadcasted_txids_are_prefixed() {
+        // The node answers with the bare hex txid.
+        let (url, _) = mock_node(
+            "200 OK",
+            r#""e1c5a6e5b7c1f8cbd2a1f9e8c84c1e2d3b5a69d4c7b8e2f1a0d9c8b7a6f5e4d3""#,
+        );
+        let res = StacksRpc::new(&url)
+            .post_transaction(signed_transaction(0))
+            .unwrap();
+        assert_eq!(
+            res.txid,
+            "0xe1c5a6e5b7c1f8cbd2a1f9e8c84c1e2d3b5a69d4c7b8e2f1a0d9c8b7a6f5e4d3"
+        );
+        assert_eq!(normalize_txid(&res.txid), res.txid);
+    }
+
+    /// Serves every request with the given status and body, counting the
+    /// requests received.
+    pub(crate) fn mock_node(
+        status: &'static str,
+        body: &'static str,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_moved = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(len) = line.strip_prefix("content-length:") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                }
+                let mut request_body = vec![0u8; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                requests_moved.fetch_add(1, Ordering::SeqCst);
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    pub(crate) fn signed_transaction(nonce: u64) -> StacksTransaction {
+        let keys = TransactionKeys::singlesig(&[1u8; 32]).unwrap();
+        TransactionBuilder::contract_call(
+            "ST000000000000000000002AMW42H.pox",
+            "get-pox-info",
+            vec![],
+        )
+        .unwrap()
+        .nonce(nonce)
+        .fee(200)
+        .build(&keys)
+        .unwrap()
     }
 }
@@ -1,14 +1,439 @@
 use std::convert::TryInto;
 
+use crate::observer_client::ObserverFeeClient;
+use crate::rpc_client::{RpcError, StacksRpc};
 use clarity_repl::clarity::codec::transaction::*;
-use clarity_repl::clarity::codec::StacksMessageCodec;
-use clarity_repl::clarity::types::{QualifiedContractIdentifier, Value};
+use clarity_repl::clarity::codec::{StacksMessageCodec, StacksString};
+use clarity_repl::clarity::representations::{ClarityName, ContractName};
+use clarity_repl::clarity::types::{PrincipalData, QualifiedContractIdentifier, Value};
 use clarity_repl::clarity::util::{
     address::AddressHashMode,
     secp256k1::{Secp256k1PrivateKey, Secp256k1PublicKey},
     StacksAddress,
 };
 
+pub const CHAIN_ID_MAINNET: u32 = 0x00000001;
+pub const CHAIN_ID_TESTNET: u32 = 0x80000000;
+
+/// Keys authorizing a spending condition (origin or sponsor).
+#[derive(Clone)]
+pub enum TransactionKeys {
+    Singlesig(Secp256k1PrivateKey),
+    /// The public keys of every participant, in the order used for deriving
+    /// the multisig address, along with the private keys available for signing.
+    Multisig {
+        public_keys: Vec<Secp256k1PublicKey>,
+        private_keys: Vec<Secp256k1PrivateKey>,
+        signatures_required: u16,
+    },
+}
+
+impl TransactionKeys {
+    pub fn singlesig(secret_key: &[u8]) -> Result<TransactionKeys, String> {
+        Secp256k1PrivateKey::from_slice(secret_key)
+            .map(TransactionKeys::Singlesig)
+            .map_err(|e| format!("unable to parse secret key: {:?}", e))
+    }
+
+    fn spending_condition(
+        &self,
+        nonce: u64,
+        fee: u64,
+    ) -> Result<TransactionSpendingCondition, String> {
+        match self {
+            TransactionKeys::Singlesig(secret_key) => {
+                let mut public_key = Secp256k1PublicKey::from_private(secret_key);
+                public_key.set_compressed(true);
+                let signer_addr = StacksAddress::from_public_keys(
+                    0,
+                    &AddressHashMode::SerializeP2PKH,
+                    1,
+                    &vec![public_key],
+                )
+                .ok_or("unable to derive signer address".to_string())?;
+                Ok(TransactionSpendingCondition::Singlesig(
+                    SinglesigSpendingCondition {
+                        signer: signer_addr.bytes,
+                        nonce,
+                        tx_fee: fee,
+                        hash_mode: SinglesigHashMode::P2PKH,
+                        key_encoding: TransactionPublicKeyEncoding::Compressed,
+                        signature: RecoverableSignature::empty(),
+                    },
+                ))
+            }
+            TransactionKeys::Multisig {
+                public_keys,
+                signatures_required,
+                ..
+            } => {
+                let signer_addr = StacksAddress::from_public_keys(
+                    0,
+                    &AddressHashMode::SerializeP2SH,
+                    *signatures_required as usize,
+                    public_keys,
+                )
+                .ok_or("unable to derive multisig address".to_string())?;
+                Ok(TransactionSpendingCondition::Multisig(
+                    MultisigSpendingCondition {
+                        signer: signer_addr.bytes,
+                        nonce,
+                        tx_fee: fee,
+                        hash_mode: MultisigHashMode::P2SH,
+                        fields: vec![],
+                        signatures_required: *signatures_required,
+                    },
+                ))
+            }
+        }
+    }
+
+    /// Signs with the keys available, and appends the public keys of the
+    /// participants that are not signing, in the order of the multisig.
+    fn sign(&self, signer: &mut StacksTransactionSigner, sponsor: bool) -> Result<(), String> {
+        let sign = |signer: &mut StacksTransactionSigner, secret_key: &Secp256k1PrivateKey| {
+            if sponsor {
+                signer.sign_sponsor(secret_key)
+            } else {
+                signer.sign_origin(secret_key)
+            }
+        };
+        let append = |signer: &mut StacksTransactionSigner, public_key: &Secp256k1PublicKey| {
+            if sponsor {
+                signer.append_sponsor(public_key)
+            } else {
+                signer.append_origin(public_key)
+            }
+        };
+
+        match self {
+            TransactionKeys::Singlesig(secret_key) => {
+                sign(signer, secret_key).map_err(|e| format!("unable to sign: {:?}", e))
+            }
+            TransactionKeys::Multisig {
+                public_keys,
+                private_keys,
+                signatures_required,
+            } => {
+                let mut signatures = 0;
+                for public_key in public_keys.iter() {
+                    let secret_key = private_keys.iter().find(|secret_key| {
+                        let mut candidate = Secp256k1PublicKey::from_private(secret_key);
+                        candidate.set_compressed(public_key.compressed());
+                        &candidate == public_key
+                    });
+                    match secret_key {
+                        Some(secret_key) if signatures < *signatures_required => {
+                            sign(signer, secret_key)
+                                .map_err(|e| format!("unable to sign: {:?}", e))?;
+                            signatures += 1;
+                        }
+                        _ => append(signer, public_key)
+                            .map_err(|e| format!("unable to append public key: {:?}", e))?,
+                    }
+                }
+                if signatures < *signatures_required {
+                    return Err(format!(
+                        "{} signatures required, {} private keys available",
+                        signatures_required, signatures
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Builds and signs transactions of any kind.
+///
+/// ```ignore
+/// let tx = TransactionBuilder::stx_transfer(recipient, 1_000_000, None)
+///     .nonce(0)
+///     .fee(200)
+///     .anchor_mode(TransactionAnchorMode::OnChainOnly)
+///     .post_condition_mode(TransactionPostConditionMode::Deny)
+///     .post_condition(stx_post_condition(
+///         PostConditionPrincipal::Origin,
+///         FungibleConditionCode::SentEq,
+///         1_000_000,
+///     ))
+///     .build(&TransactionKeys::singlesig(&secret_key)?)?;
+/// ```
+#[derive(Clone)]
+pub struct TransactionBuilder {
+    payload: TransactionPayload,
+    version: TransactionVersion,
+    chain_id: u32,
+    nonce: u64,
+    fee: u64,
+    anchor_mode: TransactionAnchorMode,
+    post_condition_mode: TransactionPostConditionMode,
+    post_conditions: Vec<TransactionPostCondition>,
+    sponsor: Option<(TransactionKeys, u64, u64)>,
+}
+
+impl TransactionBuilder {
+    pub fn new(payload: TransactionPayload) -> TransactionBuilder {
+        TransactionBuilder {
+            payload,
+            version: TransactionVersion::Testnet,
+            chain_id: CHAIN_ID_TESTNET,
+            nonce: 0,
+            fee: 0,
+            anchor_mode: TransactionAnchorMode::Any,
+            post_condition_mode: TransactionPostConditionMode::Allow,
+            post_conditions: vec![],
+            sponsor: None,
+        }
+    }
+
+    pub fn stx_transfer(
+        recipient: PrincipalData,
+        amount: u64,
+        memo: Option<[u8; 34]>,
+    ) -> TransactionBuilder {
+        TransactionBuilder::new(TransactionPayload::TokenTransfer(
+            recipient,
+            amount,
+            TokenTransferMemo(memo.unwrap_or([0u8; 34])),
+        ))
+    }
+
+    pub fn contract_call(
+        contract_id: &str,
+        function_name: &str,
+        args: Vec<Value>,
+    ) -> Result<TransactionBuilder, String> {
+        let contract_id = QualifiedContractIdentifier::parse(contract_id)
+            .map_err(|e| format!("contract identifier invalid: {:?}", e))?;
+        let function_name: ClarityName = function_name
+            .to_string()
+            .try_into()
+            .map_err(|e| format!("function name invalid: {:?}", e))?;
+        Ok(TransactionBuilder::new(TransactionPayload::ContractCall(
+            TransactionContractCall {
+                address: contract_id.issuer.into(),
+                contract_name: contract_id.name,
+                function_name,
+                function_args: args,
+            },
+        )))
+    }
+
+    pub fn contract_deploy(contract_name: &str, code: &str) -> Result<TransactionBuilder, String> {
+        let name: ContractName = contract_name
+            .to_string()
+            .try_into()
+            .map_err(|e| format!("contract name invalid: {:?}", e))?;
+        let code_body = StacksString::from_str(code)
+            .ok_or("contract source contains invalid characters".to_string())?;
+        Ok(TransactionBuilder::new(TransactionPayload::SmartContract(
+            TransactionSmartContract { name, code_body },
+        )))
+    }
+
+    pub fn mainnet(mut self, mainnet: bool) -> Self {
+        if mainnet {
+            self.version = TransactionVersion::Mainnet;
+            self.chain_id = CHAIN_ID_MAINNET;
+        } else {
+            self.version = TransactionVersion::Testnet;
+            self.chain_id = CHAIN_ID_TESTNET;
+        }
+        self
+    }
+
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    /// Fee paid by the origin. Ignored for sponsored transactions.
+    pub fn fee(mut self, fee: u64) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn anchor_mode(mut self, anchor_mode: TransactionAnchorMode) -> Self {
+        self.anchor_mode = anchor_mode;
+        self
+    }
+
+    pub fn post_condition_mode(
+        mut self,
+        post_condition_mode: TransactionPostConditionMode,
+    ) -> Self {
+        self.post_condition_mode = post_condition_mode;
+        self
+    }
+
+    pub fn post_condition(mut self, post_condition: TransactionPostCondition) -> Self {
+        self.post_conditions.push(post_condition);
+        self
+    }
+
+    /// Has the fee paid by a sponsor, signing with its own nonce.
+    pub fn sponsor(mut self, keys: TransactionKeys, nonce: u64, fee: u64) -> Self {
+        self.sponsor = Some((keys, nonce, fee));
+        self
+    }
+
+    /// Sets the fee to the node's middle estimation. When the node's estimator
+    /// is unavailable, falls back on the median fee paid by comparable
+    /// transactions, as observed by `fallback`.
+    pub fn estimate_fee(
+        self,
+        rpc: &StacksRpc,
+        fallback: Option<&ObserverFeeClient>,
+    ) -> Result<Self, RpcError> {
+        let node_estimation =
+            rpc.estimate_transaction_fee(&self.payload, None)
+                .and_then(|estimation| {
+                    estimation
+                        .estimations
+                        .get(1)
+                        .map(|estimation| estimation.fee)
+                        .ok_or(RpcError::Decode("fee estimations missing".into()))
+                });
+        let fee = match (node_estimation, fallback) {
+            (Ok(fee), _) => fee,
+            (Err(e), None) => return Err(e),
+            (Err(e), Some(observer)) => match observer.get_fee_estimate(&self.payload)? {
+                Some(estimate) => estimate.fee_medium,
+                None => return Err(e),
+            },
+        };
+        Ok(self.with_estimated_fee(fee))
+    }
+
+    fn with_estimated_fee(mut self, fee: u64) -> Self {
+        match self.sponsor {
+            Some((_, _, ref mut sponsor_fee)) => *sponsor_fee = fee,
+            None => self.fee = fee,
+        }
+        self
+    }
+
+    pub fn build(self, origin_keys: &TransactionKeys) -> Result<StacksTransaction, String> {
+        let auth = match self.sponsor {
+            None => {
+                TransactionAuth::Standard(origin_keys.spending_condition(self.nonce, self.fee)?)
+            }
+            // The origin signs with a placeholder sponsor, replaced by the sponsor
+            // before signing.
+            Some(_) => TransactionAuth::Sponsored(
+                origin_keys.spending_condition(self.nonce, 0)?,
+                TransactionSpendingCondition::new_initial_sighash(),
+            ),
+        };
+        let unsigned_tx = StacksTransaction {
+            version: self.version,
+            chain_id: self.chain_id,
+            auth,
+            anchor_mode: self.anchor_mode,
+            post_condition_mode: self.post_condition_mode,
+            post_conditions: self.post_conditions,
+            payload: self.payload,
+        };
+
+        let mut tx_signer = StacksTransactionSigner::new(&unsigned_tx);
+        origin_keys.sign(&mut tx_signer, false)?;
+
+        let (sponsor_keys, sponsor_nonce, sponsor_fee) = match self.sponsor {
+            Some(sponsor) => sponsor,
+            None => {
+                return tx_signer
+                    .get_tx()
+                    .ok_or("transaction incomplete".to_string())
+            }
+        };
+
+        let mut origin_signed_tx = tx_signer.get_tx_incomplete();
+        let sponsor_condition = sponsor_keys.spending_condition(sponsor_nonce, sponsor_fee)?;
+        let mut tx_signer =
+            StacksTransactionSigner::new_sponsor(&origin_signed_tx, sponsor_condition)
+                .map_err(|e| format!("unable to set sponsor: {:?}", e))?;
+        sponsor_keys.sign(&mut tx_signer, true)?;
+        origin_signed_tx = tx_signer
+            .get_tx()
+            .ok_or("transaction incomplete".to_string())?;
+        Ok(origin_signed_tx)
+    }
+}
+
+/// Parses `origin`, a standard principal (`ST...`) or a contract principal
+/// (`ST....contract`).
+pub fn parse_post_condition_principal(principal: &str) -> Result<PostConditionPrincipal, String> {
+    if principal == "origin" {
+        return Ok(PostConditionPrincipal::Origin);
+    }
+    match PrincipalData::parse(principal) {
+        Ok(PrincipalData::Standard(standard)) => {
+            Ok(PostConditionPrincipal::Standard(standard.into()))
+        }
+        Ok(PrincipalData::Contract(contract)) => Ok(PostConditionPrincipal::Contract(
+            contract.issuer.into(),
+            contract.name,
+        )),
+        Err(e) => Err(format!("principal {} invalid: {:?}", principal, e)),
+    }
+}
+
+/// Parses an asset identifier (`ST....contract::asset-name`).
+pub fn parse_asset_info(asset_identifier: &str) -> Result<AssetInfo, String> {
+    let (contract_id, asset_name) = match asset_identifier.split_once("::") {
+        Some(parts) => parts,
+        None => return Err(format!("asset identifier {} invalid", asset_identifier)),
+    };
+    let contract_id = QualifiedContractIdentifier::parse(contract_id)
+        .map_err(|e| format!("contract identifier invalid: {:?}", e))?;
+    let asset_name: ClarityName = asset_name
+        .to_string()
+        .try_into()
+        .map_err(|e| format!("asset name invalid: {:?}", e))?;
+    Ok(AssetInfo {
+        contract_address: contract_id.issuer.into(),
+        contract_name: contract_id.name,
+        asset_name,
+    })
+}
+
+pub fn stx_post_condition(
+    principal: PostConditionPrincipal,
+    condition_code: FungibleConditionCode,
+    amount: u64,
+) -> TransactionPostCondition {
+    TransactionPostCondition::STX(principal, condition_code, amount)
+}
+
+pub fn ft_post_condition(
+    principal: PostConditionPrincipal,
+    asset_identifier: &str,
+    condition_code: FungibleConditionCode,
+    amount: u64,
+) -> Result<TransactionPostCondition, String> {
+    Ok(TransactionPostCondition::Fungible(
+        principal,
+        parse_asset_info(asset_identifier)?,
+        condition_code,
+        amount,
+    ))
+}
+
+pub fn nft_post_condition(
+    principal: PostConditionPrincipal,
+    asset_identifier: &str,
+    asset_value: Value,
+    condition_code: NonfungibleConditionCode,
+) -> Result<TransactionPostCondition, String> {
+    Ok(TransactionPostCondition::Nonfungible(
+        principal,
+        parse_asset_info(asset_identifier)?,
+        asset_value,
+        condition_code,
+    ))
+}
+
 pub fn build_contrat_call_transaction(
     contract_id: String,
     function_name: String,
@@ -17,53 +442,101 @@ pub fn build_contrat_call_transaction(
     fee: u64,
     sender_secret_key: &[u8],
 ) -> StacksTransaction {
-    let contract_id =
-        QualifiedContractIdentifier::parse(&contract_id).expect("Contract identifier invalid");
-
-    let payload = TransactionContractCall {
-        address: contract_id.issuer.into(),
-        contract_name: contract_id.name.into(),
-        function_name: function_name.try_into().unwrap(),
-        function_args: args,
-    };
+    let keys = TransactionKeys::singlesig(sender_secret_key).expect("Secret key invalid");
+    TransactionBuilder::contract_call(&contract_id, &function_name, args)
+        .expect("Contract identifier invalid")
+        .nonce(nonce)
+        .fee(fee)
+        .build(&keys)
+        .expect("FATAL: invalid transaction")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
 
-    let secret_key = Secp256k1PrivateKey::from_slice(sender_secret_key).unwrap();
-    let mut public_key = Secp256k1PublicKey::from_private(&secret_key);
-    public_key.set_compressed(true);
+    const SECRET_KEY: &str = "753b7cc01a1a2e86221266a154af739463fce51219d97e4f856cd7200c3bd2a601";
+    const SPONSOR_SECRET_KEY: &str =
+        "7287ba251d44a4d3fd9276c88ce34c5c52a038955511cccaf77e61068649c17801";
 
-    let anchor_mode = TransactionAnchorMode::Any;
-    let signer_addr =
-        StacksAddress::from_public_keys(0, &AddressHashMode::SerializeP2PKH, 1, &vec![public_key])
+    fn keys(secret_key: &str) -> TransactionKeys {
+        let bytes = clarity_repl::clarity::util::hash::hex_bytes(secret_key).unwrap();
+        TransactionKeys::singlesig(&bytes).unwrap()
+    }
+
+    #[test]
+    fn sponsored_stx_transfer_with_post_conditions() {
+        let recipient = PrincipalData::parse("ST2CY5V39NHDPWSXMW9QDT3HC3GD6Q6XX4CFRK9AG").unwrap();
+        let tx = TransactionBuilder::stx_transfer(recipient, 1_000, None)
+            .nonce(3)
+            .anchor_mode(TransactionAnchorMode::OnChainOnly)
+            .post_condition_mode(TransactionPostConditionMode::Deny)
+            .post_condition(stx_post_condition(
+                parse_post_condition_principal("origin").unwrap(),
+                FungibleConditionCode::SentEq,
+                1_000,
+            ))
+            .sponsor(keys(SPONSOR_SECRET_KEY), 7, 300)
+            .build(&keys(SECRET_KEY))
             .unwrap();
 
-    let spending_condition = TransactionSpendingCondition::Singlesig(SinglesigSpendingCondition {
-        signer: signer_addr.bytes.clone(),
-        nonce: nonce,
-        tx_fee: fee,
-        hash_mode: SinglesigHashMode::P2PKH,
-        key_encoding: TransactionPublicKeyEncoding::Compressed,
-        signature: RecoverableSignature::empty(),
-    });
-
-    let auth = TransactionAuth::Standard(spending_condition);
-    let unsigned_tx = StacksTransaction {
-        version: TransactionVersion::Testnet,
-        chain_id: 0x80000000, // MAINNET=0x00000001
-        auth: auth,
-        anchor_mode: anchor_mode,
-        post_condition_mode: TransactionPostConditionMode::Allow,
-        post_conditions: vec![],
-        payload: TransactionPayload::ContractCall(payload),
-    };
+        let bytes = tx.serialize_to_vec();
+        let decoded = StacksTransaction::consensus_deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(decoded, tx);
+        match tx.auth {
+            TransactionAuth::Sponsored(ref origin, ref sponsor) => {
+                assert_eq!(origin.nonce(), 3);
+                assert_eq!(sponsor.nonce(), 7);
+                assert_eq!(sponsor.tx_fee(), 300);
+            }
+            _ => panic!("expected a sponsored transaction"),
+        }
+        assert_eq!(tx.post_conditions.len(), 1);
+        assert_eq!(tx.anchor_mode, TransactionAnchorMode::OnChainOnly);
+    }
 
-    let mut unsigned_tx_bytes = vec![];
-    unsigned_tx
-        .consensus_serialize(&mut unsigned_tx_bytes)
-        .expect("FATAL: invalid transaction");
+    #[test]
+    fn multisig_requires_enough_private_keys() {
+        let secret_key = match keys(SECRET_KEY) {
+            TransactionKeys::Singlesig(secret_key) => secret_key,
+            _ => unreachable!(),
+        };
+        let other_secret_key = match keys(SPONSOR_SECRET_KEY) {
+            TransactionKeys::Singlesig(secret_key) => secret_key,
+            _ => unreachable!(),
+        };
+        let public_keys = vec![&secret_key, &other_secret_key]
+            .into_iter()
+            .map(|secret_key| {
+                let mut public_key = Secp256k1PublicKey::from_private(secret_key);
+                public_key.set_compressed(true);
+                public_key
+            })
+            .collect::<Vec<_>>();
 
-    let mut tx_signer = StacksTransactionSigner::new(&unsigned_tx);
-    tx_signer.sign_origin(&secret_key).unwrap();
-    let signed_tx = tx_signer.get_tx().unwrap();
+        let builder =
+            TransactionBuilder::contract_deploy("counter", "(define-data-var count uint u0)")
+                .unwrap()
+                .fee(1_000);
+        let two_of_two = TransactionKeys::Multisig {
+            public_keys: public_keys.clone(),
+            private_keys: vec![secret_key.clone()],
+            signatures_required: 2,
+        };
+        assert!(builder.clone().build(&two_of_two).is_err());
 
-    signed_tx
+        let one_of_two = TransactionKeys::Multisig {
+            public_keys,
+            private_keys: vec![secret_key],
+            signatures_required: 1,
+        };
+        let tx = builder.build(&one_of_two).unwrap();
+        match tx.auth {
+            TransactionAuth::Standard(TransactionSpendingCondition::Multisig(ref condition)) => {
+                assert_eq!(condition.fields.len(), 2);
+            }
+            _ => panic!("expected a multisig transaction"),
+        }
+    }
 }